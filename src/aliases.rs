@@ -0,0 +1,118 @@
+//! Alternative names for languages, eg. `golang` or `c++`, accepted
+//! wherever a language is. Operators can add their own with
+//! `--language-aliases`, which the server loads with `load` and installs
+//! with `set` at startup.
+
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::OnceLock;
+use syntect::parsing::SyntaxSet;
+
+/// The built-in aliases, by the syntax token they stand for.
+const BUILTIN: &[(&str, &str)] = &[
+    ("golang", "go"),
+    ("shell", "sh"),
+    ("sh", "sh"),
+    ("zsh", "sh"),
+    ("console", "sh"),
+    ("node", "js"),
+    ("nodejs", "js"),
+    ("js", "js"),
+    ("jsx", "js"),
+    ("c++", "cpp"),
+    ("cplusplus", "cpp"),
+    ("ts", "ts"),
+    ("typescript", "ts"),
+    ("yml", "yaml"),
+    ("py3", "py"),
+    ("python3", "py"),
+    ("c#", "cs"),
+    ("csharp", "cs"),
+    ("objc", "m"),
+    ("objective-c", "m"),
+    ("kotlin", "kt"),
+    ("powershell", "ps1"),
+    ("docker", "Dockerfile"),
+    ("make", "Makefile"),
+    ("text", "txt"),
+    ("plain", "txt"),
+    ("plaintext", "txt"),
+    ("terraform", "tf"),
+    ("hcl", "tf"),
+    ("protobuf", "proto"),
+];
+
+/// Every alias, lowercased, and the token of its syntax.
+static ALIASES: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// Installs the aliases `resolve` uses. Only the first call has an effect,
+/// and until it's made, the built-in aliases are used.
+pub fn set(aliases: BTreeMap<String, String>) {
+    let _ = ALIASES.set(aliases);
+}
+
+fn aliases() -> &'static BTreeMap<String, String> {
+    ALIASES.get_or_init(|| {
+        BUILTIN
+            .iter()
+            .map(|(alias, token)| (alias.to_string(), token.to_string()))
+            .collect()
+    })
+}
+
+/// The syntax token `language` is an alias for, or `language` itself.
+pub fn resolve(language: &str) -> &str {
+    aliases()
+        .get(&language.trim().to_lowercase())
+        .map(String::as_str)
+        .unwrap_or(language)
+}
+
+/// Every alias, lowercased, and the token of its syntax.
+pub fn all() -> &'static BTreeMap<String, String> {
+    aliases()
+}
+
+/// The built-in aliases, then those in `path`, a JSON or TOML (by the `.toml`
+/// extension) map of aliases to the language they stand for, by name or
+/// token. An alias in the file replaces a built-in one. Only the aliases
+/// whose syntax is in `ps` are kept.
+pub fn load(path: Option<&Path>, ps: &SyntaxSet) -> Result<BTreeMap<String, String>, Error> {
+    let mut aliases = BTreeMap::new();
+    for (alias, token) in BUILTIN {
+        if ps.find_syntax_by_token(token).is_some() {
+            aliases.insert(alias.to_string(), token.to_string());
+        }
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(aliases),
+    };
+    let custom = read(path)?;
+    for (alias, language) in custom {
+        match ps.find_syntax_by_token(&language) {
+            Some(_) => {
+                aliases.insert(alias.trim().to_lowercase(), language);
+            }
+            None => {
+                tracing::warn!(alias = %alias, language = %language, "Skipping alias for an unknown language");
+            }
+        }
+    }
+    tracing::info!(path = %path.display(), count = aliases.len(), "Loaded language aliases");
+    Ok(aliases)
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let is_toml = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+    if is_toml {
+        Ok(toml::from_str(&contents)?)
+    } else {
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
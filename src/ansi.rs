@@ -0,0 +1,448 @@
+//! ANSI escape sequences in pasted terminal output. With the `strip_ansi`
+//! parameter they're removed, and with `ansi` the colors and styles of
+//! their SGR sequences are drawn instead of highlighting the code.
+//!
+//! CSI sequences like the `ESC[0;32m` of colored output, OSC sequences like
+//! the hyperlinks of `ls --hyperlink`, and the other escapes a terminal
+//! would act on rather than print are recognized. Only actual escape
+//! characters start a sequence, so a literal `\x1b` in a string is left
+//! alone. Sequences cut short end where the text stops looking like one,
+//! and an unterminated string sequence at the end of a line, so a stray
+//! escape never swallows the rest of the code.
+
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::str::Chars;
+use syntect::highlighting::{Color, FontStyle, Style, Theme};
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+
+/// The 8-bit forms of CSI and OSC, and of the other string sequences.
+const C1_CSI: char = '\u{9b}';
+const C1_OSC: char = '\u{9d}';
+const C1_STRINGS: [char; 4] = ['\u{90}', '\u{98}', '\u{9e}', '\u{9f}'];
+const C1_ST: char = '\u{9c}';
+
+/// The 16 basic colors, as xterm draws them.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// Removes the escape sequences from `code`.
+pub fn strip(code: &str) -> Cow<'_, str> {
+    if !has_escapes(code) {
+        return Cow::Borrowed(code);
+    }
+    let text = Tokens::new(code).filter_map(|token| match token {
+        Token::Text(c) => Some(c),
+        Token::Csi { .. } => None,
+    });
+    Cow::Owned(text.collect())
+}
+
+/// The attributes SGR sequences set on the text after them. Colors left
+/// unset are the theme's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+/// Terminal output with its escape sequences parsed out: the text, with
+/// tabs expanded as a terminal would, and the attributes of each run of it.
+#[derive(Debug, Clone, Default)]
+pub struct Parsed {
+    pub text: String,
+    /// How many characters of `text` each run covers, in order.
+    pub runs: Vec<(Attributes, usize)>,
+}
+
+/// Parses the SGR sequences of `code`, dropping every other sequence.
+pub fn parse(code: &str, tab_width: u8) -> Parsed {
+    let tab_width = tab_width.max(1) as usize;
+    let mut parsed = Parsed::default();
+    let mut attributes = Attributes::default();
+    let mut column = 0;
+    for token in Tokens::new(code) {
+        let (c, count) = match token {
+            Token::Csi { params, final_byte: 'm' } => {
+                apply_sgr(&mut attributes, &params);
+                continue;
+            }
+            Token::Csi { .. } => continue,
+            Token::Text('\t') => (' ', tab_width - column % tab_width),
+            Token::Text(c) => (c, 1),
+        };
+        column = if c == '\n' { 0 } else { column + count };
+        parsed.text.extend(std::iter::repeat(c).take(count));
+        match parsed.runs.last_mut() {
+            Some((last, len)) if *last == attributes => *len += count,
+            _ => parsed.runs.push((attributes, count)),
+        }
+    }
+    parsed
+}
+
+/// Splits `code`, the text of `parse` or the same number of characters
+/// derived from it, into lines of styled spans, as highlighting would.
+pub fn highlight<'a>(code: &'a str, runs: &[(Attributes, usize)], theme: &Theme) -> Vec<Vec<(Style, &'a str)>> {
+    let default_foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+    let default_background = theme.settings.background.unwrap_or(Color::BLACK);
+    let style = |attributes: &Attributes| {
+        let mut foreground = attributes.foreground.unwrap_or(default_foreground);
+        let mut background = attributes.background.unwrap_or(default_background);
+        if attributes.inverse {
+            std::mem::swap(&mut foreground, &mut background);
+        }
+        let mut font_style = FontStyle::empty();
+        font_style.set(FontStyle::BOLD, attributes.bold);
+        font_style.set(FontStyle::ITALIC, attributes.italic);
+        font_style.set(FontStyle::UNDERLINE, attributes.underline);
+        Style {
+            foreground,
+            background,
+            font_style,
+        }
+    };
+
+    // Runs are counted in characters, and each is split where lines end.
+    let mut runs = runs.iter().map(|(attributes, len)| (style(attributes), *len));
+    let mut current = runs.next();
+    let mut lines = vec![];
+    let mut line = vec![];
+    let mut start = 0;
+    let mut taken = 0;
+    for (i, c) in code.char_indices() {
+        let end = i + c.len_utf8();
+        taken += 1;
+        let (run_style, run_len) = current.unwrap_or((style(&Attributes::default()), usize::MAX));
+        let run_ends = taken >= run_len;
+        if c == '\n' || run_ends || end == code.len() {
+            line.push((run_style, &code[start..end]));
+            start = end;
+        }
+        if run_ends {
+            current = runs.next();
+            taken = 0;
+        }
+        if c == '\n' {
+            lines.push(std::mem::take(&mut line));
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+fn has_escapes(code: &str) -> bool {
+    code.contains(|c| c == ESC || c == C1_CSI || c == C1_OSC || C1_STRINGS.contains(&c))
+}
+
+/// A printed character of terminal output, or a complete CSI sequence,
+/// with its parameters and final byte. The other sequences are skipped.
+enum Token {
+    Text(char),
+    Csi { params: String, final_byte: char },
+}
+
+struct Tokens<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(code: &'a str) -> Self {
+        Tokens {
+            chars: code.chars().peekable(),
+        }
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let chars = &mut self.chars;
+        loop {
+            match chars.next()? {
+                ESC => match chars.peek() {
+                    Some('[') => {
+                        chars.next();
+                        if let Some(token) = read_csi(chars) {
+                            return Some(token);
+                        }
+                    }
+                    // OSC, and the DCS, SOS, PM, and APC strings.
+                    Some(']' | 'P' | 'X' | '^' | '_') => {
+                        chars.next();
+                        skip_string(chars);
+                    }
+                    // Escapes with intermediate bytes, like `ESC ( B`, which
+                    // selects a character set.
+                    Some(' '..='/') => {
+                        while chars.next_if(|c| matches!(c, ' '..='/')).is_some() {}
+                        chars.next_if(|c| matches!(c, '0'..='~'));
+                    }
+                    Some('0'..='~') => {
+                        chars.next();
+                    }
+                    // A lone escape, dropped on its own.
+                    _ => {}
+                },
+                C1_CSI => {
+                    if let Some(token) = read_csi(chars) {
+                        return Some(token);
+                    }
+                }
+                C1_OSC => skip_string(chars),
+                c if C1_STRINGS.contains(&c) => skip_string(chars),
+                c => return Some(Token::Text(c)),
+            }
+        }
+    }
+}
+
+/// Reads the parameter, intermediate, and final bytes of a CSI sequence,
+/// which is only a token when it's complete.
+fn read_csi(chars: &mut Peekable<Chars>) -> Option<Token> {
+    let mut params = String::new();
+    while let Some(c) = chars.next_if(|c| matches!(c, '0'..='?')) {
+        params.push(c);
+    }
+    while chars.next_if(|c| matches!(c, ' '..='/')).is_some() {}
+    chars
+        .next_if(|c| matches!(c, '@'..='~'))
+        .map(|final_byte| Token::Csi { params, final_byte })
+}
+
+/// Skips the body of a string sequence, through its BEL or string
+/// terminator, or up to the end of the line when it has none.
+fn skip_string(chars: &mut Peekable<Chars>) {
+    while let Some(&c) = chars.peek() {
+        match c {
+            '\n' | '\r' => return,
+            BEL | C1_ST => {
+                chars.next();
+                return;
+            }
+            ESC => {
+                chars.next();
+                chars.next_if_eq(&'\\');
+                return;
+            }
+            _ => {
+                chars.next();
+            }
+        }
+    }
+}
+
+/// Applies the parameters of an SGR sequence, eg. `1;38;5;208`, to
+/// `attributes`. Extended colors may also be given with colons, as in
+/// `38:2::255:128:0`. Unknown and malformed parameters are ignored.
+fn apply_sgr(attributes: &mut Attributes, params: &str) {
+    // Private sequences, like `ESC[>4;2m`, aren't SGR.
+    if params.starts_with(['<', '=', '>', '?']) {
+        return;
+    }
+    let mut params = params.split(';');
+    while let Some(param) = params.next() {
+        if param.contains(':') {
+            let mut sub: Vec<&str> = param.split(':').collect();
+            let target = sub.remove(0);
+            // Underline styles, like the curly `4:3`.
+            if target == "4" {
+                attributes.underline = sub.first() != Some(&"0");
+                continue;
+            }
+            // The colorspace ID of `38:2:<id>:r:g:b` is optional.
+            if sub.first() == Some(&"2") && sub.len() == 5 {
+                sub.remove(1);
+            }
+            if let Some(color) = extended_color(&mut sub.into_iter()) {
+                set_color(attributes, target, color);
+            }
+            continue;
+        }
+        let code: u16 = match param {
+            "" => 0,
+            param => match param.parse() {
+                Ok(code) => code,
+                Err(_) => continue,
+            },
+        };
+        match code {
+            0 => *attributes = Attributes::default(),
+            1 => attributes.bold = true,
+            3 => attributes.italic = true,
+            4 => attributes.underline = true,
+            7 => attributes.inverse = true,
+            22 => attributes.bold = false,
+            23 => attributes.italic = false,
+            24 => attributes.underline = false,
+            27 => attributes.inverse = false,
+            30..=37 => attributes.foreground = Some(palette(code as u8 - 30)),
+            39 => attributes.foreground = None,
+            40..=47 => attributes.background = Some(palette(code as u8 - 40)),
+            49 => attributes.background = None,
+            90..=97 => attributes.foreground = Some(palette(code as u8 - 90 + 8)),
+            100..=107 => attributes.background = Some(palette(code as u8 - 100 + 8)),
+            38 | 48 => {
+                if let Some(color) = extended_color(&mut params) {
+                    set_color(attributes, if code == 38 { "38" } else { "48" }, color);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn set_color(attributes: &mut Attributes, target: &str, color: Color) {
+    match target {
+        "38" => attributes.foreground = Some(color),
+        "48" => attributes.background = Some(color),
+        _ => {}
+    }
+}
+
+/// Reads the color after a `38` or `48`: `5;n` from the 256 color palette,
+/// or `2;r;g;b`.
+fn extended_color<'a>(params: &mut impl Iterator<Item = &'a str>) -> Option<Color> {
+    let mut next = || params.next().and_then(|param| param.parse::<u8>().ok());
+    match next()? {
+        5 => Some(palette(next()?)),
+        2 => Some(rgb(next()?, next()?, next()?)),
+        _ => None,
+    }
+}
+
+/// A color of the 256 color palette: the basic 16, a 6×6×6 cube, and 24
+/// grays.
+fn palette(index: u8) -> Color {
+    match index {
+        0..=15 => {
+            let (r, g, b) = PALETTE[index as usize];
+            rgb(r, g, b)
+        }
+        16..=231 => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+            let index = index - 16;
+            rgb(level(index / 36), level(index / 6 % 6), level(index % 6))
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            rgb(gray, gray, gray)
+        }
+    }
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> Color {
+    Color { r, g, b, a: 0xff }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of each run of `parsed`, with its attributes.
+    fn runs(parsed: &Parsed) -> Vec<(String, Attributes)> {
+        let mut chars = parsed.text.chars();
+        parsed
+            .runs
+            .iter()
+            .map(|&(attributes, len)| (chars.by_ref().take(len).collect(), attributes))
+            .collect()
+    }
+
+    fn colored(foreground: (u8, u8, u8), bold: bool) -> Attributes {
+        Attributes {
+            foreground: Some(rgb(foreground.0, foreground.1, foreground.2)),
+            bold,
+            ..Attributes::default()
+        }
+    }
+
+    /// `ls --color --hyperlink` of a directory, an executable, and a file.
+    const LS: &str = "\u{1b}[0m\u{1b}[01;34msrc\u{1b}[0m  \u{1b}[01;32mbuild.sh\u{1b}[0m*  \
+        \u{1b}]8;;file:///home/me/Cargo.toml\u{1b}\\Cargo.toml\u{1b}]8;;\u{1b}\\\n";
+
+    /// `git diff --color` of a one line change.
+    const GIT_DIFF: &str = "\u{1b}[1mdiff --git a/x b/x\u{1b}[m\n\u{1b}[36m@@ -1 +1 @@\u{1b}[m\n\
+        \u{1b}[31m-old\u{1b}[m\n\u{1b}[32m+new\u{1b}[m\n";
+
+    #[test]
+    fn ls_output_is_stripped() {
+        assert_eq!(strip(LS), "src  build.sh*  Cargo.toml\n");
+    }
+
+    #[test]
+    fn ls_output_is_parsed() {
+        let parsed = parse(LS, 4);
+        assert_eq!(parsed.text, "src  build.sh*  Cargo.toml\n");
+        assert_eq!(
+            runs(&parsed),
+            vec![
+                ("src".to_owned(), colored((0, 0, 238), true)),
+                ("  ".to_owned(), Attributes::default()),
+                ("build.sh".to_owned(), colored((0, 205, 0), true)),
+                ("*  Cargo.toml\n".to_owned(), Attributes::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn git_diff_output_is_stripped() {
+        assert_eq!(strip(GIT_DIFF), "diff --git a/x b/x\n@@ -1 +1 @@\n-old\n+new\n");
+    }
+
+    #[test]
+    fn git_diff_output_is_parsed() {
+        let parsed = parse(GIT_DIFF, 4);
+        let bold = Attributes {
+            bold: true,
+            ..Attributes::default()
+        };
+        assert_eq!(
+            runs(&parsed),
+            vec![
+                ("diff --git a/x b/x".to_owned(), bold),
+                ("\n".to_owned(), Attributes::default()),
+                ("@@ -1 +1 @@".to_owned(), colored((0, 205, 205), false)),
+                ("\n".to_owned(), Attributes::default()),
+                ("-old".to_owned(), colored((205, 0, 0), false)),
+                ("\n".to_owned(), Attributes::default()),
+                ("+new".to_owned(), colored((0, 205, 0), false)),
+                ("\n".to_owned(), Attributes::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_literal_escape_typed_as_text_is_left_alone() {
+        let code = "println!(\"\\x1b[31mred\\x1b[0m\");\n";
+        assert!(matches!(strip(code), Cow::Borrowed(_)));
+        let parsed = parse(code, 4);
+        assert_eq!(parsed.text, code);
+        assert_eq!(runs(&parsed), vec![(code.to_owned(), Attributes::default())]);
+    }
+}
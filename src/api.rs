@@ -0,0 +1,233 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use serde_json::json;
+use std::future::{ready, Ready};
+
+use inkify::render::RenderError;
+
+use crate::logging::LogFields;
+
+/// The API surface a request was routed through. Routes are mounted both at
+/// the root (legacy, frozen behavior) and under `/v1/`; the version is stored
+/// in the request extensions by the scope middleware so handlers can be
+/// shared between the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    Legacy,
+    V1,
+}
+
+impl ApiVersion {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::Legacy => "",
+            ApiVersion::V1 => "/v1",
+        }
+    }
+}
+
+impl FromRequest for ApiVersion {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let version = req
+            .extensions()
+            .get::<ApiVersion>()
+            .copied()
+            .unwrap_or(ApiVersion::Legacy);
+        ready(Ok(version))
+    }
+}
+
+/// How /generate returns the image, from the `response` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseMode {
+    /// The image bytes as the body.
+    #[default]
+    Binary,
+    /// A JSON object with the image in base64 and its metadata.
+    Json,
+}
+
+impl ResponseMode {
+    pub const NAMES: &'static [&'static str] = &["binary", "json"];
+}
+
+impl std::str::FromStr for ResponseMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "binary" => Ok(ResponseMode::Binary),
+            "json" => Ok(ResponseMode::Json),
+            _ => Err(anyhow::format_err!(
+                "Invalid response `{}`, expected one of: {}",
+                s,
+                ResponseMode::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// An error which can be rendered in the error format of either API version.
+///
+/// Legacy routes respond with `{"error": "<message>"}`, while `/v1` routes
+/// respond with `{"error": {"code": "<code>", "message": "<message>", "status": <status>}}`,
+/// plus the `request_id` when built for a request.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{message}")]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub headers: Vec<(&'static str, String)>,
+
+    /// Extra fields of the `/v1` error object, such as how long a render ran
+    /// before timing out.
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            headers: vec![],
+            details: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, name: &str, value: impl Into<serde_json::Value>) -> Self {
+        self.details.insert(name.to_owned(), value.into());
+        self
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn internal(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, code, message)
+    }
+
+    pub fn body(&self, version: ApiVersion) -> serde_json::Value {
+        match version {
+            ApiVersion::Legacy => json!({ "error": self.message }),
+            ApiVersion::V1 => {
+                let mut error = json!({
+                    "code": self.code,
+                    "message": self.message,
+                    "status": self.status.as_u16(),
+                });
+                for (name, value) in &self.details {
+                    error[name] = value.clone();
+                }
+                if let Some(request_id) = crate::requestid::current() {
+                    error["request_id"] = request_id.into();
+                }
+                json!({ "error": error })
+            }
+        }
+    }
+
+    pub fn to_response(&self, version: ApiVersion) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            res.append_header((*name, value.as_str()));
+        }
+        let mut res = res.json(self.body(version));
+        res.extensions_mut().insert(LogFields {
+            error_code: Some(self.code),
+            ..Default::default()
+        });
+        res
+    }
+}
+
+impl From<RenderError> for ApiError {
+    fn from(e: RenderError) -> Self {
+        let message = e.to_string();
+        match e {
+            RenderError::UnknownLanguage(_) => Self::bad_request("unknown_language", message),
+            RenderError::InvalidTheme(_) => Self::bad_request("invalid_theme", message),
+            RenderError::Highlight => Self::internal("highlight_failed", message),
+            RenderError::Draw(_) => Self::internal("formatter_failed", message),
+            RenderError::Encode => Self::internal("encode_failed", message),
+            RenderError::Cancelled => Self::new(StatusCode::SERVICE_UNAVAILABLE, "render_cancelled", message),
+        }
+    }
+}
+
+/// Error handler for query string deserialization failures on `/v1` routes,
+/// so they use the unified error format rather than actix's plain text body.
+pub fn v1_query_error(err: actix_web::error::QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = ApiError::bad_request("invalid_query", err.to_string()).to_response(ApiVersion::V1);
+    InternalError::from_response(err, response).into()
+}
+
+/// Fallback for unknown routes under `/v1`.
+pub async fn v1_not_found(req: HttpRequest) -> HttpResponse {
+    ApiError::not_found("not_found", format!("No route for {} {}", req.method(), req.path()))
+        .to_response(ApiVersion::V1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The legacy error body is a frozen contract: clients of the unprefixed
+    // routes match on a bare string under `error`.
+    #[test]
+    fn legacy_error_body_is_a_message() {
+        let error = ApiError::bad_request("invalid_theme", "Unknown theme `Nope`").with_detail("elapsed_ms", 12);
+        assert_eq!(error.body(ApiVersion::Legacy), json!({ "error": "Unknown theme `Nope`" }));
+    }
+
+    #[test]
+    fn v1_error_body_is_an_object() {
+        let error = ApiError::bad_request("invalid_theme", "Unknown theme `Nope`");
+        assert_eq!(
+            error.body(ApiVersion::V1),
+            json!({ "error": { "code": "invalid_theme", "message": "Unknown theme `Nope`", "status": 400 } })
+        );
+    }
+
+    #[test]
+    fn v1_error_body_has_details() {
+        let error = ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "render_timeout", "Too slow")
+            .with_detail("elapsed_ms", 5000);
+        assert_eq!(
+            error.body(ApiVersion::V1),
+            json!({
+                "error": { "code": "render_timeout", "message": "Too slow", "status": 503, "elapsed_ms": 5000 }
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn responses_have_the_status_headers_and_body_of_their_version() {
+        let error =
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "Slow down").with_header("Retry-After", "7");
+        for version in [ApiVersion::Legacy, ApiVersion::V1] {
+            let res = error.to_response(version);
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(res.headers().get("Retry-After").unwrap(), "7");
+            let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, error.body(version));
+        }
+    }
+}
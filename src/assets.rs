@@ -0,0 +1,114 @@
+//! The syntaxes and themes shared by every endpoint: silicon's defaults,
+//! plus any the operator installs at startup.
+
+use silicon::assets::HighlightingAssets;
+use std::path::{Path, PathBuf};
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::{SyntaxDefinition, SyntaxSet};
+
+/// A directory of assets which couldn't be read.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read the themes directory: {0}")]
+    Themes(std::io::Error),
+
+    #[error("Failed to read the syntaxes directory: {0}")]
+    Syntaxes(std::io::Error),
+}
+
+impl Error {
+    /// The setting naming the directory.
+    pub fn setting(&self) -> &'static str {
+        match self {
+            Error::Themes(_) => "INKIFY_THEMES_DIR",
+            Error::Syntaxes(_) => "INKIFY_SYNTAXES_DIR",
+        }
+    }
+}
+
+/// Loads the default assets, adding every `.tmTheme` file in `themes_dir`
+/// under its file name, as for a `theme` bundled with silicon, and every
+/// `.sublime-syntax` file in `syntaxes_dir` or below it. A theme with the
+/// name of a default one replaces it. Files which fail to parse are logged
+/// and skipped, but a directory which can't be read is an error.
+pub fn load(themes_dir: Option<&str>, syntaxes_dir: Option<&str>) -> Result<HighlightingAssets, Error> {
+    let mut assets = HighlightingAssets::new();
+    if let Some(dir) = themes_dir {
+        add_themes(&mut assets.theme_set, Path::new(dir)).map_err(Error::Themes)?;
+    }
+    if let Some(dir) = syntaxes_dir {
+        assets.syntax_set = add_syntaxes(assets.syntax_set, Path::new(dir)).map_err(Error::Syntaxes)?;
+    }
+    Ok(assets)
+}
+
+fn add_themes(theme_set: &mut ThemeSet, dir: &Path) -> std::io::Result<()> {
+    let entries = std::fs::read_dir(dir)?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("tmTheme"))
+        })
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        match ThemeSet::get_theme(&path) {
+            Ok(theme) => {
+                if theme_set.themes.insert(name.clone(), theme).is_some() {
+                    tracing::warn!(theme = %name, "Replacing a default theme");
+                }
+                tracing::info!(theme = %name, "Loaded theme");
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Skipping theme which failed to parse");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Links the syntax definitions found under `dir` into `syntax_set`, so
+/// they can reference the default syntaxes and each other.
+fn add_syntaxes(syntax_set: SyntaxSet, dir: &Path) -> std::io::Result<SyntaxSet> {
+    let mut paths = vec![];
+    find_files(dir, "sublime-syntax", &mut paths)?;
+    paths.sort();
+
+    let mut builder = syntax_set.into_builder();
+    for path in paths {
+        let name = path.file_stem().and_then(|stem| stem.to_str());
+        let definition = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| SyntaxDefinition::load_from_str(&s, true, name).map_err(|e| e.to_string()));
+        match definition {
+            Ok(definition) => {
+                tracing::info!(syntax = %definition.name, "Loaded syntax");
+                builder.add(definition);
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Skipping syntax which failed to parse");
+            }
+        }
+    }
+    Ok(builder.build())
+}
+
+/// Collects the files under `dir` with `extension`, recursively.
+fn find_files(dir: &Path, extension: &str, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, extension, out)?;
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension)) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
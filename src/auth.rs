@@ -0,0 +1,34 @@
+use actix_web::HttpRequest;
+
+/// Returns the credential presented by the request, from either an
+/// `Authorization: Bearer <key>` or an `X-Api-Key: <key>` header.
+pub fn presented_key(req: &HttpRequest) -> Option<&str> {
+    let bearer = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            let (scheme, token) = v.trim().split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then_some(token.trim())
+        });
+    bearer.or_else(|| {
+        req.headers()
+            .get("X-Api-Key")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim())
+    })
+}
+
+/// Whether `presented` matches any of `keys`. Every key is compared, and each
+/// comparison takes time independent of where the first mismatch is.
+pub fn key_matches(presented: &str, keys: &[String]) -> bool {
+    keys.iter()
+        .fold(false, |matched, key| constant_time_eq(presented.as_bytes(), key.as_bytes()) | matched)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
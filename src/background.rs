@@ -0,0 +1,132 @@
+//! Fitting a background image to the final canvas, for the `background_fit`
+//! and `background_position` parameters.
+
+use anyhow::Error;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// How a background image is fitted to the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fit {
+    /// Scaled to cover the canvas, cropping whatever overflows.
+    #[default]
+    Cover,
+    /// Scaled to fit inside the canvas, over the background color.
+    Contain,
+    /// Repeated at its own size from the top left.
+    Tile,
+    /// Scaled to the canvas, ignoring the aspect ratio.
+    Stretch,
+}
+
+impl Fit {
+    pub const NAMES: &'static [&'static str] = &["cover", "contain", "tile", "stretch"];
+}
+
+impl std::str::FromStr for Fit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cover" => Ok(Fit::Cover),
+            "contain" => Ok(Fit::Contain),
+            "tile" => Ok(Fit::Tile),
+            "stretch" => Ok(Fit::Stretch),
+            _ => Err(format_err!(
+                "Invalid background_fit `{}`, expected one of: {}",
+                s,
+                Fit::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Which part of a covering image is kept, or where a contained image is
+/// placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Position {
+    #[default]
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+impl Position {
+    pub const NAMES: &'static [&'static str] = &["center", "top", "bottom", "left", "right"];
+
+    /// Horizontal and vertical alignment, from 0 (left or top) to 1.
+    fn alignment(self) -> (f32, f32) {
+        match self {
+            Position::Center => (0.5, 0.5),
+            Position::Top => (0.5, 0.0),
+            Position::Bottom => (0.5, 1.0),
+            Position::Left => (0.0, 0.5),
+            Position::Right => (1.0, 0.5),
+        }
+    }
+}
+
+impl std::str::FromStr for Position {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "center" => Ok(Position::Center),
+            "top" => Ok(Position::Top),
+            "bottom" => Ok(Position::Bottom),
+            "left" => Ok(Position::Left),
+            "right" => Ok(Position::Right),
+            _ => Err(format_err!(
+                "Invalid background_position `{}`, expected one of: {}",
+                s,
+                Position::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Fits `image` to exactly `width` by `height`. `fill` shows around a
+/// contained image.
+pub fn fit(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    fit: Fit,
+    position: Position,
+    fill: Rgba<u8>,
+) -> RgbaImage {
+    let (iw, ih) = (image.width().max(1) as f32, image.height().max(1) as f32);
+    let (ax, ay) = position.alignment();
+    match fit {
+        Fit::Stretch => image.resize_exact(width, height, FilterType::Triangle).to_rgba8(),
+        Fit::Cover => {
+            let scale = (width as f32 / iw).max(height as f32 / ih);
+            let (sw, sh) = ((iw * scale).ceil() as u32, (ih * scale).ceil() as u32);
+            let mut scaled = image.resize_exact(sw, sh, FilterType::Triangle).to_rgba8();
+            let x = ((sw.saturating_sub(width)) as f32 * ax) as u32;
+            let y = ((sh.saturating_sub(height)) as f32 * ay) as u32;
+            imageops::crop(&mut scaled, x, y, width, height).to_image()
+        }
+        Fit::Contain => {
+            let scale = (width as f32 / iw).min(height as f32 / ih);
+            let (sw, sh) = (
+                ((iw * scale).round() as u32).clamp(1, width.max(1)),
+                ((ih * scale).round() as u32).clamp(1, height.max(1)),
+            );
+            let scaled = image.resize_exact(sw, sh, FilterType::Triangle).to_rgba8();
+            let mut canvas = RgbaImage::from_pixel(width, height, fill);
+            let x = ((width - sw) as f32 * ax) as i64;
+            let y = ((height - sh) as f32 * ay) as i64;
+            imageops::overlay(&mut canvas, &scaled, x, y);
+            canvas
+        }
+        Fit::Tile => {
+            let tile = image.to_rgba8();
+            let mut canvas = RgbaImage::new(width, height);
+            imageops::tile(&mut canvas, &tile);
+            canvas
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! The language badge, for the `show_language` parameter: a pill with the
+//! syntax name in a corner of the window, drawn over the formatter's output.
+
+use anyhow::Error;
+use image::{DynamicImage, Rgba, RgbaImage};
+use syntect::highlighting::{Color, Theme};
+
+use crate::config::{Config, WindowStyle};
+use crate::fontcache;
+use crate::highlight::{Layout, CODE_PAD};
+use crate::watermark;
+
+/// Size of the badge font relative to the code's.
+const FONT_RATIO: f32 = 0.6;
+
+/// Padding inside the pill, and between it and the window's edge, before
+/// scaling.
+const PAD_HORIZ: u32 = 8;
+const PAD_VERT: u32 = 3;
+const MARGIN: u32 = 12;
+
+/// Width silicon's traffic lights take up. Silicon draws them at a fixed
+/// size, so this isn't scaled.
+const CONTROLS_WIDTH: u32 = 90;
+
+/// Opacity of the pill over the window when no color is given.
+const DEFAULT_OPACITY: u8 = 0x33;
+
+/// Which corner of the window the badge is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Position {
+    TopLeft,
+    #[default]
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Position {
+    pub const NAMES: &'static [&'static str] = &["top-left", "top-right", "bottom-left", "bottom-right"];
+
+    fn is_top(self) -> bool {
+        matches!(self, Position::TopLeft | Position::TopRight)
+    }
+
+    fn is_left(self) -> bool {
+        matches!(self, Position::TopLeft | Position::BottomLeft)
+    }
+}
+
+impl std::str::FromStr for Position {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "top-left" => Ok(Position::TopLeft),
+            "top-right" => Ok(Position::TopRight),
+            "bottom-left" => Ok(Position::BottomLeft),
+            "bottom-right" => Ok(Position::BottomRight),
+            _ => Err(format_err!(
+                "Invalid badge_position `{}`, expected one of: {}",
+                s,
+                Position::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Draws a badge reading `text` onto `image`, the bare window out of the
+/// formatter with `row_count` rows.
+///
+/// At the top, the badge sits in the space above the first row, which holds
+/// silicon's title bar when it drew one; a badge which would run into the
+/// title moves to the bottom corner on the same side. At the bottom it sits
+/// in the padding below the last row.
+pub fn draw(
+    image: DynamicImage,
+    text: &str,
+    row_count: u32,
+    conf: &Config,
+    theme: &Theme,
+) -> Result<DynamicImage, Error> {
+    let mut image = image.to_rgba8();
+    let layout = Layout::new(image.height(), row_count, conf)?;
+    let font: Vec<(String, f32)> = conf
+        .title_font()
+        .into_iter()
+        .map(|(name, size)| (name, size * FONT_RATIO))
+        .collect();
+    let fonts = fontcache::collection(&font)?;
+    let (pad_horiz, pad_vert) = (conf.scaled(PAD_HORIZ), conf.scaled(PAD_VERT));
+    let width = fonts.get_text_len(text) + 2 * pad_horiz;
+    let height = fonts.get_font_height() + 2 * pad_vert;
+    let margin = conf.scaled(MARGIN);
+
+    let mac_bar = conf.window_style == WindowStyle::Mac;
+    let controls = if mac_bar && !conf.no_window_controls { CONTROLS_WIDTH } else { 0 };
+    let x_at = |position: Position| {
+        if position.is_left() {
+            margin + if position.is_top() { controls } else { 0 }
+        } else {
+            image.width().saturating_sub(width + margin)
+        }
+    };
+    let top_band = layout.row_top(1);
+    let bottom_band = (image.height().saturating_sub(CODE_PAD), image.height());
+
+    let mut position = conf.badge_position;
+    if position.is_top() && mac_bar {
+        if let Some(title) = conf.window_title.as_deref().filter(|title| !title.is_empty()) {
+            let title_width = fontcache::collection(&conf.title_font())?.get_text_len(title);
+            let title_left = image.width().saturating_sub(title_width) / 2;
+            let x = x_at(position);
+            if x < title_left + title_width && title_left < x + width {
+                position = if position.is_left() {
+                    Position::BottomLeft
+                } else {
+                    Position::BottomRight
+                };
+            }
+        }
+    }
+
+    let (band_top, band_bottom) = if position.is_top() {
+        (0, top_band)
+    } else {
+        bottom_band
+    };
+    let x = x_at(position);
+    let y = band_top + (band_bottom - band_top).saturating_sub(height) / 2;
+
+    let foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+    let (fill, text_color) = match conf.badge_color {
+        Some(fill) => (fill, contrasting(fill)),
+        None => (
+            Rgba([foreground.r, foreground.g, foreground.b, DEFAULT_OPACITY]),
+            Rgba([foreground.r, foreground.g, foreground.b, 255]),
+        ),
+    };
+    draw_pill(&mut image, x, y, width, height, fill);
+    watermark::draw_text(&mut image, &fonts, text, x + pad_horiz, y + pad_vert, text_color, 1.0);
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+/// Fills a rectangle with fully rounded ends, anti-aliasing the edge.
+fn draw_pill(image: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    let r = height as f32 / 2.0;
+    let (left, right) = (x as f32 + r, (x + width) as f32 - r);
+    let cy = y as f32 + r;
+    let alpha = color.0[3] as f32 / 255.0;
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            let (fx, fy) = (px as f32 + 0.5, py as f32 + 0.5);
+            let dx = if fx < left { left - fx } else if fx > right { fx - right } else { 0.0 };
+            let dy = fy - cy;
+            let coverage = (r - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0);
+            watermark::over(image.get_pixel_mut(px, py), color, alpha * coverage);
+        }
+    }
+}
+
+/// Black or white, whichever reads better on `color`.
+fn contrasting(color: Rgba<u8>) -> Rgba<u8> {
+    let [r, g, b, _] = color.0;
+    let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+    if luma > 150.0 {
+        Rgba([0, 0, 0, 255])
+    } else {
+        Rgba([255, 255, 255, 255])
+    }
+}
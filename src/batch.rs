@@ -0,0 +1,86 @@
+//! `POST /generate/batch`, which renders several images in one request and
+//! returns them as a zip archive or a JSON array. Every item succeeds or
+//! fails on its own.
+
+use anyhow::Error;
+use std::io::{Cursor, Write};
+
+use crate::api::ApiError;
+use crate::query::{self, ConfigQuery};
+
+/// The most items a batch may have when `INKIFY_MAX_BATCH_SIZE` isn't set.
+pub const DEFAULT_MAX_SIZE: usize = 50;
+
+const MAX_ID_LEN: usize = 100;
+
+/// An item of a batch, or the error which made it unusable.
+pub struct Item {
+    pub id: String,
+    pub query: Result<(ConfigQuery, Vec<String>), ApiError>,
+}
+
+/// Parses a JSON array of parameter objects, each with an `id`. The batch
+/// as a whole is rejected when it isn't such an array, is too large, or has
+/// missing, invalid, or duplicate IDs, while an item with invalid
+/// parameters only fails that item.
+pub fn parse(body: &[u8], max_size: usize) -> Result<Vec<Item>, ApiError> {
+    let invalid = |message: String| ApiError::bad_request("invalid_batch", message);
+    let items: Vec<serde_json::Value> =
+        serde_json::from_slice(body).map_err(|e| invalid(format!("Expected a JSON array of objects: {}", e)))?;
+    if items.is_empty() {
+        return Err(invalid("The batch is empty".to_owned()));
+    }
+    if items.len() > max_size {
+        return Err(ApiError::new(
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            format!("A batch may have at most {} items, got {}", max_size, items.len()),
+        ));
+    }
+
+    let mut parsed: Vec<Item> = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        let mut params = match item {
+            serde_json::Value::Object(params) => params,
+            _ => return Err(invalid(format!("Item {} isn't an object", i))),
+        };
+        let id = match params.remove("id") {
+            Some(serde_json::Value::String(id)) if valid_id(&id) => id,
+            Some(_) => {
+                return Err(invalid(format!(
+                    "The id of item {} should be 1 to {} letters, digits, '.', '_', or '-'",
+                    i, MAX_ID_LEN
+                )))
+            }
+            None => return Err(invalid(format!("Item {} has no id", i))),
+        };
+        if parsed.iter().any(|other| other.id == id) {
+            return Err(invalid(format!("The id `{}` is used more than once", id)));
+        }
+        let query = serde_json::to_vec(&params)
+            .map_err(Error::from)
+            .and_then(|params| query::parse_json_query(&params).map_err(Error::from))
+            .map_err(|e| ApiError::bad_request("invalid_parameter", e.to_string()));
+        parsed.push(Item { id, query });
+    }
+    Ok(parsed)
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && !id.starts_with('.')
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// A zip archive of the `files`, by name. The images are already
+/// compressed, so they're stored as is.
+pub fn zip(files: &[(String, &[u8])]) -> Result<Vec<u8>, Error> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, bytes) in files {
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(bytes)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
@@ -0,0 +1,291 @@
+use anyhow::Error;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crate::api::ApiVersion;
+use crate::query::ConfigQuery;
+use crate::metrics::METRICS;
+
+/// Starts an entry serialized by `Rendered::encode`.
+const MAGIC: &[u8] = b"inkify-cache 1\n";
+
+/// A store of rendered images by cache key. The stores are tried in order,
+/// fastest first, and a hit is copied into the faster ones.
+pub trait CacheBackend: Send + Sync {
+    /// Named in the `X-Cache-Tier` header of hits.
+    fn name(&self) -> &'static str;
+
+    /// Whether the methods block on IO, and so have to be called on the
+    /// blocking pool.
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    /// The image stored for `key`, when there's one of `content_type`.
+    fn get(&self, key: &str, content_type: &str) -> Option<Arc<Rendered>>;
+
+    /// Stores the image for `key`. Failures are logged rather than
+    /// returned, since a cache is only an optimization.
+    fn insert(&self, key: &str, rendered: Arc<Rendered>);
+}
+
+/// An encoded image together with the headers describing how it was made,
+/// so cache hits respond identically to the original render.
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+
+    /// Name of the syntax used, whether requested or detected.
+    pub language: String,
+
+    /// Name of the theme used.
+    pub theme: String,
+
+    /// The window title drawn, if any.
+    pub window_title: Option<String>,
+
+    /// Dimensions of the image in pixels.
+    pub width: u32,
+    pub height: u32,
+
+    /// How long the original render took.
+    pub render_ms: u64,
+}
+
+/// The metadata of a serialized `Rendered`.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Header {
+    content_type: String,
+    headers: Vec<(String, String)>,
+    language: String,
+    theme: String,
+    window_title: Option<String>,
+    width: u32,
+    height: u32,
+    render_ms: u64,
+    len: usize,
+    sha256: String,
+}
+
+impl Rendered {
+    /// Serializes the image for the caches outside the process, as a magic
+    /// line, the metadata as a line of JSON, then the image.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let header = Header {
+            content_type: self.content_type.clone(),
+            headers: self.headers.clone(),
+            language: self.language.clone(),
+            theme: self.theme.clone(),
+            window_title: self.window_title.clone(),
+            width: self.width,
+            height: self.height,
+            render_ms: self.render_ms,
+            len: self.bytes.len(),
+            sha256: checksum(&self.bytes),
+        };
+        let mut encoded = MAGIC.to_vec();
+        serde_json::to_writer(&mut encoded, &header)?;
+        encoded.push(b'\n');
+        encoded.extend_from_slice(&self.bytes);
+        Ok(encoded)
+    }
+
+    /// Deserializes an image serialized by `encode`, failing when it's
+    /// corrupted, including when the image doesn't match its checksum.
+    pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+        let rest = encoded
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| format_err!("Not a cache entry"))?;
+        let newline = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| format_err!("The metadata is truncated"))?;
+        let header: Header = serde_json::from_slice(&rest[..newline])?;
+        let bytes = &rest[newline + 1..];
+        if bytes.len() != header.len || checksum(bytes) != header.sha256 {
+            return Err(format_err!("The image doesn't match its checksum"));
+        }
+        Ok(Rendered {
+            bytes: bytes.to_vec(),
+            content_type: header.content_type,
+            headers: header.headers,
+            language: header.language,
+            theme: header.theme,
+            window_title: header.window_title,
+            width: header.width,
+            height: header.height,
+            render_ms: header.render_ms,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+            + self.language.len()
+            + self.theme.len()
+            + self.window_title.as_ref().map_or(0, |title| title.len())
+            + self
+                .headers
+                .iter()
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>()
+    }
+}
+
+/// Where a generated image came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// One of the caches, by name.
+    Cache(&'static str),
+
+    /// A render made for this request.
+    Rendered,
+
+    /// A render of the same image another request had in flight.
+    Coalesced,
+}
+
+/// Mixed into every key, so the disk and Redis caches, which outlive the
+/// process, don't serve images rendered by another version after an upgrade.
+const KEY_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Hashes the normalized generation parameters. The query is serialized
+/// after deserialization, so equivalent spellings of a value (eg. `080` and
+/// `80`) share a key. The background image URL is part of the query and so
+/// part of the key.
+pub fn cache_key(query: &ConfigQuery, version: ApiVersion) -> String {
+    let normalized = serde_json::to_vec(query).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_VERSION.as_bytes());
+    hasher.update([0]);
+    hasher.update(version.prefix().as_bytes());
+    hasher.update([0]);
+    hasher.update(&normalized);
+    hex_digest(hasher)
+}
+
+/// Hashes the themes and sample language of a theme preview. Previews
+/// don't depend on the API version, and the prefix keeps their keys apart
+/// from those of generated images.
+pub fn preview_key(themes: &[String], language: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_VERSION.as_bytes());
+    hasher.update([0]);
+    hasher.update(b"preview\0");
+    hasher.update(language.as_bytes());
+    for theme in themes {
+        hasher.update([0]);
+        hasher.update(theme.as_bytes());
+    }
+    hex_digest(hasher)
+}
+
+fn checksum(bytes: &[u8]) -> String {
+    hex_digest(Sha256::new_with_prefix(bytes))
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// LRU cache of rendered images, bounded both by entry count and total
+/// bytes.
+pub struct RenderCache {
+    max_bytes: usize,
+    inner: Option<Mutex<Inner>>,
+}
+
+struct Inner {
+    entries: LruCache<String, Arc<Rendered>>,
+    bytes: usize,
+}
+
+impl RenderCache {
+    /// Creates a cache. A capacity of zero entries or bytes disables caching.
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        let inner = NonZeroUsize::new(max_entries)
+            .filter(|_| max_bytes > 0)
+            .map(|cap| {
+                Mutex::new(Inner {
+                    entries: LruCache::new(cap),
+                    bytes: 0,
+                })
+            });
+        RenderCache { max_bytes, inner }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<Rendered>> {
+        let inner = self.inner.as_ref()?;
+        let hit = inner.lock().unwrap().entries.get(key).cloned();
+        if hit.is_some() {
+            METRICS.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            METRICS.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    pub fn insert(&self, key: String, rendered: Arc<Rendered>) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let size = rendered.size();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = inner.lock().unwrap();
+        inner.bytes += size;
+        if let Some((_, evicted)) = inner.entries.push(key, rendered) {
+            inner.bytes -= evicted.size();
+        }
+        while inner.bytes > self.max_bytes {
+            match inner.entries.pop_lru() {
+                Some((_, evicted)) => inner.bytes -= evicted.size(),
+                None => break,
+            }
+        }
+        METRICS.memory_bytes.store(inner.bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Drops every entry, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return 0,
+        };
+        let mut inner = inner.lock().unwrap();
+        let cleared = inner.entries.len();
+        inner.entries.clear();
+        inner.bytes = 0;
+        METRICS.memory_bytes.store(0, Ordering::Relaxed);
+        cleared
+    }
+}
+
+impl CacheBackend for RenderCache {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn blocks(&self) -> bool {
+        false
+    }
+
+    fn get(&self, key: &str, content_type: &str) -> Option<Arc<Rendered>> {
+        RenderCache::get(self, key).filter(|rendered| rendered.content_type == content_type)
+    }
+
+    fn insert(&self, key: &str, rendered: Arc<Rendered>) {
+        RenderCache::insert(self, key.to_owned(), rendered)
+    }
+}
@@ -0,0 +1,36 @@
+//! Cancelling a render which outlived its timeout. Renders check the flag
+//! between stages, so the thread is freed soon after the request gave up on
+//! it rather than when the render would have finished.
+
+use actix_web::http::StatusCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::api::ApiError;
+
+/// Shared between a render and the request waiting on it. The default is
+/// never cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails once the render has been cancelled, stopping it at this stage.
+    pub fn check(&self) -> Result<(), ApiError> {
+        if self.is_cancelled() {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "render_cancelled",
+                "The render was cancelled",
+            ));
+        }
+        Ok(())
+    }
+}
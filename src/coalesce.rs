@@ -0,0 +1,77 @@
+//! Coalescing identical renders. When several requests for the same image
+//! arrive together, eg. a link unfurled by a few chat clients at once, the
+//! first renders it and the rest await the same render.
+
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+
+use crate::api::ApiError;
+use crate::cache::Rendered;
+
+type Render = Shared<BoxFuture<'static, Result<Arc<Rendered>, ApiError>>>;
+
+/// The renders in flight, by cache key.
+#[derive(Default)]
+pub struct Coalescer {
+    in_flight: Mutex<HashMap<String, Render>>,
+}
+
+impl Coalescer {
+    /// Awaits the render of `key` already in flight, or starts one with
+    /// `render`. The flag is true when another request started the render.
+    /// A failed or panicked render fails every request awaiting it, and the
+    /// render leaves the map once it's done, whichever way it ends.
+    pub async fn render<F>(
+        &'static self,
+        key: &str,
+        render: impl FnOnce() -> F,
+    ) -> (Result<Arc<Rendered>, ApiError>, bool)
+    where
+        F: Future<Output = Result<Arc<Rendered>, ApiError>> + Send + 'static,
+    {
+        let (shared, coalesced) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(shared) => (shared.clone(), true),
+                None => {
+                    let done = Done {
+                        coalescer: self,
+                        key: key.to_owned(),
+                    };
+                    let future = render();
+                    let shared = async move {
+                        let _done = done;
+                        AssertUnwindSafe(future)
+                            .catch_unwind()
+                            .await
+                            .unwrap_or_else(|_| Err(ApiError::internal("render_failed", "The render panicked")))
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.insert(key.to_owned(), shared.clone());
+                    (shared, false)
+                }
+            }
+        };
+        (shared.await, coalesced)
+    }
+}
+
+/// Removes a render from the map when it completes, panics, or is dropped.
+struct Done {
+    coalescer: &'static Coalescer,
+    key: String,
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        // Taken out of the map before it's dropped, since dropping the last
+        // handle would drop this again while the lock is held.
+        let removed = self.coalescer.in_flight.lock().unwrap().remove(&self.key);
+        drop(removed);
+    }
+}
@@ -1,17 +1,53 @@
 use anyhow::Error;
-use std::collections::HashMap;
+use image::RgbaImage;
+use regex::Regex;
 use silicon::formatter::{ImageFormatter, ImageFormatterBuilder};
 use silicon::utils::{Background, ShadowAdder};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use syntect::highlighting::{Theme, ThemeSet};
 use syntect::parsing::{SyntaxReference, SyntaxSet};
-use tensorflow::{Graph, SavedModelBundle, SessionOptions, Tensor};
 
+use crate::aliases;
+use crate::background;
+use crate::badge;
+use crate::detector::{self, LanguageDetector, PredictError};
+use crate::gradient::Gradient;
+use crate::heuristic;
 use crate::rgba::{ImageRgba, Rgba};
+use crate::secrets;
+use crate::suggest;
+use crate::watermark::Watermark;
+use crate::whitespace;
+use crate::window::{self, Caption, Layout, Preset};
 
 type FontList = Vec<(String, f32)>;
 type Lines = Vec<u32>;
 
+/// Silicon's font when none is given.
+pub const DEFAULT_FONT: &str = "Hack";
+const DEFAULT_FONT_SIZE: f32 = 26.0;
+
+/// Title of a window of plain text when none is given.
+const DEFAULT_WINDOW_TITLE: &str = "Inkify";
+
+/// Silicon's padding to the right of the code.
+pub const DEFAULT_CODE_PAD_RIGHT: u32 = 25;
+
+/// Row shown between disjoint line ranges.
+pub const RANGE_SEPARATOR: &str = "…";
+
+/// Silicon's corner radius, used when composited without a `corner_radius`.
+pub const DEFAULT_CORNER_RADIUS: u32 = 12;
+
+/// Upper bound of the `corner_radius` parameter.
+pub const MAX_CORNER_RADIUS: u32 = 50;
+
+/// Bounds of the `scale` parameter.
+pub const MIN_SCALE: f32 = 1.0;
+pub const MAX_SCALE: f32 = 4.0;
+
 macro_rules! unwrap_or_return {
     ( $e:expr, $r:expr ) => {
         match $e {
@@ -21,7 +57,7 @@ macro_rules! unwrap_or_return {
     };
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct Config {
     /// Background image URL
     pub background_image: Option<Vec<u8>>,
@@ -38,9 +74,33 @@ pub struct Config {
     /// Lines to high light. rg. '1-3; 4'
     pub highlight_lines: Option<Lines>,
 
+    /// Lines highlighted in their own color, eg. '1-3:#2ecc4040'. A line is
+    /// in at most one of these and `highlight_lines`.
+    #[serde(skip)]
+    pub highlight_colors: Vec<(u32, ImageRgba<u8>)>,
+
+    /// Lines to cover with a solid block.
+    #[serde(skip)]
+    pub redact_lines: Option<Lines>,
+
+    /// Text to cover with a solid block wherever it matches in a line.
+    #[serde(skip)]
+    pub redact_pattern: Option<Regex>,
+
+    /// Cover secrets matching the rules in `secrets`.
+    pub auto_redact: bool,
+
+    /// Names of the `secrets` rules not to apply.
+    #[serde(skip)]
+    pub auto_redact_allow: Vec<String>,
+
     /// The language for syntax highlighting. You can use full name ("Rust") or file extension ("rs").
     pub language: Option<String>,
 
+    /// The code's file name, whose extension picks the language when it
+    /// isn't given.
+    pub filename: Option<String>,
+
     /// Pad between lines
     pub line_pad: u32,
 
@@ -80,14 +140,203 @@ pub struct Config {
     /// Tab width
     pub tab_width: u8,
 
-    /// The syntax highlight theme. It can be a theme name or path to a .tmTheme file.
+    /// The syntax highlight theme. It can be a theme name, or the path to a
+    /// .tmTheme file when the server allows it.
     pub theme: String,
 
+    /// A theme given in the request, used instead of `theme`.
+    #[serde(skip)]
+    pub inline_theme: Option<Theme>,
+
+    /// Families appended to the font list, for text it can't draw.
+    #[serde(skip)]
+    pub fallback_fonts: Vec<String>,
+
+    /// Multiplier applied to font sizes, paddings, and shadows for high-DPI output.
+    pub scale: f32,
+
+    /// Wrap lines longer than this many columns.
+    pub max_width: Option<usize>,
+
+    /// The line ranges `code` was sliced to, after clamping.
+    pub line_range: Option<Vec<(u32, u32)>>,
+
+    /// Whether `code` is a unified diff, rendered by `diff`.
+    pub diff: bool,
+
+    /// Whether `code` is terminal output, drawn in the colors of its ANSI
+    /// escape sequences rather than highlighted.
+    pub ansi: bool,
+
+    /// Text drawn below the code, set apart by a blank row, like the output
+    /// of a notebook cell. It isn't highlighted, and its rows aren't
+    /// numbered.
+    pub output: Option<String>,
+
+    /// Refuse to render when the language can only be guessed with low
+    /// confidence.
+    pub strict_language: bool,
+
+    /// The language used when detection finds none. Plain text when unset.
+    pub fallback_language: Option<String>,
+
+    /// Which whitespace is drawn visibly, if any.
+    #[serde(skip)]
+    pub show_whitespace: Option<whitespace::Mode>,
+
+    /// Draw a guide line at each level of indentation.
+    pub indent_guides: bool,
+
+    /// Color of the indentation guides. Defaults to a faint theme foreground.
+    #[serde(skip)]
+    pub indent_guide_color: Option<ImageRgba<u8>>,
+
+    /// Draw the language's name in a corner of the window.
+    pub show_language: bool,
+
+    /// Which corner of the window the language badge is in.
+    #[serde(skip)]
+    pub badge_position: badge::Position,
+
+    /// Color of the language badge. Defaults to a faint theme foreground.
+    #[serde(skip)]
+    pub badge_color: Option<ImageRgba<u8>>,
+
+    /// How the window around the code is drawn.
+    #[serde(default)]
+    pub window_style: WindowStyle,
+
+    /// Radius of the window's corners, overriding silicon's default.
+    pub corner_radius: Option<u32>,
+
+    /// Gradient for the padding area, in place of the background color.
+    #[serde(skip)]
+    pub background_gradient: Option<Gradient>,
+
+    /// How the background image is fitted to the canvas.
+    #[serde(skip)]
+    pub background_fit: background::Fit,
+
+    /// Which part of the background image is kept or where it's placed.
+    #[serde(skip)]
+    pub background_position: background::Position,
+
+    /// Text or an image drawn onto the final image.
+    #[serde(skip)]
+    pub watermark: Option<Watermark>,
+
+    /// Text drawn beneath the window.
+    #[serde(skip)]
+    pub caption: Option<Caption>,
+
+    /// Fixed size canvas the window is centered on, in place of the padding.
+    #[serde(skip)]
+    pub preset: Option<Preset>,
+
+    /// How the windows are arranged, when the image is of several snippets,
+    /// and only then. Each snippet is drawn with a config of its own, which
+    /// shares the settings of the image's.
+    #[serde(skip)]
+    pub layout: Option<Layout>,
+
+    /// Space between the windows of snippets.
+    pub snippet_gap: u32,
+
+    /// Largest width and height of the final image, which is scaled down
+    /// to fit when it's larger.
+    pub max_output_width: Option<u32>,
+    pub max_output_height: Option<u32>,
+
+    /// Render byte-identical images for identical inputs: the detection
+    /// model, which may time out, is skipped for the heuristic.
+    pub deterministic: bool,
+
+    /// How hard the PNG encoder works.
+    #[serde(default)]
+    pub png_compression: PngCompression,
+
+    /// The language detection model, if one is loaded.
     #[serde(skip_deserializing)]
-    pub tf_model_graph: Option<Graph>,
-    
-    #[serde(skip_deserializing)]
-    pub tf_model: Option<SavedModelBundle>,
+    pub detector: Option<Arc<dyn LanguageDetector>>,
+
+    /// How long the detection model may take before plain text is used.
+    #[serde(skip)]
+    pub detection_timeout: Duration,
+}
+
+/// The window drawn around the code. Silicon draws the `mac` style itself;
+/// the others are composited around its output by `window::decorate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowStyle {
+    /// Traffic light controls and a centered title.
+    #[default]
+    Mac,
+    /// Minimize, maximize, and close buttons on the right and a left-aligned title.
+    Windows,
+    /// A title bar with a centered title and no buttons.
+    Bar,
+    /// No controls and no title area.
+    None,
+}
+
+impl WindowStyle {
+    pub const NAMES: &'static [&'static str] = &["mac", "windows", "bar", "none"];
+
+    /// Whether the title bar is drawn by `window::decorate` rather than silicon.
+    pub fn is_composited(self) -> bool {
+        matches!(self, WindowStyle::Windows | WindowStyle::Bar)
+    }
+}
+
+impl std::str::FromStr for WindowStyle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mac" => Ok(WindowStyle::Mac),
+            "windows" => Ok(WindowStyle::Windows),
+            "bar" => Ok(WindowStyle::Bar),
+            "none" => Ok(WindowStyle::None),
+            _ => Err(Error::msg(format!(
+                "Invalid window_style `{}`, expected one of: {}",
+                s,
+                WindowStyle::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+/// How hard the PNG encoder works, trading the size of the image for the
+/// time it takes to encode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    pub const NAMES: &'static [&'static str] = &["fast", "default", "best"];
+}
+
+impl std::str::FromStr for PngCompression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(Error::msg(format!(
+                "Invalid png_compression `{}`, expected one of: {}",
+                s,
+                PngCompression::NAMES.join(", ")
+            ))),
+        }
+    }
 }
 
 impl Config {
@@ -98,7 +347,13 @@ impl Config {
             code: "".to_owned(),
             font: None,
             highlight_lines: None,
+            highlight_colors: vec![],
+            redact_lines: None,
+            redact_pattern: None,
+            auto_redact: false,
+            auto_redact_allow: vec![],
             language: None,
+            filename: None,
             line_pad: 2,
             line_offset: 1,
             no_window_controls: false,
@@ -113,190 +368,609 @@ impl Config {
             shadow_offset_x: 0,
             tab_width: 4,
             theme: "Dracula".to_owned(),
-            tf_model_graph: None,
-            tf_model: None,
+            inline_theme: None,
+            fallback_fonts: vec![],
+            scale: 1.0,
+            max_width: None,
+            line_range: None,
+            diff: false,
+            ansi: false,
+            output: None,
+            strict_language: false,
+            fallback_language: None,
+            show_whitespace: None,
+            indent_guides: false,
+            indent_guide_color: None,
+            show_language: false,
+            badge_position: badge::Position::TopRight,
+            badge_color: None,
+            window_style: WindowStyle::Mac,
+            corner_radius: None,
+            background_gradient: None,
+            background_fit: background::Fit::Cover,
+            background_position: background::Position::Center,
+            watermark: None,
+            caption: None,
+            preset: None,
+            layout: None,
+            snippet_gap: window::DEFAULT_SNIPPET_GAP,
+            max_output_width: None,
+            max_output_height: None,
+            deterministic: false,
+            png_compression: PngCompression::Default,
+            detector: None,
+            detection_timeout: detector::DEFAULT_TIMEOUT,
         }
     }
 
-    pub fn load_tensorflow_model(&mut self, export_dir: &str) {
-        let mut graph = Graph::new();
-        let model = match SavedModelBundle::load(&SessionOptions::new(), &["serve"], &mut graph, export_dir) {
-            Ok(model) => model,
-            Err(e) => {
-                eprintln!("Failed to load TensorFlow model: {}", e);
-                return;
+    pub fn language<'a>(&self, ps: &'a SyntaxSet) -> Result<&'a SyntaxReference, Error> {
+        self.detect_language(ps).map(|(language, _)| language)
+    }
+
+    /// The syntax to highlight with, and how it was chosen.
+    ///
+    /// ```
+    /// use inkify::config::Detection;
+    /// use syntect::parsing::SyntaxSet;
+    ///
+    /// let ps = SyntaxSet::load_defaults_newlines();
+    /// let mut conf = inkify::Config::default();
+    /// conf.code = "print('Hello, world!')".to_owned();
+    /// conf.filename = Some("hello.py".to_owned());
+    /// let (syntax, detection) = conf.detect_language(&ps)?;
+    /// assert_eq!(syntax.name, "Python");
+    /// assert_eq!(detection, Detection::Extension);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn detect_language<'a>(&self, ps: &'a SyntaxSet) -> Result<(&'a SyntaxReference, Detection), Error> {
+        if let Some(found) = self.language_without_model(ps)? {
+            return Ok(found);
+        }
+        self.language_from_model(ps)
+    }
+
+    /// The syntax the detection model predicts. When it predicts a
+    /// language without a syntax, times out, or panics, the fallback
+    /// language is used. When there's no model, it fails, or the render is
+    /// deterministic, the heuristic is used instead, and the fallback
+    /// language when that finds nothing.
+    fn language_from_model<'a>(&self, ps: &'a SyntaxSet) -> Result<(&'a SyntaxReference, Detection), Error> {
+        if let Some(model) = self.detector.as_ref().filter(|_| !self.deterministic) {
+            match detector::predict(model.clone(), &self.code, self.detection_timeout) {
+                Ok(predictions) => {
+                    return Ok(match most_likely(ps, &predictions) {
+                        Some((language, confidence)) => (language, Detection::Model { confidence }),
+                        None => (self.fallback_syntax(ps), Detection::Fallback),
+                    });
+                }
+                Err(PredictError::Failed(e)) => {
+                    tracing::warn!(error = %e, "Language detection failed, using the heuristic");
+                }
+                Err(e) => {
+                    let reason = match e {
+                        PredictError::Timeout(_) => "timeout",
+                        _ => "panic",
+                    };
+                    tracing::warn!(error = %e, "Language detection unavailable, using the fallback language");
+                    return Ok((self.fallback_syntax(ps), Detection::Unavailable { reason }));
+                }
             }
-        };
+        }
 
-        self.tf_model = Some(model);
-        self.tf_model_graph = Some(graph);
+        Ok(match most_likely(ps, &heuristic::predict(&self.code)) {
+            Some((language, confidence)) => (language, Detection::Heuristic { confidence }),
+            None => (self.fallback_syntax(ps), Detection::Fallback),
+        })
     }
 
-    pub fn language<'a>(&self, ps: &'a SyntaxSet) -> Result<&'a SyntaxReference, Error> {
-        let language = match &self.language {
-            Some(language) => ps
-                .find_syntax_by_token(language)
-                .ok_or_else(|| Error::msg(format!("Invalid language: {}", language)))?,
-            None => {
-                let first_line = self.code.lines().next().unwrap_or_default();
-                ps.find_syntax_by_first_line(first_line).unwrap_or_else(|| {
-                    // Try using tensorflow to detect the language
-                    let input_data = Tensor::new(&[1]).with_values(&[self.code.clone()]).unwrap();
-                    let predictions = self.predict_language_with_tensorflow(ps, input_data).unwrap();
-
-                    let mut max_score = -std::f32::INFINITY;
-                    let mut max_language = "log";
-                    for (language, score) in &predictions {  // Borrow predictions here
-                        if *score > max_score {
-                            max_score = *score;
-                            max_language = language;
-                        }
-                    }
-                    
-                    ps.find_syntax_by_token(max_language).unwrap_or_else(|| ps.find_syntax_by_token("log").unwrap())
+    /// The syntax of `fallback_language`, or plain text.
+    pub fn fallback_syntax<'a>(&self, ps: &'a SyntaxSet) -> &'a SyntaxReference {
+        self.fallback_language
+            .as_deref()
+            .and_then(|language| ps.find_syntax_by_token(aliases::resolve(language)))
+            .unwrap_or_else(|| ps.find_syntax_plain_text())
+    }
+
+    /// The syntax chosen by the `language` parameter, then by the extension
+    /// of `filename`, then by the first line, or `None` when it's down to
+    /// the detection model.
+    pub fn language_without_model<'a>(
+        &self,
+        ps: &'a SyntaxSet,
+    ) -> Result<Option<(&'a SyntaxReference, Detection)>, Error> {
+        // Terminal output is drawn in its own colors, as plain text.
+        if self.ansi {
+            return Ok(Some((ps.find_syntax_plain_text(), Detection::Given)));
+        }
+        if let Some(language) = &self.language {
+            return Ok(Some((find_language(ps, language)?, Detection::Given)));
+        }
+
+        let by_filename = self
+            .filename
+            .as_deref()
+            .and_then(|path| path.rsplit(['/', '\\']).next())
+            .and_then(|name| {
+                language_from_filename(name, ps).or_else(|| {
+                    let (_, extension) = name.rsplit_once('.')?;
+                    heuristic::extension_token(extension).map(str::to_owned)
                 })
-            },
-        };
-        Ok(language)
+            })
+            .and_then(|token| ps.find_syntax_by_token(&token));
+        if let Some(language) = by_filename {
+            return Ok(Some((language, Detection::Extension)));
+        }
+
+        let first_line = self.code.lines().next().unwrap_or_default();
+        Ok(ps
+            .find_syntax_by_first_line(first_line)
+            .map(|language| (language, Detection::FirstLine)))
     }
 
-    pub fn predict_language_with_tensorflow<'a>(&self, ps: &'a SyntaxSet, input_data: Tensor<String>) -> Result<HashMap<String, f32>, Error> {
-        if self.tf_model_graph.is_none() || self.tf_model.is_none() {
-            return Err(Error::msg("TensorFlow model not loaded"));
+    /// The theme to render with: the inline theme, or else the installed
+    /// theme named `theme`. Only when `files_dir` is given can `theme` name
+    /// a .tmTheme file, relative to it, and never one outside it.
+    pub fn theme(&self, ts: &ThemeSet, files_dir: Option<&Path>) -> Result<Theme, Error> {
+        if let Some(theme) = &self.inline_theme {
+            return Ok(theme.clone());
+        }
+        if let Some(theme) = ts.themes.get(&self.theme) {
+            return Ok(theme.clone());
+        }
+        match files_dir.and_then(|dir| theme_file(dir, &self.theme)) {
+            Some(path) => ThemeSet::get_theme(path).map_err(|e| Error::msg(format!("Invalid theme: {}", e))),
+            None => Err(Error::msg(suggest::with_suggestions(
+                format!("Invalid theme `{}`", self.theme),
+                &self.theme,
+                ts.themes.keys().map(|name| name.as_str()),
+            ))),
         }
+    }
 
-        let graph = self.tf_model_graph.as_ref().unwrap();
-        let model = self.tf_model.as_ref().unwrap();
-        let mut args = tensorflow::SessionRunArgs::new();
+    /// The number shown for each line of `code`, or `None` for the separator
+    /// rows between line ranges, and for the rows of `output`.
+    pub fn line_numbers(&self) -> Vec<Option<u32>> {
+        let mut numbers = self.code_line_numbers();
+        if let Some(output) = &self.output {
+            numbers.extend(std::iter::repeat(None).take(1 + output.lines().count()));
+        }
+        numbers
+    }
 
-        let input_tensor = graph.operation_by_name_required("Placeholder")?;
+    fn code_line_numbers(&self) -> Vec<Option<u32>> {
+        match &self.line_range {
+            Some(ranges) if ranges.len() > 1 => {
+                let mut numbers = vec![];
+                for (i, &(start, end)) in ranges.iter().enumerate() {
+                    if i > 0 {
+                        numbers.push(None);
+                    }
+                    numbers.extend((start..=end).map(Some));
+                }
+                numbers
+            }
+            _ => (0..self.code.lines().count() as u32)
+                .map(|i| Some(self.line_offset + i))
+                .collect(),
+        }
+    }
 
-        let output_tensor_scores = graph.operation_by_name_required("head/predictions/probabilities")?;
+    /// The largest line number shown for the code. Lines past it can't be
+    /// highlighted or redacted, so ranges are clamped to it.
+    pub fn last_line_number(&self) -> u32 {
+        self.code_line_numbers().into_iter().flatten().max().unwrap_or(0)
+    }
 
-        let output_tensor_classes = graph.operation_by_name_required("head/Tile")?;
+    /// The 1-based positions in `code` of the lines to highlight. When the
+    /// code was sliced to a line range, `highlight_lines` refers to the
+    /// numbers shown rather than positions.
+    pub fn highlighted_rows(&self) -> Vec<u32> {
+        self.rows_of(&self.highlight_lines.clone().unwrap_or_default())
+    }
 
-        args.add_feed(&input_tensor, 0, &input_data);
-        let output_token_scores = args.request_fetch(&output_tensor_scores, 0);
-        let output_token_classes = args.request_fetch(&output_tensor_classes, 0);
+    /// The 1-based positions in `code` of the lines highlighted in their own
+    /// color, as for `highlighted_rows`.
+    pub fn colored_rows(&self) -> Vec<(u32, ImageRgba<u8>)> {
+        self.highlight_colors
+            .iter()
+            .flat_map(|&(line, color)| self.rows_of(&[line]).into_iter().map(move |row| (row, color)))
+            .collect()
+    }
 
-        model.session.run(&mut args)?;
+    /// The 1-based positions in `code` of the lines to redact, as for
+    /// `highlighted_rows`, including the body of any private key when
+    /// redacting secrets.
+    pub fn redacted_rows(&self) -> Vec<u32> {
+        let mut rows = self.rows_of(&self.redact_lines.clone().unwrap_or_default());
+        if self.auto_redact && !self.auto_redact_allow.iter().any(|name| name == "private_key") {
+            rows.extend(secrets::private_key_lines(&self.code));
+        }
+        rows
+    }
 
-        let scores: Tensor<f32> = args.fetch(output_token_scores)?;
+    /// The patterns whose matches are redacted in each line.
+    pub fn redact_patterns(&self) -> Vec<&Regex> {
+        let mut patterns: Vec<&Regex> = self.redact_pattern.iter().collect();
+        if self.auto_redact {
+            patterns.extend(secrets::patterns(&self.auto_redact_allow));
+        }
+        patterns
+    }
 
-        let classes: Tensor<String> = args.fetch(output_token_classes)?;
+    /// Whether any text is redacted.
+    pub fn redacts(&self) -> bool {
+        self.redact_lines.is_some() || self.redact_pattern.is_some() || self.auto_redact
+    }
 
-        let mut result: HashMap<String, f32> = HashMap::new();
-        for (i, score) in scores.iter().enumerate() {
-            let class = classes[i].clone();
-            let log_score = score.log2();
-            result.insert(class, log_score);
+    fn rows_of(&self, lines: &[u32]) -> Vec<u32> {
+        if self.line_range.is_none() {
+            return lines.to_vec();
         }
+        self.line_numbers()
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.is_some_and(|n| lines.contains(&n)))
+            .map(|(i, _)| i as u32 + 1)
+            .collect()
+    }
 
-        Ok(result)
+    /// Scales a length in pixels by `self.scale`.
+    pub fn scaled(&self, px: u32) -> u32 {
+        (px as f32 * self.scale).round() as u32
     }
-    
 
-    pub fn theme(&self, ts: &ThemeSet) -> Result<Theme, Error> {
-        if let Some(theme) = ts.themes.get(&self.theme) {
-            Ok(theme.clone())
-        } else {
-            ThemeSet::get_theme(PathBuf::from(&self.theme))
-                .map_err(|e| Error::msg(format!("Invalid theme: {}", e)))
+    /// The font list with sizes multiplied by `self.scale`. An explicit size
+    /// from the font parameter is scaled like the default one. The fallback
+    /// fonts follow, at the size of the first font.
+    fn scaled_font(&self) -> FontList {
+        let mut font = match &self.font {
+            Some(font) => font
+                .iter()
+                .map(|(name, size)| (name.clone(), size * self.scale))
+                .collect(),
+            None if self.scale != 1.0 || !self.fallback_fonts.is_empty() => {
+                vec![(DEFAULT_FONT.to_owned(), DEFAULT_FONT_SIZE * self.scale)]
+            }
+            None => vec![],
+        };
+        let size = font.first().map_or(DEFAULT_FONT_SIZE * self.scale, |(_, size)| *size);
+        for fallback in &self.fallback_fonts {
+            if !font.iter().any(|(name, _)| name.trim().eq_ignore_ascii_case(fallback)) {
+                font.push((fallback.clone(), size));
+            }
         }
+        font
+    }
+
+    /// Whether the window is finished by `window::decorate` rather than
+    /// silicon, because silicon can't draw its style, corner radius,
+    /// background, caption, preset canvas, highlight colors, diff tints,
+    /// redactions, indentation guides, or language badge, or because it's
+    /// one of several snippets arranged together. Background images are
+    /// always fitted here, since silicon would stretch them.
+    pub fn is_composited(&self) -> bool {
+        self.window_style.is_composited()
+            || self.corner_radius.is_some()
+            || self.background_gradient.is_some()
+            || self.background_image.is_some()
+            || self.caption.is_some()
+            || self.preset.is_some()
+            || !self.highlight_colors.is_empty()
+            || self.diff
+            || self.redacts()
+            || self.indent_guides
+            || self.show_language
+            || self.layout.is_some()
+    }
+
+    /// The corner radius in pixels, after scaling.
+    pub fn corner_radius(&self) -> u32 {
+        match self.corner_radius {
+            _ if self.no_round_corner => 0,
+            Some(radius) => self.scaled(radius),
+            None => self.scaled(DEFAULT_CORNER_RADIUS),
+        }
+    }
+
+    /// The font list for text drawn outside silicon, such as a composited
+    /// window title.
+    pub fn title_font(&self) -> FontList {
+        match self.scaled_font() {
+            font if font.is_empty() => vec![(DEFAULT_FONT.to_owned(), DEFAULT_FONT_SIZE)],
+            font => font,
+        }
+    }
+
+    /// The font family of the code at `size`, before scaling.
+    pub fn font_at(&self, size: f32) -> FontList {
+        self.title_font()
+            .into_iter()
+            .map(|(name, _)| (name, size * self.scale))
+            .collect()
     }
 
     pub fn get_formatter(&self) -> Result<ImageFormatter, Error> {
+        Ok(self.get_formatter_builder()?.build()?)
+    }
+
+    /// Identifies the formatter `get_formatter_builder` builds, so one built
+    /// for an earlier request can be reused. Covers everything passed to the
+    /// builder, so has to change along with it. `None` when silicon draws
+    /// the shadow over a background image, which isn't worth hashing.
+    pub fn formatter_key(&self) -> Option<String> {
+        let shadow = if self.is_composited() {
+            None
+        } else if self.background_image.is_some() {
+            return None;
+        } else {
+            Some((
+                self.no_round_corner,
+                self.background.to_string(),
+                self.shadow_color.to_string(),
+                self.shadow_blur_radius * self.scale,
+                self.shadow_offset_x,
+                self.shadow_offset_y,
+                self.scaled(self.pad_horiz),
+                self.scaled(self.pad_vert),
+            ))
+        };
+        let title = (self.window_style == WindowStyle::Mac).then(|| (self.no_window_controls, &self.window_title));
+        Some(format!(
+            "{:?}",
+            (
+                self.scaled_font(),
+                self.scaled(self.line_pad),
+                self.no_line_number,
+                self.tab_width,
+                &self.highlight_lines,
+                self.line_offset,
+                self.scale,
+                title,
+                shadow,
+            )
+        ))
+    }
+
+    /// The formatter configuration, for callers which need to adjust it
+    /// before building. When composited, the formatter leaves the corners,
+    /// shadow, and any title bar it can't draw to `window::decorate`.
+    pub fn get_formatter_builder(&self) -> Result<ImageFormatterBuilder<String>, Error> {
         let formatter = ImageFormatterBuilder::new()
-            .line_pad(self.line_pad)
-            .window_controls(!self.no_window_controls)
-            .window_title(self.window_title.clone())
+            .line_pad(self.scaled(self.line_pad))
+            .code_pad_right(self.scaled(DEFAULT_CODE_PAD_RIGHT))
             .line_number(!self.no_line_number)
-            .font(self.font.clone().unwrap_or_default())
-            .round_corner(!self.no_round_corner)
-            .shadow_adder(self.get_shadow_adder()?)
+            .font(self.scaled_font())
             .tab_width(self.tab_width)
             .highlight_lines(self.highlight_lines.clone().unwrap_or_default())
             .line_offset(self.line_offset);
 
-        Ok(formatter.build()?)
+        let formatter = match self.window_style {
+            WindowStyle::Mac => formatter
+                .window_controls(!self.no_window_controls)
+                .window_title(self.window_title.clone()),
+            _ => formatter.window_controls(false).window_title(None),
+        };
+        if self.is_composited() {
+            return Ok(formatter.round_corner(false));
+        }
+        Ok(formatter
+            .round_corner(!self.no_round_corner)
+            .shadow_adder(self.get_shadow_adder(None)?))
+    }
+
+    /// The shadow and background around the window. Backgrounds which are
+    /// generated at the final size need the size of the window, which is only
+    /// known once composited.
+    pub fn get_shadow_adder(&self, window_size: Option<(u32, u32)>) -> Result<ShadowAdder, Error> {
+        let background = match (window_size, &self.background_image) {
+            (Some((width, height)), _) => {
+                let (width, height) = self.canvas_size(width, height);
+                Background::Image(self.background_at(width, height)?)
+            }
+            (None, Some(bytes)) => Background::Image(image::load_from_memory(bytes)?.to_rgba8()),
+            (None, None) => Background::Solid(self.background.to_rgba()),
+        };
+        Ok(self.shadow_adder_with(
+            background,
+            self.scaled(self.pad_horiz),
+            self.scaled(self.pad_vert),
+        ))
     }
 
-    pub fn get_shadow_adder(&self) -> Result<ShadowAdder, Error> {
-        Ok(ShadowAdder::new()
-            .background(match &self.background_image {
-                Some(path) => Background::Image(image::load_from_memory(path)?.to_rgba8()),
-                None => Background::Solid(self.background.to_rgba()),
-            })
+    /// The shadow around the window, over the given background and padded
+    /// by the given number of pixels.
+    pub fn shadow_adder_with(&self, background: Background, pad_horiz: u32, pad_vert: u32) -> ShadowAdder {
+        ShadowAdder::new()
+            .background(background)
             .shadow_color(self.shadow_color.to_rgba())
-            .blur_radius(self.shadow_blur_radius)
-            .pad_horiz(self.pad_horiz)
-            .pad_vert(self.pad_vert)
-            .offset_x(self.shadow_offset_x)
-            .offset_y(self.shadow_offset_y))
+            .blur_radius(self.shadow_blur_radius * self.scale)
+            .pad_horiz(pad_horiz)
+            .pad_vert(pad_vert)
+            .offset_x((self.shadow_offset_x as f32 * self.scale).round() as i32)
+            .offset_y((self.shadow_offset_y as f32 * self.scale).round() as i32)
     }
-}
-
-/// Query parameters for the /generate endpoint, using Option to make all options
-/// with defaults optional.
-#[derive(Debug, Clone, serde::Deserialize)]
-pub struct ConfigQuery {
-    /// Background image URL
-    pub background_image: Option<String>,
 
-    /// Background color of the image
-    pub background: Option<String>,
+    /// The background color, gradient, or fitted image at exactly `width`
+    /// by `height`.
+    pub fn background_at(&self, width: u32, height: u32) -> Result<RgbaImage, Error> {
+        if let Some(gradient) = &self.background_gradient {
+            return Ok(gradient.render(width, height));
+        }
+        Ok(match &self.background_image {
+            Some(bytes) => background::fit(
+                &image::load_from_memory(bytes)?,
+                width,
+                height,
+                self.background_fit,
+                self.background_position,
+                self.background.to_rgba(),
+            ),
+            None => RgbaImage::from_pixel(width, height, self.background.to_rgba()),
+        })
+    }
 
-    /// The code to highlight.
-    pub code: String,
+    /// Size of the final image around a window of the given size.
+    pub fn canvas_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            width + 2 * self.scaled(self.pad_horiz),
+            height + 2 * self.scaled(self.pad_vert),
+        )
+    }
+}
 
-    /// The fallback font list. eg. 'Hack; SimSun=31'
-    pub font: Option<String>,
+/// How the language to highlight with was chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detection {
+    /// From the `language` parameter, or a file name.
+    Given,
+    /// From the extension of the `filename` parameter.
+    Extension,
+    /// By syntect, from the first line, eg. a shebang.
+    FirstLine,
+    /// By the detection model, with the probability it gave.
+    Model { confidence: f32 },
+    /// By keyword frequencies, without the model, with an estimated
+    /// probability of at most 0.5, or 0.9 from a shebang.
+    Heuristic { confidence: f32 },
+    /// The model predicted a language without a syntax, neither the model
+    /// nor the heuristic found one, or the one found wasn't confident enough
+    /// for the request, so the fallback language was used.
+    Fallback,
+    /// The model timed out or panicked, for `reason`, so the fallback
+    /// language was used.
+    Unavailable { reason: &'static str },
+}
 
-    /// Lines to high light. rg. '1-3; 4'
-    pub highlight_lines: Option<String>,
+impl Detection {
+    /// How the language was chosen, as reported to clients.
+    pub fn source(self) -> &'static str {
+        match self {
+            Detection::Given => "explicit",
+            Detection::Extension => "extension",
+            Detection::FirstLine => "first-line",
+            Detection::Model { .. } => "model",
+            Detection::Heuristic { .. } => "heuristic",
+            Detection::Fallback | Detection::Unavailable { .. } => "fallback",
+        }
+    }
+}
 
-    /// The language for syntax highlighting. You can use full name ("Rust") or file extension ("rs").
-    pub language: Option<String>,
+/// The syntax for `language`, by name, extension, or alias. The error
+/// suggests close matches.
+pub fn find_language<'a>(ps: &'a SyntaxSet, language: &str) -> Result<&'a SyntaxReference, Error> {
+    ps.find_syntax_by_token(aliases::resolve(language)).ok_or_else(|| {
+        let candidates = ps
+            .syntaxes()
+            .iter()
+            .map(|syntax| syntax.name.as_str())
+            .chain(aliases::all().keys().map(String::as_str));
+        Error::msg(suggest::with_suggestions(
+            format!("Invalid language: {}", language),
+            language,
+            candidates,
+        ))
+    })
+}
 
-    /// Pad between lines
-    pub line_pad: Option<u32>,
+/// The highest scoring of `predictions`, which are log2 probabilities, and
+/// its probability, unless there's no syntax for it.
+fn most_likely<'a>(ps: &'a SyntaxSet, predictions: &[(String, f32)]) -> Option<(&'a SyntaxReference, f32)> {
+    let (language, score) = predictions.iter().max_by(|a, b| a.1.total_cmp(&b.1))?;
+    ps.find_syntax_by_token(language).map(|syntax| (syntax, score.exp2()))
+}
 
-    /// Line number offset
-    pub line_offset: Option<u32>,
+/// The window title when none was given: `snippet.<ext>` for the primary
+/// extension of `syntax`, or "Inkify" for plain text.
+pub fn default_window_title(syntax: &SyntaxReference) -> String {
+    match syntax.file_extensions.first() {
+        Some(extension) if syntax.name != "Plain Text" => format!("snippet.{}", extension),
+        _ => DEFAULT_WINDOW_TITLE.to_owned(),
+    }
+}
 
-    /// Hide the window controls.
-    pub no_window_controls: Option<bool>,
+/// Returns a language token for `filename` which the syntax set recognizes,
+/// trying the whole name (eg. `Makefile`) before its extension.
+pub fn language_from_filename(filename: &str, ps: &SyntaxSet) -> Option<String> {
+    if ps.find_syntax_by_extension(filename).is_some() {
+        return Some(filename.to_owned());
+    }
+    let (_, extension) = filename.rsplit_once('.')?;
+    ps.find_syntax_by_extension(extension)
+        .map(|_| extension.to_owned())
+}
 
-    /// Show window title
-    pub window_title: Option<String>,
+/// The .tmTheme file `name` refers to in `dir`, which must be canonical.
+/// Paths which resolve outside it, eg. through `..` or a symlink, are
+/// refused.
+fn theme_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = dir.join(name).canonicalize().ok()?;
+    let is_theme = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("tmTheme"));
+    (is_theme && path.starts_with(dir) && path.is_file()).then_some(path)
+}
 
-    /// Hide the line number.
-    pub no_line_number: Option<bool>,
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// Don't round the corner
-    pub no_round_corner: Option<bool>,
+    fn detect(conf: &Config) -> (String, Detection) {
+        let ps = SyntaxSet::load_defaults_newlines();
+        let (syntax, detection) = conf.detect_language(&ps).unwrap();
+        (syntax.name.clone(), detection)
+    }
 
-    /// Pad horiz
-    pub pad_horiz: Option<u32>,
+    fn code(code: &str) -> Config {
+        let mut conf = Config::default();
+        conf.code = code.to_owned();
+        conf
+    }
 
-    /// Pad vert
-    pub pad_vert: Option<u32>,
+    #[test]
+    fn a_given_language_wins() {
+        let mut conf = code("#!/bin/sh\necho hi\n");
+        conf.language = Some("rs".to_owned());
+        conf.filename = Some("main.py".to_owned());
+        assert_eq!(detect(&conf), ("Rust".to_owned(), Detection::Given));
+    }
 
-    /// Color of shadow
-    pub shadow_color: Option<String>,
+    #[test]
+    fn aliases_are_resolved() {
+        let mut conf = code("x = 1\n");
+        conf.language = Some("python3".to_owned());
+        assert_eq!(detect(&conf).0, "Python");
+    }
 
-    /// Blur radius of the shadow. (set it to 0 to hide shadow)
-    pub shadow_blur_radius: Option<f32>,
+    #[test]
+    fn the_filename_extension_comes_next() {
+        let mut conf = code("#!/bin/sh\necho hi\n");
+        conf.filename = Some("src/lib.rs".to_owned());
+        assert_eq!(detect(&conf), ("Rust".to_owned(), Detection::Extension));
+    }
 
-    /// Shadow's offset in Y axis
-    pub shadow_offset_y: Option<i32>,
+    #[test]
+    fn then_the_first_line() {
+        assert_eq!(detect(&code("#!/usr/bin/env python3\nprint(1)\n")), ("Python".to_owned(), Detection::FirstLine));
+    }
 
-    /// Shadow's offset in X axis
-    pub shadow_offset_x: Option<i32>,
+    #[test]
+    fn without_a_model_the_heuristic_or_fallback_is_used() {
+        let (language, detection) = detect(&code("fn main() {\n    let mut v: Vec<u8> = Vec::new();\n}\n"));
+        assert_eq!(language, "Rust");
+        assert!(matches!(detection, Detection::Heuristic { .. }), "{:?}", detection);
 
-    /// Tab width
-    pub tab_width: Option<u8>,
+        let mut conf = code("");
+        conf.fallback_language = Some("rs".to_owned());
+        assert_eq!(detect(&conf), ("Rust".to_owned(), Detection::Fallback));
+    }
 
-    /// The syntax highlight theme. It can be a theme name or path to a .tmTheme file.
-    pub theme: Option<String>,
+    #[test]
+    fn unknown_languages_are_errors_with_suggestions() {
+        let ps = SyntaxSet::load_defaults_newlines();
+        let mut conf = code("x");
+        conf.language = Some("pythn".to_owned());
+        let e = conf.detect_language(&ps).unwrap_err().to_string();
+        assert!(e.starts_with("Invalid language: pythn"), "{}", e);
+        assert!(e.contains("Python"), "{}", e);
+    }
 }
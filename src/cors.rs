@@ -0,0 +1,102 @@
+//! CORS for browser front ends, enabled by `INKIFY_CORS_ORIGINS`.
+
+use actix_cors::Cors;
+use actix_web::http::header::HeaderValue;
+use anyhow::Error;
+
+/// The response headers scripts on an allowed origin may read.
+const EXPOSED_HEADERS: &[&str] = &[
+    "ETag",
+    "Retry-After",
+    "Content-Disposition",
+    "X-Cache",
+    "X-Detected-Language",
+    "X-Detection-Confidence",
+    "X-Detection-Fallback",
+    "X-Fallback-Fonts",
+    "X-Fallback-Language-Used",
+    "X-Font-Fallback",
+    "X-Image-Height",
+    "X-Image-Width",
+    "X-Language",
+    "X-Line-Range",
+    "X-Missing-Fallback-Fonts",
+    "X-Preset-Downscaled",
+    "X-Preset-Scale",
+    "X-Redactions",
+    "X-Request-Id",
+    "X-Size-Estimated",
+    "X-Warning",
+    "X-Window-Title",
+];
+
+/// An origin allowed to make cross-origin requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigin {
+    /// `*`, any origin.
+    Any,
+
+    /// An origin matched exactly, eg. `https://example.com`.
+    Exact(String),
+
+    /// Any subdomain of a domain, eg. `https://*.example.com`, which doesn't
+    /// match the domain itself.
+    Subdomains { scheme: String, domain: String },
+}
+
+impl AllowedOrigin {
+    pub fn parse(origin: &str) -> Result<Self, Error> {
+        if origin == "*" {
+            return Ok(AllowedOrigin::Any);
+        }
+        let (scheme, host) = origin
+            .split_once("://")
+            .ok_or_else(|| format_err!("`{}` isn't an origin, eg. https://example.com", origin))?;
+        if host.is_empty() || host.contains('/') {
+            return Err(format_err!(
+                "`{}` isn't an origin, it should have no path or trailing slash",
+                origin
+            ));
+        }
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.contains('*') => Ok(AllowedOrigin::Subdomains {
+                scheme: scheme.to_lowercase(),
+                domain: domain.to_lowercase(),
+            }),
+            _ if host.contains('*') => Err(format_err!(
+                "`{}` can only have a wildcard at the start of the host, eg. https://*.example.com",
+                origin
+            )),
+            _ => Ok(AllowedOrigin::Exact(origin.to_lowercase())),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(allowed) => origin == *allowed,
+            AllowedOrigin::Subdomains { scheme, domain } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(domain.as_str()))
+                .and_then(|subdomain| subdomain.strip_suffix('.'))
+                .is_some_and(|subdomain| !subdomain.is_empty()),
+        }
+    }
+}
+
+/// The CORS middleware for `origins`, which handles preflight requests to
+/// every route.
+pub fn middleware(origins: &'static [AllowedOrigin]) -> Cors {
+    Cors::default()
+        .allowed_origin_fn(move |origin: &HeaderValue, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| origins.iter().any(|allowed| allowed.matches(origin)))
+        })
+        .allowed_methods(["GET", "HEAD", "POST"])
+        .allow_any_header()
+        .expose_headers(EXPOSED_HEADERS.iter().copied())
+        .max_age(3600)
+}
@@ -0,0 +1,114 @@
+//! Server-wide defaults for generation parameters, from `INKIFY_DEFAULT_*`
+//! variables named after the parameter, eg. `INKIFY_DEFAULT_THEME=Nord` or
+//! `INKIFY_DEFAULT_PAD_HORIZ=40`. Parameters passed with a request override
+//! them.
+
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+
+use crate::query::{self, ConfigQuery};
+use crate::settings;
+
+const PREFIX: &str = "INKIFY_DEFAULT_";
+
+/// Parameters which give the code itself, and so can't have a default.
+const CODE_PARAMS: &[&str] = &["code", "code_b64", "code_gz", "code_url", "gist", "gist_file", "github"];
+
+/// Parameters which exclude or take precedence over each other. A request
+/// passing either of a pair overrides the defaults for both.
+const EXCLUSIVE: &[(&str, &str)] = &[
+    ("theme", "theme_b64"),
+    ("background_gradient", "background_image"),
+    ("watermark", "watermark_image"),
+    ("diff", "line_range"),
+    ("no_line_number", "line_number"),
+    ("no_window_controls", "window_controls"),
+    ("no_round_corner", "round_corner"),
+    ("markdown", "snippets"),
+    ("notebook_cell", "snippets"),
+    ("line_range", "snippets"),
+];
+
+lazy_static! {
+    /// The defaults, as JSON values by parameter name.
+    static ref DEFAULTS: Map<String, Value> = from_env();
+}
+
+/// The defaults, as JSON values by parameter name, for the help text.
+pub fn active() -> &'static Map<String, Value> {
+    &DEFAULTS
+}
+
+/// The environment variable which sets `param`.
+pub fn variable(param: &str) -> String {
+    format!("{}{}", PREFIX, param.to_uppercase())
+}
+
+/// Fills the parameters `info` doesn't pass from the defaults.
+pub fn apply(info: &mut ConfigQuery) {
+    if DEFAULTS.is_empty() {
+        return;
+    }
+    let mut params = match serde_json::to_value(&*info) {
+        Ok(Value::Object(params)) => params,
+        _ => return,
+    };
+    let passed = |name: &str| params.get(name).is_some_and(|value| !value.is_null());
+    let missing: Vec<(&String, &Value)> = DEFAULTS
+        .iter()
+        .filter(|(name, _)| {
+            !passed(name)
+                && !EXCLUSIVE.iter().any(|&(a, b)| {
+                    (name.as_str() == a && passed(b)) || (name.as_str() == b && passed(a))
+                })
+        })
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+    for (name, value) in missing {
+        params.insert(name.clone(), value.clone());
+    }
+    match serde_json::from_value(Value::Object(params)) {
+        Ok(merged) => *info = merged,
+        Err(e) => tracing::warn!(error = %e, "Failed to apply the default parameters"),
+    }
+}
+
+/// Reads every `INKIFY_DEFAULT_*` variable. An unknown parameter, or a value
+/// of the wrong type, stops startup.
+fn from_env() -> Map<String, Value> {
+    let mut vars: Vec<(String, String)> = std::env::vars()
+        .filter_map(|(name, _)| {
+            let param = name.strip_prefix(PREFIX)?.to_lowercase();
+            let value = settings::env_string(&name)?;
+            Some((param, value))
+        })
+        .collect();
+    vars.sort();
+
+    let mut defaults = Map::new();
+    for (param, value) in vars {
+        let name = variable(&param);
+        if CODE_PARAMS.contains(&param.as_str()) {
+            settings::fail::<()>(&name, format!("{} can't have a default", param));
+        }
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair(&param, &value)
+            .finish();
+        if !query::unknown_params(&form).is_empty() {
+            settings::fail::<()>(&name, format!("unknown parameter `{}`", param));
+        }
+        let query: ConfigQuery =
+            serde_urlencoded::from_str(&form).unwrap_or_else(|e| settings::fail(&name, e));
+        if let Ok(Value::Object(params)) = serde_json::to_value(query) {
+            if let Some(value) = params.get(&param) {
+                defaults.insert(param, value.clone());
+            }
+        }
+    }
+    if !defaults.is_empty() {
+        tracing::info!(params = ?defaults.keys().collect::<Vec<_>>(), "Loaded default parameters");
+    }
+    defaults
+}
@@ -0,0 +1,387 @@
+//! Derives a syntax theme from the dominant colors of an image.
+
+use image::DynamicImage;
+use std::io::Cursor;
+use syntect::highlighting::{Theme, ThemeSet};
+
+type Rgb = [u8; 3];
+
+/// Minimum WCAG contrast ratio against the background for code tokens.
+const MIN_TEXT_CONTRAST: f32 = 4.5;
+
+/// Comments are intentionally dimmer than the rest of the code.
+const MIN_COMMENT_CONTRAST: f32 = 3.0;
+
+const PALETTE_SIZE: usize = 8;
+const KMEANS_ITERATIONS: usize = 12;
+
+/// Name of the derived theme when none is given.
+pub const DEFAULT_NAME: &str = "Inkify Derived";
+
+/// Sample rendered with the derived theme for the preview image.
+pub const SAMPLE_CODE: &str = r#"// Derived by Inkify
+use std::collections::HashMap;
+
+/// Counts the words in a string.
+fn count_words(text: &str) -> HashMap<&str, usize> {
+    let mut counts = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    counts
+}
+
+const GREETING: &str = "hello world";
+"#;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PaletteColor {
+    pub color: String,
+    pub share: f32,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Adjustment {
+    pub slot: &'static str,
+    pub from: String,
+    pub to: String,
+    pub contrast_before: f32,
+    pub contrast_after: f32,
+}
+
+#[derive(Debug)]
+pub struct DerivedTheme {
+    pub name: String,
+    pub dark: bool,
+    pub palette: Vec<PaletteColor>,
+    pub adjustments: Vec<Adjustment>,
+    pub tm_theme: String,
+    pub theme: Theme,
+}
+
+/// Extracts a palette from `image` and synthesizes a legible theme from it.
+pub fn derive_theme(image: &DynamicImage, name: &str) -> Result<DerivedTheme, anyhow::Error> {
+    let clusters = palette(image);
+    if clusters.is_empty() {
+        return Err(format_err!("The image has no opaque pixels"));
+    }
+    let total: usize = clusters.iter().map(|(_, n)| n).sum();
+
+    // The most common color becomes the background.
+    let background = clusters[0].0;
+    let dark = luminance(background) < 0.5;
+
+    let mut adjustments = vec![];
+    let mut slot = |slot: &'static str, color: Rgb, min: f32| -> Rgb {
+        let before = contrast(color, background);
+        let adjusted = enforce_contrast(color, background, min);
+        if adjusted != color {
+            adjustments.push(Adjustment {
+                slot,
+                from: hex(color),
+                to: hex(adjusted),
+                contrast_before: round2(before),
+                contrast_after: round2(contrast(adjusted, background)),
+            });
+        }
+        adjusted
+    };
+
+    // Accents are the most saturated colors with hues distinct from each
+    // other. When the image doesn't have enough of them the hue of the most
+    // saturated one is rotated to fill the remaining slots.
+    let mut candidates: Vec<Rgb> = clusters.iter().skip(1).map(|(c, _)| *c).collect();
+    candidates.sort_by(|a, b| saturation(*b).partial_cmp(&saturation(*a)).unwrap());
+    let mut accents: Vec<Rgb> = vec![];
+    for color in candidates {
+        if saturation(color) < 0.15 {
+            continue;
+        }
+        if accents.iter().all(|a| hue_distance(hue(*a), hue(color)) >= 30.0) {
+            accents.push(color);
+        }
+    }
+    let seed = accents.first().copied().unwrap_or(if dark {
+        [0x8b, 0xe9, 0xfd]
+    } else {
+        [0x00, 0x5c, 0xc5]
+    });
+    let mut rotation = 1.0;
+    while accents.len() < 5 {
+        accents.push(rotate_hue(seed, 72.0 * rotation));
+        rotation += 1.0;
+    }
+
+    let base_fg = if dark { [0xf8, 0xf8, 0xf2] } else { [0x24, 0x29, 0x2e] };
+    let foreground = slot("foreground", mix(base_fg, seed, 0.1), MIN_TEXT_CONTRAST);
+    let comment = slot("comment", mix(background, foreground, 0.5), MIN_COMMENT_CONTRAST);
+    let keyword = slot("keyword", accents[0], MIN_TEXT_CONTRAST);
+    let string = slot("string", accents[1], MIN_TEXT_CONTRAST);
+    let function = slot("function", accents[2], MIN_TEXT_CONTRAST);
+    let constant = slot("constant", accents[3], MIN_TEXT_CONTRAST);
+    let types = slot("type", accents[4], MIN_TEXT_CONTRAST);
+
+    let selection = mix(background, foreground, 0.2);
+    let line_highlight = mix(background, foreground, 0.1);
+    let gutter_fg = mix(background, foreground, 0.45);
+
+    let settings = [
+        ("background", hex(background)),
+        ("foreground", hex(foreground)),
+        ("caret", hex(foreground)),
+        ("selection", hex(selection)),
+        ("lineHighlight", hex(line_highlight)),
+        ("gutter", hex(background)),
+        ("gutterForeground", hex(gutter_fg)),
+    ];
+    let scopes = [
+        ("Comment", "comment", comment, Some("italic")),
+        ("Keyword", "keyword, storage", keyword, None),
+        ("String", "string", string, None),
+        ("Function", "entity.name.function, support.function", function, None),
+        ("Constant", "constant, constant.numeric, constant.language", constant, None),
+        ("Type", "entity.name.type, support.type, storage.type", types, None),
+        ("Variable", "variable", foreground, None),
+    ];
+    let tm_theme = tm_theme_xml(name, &settings, &scopes);
+    let theme = ThemeSet::load_from_reader(&mut Cursor::new(tm_theme.as_bytes()))?;
+
+    let palette = clusters
+        .iter()
+        .map(|(color, n)| PaletteColor {
+            color: hex(*color),
+            share: round2(*n as f32 / total as f32),
+        })
+        .collect();
+
+    Ok(DerivedTheme {
+        name: name.to_owned(),
+        dark,
+        palette,
+        adjustments,
+        tm_theme,
+        theme,
+    })
+}
+
+/// Runs k-means over a downsampled copy of the image, returning the cluster
+/// centers sorted by the number of pixels they cover.
+fn palette(image: &DynamicImage) -> Vec<(Rgb, usize)> {
+    let small = image.thumbnail(96, 96).to_rgba8();
+    let pixels: Vec<[f32; 3]> = small
+        .pixels()
+        .filter(|p| p.0[3] >= 128)
+        .map(|p| [p.0[0] as f32, p.0[1] as f32, p.0[2] as f32])
+        .collect();
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    // Deterministic initialization: spread the initial centers across the
+    // pixels ordered by brightness.
+    let mut by_brightness = pixels.clone();
+    by_brightness.sort_by(|a, b| (a[0] + a[1] + a[2]).partial_cmp(&(b[0] + b[1] + b[2])).unwrap());
+    let k = PALETTE_SIZE.min(pixels.len());
+    let mut centers: Vec<[f32; 3]> = (0..k)
+        .map(|i| by_brightness[(i * 2 + 1) * by_brightness.len() / (k * 2)])
+        .collect();
+
+    let mut assignments = vec![0; pixels.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        for (i, p) in pixels.iter().enumerate() {
+            assignments[i] = nearest(&centers, p);
+        }
+        let mut sums = vec![[0f32; 3]; k];
+        let mut counts = vec![0usize; k];
+        for (p, &c) in pixels.iter().zip(&assignments) {
+            sums[c][0] += p[0];
+            sums[c][1] += p[1];
+            sums[c][2] += p[2];
+            counts[c] += 1;
+        }
+        for c in 0..k {
+            if counts[c] > 0 {
+                let n = counts[c] as f32;
+                centers[c] = [sums[c][0] / n, sums[c][1] / n, sums[c][2] / n];
+            }
+        }
+    }
+
+    let mut counts = vec![0usize; k];
+    for &c in &assignments {
+        counts[c] += 1;
+    }
+    let mut clusters: Vec<(Rgb, usize)> = centers
+        .iter()
+        .zip(counts)
+        .filter(|(_, n)| *n > 0)
+        .map(|(c, n)| ([c[0].round() as u8, c[1].round() as u8, c[2].round() as u8], n))
+        .collect();
+    clusters.sort_by(|a, b| b.1.cmp(&a.1));
+    clusters
+}
+
+fn nearest(centers: &[[f32; 3]], p: &[f32; 3]) -> usize {
+    let mut best = 0;
+    let mut best_dist = f32::MAX;
+    for (i, c) in centers.iter().enumerate() {
+        let d = (c[0] - p[0]).powi(2) + (c[1] - p[1]).powi(2) + (c[2] - p[2]).powi(2);
+        if d < best_dist {
+            best_dist = d;
+            best = i;
+        }
+    }
+    best
+}
+
+/// Moves `color` towards white or black (whichever is further from the
+/// background) until it reaches the `min` contrast ratio.
+fn enforce_contrast(color: Rgb, background: Rgb, min: f32) -> Rgb {
+    if contrast(color, background) >= min {
+        return color;
+    }
+    let target = if luminance(background) < 0.5 { [255, 255, 255] } else { [0, 0, 0] };
+    let mut adjusted = color;
+    for step in 1..=20 {
+        adjusted = mix(color, target, step as f32 / 20.0);
+        if contrast(adjusted, background) >= min {
+            break;
+        }
+    }
+    adjusted
+}
+
+/// WCAG relative luminance.
+pub fn luminance(c: Rgb) -> f32 {
+    let channel = |v: u8| {
+        let v = v as f32 / 255.0;
+        if v <= 0.03928 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * channel(c[0]) + 0.7152 * channel(c[1]) + 0.0722 * channel(c[2])
+}
+
+/// WCAG contrast ratio between two colors, from 1 to 21.
+pub fn contrast(a: Rgb, b: Rgb) -> f32 {
+    let (la, lb) = (luminance(a), luminance(b));
+    let (hi, lo) = if la > lb { (la, lb) } else { (lb, la) };
+    (hi + 0.05) / (lo + 0.05)
+}
+
+fn mix(a: Rgb, b: Rgb, t: f32) -> Rgb {
+    let lerp = |x: u8, y: u8| (x as f32 + (y as f32 - x as f32) * t).round() as u8;
+    [lerp(a[0], b[0]), lerp(a[1], b[1]), lerp(a[2], b[2])]
+}
+
+fn to_hsl(c: Rgb) -> (f32, f32, f32) {
+    let r = c[0] as f32 / 255.0;
+    let g = c[1] as f32 / 255.0;
+    let b = c[2] as f32 / 255.0;
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    if max == min {
+        return (0.0, 0.0, l);
+    }
+    let d = max - min;
+    let s = if l > 0.5 { d / (2.0 - max - min) } else { d / (max + min) };
+    let h = if max == r {
+        (g - b) / d + if g < b { 6.0 } else { 0.0 }
+    } else if max == g {
+        (b - r) / d + 2.0
+    } else {
+        (r - g) / d + 4.0
+    };
+    (h * 60.0, s, l)
+}
+
+fn from_hsl(h: f32, s: f32, l: f32) -> Rgb {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let hp = (h.rem_euclid(360.0)) / 60.0;
+    let x = c * (1.0 - (hp % 2.0 - 1.0).abs());
+    let (r, g, b) = match hp as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    let to_u8 = |v: f32| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    [to_u8(r), to_u8(g), to_u8(b)]
+}
+
+fn hue(c: Rgb) -> f32 {
+    to_hsl(c).0
+}
+
+fn saturation(c: Rgb) -> f32 {
+    to_hsl(c).1
+}
+
+fn hue_distance(a: f32, b: f32) -> f32 {
+    let d = (a - b).abs() % 360.0;
+    d.min(360.0 - d)
+}
+
+fn rotate_hue(c: Rgb, degrees: f32) -> Rgb {
+    let (h, s, l) = to_hsl(c);
+    from_hsl(h + degrees, s.max(0.5), l.clamp(0.35, 0.7))
+}
+
+pub fn hex(c: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+
+fn round2(v: f32) -> f32 {
+    (v * 100.0).round() / 100.0
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn tm_theme_xml(
+    name: &str,
+    settings: &[(&str, String)],
+    scopes: &[(&str, &str, Rgb, Option<&str>)],
+) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n");
+    xml.push_str("<plist version=\"1.0\">\n<dict>\n");
+    xml.push_str(&format!("  <key>name</key>\n  <string>{}</string>\n", xml_escape(name)));
+    xml.push_str("  <key>author</key>\n  <string>Inkify</string>\n");
+    xml.push_str("  <key>settings</key>\n  <array>\n");
+    xml.push_str("    <dict>\n      <key>settings</key>\n      <dict>\n");
+    for (key, value) in settings {
+        xml.push_str(&format!("        <key>{}</key>\n        <string>{}</string>\n", key, value));
+    }
+    xml.push_str("      </dict>\n    </dict>\n");
+    for (label, scope, color, font_style) in scopes {
+        xml.push_str("    <dict>\n");
+        xml.push_str(&format!("      <key>name</key>\n      <string>{}</string>\n", label));
+        xml.push_str(&format!("      <key>scope</key>\n      <string>{}</string>\n", scope));
+        xml.push_str("      <key>settings</key>\n      <dict>\n");
+        xml.push_str(&format!(
+            "        <key>foreground</key>\n        <string>{}</string>\n",
+            hex(*color)
+        ));
+        if let Some(font_style) = font_style {
+            xml.push_str(&format!(
+                "        <key>fontStyle</key>\n        <string>{}</string>\n",
+                font_style
+            ));
+        }
+        xml.push_str("      </dict>\n    </dict>\n");
+    }
+    xml.push_str("  </array>\n</dict>\n</plist>\n");
+    xml
+}
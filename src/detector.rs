@@ -0,0 +1,177 @@
+//! Language detection backends. The same classifier can be loaded as a
+//! TensorFlow SavedModel (`--tensorflow-model-dir`) or an ONNX export
+//! (`--onnx-model-path`), each behind its own cargo feature, and callers only
+//! see the `LanguageDetector` trait.
+
+use anyhow::Error;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A model which predicts the language of a snippet.
+pub trait LanguageDetector: std::fmt::Debug + Send + Sync {
+    /// The log2 probability of each language the model knows, in the
+    /// model's class names.
+    fn predict(&self, code: &str) -> Result<Vec<(String, f32)>, Error>;
+}
+
+/// How long a prediction may take when `INKIFY_DETECTION_TIMEOUT_MS` isn't
+/// set.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the warmup prediction at startup may take, which is slower than
+/// the rest while the model's kernels are compiled.
+const WARMUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Predicted once at startup to warm the model up.
+const WARMUP_CODE: &str = "fn main() {\n    println!(\"Hello, world!\");\n}\n";
+
+/// Why a prediction didn't complete.
+#[derive(Debug, thiserror::Error)]
+pub enum PredictError {
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("the model panicked")]
+    Panicked,
+
+    #[error("prediction failed: {0}")]
+    Failed(Error),
+}
+
+/// Runs `detector` on a thread of its own, so a prediction which hangs can
+/// be abandoned after `timeout`, leaving the thread to finish or not, and a
+/// panic in the model's bindings doesn't take the caller's thread with it.
+pub fn predict(
+    detector: Arc<dyn LanguageDetector>,
+    code: &str,
+    timeout: Duration,
+) -> Result<Vec<(String, f32)>, PredictError> {
+    let (sender, receiver) = mpsc::channel();
+    let code = code.to_owned();
+    std::thread::Builder::new()
+        .name("inkify-detect".to_owned())
+        .spawn(move || {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| detector.predict(&code)));
+            // The receiver is gone when the prediction was abandoned.
+            let _ = sender.send(result);
+        })
+        .map_err(|e| PredictError::Failed(e.into()))?;
+
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok(result)) => result.map_err(PredictError::Failed),
+        Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => Err(PredictError::Panicked),
+        Err(RecvTimeoutError::Timeout) => {
+            tracing::warn!(?timeout, "Abandoning language detection which timed out");
+            Err(PredictError::Timeout(timeout))
+        }
+    }
+}
+
+/// Makes a first prediction with `detector`, so the backend's lazy setup
+/// doesn't slow down the first request. Returns how long it took.
+pub fn warm_up(detector: Arc<dyn LanguageDetector>) -> Result<Duration, PredictError> {
+    let started = Instant::now();
+    predict(detector, WARMUP_CODE, WARMUP_TIMEOUT)?;
+    Ok(started.elapsed())
+}
+
+/// A TensorFlow SavedModel for language detection, loaded once at startup
+/// and shared between requests.
+#[cfg(feature = "tensorflow")]
+#[derive(Debug)]
+pub struct TensorflowDetector {
+    graph: tensorflow::Graph,
+    bundle: tensorflow::SavedModelBundle,
+}
+
+#[cfg(feature = "tensorflow")]
+impl TensorflowDetector {
+    pub fn load(export_dir: &str) -> Result<Self, Error> {
+        use tensorflow::{Graph, SavedModelBundle, SessionOptions};
+
+        let mut graph = Graph::new();
+        let bundle = SavedModelBundle::load(&SessionOptions::new(), &["serve"], &mut graph, export_dir)?;
+        Ok(TensorflowDetector { graph, bundle })
+    }
+}
+
+#[cfg(feature = "tensorflow")]
+impl LanguageDetector for TensorflowDetector {
+    fn predict(&self, code: &str) -> Result<Vec<(String, f32)>, Error> {
+        use tensorflow::{SessionRunArgs, Tensor};
+
+        let input_data = Tensor::new(&[1]).with_values(&[code.to_owned()])?;
+        let mut args = SessionRunArgs::new();
+
+        let input_tensor = self.graph.operation_by_name_required("Placeholder")?;
+
+        let output_tensor_scores = self.graph.operation_by_name_required("head/predictions/probabilities")?;
+
+        let output_tensor_classes = self.graph.operation_by_name_required("head/Tile")?;
+
+        args.add_feed(&input_tensor, 0, &input_data);
+        let output_token_scores = args.request_fetch(&output_tensor_scores, 0);
+        let output_token_classes = args.request_fetch(&output_tensor_classes, 0);
+
+        self.bundle.session.run(&mut args)?;
+
+        let scores: Tensor<f32> = args.fetch(output_token_scores)?;
+
+        let classes: Tensor<String> = args.fetch(output_token_classes)?;
+
+        Ok(scores
+            .iter()
+            .enumerate()
+            .map(|(i, score)| (classes[i].clone(), score.log2()))
+            .collect())
+    }
+}
+
+/// An ONNX export of the classifier, run with ONNX Runtime. The model takes
+/// the code as a string tensor of shape `[1]`, and outputs the probabilities
+/// followed by the class names, as the SavedModel does.
+#[cfg(feature = "onnx")]
+#[derive(Debug)]
+pub struct OnnxDetector {
+    session: ort::Session,
+}
+
+#[cfg(feature = "onnx")]
+impl OnnxDetector {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let environment = ort::Environment::builder().with_name("inkify").build()?.into_arc();
+        let session = ort::SessionBuilder::new(&environment)?.with_model_from_file(path)?;
+        if session.outputs.len() < 2 {
+            return Err(format_err!(
+                "Expected the model to output probabilities and classes, found {} outputs",
+                session.outputs.len()
+            ));
+        }
+        Ok(OnnxDetector { session })
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl LanguageDetector for OnnxDetector {
+    fn predict(&self, code: &str) -> Result<Vec<(String, f32)>, Error> {
+        use ndarray::{Array1, CowArray};
+        use ort::tensor::OrtOwnedTensor;
+        use ort::Value;
+
+        let input = CowArray::from(Array1::from_vec(vec![code.to_owned()])).into_dyn();
+        let inputs = vec![Value::from_array(self.session.allocator(), &input)?];
+        let outputs = self.session.run(inputs)?;
+
+        let scores: OrtOwnedTensor<f32, _> = outputs[0].try_extract()?;
+        let classes: OrtOwnedTensor<String, _> = outputs[1].try_extract()?;
+
+        Ok(classes
+            .view()
+            .iter()
+            .zip(scores.view().iter())
+            .map(|(class, score)| (class.clone(), score.log2()))
+            .collect())
+    }
+}
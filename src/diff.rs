@@ -0,0 +1,195 @@
+//! Rendering of unified diffs, for the `diff` parameter. Removed and added
+//! lines are tinted, the `+`/`-` prefix column is kept, and the gutter shows
+//! both the old and new line numbers.
+//!
+//! The changed code itself is highlighted in its own language rather than
+//! with syntect's diff syntax, which only colors whole lines.
+
+use anyhow::Error;
+use image::Rgba;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, FontStyle, Style, Theme};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+/// Tints of removed and added lines, blended over the code.
+const REMOVED_TINT: Rgba<u8> = Rgba([248, 81, 73, 56]);
+const ADDED_TINT: Rgba<u8> = Rgba([46, 160, 67, 56]);
+
+/// Colors of the `-` and `+` prefixes.
+const REMOVED_COLOR: Color = Color { r: 248, g: 81, b: 73, a: 255 };
+const ADDED_COLOR: Color = Color { r: 63, g: 185, b: 80, a: 255 };
+
+/// Whether `language` asks for diff rendering.
+pub fn is_diff_language(language: &str) -> bool {
+    matches!(language.to_ascii_lowercase().as_str(), "diff" | "patch")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Context,
+    Removed,
+    Added,
+    /// Hunk and file headers, and other lines outside a hunk.
+    Meta,
+}
+
+/// A line of a unified diff.
+#[derive(Debug, Clone)]
+pub struct Line<'a> {
+    pub kind: Kind,
+    /// The `+`, `-`, or space before the code. Empty for meta lines.
+    pub prefix: &'a str,
+    /// The rest of the line, including its ending.
+    pub text: &'a str,
+    pub old: Option<u32>,
+    pub new: Option<u32>,
+}
+
+/// Splits `code` into lines, numbering them from the hunk headers. A hunk
+/// ends once as many lines as its header counts have been read, so a `---`
+/// file header after it isn't mistaken for a removed line.
+pub fn parse(code: &str) -> Vec<Line<'_>> {
+    let (mut old, mut new) = (0, 0);
+    let (mut old_left, mut new_left) = (0, 0);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let in_hunk = old_left > 0 || new_left > 0;
+            let (kind, prefix, text) = match line.chars().next() {
+                Some('-') if in_hunk => (Kind::Removed, &line[..1], &line[1..]),
+                Some('+') if in_hunk => (Kind::Added, &line[..1], &line[1..]),
+                Some(' ') if in_hunk => (Kind::Context, &line[..1], &line[1..]),
+                // Some tools strip the space from blank context lines.
+                Some('\n') | Some('\r') if in_hunk => (Kind::Context, "", line),
+                _ => (Kind::Meta, "", line),
+            };
+            let numbers = match kind {
+                Kind::Removed => (Some(old), None),
+                Kind::Added => (None, Some(new)),
+                Kind::Context => (Some(old), Some(new)),
+                Kind::Meta => (None, None),
+            };
+            match kind {
+                Kind::Removed => old_left -= 1,
+                Kind::Added => new_left -= 1,
+                Kind::Context => {
+                    old_left -= 1;
+                    new_left -= 1;
+                }
+                Kind::Meta => {
+                    if let Some(header) = parse_hunk_header(line) {
+                        (old, old_left, new, new_left) = header;
+                    }
+                }
+            }
+            if kind != Kind::Meta {
+                old += numbers.0.is_some() as u32;
+                new += numbers.1.is_some() as u32;
+            }
+            old_left = old_left.max(0);
+            new_left = new_left.max(0);
+            Line {
+                kind,
+                prefix,
+                text,
+                old: numbers.0,
+                new: numbers.1,
+            }
+        })
+        .collect()
+}
+
+/// The start and length of the old and new sides from a hunk header like
+/// `@@ -12,7 +12,9 @@`. A missing length is 1.
+fn parse_hunk_header(line: &str) -> Option<(u32, i64, u32, i64)> {
+    let ranges = line.strip_prefix("@@ ")?.split(" @@").next()?;
+    let (old, new) = ranges.split_once(' ')?;
+    let side = |range: &str, sign: char| -> Option<(u32, i64)> {
+        let range = range.strip_prefix(sign)?;
+        let (start, len) = range.split_once(',').unwrap_or((range, "1"));
+        Some((start.parse().ok()?, len.parse().ok()?))
+    };
+    let (old_start, old_len) = side(old, '-')?;
+    let (new_start, new_len) = side(new, '+')?;
+    Some((old_start, old_len, new_start, new_len))
+}
+
+/// The language of the changed file, from the `+++` header, or the `---`
+/// one for a deleted file.
+pub fn language(code: &str, ps: &SyntaxSet) -> Option<String> {
+    let path = |prefix: &str| {
+        code.lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .map(|path| path.split('\t').next().unwrap_or(path).trim())
+            .filter(|path| *path != "/dev/null")
+            .map(|path| path.rsplit('/').next().unwrap_or(path).to_owned())
+    };
+    let filename = path("+++ ").or_else(|| path("--- "))?;
+    crate::config::language_from_filename(&filename, ps)
+}
+
+/// Highlights the code of each line in `syntax`, after its prefix. Meta
+/// lines are drawn in the theme's accent color instead.
+pub fn highlight<'a>(
+    lines: &[Line<'a>],
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    ps: &SyntaxSet,
+) -> Result<Vec<Vec<(Style, &'a str)>>, Error> {
+    let mut h = HighlightLines::new(syntax, theme);
+    let foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+    let background = theme.settings.background.unwrap_or(Color::BLACK);
+    let style = |foreground, font_style| Style {
+        foreground,
+        background,
+        font_style,
+    };
+    let meta = style(
+        theme.settings.accent.or(theme.settings.gutter_foreground).unwrap_or(foreground),
+        FontStyle::BOLD,
+    );
+
+    lines
+        .iter()
+        .map(|line| {
+            let prefix = match line.kind {
+                Kind::Meta => return Ok(vec![(meta, line.text)]),
+                Kind::Removed => style(REMOVED_COLOR, FontStyle::empty()),
+                Kind::Added => style(ADDED_COLOR, FontStyle::empty()),
+                Kind::Context => style(foreground, FontStyle::empty()),
+            };
+            let mut ranges = vec![(prefix, line.prefix)];
+            ranges.extend(h.highlight_line(line.text, ps)?);
+            Ok(ranges)
+        })
+        .collect()
+}
+
+/// Gutter text for each line: the old and new line numbers, either blank
+/// where the line doesn't exist on that side.
+pub fn gutter(lines: &[Line]) -> Vec<String> {
+    let width = lines
+        .iter()
+        .flat_map(|line| [line.old, line.new])
+        .flatten()
+        .max()
+        .map_or(1, |n| n.to_string().len());
+    let number = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+    lines
+        .iter()
+        .map(|line| format!("{:>width$} {:>width$}", number(line.old), number(line.new), width = width))
+        .collect()
+}
+
+/// The 1-based lines to tint, with their tint.
+pub fn tints(lines: &[Line]) -> Vec<(u32, Rgba<u8>)> {
+    lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| match line.kind {
+            Kind::Removed => Some((i as u32 + 1, REMOVED_TINT)),
+            Kind::Added => Some((i as u32 + 1, ADDED_TINT)),
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,196 @@
+//! Rendered images cached on disk, for `--cache-dir`, so they survive
+//! restarts. Entries expire after `--cache-ttl`, and the least recently used
+//! are evicted to stay under `--cache-max-bytes`, going by modification
+//! time, which is bumped on every hit.
+//!
+//! Each entry is a file named after the cache key, holding the image as
+//! serialized by `Rendered::encode`. Files which don't decode are deleted and
+//! treated as misses.
+
+use anyhow::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::cache::{CacheBackend, Rendered};
+use crate::metrics::METRICS;
+
+/// Entry lifetime when `--cache-ttl` isn't given.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Size budget when `--cache-max-bytes` isn't given.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How often expired and over-budget entries are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const EXTENSION: &str = "inkify";
+
+pub struct DiskCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+}
+
+impl DiskCache {
+    /// Opens the cache in `dir`, creating it if needed.
+    pub fn open(dir: &Path, ttl: Duration, max_bytes: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        Ok(DiskCache {
+            dir: dir.to_owned(),
+            ttl,
+            max_bytes,
+        })
+    }
+
+    /// Reads the entry for `key`, if there's a fresh, intact one with the
+    /// `content_type` asked for.
+    fn read_entry(&self, key: &str, content_type: &str) -> Option<Rendered> {
+        let path = self.path(key);
+        let rendered = match self.read(&path) {
+            Ok(Some(rendered)) if rendered.content_type == content_type => Some(rendered),
+            Ok(Some(rendered)) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    stored = %rendered.content_type,
+                    requested = %content_type,
+                    "Discarding cached image of the wrong format"
+                );
+                remove(&path);
+                None
+            }
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Discarding corrupted cache entry");
+                remove(&path);
+                None
+            }
+        };
+        if rendered.is_some() {
+            METRICS.disk_cache_hits.fetch_add(1, Ordering::Relaxed);
+            // Marks the entry as recently used.
+            let touched = File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            if let Err(e) = touched {
+                tracing::debug!(path = %path.display(), error = %e, "Failed to touch cache entry");
+            }
+        } else {
+            METRICS.disk_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        rendered
+    }
+
+    /// Deletes expired entries, then the least recently used until the rest
+    /// fit the budget. Blocks on IO.
+    pub fn sweep(&self) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let modified = metadata.modified().unwrap_or(now);
+            if self.expired(modified, now) {
+                remove(&path);
+            } else {
+                entries.push((modified, metadata.len(), path));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            remove(&path);
+            total -= len;
+        }
+        METRICS.disk_cache_bytes.store(total, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sweeps the cache on a thread of its own, every minute.
+    pub fn spawn_sweeper(&'static self) {
+        let spawned = std::thread::Builder::new()
+            .name("inkify-cache-sweep".to_owned())
+            .spawn(move || loop {
+                if let Err(e) = self.sweep() {
+                    tracing::warn!(dir = %self.dir.display(), error = %e, "Failed to sweep the disk cache");
+                }
+                std::thread::sleep(SWEEP_INTERVAL);
+            });
+        if let Err(e) = spawned {
+            tracing::warn!(error = %e, "Failed to start the disk cache sweeper");
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, EXTENSION))
+    }
+
+    fn expired(&self, modified: SystemTime, now: SystemTime) -> bool {
+        now.duration_since(modified).is_ok_and(|age| age > self.ttl)
+    }
+
+    /// `Ok(None)` for a missing or expired entry, and an error for one which
+    /// is corrupted.
+    fn read(&self, path: &Path) -> Result<Option<Rendered>, Error> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let modified = file.metadata()?.modified()?;
+        if self.expired(modified, SystemTime::now()) {
+            remove(path);
+            return Ok(None);
+        }
+
+        let mut encoded = vec![];
+        file.read_to_end(&mut encoded)?;
+        Rendered::decode(&encoded).map(Some)
+    }
+
+    /// Writes the entry for `key`, replacing any other atomically.
+    fn write(&self, key: &str, rendered: &Rendered) -> Result<(), Error> {
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&rendered.encode()?)?;
+        file.persist(self.path(key))?;
+        Ok(())
+    }
+}
+
+impl CacheBackend for DiskCache {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn get(&self, key: &str, content_type: &str) -> Option<Arc<Rendered>> {
+        self.read_entry(key, content_type).map(Arc::new)
+    }
+
+    fn insert(&self, key: &str, rendered: Arc<Rendered>) {
+        if let Err(e) = self.write(key, &rendered) {
+            tracing::warn!(key = %key, error = %e, "Failed to write cache entry");
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to remove cache entry");
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Fallback fonts, appended to every font list so text the requested fonts
+//! can't draw, such as CJK and emoji, renders out of the box.
+
+use font_kit::font::Font;
+use font_kit::source::SystemSource;
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::families;
+
+/// Drawn in place of a character no font has a glyph for.
+const REPLACEMENT: char = '\u{fffd}';
+
+lazy_static! {
+    /// The configured fallbacks which are installed.
+    static ref INSTALLED: Vec<String> = crate::SETTINGS
+        .fallback_fonts
+        .iter()
+        .filter(|name| {
+            let installed = families::is_installed(name);
+            if !installed {
+                tracing::warn!(font = %name, "Fallback font isn't installed");
+            }
+            installed
+        })
+        .cloned()
+        .collect();
+}
+
+/// The configured fallbacks which are installed, in order.
+pub fn installed() -> &'static [String] {
+    &INSTALLED
+}
+
+/// The configured fallbacks which aren't installed.
+pub fn missing() -> Vec<&'static str> {
+    crate::SETTINGS
+        .fallback_fonts
+        .iter()
+        .filter(|name| !INSTALLED.contains(name))
+        .map(|name| name.as_str())
+        .collect()
+}
+
+/// Replaces the characters of `code` which none of the `families` has a
+/// glyph for with U+FFFD, or `?` when that's missing too, rather than leave
+/// them to the rasterizer. ASCII is assumed to be covered. When none of the
+/// families can be loaded, `code` is left alone.
+pub fn replace_missing<'a>(code: &'a str, families: &[&str]) -> Cow<'a, str> {
+    if code.is_ascii() {
+        return Cow::Borrowed(code);
+    }
+    let source = SystemSource::new();
+    let fonts: Vec<Font> = families
+        .iter()
+        .filter_map(|name| source.select_family_by_name(name.trim()).ok())
+        .filter_map(|family| family.fonts().first().and_then(|face| face.load().ok()))
+        .collect();
+    if fonts.is_empty() {
+        return Cow::Borrowed(code);
+    }
+
+    let mut covered: HashMap<char, bool> = HashMap::new();
+    let mut has_glyph = |c: char| {
+        c.is_ascii()
+            || c.is_control()
+            || *covered
+                .entry(c)
+                .or_insert_with(|| fonts.iter().any(|font| font.glyph_for_char(c).is_some()))
+    };
+    if code.chars().all(&mut has_glyph) {
+        return Cow::Borrowed(code);
+    }
+    let replacement = if has_glyph(REPLACEMENT) { REPLACEMENT } else { '?' };
+    Cow::Owned(
+        code.chars()
+            .map(|c| if has_glyph(c) { c } else { replacement })
+            .collect(),
+    )
+}
@@ -0,0 +1,93 @@
+//! Details of the system's font families, for /fonts.
+
+use anyhow::Error;
+use font_kit::properties::Style;
+use font_kit::source::SystemSource;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Loading every face is slow, and fonts aren't installed while the
+    /// server runs, so the families are only loaded once.
+    static ref FAMILIES: Result<Vec<Family>, String> = load().map_err(|e| e.to_string());
+
+    /// The names of the families, lowercased.
+    static ref NAMES: Vec<String> = match SystemSource::new().all_families() {
+        Ok(names) => names.iter().map(|name| name.to_lowercase()).collect(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list fonts");
+            vec![]
+        }
+    };
+}
+
+/// A font family and the faces it has.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Family {
+    pub name: String,
+
+    /// Whether any face is monospace, and so suited to code.
+    pub monospace: bool,
+
+    /// The weights of the faces, from 100 (thin) to 900 (black).
+    pub weights: Vec<u16>,
+
+    /// The styles of the faces: 'normal', 'italic', or 'oblique'.
+    pub styles: Vec<&'static str>,
+}
+
+/// The families installed, sorted by name. Loads them on the first call.
+pub fn all() -> Result<&'static [Family], Error> {
+    FAMILIES.as_deref().map_err(|e| format_err!("{}", e))
+}
+
+/// Whether a family named `name` is installed, ignoring case and
+/// surrounding whitespace.
+pub fn is_installed(name: &str) -> bool {
+    let name = name.trim().to_lowercase();
+    NAMES.iter().any(|installed| *installed == name)
+}
+
+/// Loads every face of every family installed. A family none of whose faces
+/// load is skipped, as are faces which fail to load.
+fn load() -> Result<Vec<Family>, Error> {
+    let source = SystemSource::new();
+    let mut names = source.all_families()?;
+    names.sort();
+    names.dedup();
+
+    let families = names
+        .into_iter()
+        .filter_map(|name| {
+            let handle = source.select_family_by_name(&name).ok()?;
+            let faces: Vec<_> = handle.fonts().iter().filter_map(|face| face.load().ok()).collect();
+            if faces.is_empty() {
+                return None;
+            }
+
+            let mut weights: Vec<u16> = faces
+                .iter()
+                .map(|face| face.properties().weight.0.round() as u16)
+                .collect();
+            weights.sort_unstable();
+            weights.dedup();
+            let mut styles: Vec<&'static str> = faces
+                .iter()
+                .map(|face| match face.properties().style {
+                    Style::Normal => "normal",
+                    Style::Italic => "italic",
+                    Style::Oblique => "oblique",
+                })
+                .collect();
+            styles.sort_unstable();
+            styles.dedup();
+
+            Some(Family {
+                name,
+                monospace: faces.iter().any(|face| face.is_monospace()),
+                weights,
+                styles,
+            })
+        })
+        .collect();
+    Ok(families)
+}
@@ -0,0 +1,514 @@
+use actix_web::http::StatusCode;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::api::ApiError;
+
+/// Limits applied to every server-side fetch of a user supplied URL.
+#[derive(Debug, Clone)]
+pub struct FetchPolicy {
+    /// Total time allowed for the request, across every redirect and
+    /// including reading the body.
+    pub timeout: Duration,
+
+    /// Maximum number of bytes read from the response body.
+    pub max_bytes: usize,
+
+    /// Allow URLs resolving to loopback, link-local, and private addresses.
+    pub allow_private: bool,
+}
+
+/// Maximum number of redirects followed. Every hop is validated against the
+/// policy before it is requested.
+const MAX_REDIRECTS: usize = 5;
+
+const USER_AGENT: &str = concat!("inkify/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
+pub struct Fetched {
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+
+    /// The response was a 304 to a conditional request, and so has no body.
+    pub not_modified: bool,
+
+    /// The caching headers of the response, for revalidating it later.
+    pub cache_control: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Fetched {
+    /// Whether the response declared an image content type. A missing header
+    /// is tolerated, since the bytes are sniffed before decoding anyway.
+    pub fn is_image(&self) -> bool {
+        match &self.content_type {
+            Some(content_type) => content_type.trim().to_ascii_lowercase().starts_with("image/"),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("response exceeds the {0} byte limit")]
+    TooLarge(usize),
+
+    #[error("URL is not allowed: {0}")]
+    Blocked(String),
+
+    #[error("upstream responded with status {0}")]
+    Status(u16),
+
+    #[error("request failed: {0}")]
+    Request(String),
+}
+
+impl FetchError {
+    /// Converts the error into an API error naming the parameter and URL
+    /// which caused it.
+    pub fn into_api_error(self, param: &str, url: &str) -> ApiError {
+        let message = format!("Failed to fetch {} `{}`: {}", param, url, self);
+        match self {
+            FetchError::InvalidUrl(_) => ApiError::bad_request("invalid_url", message),
+            FetchError::Blocked(_) => ApiError::new(StatusCode::FORBIDDEN, "url_blocked", message),
+            FetchError::TooLarge(_) => {
+                ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "fetch_too_large", message)
+            }
+            _ => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "fetch_failed", message),
+        }
+    }
+}
+
+/// Whether `url` is a `data:` URI, which is decoded rather than fetched.
+pub fn is_data_uri(url: &str) -> bool {
+    url.get(..5).is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+/// Decodes a `data:image/<type>;base64,<data>` URI without any network
+/// access, for deployments which can't fetch URLs. The decoded size is
+/// limited to `max_bytes`, as for a download.
+pub fn decode_data_uri(param: &str, uri: &str, max_bytes: usize) -> Result<Vec<u8>, ApiError> {
+    let invalid = |reason: &str| {
+        ApiError::bad_request("invalid_data_uri", format!("{} is not a valid data URI: {}", param, reason))
+    };
+    let (header, data) = uri
+        .get(5..)
+        .and_then(|rest| rest.split_once(','))
+        .ok_or_else(|| invalid("expected data:<media type>;base64,<data>"))?;
+    let mut params = header.split(';').map(|p| p.trim());
+    let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+    if !params.any(|p| p.eq_ignore_ascii_case("base64")) {
+        return Err(invalid("only base64 encoded data is supported"));
+    }
+    if !media_type.starts_with("image/") || image::ImageFormat::from_mime_type(&media_type).is_none() {
+        return Err(ApiError::bad_request(
+            "unsupported_media_type",
+            format!("{} data URI has unsupported media type `{}`", param, media_type),
+        ));
+    }
+
+    let too_large = || {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "fetch_too_large",
+            format!("{} data URI exceeds the {} byte limit", param, max_bytes),
+        )
+    };
+    // Four base64 characters decode to three bytes, so oversized data can be
+    // rejected before decoding it.
+    if data.len() / 4 * 3 > max_bytes + 3 {
+        return Err(too_large());
+    }
+    let bytes = crate::source::decode_base64(data).ok_or_else(|| invalid("the data is not valid base64"))?;
+    if bytes.len() > max_bytes {
+        return Err(too_large());
+    }
+    if image::guess_format(&bytes).is_err() {
+        return Err(invalid("the data is not an image"));
+    }
+    Ok(bytes)
+}
+
+pub async fn fetch(url: &str, policy: &FetchPolicy) -> Result<Fetched, FetchError> {
+    fetch_with_headers(url, policy, &[]).await
+}
+
+/// Like `fetch`, sending additional request headers with every hop. Headers
+/// carrying credentials are only sent while the redirects stay on the origin
+/// of `url`, so a redirect elsewhere can't collect them.
+pub async fn fetch_with_headers(
+    url: &str,
+    policy: &FetchPolicy,
+    headers: &[(&str, String)],
+) -> Result<Fetched, FetchError> {
+    // A single deadline covers the whole redirect chain and the body, so a
+    // slow server can't stretch the fetch out hop by hop.
+    actix_web::rt::time::timeout(policy.timeout, fetch_within(url, policy, headers))
+        .await
+        .map_err(|_| FetchError::Timeout(policy.timeout))?
+}
+
+async fn fetch_within(url: &str, policy: &FetchPolicy, headers: &[(&str, String)]) -> Result<Fetched, FetchError> {
+    let mut url = reqwest::Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+    let origin = url.origin();
+    let mut send_credentials = true;
+
+    let map_err = |e: reqwest::Error| {
+        if e.is_timeout() {
+            FetchError::Timeout(policy.timeout)
+        } else {
+            FetchError::Request(e.to_string())
+        }
+    };
+
+    let mut redirects = 0;
+    let mut res = loop {
+        let addr = check_url(&url, policy).await?;
+
+        // Redirects are followed by hand so each hop can be checked, and the
+        // host is pinned to the address that was checked so a second DNS
+        // lookup can't point the request somewhere else.
+        let mut builder = reqwest::Client::builder()
+            .timeout(policy.timeout)
+            .redirect(reqwest::redirect::Policy::none());
+        if let (Some(addr), Some(host)) = (addr, url.host_str()) {
+            builder = builder.resolve(host, addr);
+        }
+        let client = builder.build().map_err(|e| FetchError::Request(e.to_string()))?;
+
+        let mut request = client.get(url.clone()).header(reqwest::header::USER_AGENT, USER_AGENT);
+        for (name, value) in headers {
+            if send_credentials || !is_credential(name) {
+                request = request.header(*name, value.as_str());
+            }
+        }
+        let res = request.send().await.map_err(map_err)?;
+        if !res.status().is_redirection() {
+            break res;
+        }
+
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(FetchError::Request("too many redirects".to_owned()));
+        }
+        let location = res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| FetchError::Request("redirect without a Location header".to_owned()))?;
+        url = url
+            .join(location)
+            .map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+        // Once a redirect leaves the origin the credentials stay behind,
+        // even if a later one comes back.
+        if url.origin() != origin {
+            send_credentials = false;
+        }
+    };
+
+    let header = |name: reqwest::header::HeaderName| {
+        res.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    };
+    let content_type = header(reqwest::header::CONTENT_TYPE);
+    let cache_control = header(reqwest::header::CACHE_CONTROL);
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+
+    let conditional = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("if-none-match") || name.eq_ignore_ascii_case("if-modified-since"));
+    if conditional && res.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(Fetched {
+            bytes: vec![],
+            content_type,
+            not_modified: true,
+            cache_control,
+            etag,
+            last_modified,
+        });
+    }
+    if !res.status().is_success() {
+        return Err(FetchError::Status(res.status().as_u16()));
+    }
+    if let Some(length) = res.content_length() {
+        if length > policy.max_bytes as u64 {
+            return Err(FetchError::TooLarge(policy.max_bytes));
+        }
+    }
+
+    // The Content-Length header can't be trusted, so the limit is enforced
+    // again while reading the body.
+    let mut bytes = vec![];
+    while let Some(chunk) = res.chunk().await.map_err(map_err)? {
+        if bytes.len() + chunk.len() > policy.max_bytes {
+            return Err(FetchError::TooLarge(policy.max_bytes));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Fetched {
+        bytes,
+        content_type,
+        not_modified: false,
+        cache_control,
+        etag,
+        last_modified,
+    })
+}
+
+/// Request headers carrying credentials, which are only sent to the origin
+/// they were meant for.
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+fn is_credential(name: &str) -> bool {
+    CREDENTIAL_HEADERS.iter().any(|credential| name.eq_ignore_ascii_case(credential))
+}
+
+/// Validates the scheme and resolved address of `url`. Returns the address
+/// the request should be pinned to, or `None` when private addresses are
+/// allowed and no pinning is necessary.
+async fn check_url(url: &reqwest::Url, policy: &FetchPolicy) -> Result<Option<SocketAddr>, FetchError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(FetchError::Blocked(format!("scheme `{}` is not allowed", url.scheme())));
+    }
+    if policy.allow_private {
+        return Ok(None);
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| FetchError::InvalidUrl("missing host".to_owned()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port))
+        .await
+        .map_err(|e| FetchError::Request(format!("failed to resolve `{}`: {}", host, e)))?
+        .collect();
+
+    // Every address has to pass, otherwise the connection could land on
+    // whichever one is blocked.
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(FetchError::Blocked(format!("`{}` resolves to {}", host, addr.ip())));
+    }
+    addrs
+        .into_iter()
+        .next()
+        .map(Some)
+        .ok_or_else(|| FetchError::Request(format!("`{}` did not resolve", host)))
+}
+
+/// Whether `ip` is a publicly routable address, rejecting loopback,
+/// link-local, RFC1918, unique-local, and other special purpose ranges.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // Shared address space (100.64.0.0/10)
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // "This network" (0.0.0.0/8)
+        || octets[0] == 0
+        // IETF protocol assignments (192.0.0.0/24)
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // Benchmarking (198.18.0.0/15)
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        // Reserved (240.0.0.0/4)
+        || octets[0] >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let first = segments[0];
+    // Addresses which embed an IPv4 address reach it through a translator
+    // or relay, so they're as public as it is.
+    if let Some(embedded) = embedded_v4(ip) {
+        return is_public_v4(embedded);
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // IPv4-compatible (::/96), deprecated
+        || segments[..6].iter().all(|&segment| segment == 0)
+        // Local-use NAT64 (64:ff9b:1::/48), whose embedding varies
+        || segments[..3] == [0x64, 0xff9b, 1]
+        // Unique local (fc00::/7)
+        || (first & 0xfe00) == 0xfc00
+        // Link-local (fe80::/10)
+        || (first & 0xffc0) == 0xfe80
+        // Site-local (fec0::/10), deprecated
+        || (first & 0xffc0) == 0xfec0)
+}
+
+/// The IPv4 address embedded in a NAT64 (64:ff9b::/96) or 6to4 (2002::/16)
+/// address.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from(((high as u32) << 16) | low as u32);
+    match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        [0x2002, high, low, ..] => Some(v4(high, low)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answers one request per entry of `responses` on a local port, sending
+    /// each request head back over the returned channel.
+    fn serve(responses: Vec<String>) -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                tx.send(String::from_utf8_lossy(&head).to_ascii_lowercase()).unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    fn redirect_to(location: &str) -> String {
+        format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", location)
+    }
+
+    fn ok() -> String {
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".into()
+    }
+
+    fn policy() -> FetchPolicy {
+        FetchPolicy { timeout: Duration::from_secs(5), max_bytes: 1024, allow_private: true }
+    }
+
+    fn headers() -> Vec<(&'static str, String)> {
+        vec![("Authorization", "Bearer secret".into()), ("Accept", "text/plain".into())]
+    }
+
+    #[actix_web::test]
+    async fn credentials_follow_redirects_on_the_same_origin() {
+        let (port, heads) = serve(vec![redirect_to("/next"), ok()]);
+        let fetched = fetch_with_headers(&format!("http://127.0.0.1:{}/", port), &policy(), &headers())
+            .await
+            .unwrap();
+        assert_eq!(fetched.bytes, b"ok");
+        for head in heads.iter().take(2) {
+            assert!(head.contains("authorization: bearer secret"), "{}", head);
+        }
+    }
+
+    #[actix_web::test]
+    async fn credentials_are_dropped_when_a_redirect_leaves_the_origin() {
+        let (other, other_heads) = serve(vec![ok()]);
+        let (port, heads) = serve(vec![redirect_to(&format!("http://127.0.0.1:{}/", other))]);
+        let fetched = fetch_with_headers(&format!("http://127.0.0.1:{}/", port), &policy(), &headers())
+            .await
+            .unwrap();
+        assert_eq!(fetched.bytes, b"ok");
+        assert!(heads.recv().unwrap().contains("authorization: bearer secret"));
+        let head = other_heads.recv().unwrap();
+        assert!(!head.contains("authorization"), "{}", head);
+        assert!(head.contains("accept: text/plain"), "{}", head);
+    }
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "64:ff9b::5db8:d822", "2002:5db8:d822::1"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn private_ipv4_addresses_are_blocked() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.1.2.3",
+            "192.0.0.8",
+            "198.18.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn private_ipv6_addresses_are_blocked() {
+        for ip in ["::1", "::", "fd00::1", "fe80::1", "ff02::1", "fec0::1", "::ffff:127.0.0.1"] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn ipv6_addresses_embedding_private_ipv4_are_blocked() {
+        for ip in [
+            // NAT64
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::a00:1",
+            // 6to4
+            "2002:7f00:1::1",
+            "2002:c0a8:101::",
+            // IPv4-compatible
+            "::7f00:1",
+            "::5db8:d822",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn credential_headers_are_matched_case_insensitively() {
+        assert!(is_credential("Authorization"));
+        assert!(is_credential("cookie"));
+        assert!(is_credential("Proxy-Authorization"));
+        assert!(!is_credential("Accept"));
+        assert!(!is_credential("If-None-Match"));
+    }
+}
@@ -0,0 +1,89 @@
+//! The `Content-Disposition` header of generated images, for the `download`
+//! and `filename` parameters.
+
+/// Longest file name suggested, in characters, including the extension.
+const MAX_LEN: usize = 100;
+
+/// Name used when neither the request nor the window title and language
+/// give one.
+const FALLBACK: &str = "inkify";
+
+/// Characters which may appear unescaped in an RFC 5987 `filename*` value,
+/// besides ASCII letters and digits.
+const ATTR_CHARS: &[char] = &['!', '#', '$', '&', '+', '-', '.', '^', '_', '`', '|', '~'];
+
+/// The file name to suggest: `requested` when given, or else the window
+/// title, with its extension swapped, or the language. Always sanitized and
+/// ending in `.<extension>`.
+pub fn suggest(requested: Option<&str>, window_title: Option<&str>, language: &str, extension: &str) -> String {
+    let without_extension = |name: &str| {
+        let name = sanitize(name);
+        match name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem.to_owned(),
+            _ => name,
+        }
+    };
+    let stem = [
+        requested.map(without_extension),
+        window_title.map(without_extension),
+        Some(sanitize(&language.to_lowercase().replace(' ', "-"))),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|stem| !stem.is_empty())
+    .unwrap_or_else(|| FALLBACK.to_owned());
+
+    let suffix = format!(".{}", extension);
+    let stem = match stem.len().checked_sub(suffix.len()) {
+        Some(at) if stem.is_char_boundary(at) && stem[at..].eq_ignore_ascii_case(&suffix) => &stem[..at],
+        _ => &stem,
+    };
+    let stem: String = stem.chars().take(MAX_LEN - suffix.len()).collect();
+    format!("{}{}", stem, suffix)
+}
+
+/// Keeps only the last path component of `name`, dropping control
+/// characters, quotes, and leading dots so it can't name a hidden file.
+/// Without separators or quotes, the result can be quoted as is.
+fn sanitize(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+    name.trim().trim_start_matches('.').trim().to_owned()
+}
+
+/// The `Content-Disposition` value for `filename`, as an attachment when
+/// `download`. A name which isn't plain ASCII is sent in `filename*` as
+/// well, with an ASCII approximation in `filename` for older clients.
+pub fn content_disposition(filename: &str, download: bool) -> String {
+    let kind = if download { "attachment" } else { "inline" };
+    if filename.is_ascii() {
+        return format!("{}; filename=\"{}\"", kind, filename);
+    }
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        kind,
+        fallback,
+        encode(filename)
+    )
+}
+
+/// Percent-encodes `s` as an RFC 5987 `value-chars`.
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        let c = b as char;
+        if c.is_ascii_alphanumeric() || ATTR_CHARS.contains(&c) {
+            out.push(c);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
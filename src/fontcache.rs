@@ -0,0 +1,132 @@
+//! Fonts and formatters reused across renders. Finding a family through
+//! fontconfig and parsing its faces dominates the time of small renders, so
+//! the font collections drawn with, and silicon's formatters which load
+//! their own, are kept per thread in small LRU caches.
+//!
+//! font-kit's fonts aren't `Send`, so neither are the collections or the
+//! formatters holding them, and each thread rendering keeps its own, which
+//! goes with it when an idle thread of the blocking pool exits.
+
+use anyhow::Error;
+use lru::LruCache;
+use silicon::font::FontCollection;
+use silicon::formatter::ImageFormatter;
+use std::cell::RefCell;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+
+/// How many font lists each thread keeps loaded.
+pub const COLLECTIONS: usize = 32;
+
+/// How many formatters each thread keeps built.
+pub const FORMATTERS: usize = 16;
+
+/// A font list, with the sizes as bits so it can be hashed.
+type Key = Vec<(String, u32)>;
+
+thread_local! {
+    static COLLECTION_CACHE: RefCell<LruCache<Key, Rc<FontCollection>>> =
+        RefCell::new(LruCache::new(NonZeroUsize::new(COLLECTIONS).unwrap()));
+
+    static FORMATTER_CACHE: RefCell<LruCache<String, ImageFormatter>> =
+        RefCell::new(LruCache::new(NonZeroUsize::new(FORMATTERS).unwrap()));
+}
+
+/// The fonts of `font`, a list of families and sizes, loading them unless
+/// this thread already has.
+pub fn collection(font: &[(String, f32)]) -> Result<Rc<FontCollection>, Error> {
+    let key: Key = font.iter().map(|(name, size)| (name.clone(), size.to_bits())).collect();
+    if let Some(fonts) = COLLECTION_CACHE.with(|cache| cache.borrow_mut().get(&key).cloned()) {
+        return Ok(fonts);
+    }
+    let fonts = Rc::new(FontCollection::new(font)?);
+    COLLECTION_CACHE.with(|cache| cache.borrow_mut().put(key, fonts.clone()));
+    Ok(fonts)
+}
+
+/// Calls `f` with the formatter identified by `key`, building it with
+/// `build` unless this thread already has. Without a key, the formatter is
+/// built for this call only.
+pub fn with_formatter<T, E>(
+    key: Option<String>,
+    build: impl FnOnce() -> Result<ImageFormatter, E>,
+    f: impl FnOnce(&mut ImageFormatter) -> T,
+) -> Result<T, E> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(f(&mut build()?)),
+    };
+    // It's taken out of the cache while in use, so `f` can't find the cache
+    // borrowed.
+    let mut formatter = match FORMATTER_CACHE.with(|cache| cache.borrow_mut().pop(&key)) {
+        Some(formatter) => formatter,
+        None => build()?,
+    };
+    let result = f(&mut formatter);
+    FORMATTER_CACHE.with(|cache| cache.borrow_mut().put(key, formatter));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::{Duration, Instant};
+
+    const ROUNDS: u32 = 5;
+
+    fn timed(mut f: impl FnMut()) -> Duration {
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            f();
+        }
+        started.elapsed()
+    }
+
+    #[test]
+    fn collections_are_loaded_once() {
+        let font = Config::default().title_font();
+        let first = collection(&font).unwrap();
+        assert!(Rc::ptr_eq(&first, &collection(&font).unwrap()));
+    }
+
+    #[test]
+    fn formatters_are_built_once_per_key() {
+        let conf = Config::default();
+        let key = conf.formatter_key().unwrap();
+        let mut builds = 0;
+        for _ in 0..3 {
+            with_formatter(
+                Some(key.clone()),
+                || {
+                    builds += 1;
+                    conf.get_formatter()
+                },
+                |_| {},
+            )
+            .unwrap();
+        }
+        assert_eq!(builds, 1);
+    }
+
+    /// The point of the caches: a render of a small snippet shouldn't pay
+    /// for finding and parsing fonts every time. Timing is unreliable while
+    /// other tests run, so run it alone with `--ignored`.
+    #[test]
+    #[ignore = "timing, run with --ignored on an idle machine"]
+    fn cached_fonts_and_formatters_are_faster() {
+        let conf = Config::default();
+        let font = conf.title_font();
+
+        let uncached = timed(|| drop(FontCollection::new(&font).unwrap()));
+        collection(&font).unwrap();
+        let cached = timed(|| drop(collection(&font).unwrap()));
+        assert!(cached * 2 < uncached, "cached fonts took {:?}, uncached {:?}", cached, uncached);
+
+        let uncached = timed(|| with_formatter(None, || conf.get_formatter(), |_| {}).unwrap());
+        let key = conf.formatter_key();
+        with_formatter(key.clone(), || conf.get_formatter(), |_| {}).unwrap();
+        let cached = timed(|| with_formatter(key.clone(), || conf.get_formatter(), |_| {}).unwrap());
+        assert!(cached * 2 < uncached, "cached formatters took {:?}, uncached {:?}", cached, uncached);
+    }
+}
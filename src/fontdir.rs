@@ -0,0 +1,81 @@
+//! Fonts loaded from a directory, for `--fonts-dir`.
+//!
+//! silicon finds fonts by family name through font-kit, which asks
+//! fontconfig on Linux. The fonts are made visible by pointing fontconfig at
+//! a configuration which scans them before the system's fonts, so a family
+//! also installed system-wide resolves to the custom one.
+
+use anyhow::Error;
+use font_kit::font::Font;
+use std::path::Path;
+
+/// The system configuration, included after the custom fonts.
+const SYSTEM_CONFIG: &str = "/etc/fonts/fonts.conf";
+
+const EXTENSIONS: &[&str] = &["ttf", "otf", "ttc"];
+
+/// Makes the fonts in `dir` available by family name. Must be called before
+/// any font is loaded. Files which fail to load are logged and skipped.
+/// Returns the number of fonts added.
+pub fn install(dir: &Path) -> Result<usize, Error> {
+    // Only the fonts which load are linked into a directory of our own, so
+    // fontconfig never sees the broken ones.
+    let staging = tempfile::Builder::new().prefix("inkify-fonts").tempdir()?.into_path();
+    let fonts_dir = staging.join("fonts");
+    std::fs::create_dir(&fonts_dir)?;
+
+    let mut count = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_font = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(extension)));
+        let file_name = match path.file_name() {
+            Some(file_name) if is_font => file_name,
+            _ => continue,
+        };
+        match Font::from_path(&path, 0) {
+            Ok(font) => {
+                link(&path.canonicalize()?, &fonts_dir.join(file_name))?;
+                tracing::info!(family = %font.family_name(), path = %path.display(), "Loaded font");
+                count += 1;
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Skipping font which failed to load");
+            }
+        }
+    }
+
+    let system = std::env::var("FONTCONFIG_FILE").unwrap_or_else(|_| SYSTEM_CONFIG.to_owned());
+    let config = format!(
+        r#"<?xml version="1.0"?>
+<!DOCTYPE fontconfig SYSTEM "fonts.dtd">
+<fontconfig>
+  <dir>{}</dir>
+  <include ignore_missing="yes">{}</include>
+</fontconfig>
+"#,
+        escape(&fonts_dir.to_string_lossy()),
+        escape(&system)
+    );
+    let config_path = staging.join("fonts.conf");
+    std::fs::write(&config_path, config)?;
+    std::env::set_var("FONTCONFIG_FILE", &config_path);
+    Ok(count)
+}
+
+#[cfg(unix)]
+fn link(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(from, to)
+}
+
+#[cfg(not(unix))]
+fn link(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::copy(from, to).map(|_| ())
+}
+
+/// Escapes `s` for XML text.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
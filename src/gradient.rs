@@ -0,0 +1,94 @@
+//! Gradient backgrounds for the padding area, from the `background_gradient`
+//! parameter.
+
+use anyhow::Error;
+use image::{Rgba, RgbaImage};
+
+/// Describes `background_gradient` in error messages.
+pub const GRAMMAR: &str =
+    "expected `linear,<angle>deg,<color>,<color>[,...]` or `radial,<color>,<color>[,...]`";
+
+/// A gradient through two or more evenly spaced color stops.
+#[derive(Debug, Clone)]
+pub enum Gradient {
+    /// Follows CSS `linear-gradient`: 0deg runs bottom to top, 90deg left
+    /// to right.
+    Linear { angle: f32, stops: Vec<Rgba<u8>> },
+    /// Runs from the center out to the corners.
+    Radial { stops: Vec<Rgba<u8>> },
+}
+
+impl std::str::FromStr for Gradient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format_err!("Invalid background_gradient `{}`, {}", s, GRAMMAR);
+        let mut parts = s.split(',').map(|part| part.trim());
+        let kind = parts.next().ok_or_else(invalid)?;
+        let mut parts: Vec<&str> = parts.collect();
+
+        let angle = match kind {
+            "linear" => {
+                let angle = parts.first().and_then(|a| a.strip_suffix("deg")).ok_or_else(invalid)?;
+                let angle = angle.trim().parse::<f32>().map_err(|_| invalid())?;
+                if !angle.is_finite() {
+                    return Err(invalid());
+                }
+                parts.remove(0);
+                Some(angle)
+            }
+            "radial" => None,
+            _ => return Err(invalid()),
+        };
+
+        if parts.len() < 2 {
+            return Err(invalid());
+        }
+        let stops = parts
+            .iter()
+            .map(|color| crate::rgba::parse_str_color(color).map(|c| c.to_rgba()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format_err!("Invalid background_gradient `{}`: {}", s, e))?;
+
+        Ok(match angle {
+            Some(angle) => Gradient::Linear { angle, stops },
+            None => Gradient::Radial { stops },
+        })
+    }
+}
+
+impl Gradient {
+    /// Renders the gradient at exactly `width` by `height`.
+    pub fn render(&self, width: u32, height: u32) -> RgbaImage {
+        let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+        match self {
+            Gradient::Linear { angle, stops } => {
+                let (dx, dy) = (angle.to_radians().sin(), -angle.to_radians().cos());
+                // The length over which the gradient runs, so the first and
+                // last stops land on the corners, as in CSS.
+                let length = (width as f32 * dx).abs() + (height as f32 * dy).abs();
+                RgbaImage::from_fn(width, height, |x, y| {
+                    let along = (x as f32 + 0.5 - cx) * dx + (y as f32 + 0.5 - cy) * dy;
+                    interpolate(stops, along / length.max(1.0) + 0.5)
+                })
+            }
+            Gradient::Radial { stops } => {
+                let radius = (cx * cx + cy * cy).sqrt().max(1.0);
+                RgbaImage::from_fn(width, height, |x, y| {
+                    let (ox, oy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                    interpolate(stops, (ox * ox + oy * oy).sqrt() / radius)
+                })
+            }
+        }
+    }
+}
+
+/// The color at `t` (0 to 1) along evenly spaced `stops`.
+fn interpolate(stops: &[Rgba<u8>], t: f32) -> Rgba<u8> {
+    let scaled = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+    let i = (scaled.floor() as usize).min(stops.len() - 2);
+    let t = scaled - i as f32;
+    let (a, b) = (stops[i].0, stops[i + 1].0);
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    Rgba([mix(a[0], b[0]), mix(a[1], b[1]), mix(a[2], b[2]), mix(a[3], b[3])])
+}
@@ -0,0 +1,86 @@
+//! Indentation guides, for the `indent_guides` parameter: a thin vertical
+//! line at every `tab_width` columns of each line's indentation, drawn over
+//! the formatter's output.
+
+use anyhow::Error;
+use image::{DynamicImage, Rgba};
+
+use crate::config::Config;
+use crate::highlight::{Layout, CODE_PAD};
+use crate::watermark;
+
+/// Opacity of the theme's foreground when no color is given.
+pub const DEFAULT_OPACITY: u8 = 0x30;
+
+/// The indentation of each line of `code` in columns, with tabs expanded to
+/// `tab_width` and the first `skip` columns, such as a diff's prefix,
+/// ignored. A blank line takes the lesser indentation of the lines around
+/// it, so it doesn't break the guides of the block it's in.
+pub fn indents(code: &str, tab_width: u8, skip: usize) -> Vec<usize> {
+    let tab_width = tab_width.max(1) as usize;
+    let measured: Vec<Option<usize>> = code
+        .lines()
+        .map(|line| {
+            let mut col = 0;
+            for c in line.chars() {
+                match c {
+                    ' ' => col += 1,
+                    '\t' => col += tab_width - col % tab_width,
+                    _ if col < skip => col += 1,
+                    _ => return Some(col.saturating_sub(skip)),
+                }
+            }
+            None
+        })
+        .collect();
+
+    let mut previous = 0;
+    measured
+        .iter()
+        .enumerate()
+        .map(|(i, indent)| match indent {
+            Some(indent) => {
+                previous = *indent;
+                *indent
+            }
+            None => {
+                let next = measured[i..].iter().flatten().next().copied().unwrap_or(0);
+                previous.min(next)
+            }
+        })
+        .collect()
+}
+
+/// Draws the guides onto `image`, the bare window out of the formatter.
+/// `rows` holds the indentation of each row formatted, and `gutter` the
+/// gutter text drawn before it, if any.
+pub fn draw(
+    image: DynamicImage,
+    rows: &[usize],
+    gutter: &[String],
+    skip: usize,
+    color: Rgba<u8>,
+    conf: &Config,
+) -> Result<DynamicImage, Error> {
+    let mut image = image.to_rgba8();
+    let layout = Layout::new(image.height(), rows.len() as u32, conf)?;
+    let advance = layout.fonts.get_text_len(" ");
+    let tab_width = conf.tab_width.max(1) as usize;
+    let alpha = color.0[3] as f32 / 255.0;
+
+    for (i, &indent) in rows.iter().enumerate() {
+        let top = layout.row_top(i as u32 + 1);
+        let gutter = gutter.get(i).map_or(0, |label| layout.fonts.get_text_len(label));
+        let left = CODE_PAD + gutter + advance * skip as u32;
+        for col in (0..indent).step_by(tab_width) {
+            let x = left + advance * col as u32;
+            if x >= image.width() {
+                break;
+            }
+            for y in top..(top + layout.pitch).min(image.height()) {
+                watermark::over(image.get_pixel_mut(x, y), color, alpha);
+            }
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(image))
+}
@@ -0,0 +1,399 @@
+//! Language detection without a model: a shebang map, then keyword
+//! frequencies scored against a small table of common languages. Less
+//! reliable than the model, which is why its probabilities are capped.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// Highest probability given to a keyword based prediction.
+const MAX_PROBABILITY: f32 = 0.5;
+
+/// Probability given to the language named by a shebang.
+const SHEBANG_PROBABILITY: f32 = 0.9;
+
+/// Matches of one pattern counted at most, so one repeated construct
+/// doesn't outweigh the rest.
+const MAX_MATCHES: usize = 10;
+
+/// Lowest total score a language needs to be predicted at all.
+const MIN_SCORE: f32 = 3.0;
+
+/// Bytes of the code scanned. The start of a snippet is enough to tell.
+const MAX_SCANNED: usize = 64 * 1024;
+
+/// Interpreters named in shebangs, without version numbers, and the syntax
+/// token of their language.
+const SHEBANGS: &[(&str, &str)] = &[
+    ("python", "py"),
+    ("node", "js"),
+    ("nodejs", "js"),
+    ("deno", "js"),
+    ("bash", "sh"),
+    ("sh", "sh"),
+    ("zsh", "sh"),
+    ("ksh", "sh"),
+    ("dash", "sh"),
+    ("ruby", "rb"),
+    ("perl", "pl"),
+    ("php", "php"),
+    ("lua", "lua"),
+    ("Rscript", "r"),
+];
+
+/// File extensions which syntect doesn't know, and the syntax token of their
+/// language.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("mjs", "js"),
+    ("cjs", "js"),
+    ("jsx", "js"),
+    ("tsx", "ts"),
+    ("mts", "ts"),
+    ("cts", "ts"),
+    ("yml", "yaml"),
+    ("h", "c"),
+    ("hh", "cpp"),
+    ("hpp", "cpp"),
+    ("hxx", "cpp"),
+    ("cc", "cpp"),
+    ("cxx", "cpp"),
+    ("bash", "sh"),
+    ("zsh", "sh"),
+    ("ksh", "sh"),
+    ("pyw", "py"),
+    ("pyi", "py"),
+    ("rake", "rb"),
+    ("gemspec", "rb"),
+    ("kts", "kt"),
+    ("markdown", "md"),
+    ("htm", "html"),
+    ("jsonc", "json"),
+];
+
+/// Patterns typical of each language, by syntax token, with their weights.
+const KEYWORDS: &[(&str, &[(&str, f32)])] = &[
+    (
+        "rs",
+        &[
+            (r"\bfn\s+\w+\s*[<(]", 3.0),
+            (r"\blet\s+(mut\s+)?\w+", 2.0),
+            (r"\bimpl\b", 3.0),
+            (r"\b\w+!\(", 2.0),
+            (r"&mut\b", 3.0),
+            (r"\bpub\s+(fn|struct|enum|mod|use|trait)\b", 3.0),
+            (r"(?m)^\s*use\s+\w+(::\w+)+", 3.0),
+            (r"\b(Vec|Option|Result|Box)<", 2.0),
+        ],
+    ),
+    (
+        "c",
+        &[
+            (r#"(?m)^\s*#include\s*[<"][\w/]+\.h[>"]"#, 3.0),
+            (r"\b(printf|malloc|free|sizeof|memcpy)\s*\(", 2.0),
+            (r"\bint\s+main\s*\(", 2.0),
+            (r"\b(unsigned|typedef)\b", 1.0),
+            (r"\bstruct\s+\w+\s*\*", 2.0),
+            (r"\bNULL\b", 2.0),
+        ],
+    ),
+    (
+        "cpp",
+        &[
+            (r"(?m)^\s*#include\s*<\w+>", 3.0),
+            (r"\bstd::", 3.0),
+            (r"\b(template|typename|namespace|nullptr)\b", 3.0),
+            (r"\bcout\s*<<|\bcin\s*>>", 3.0),
+            (r"\bclass\s+\w+", 1.0),
+            (r"\bint\s+main\s*\(", 1.0),
+        ],
+    ),
+    (
+        "go",
+        &[
+            (r"(?m)^package\s+\w+\s*$", 4.0),
+            (r"\bfunc\s+(\(\w+\s+\*?\w+\)\s*)?\w+\(", 3.0),
+            (r":=", 2.0),
+            (r"\bfmt\.\w+", 3.0),
+            (r"(?m)^import\s+\(", 3.0),
+            (r"\bchan\b|\bgo\s+func\b|\bdefer\b", 2.0),
+        ],
+    ),
+    (
+        "py",
+        &[
+            (r"(?m)^\s*def\s+\w+\(.*\)\s*(->\s*[\w\[\], .]+)?:\s*$", 3.0),
+            (r"(?m)^\s*(from\s+[\w.]+\s+import|import\s+[\w.]+\s*$)", 2.0),
+            (r"(?m)^\s*(if|elif|else|for|while|with|try|except)\b.*:\s*$", 2.0),
+            (r"\bself\.", 2.0),
+            (r"\b(None|True|False)\b", 2.0),
+            (r"\b__\w+__\b", 2.0),
+        ],
+    ),
+    (
+        "js",
+        &[
+            (r"\b(const|let|var)\s+\w+\s*=", 2.0),
+            (r"=>", 1.0),
+            (r"\bfunction\s*\w*\s*\(", 2.0),
+            (r"\bconsole\.\w+\(", 3.0),
+            (r"\brequire\(|\bmodule\.exports\b|\bexport\s+(default|const|function)\b", 3.0),
+            (r"===|!==", 2.0),
+            (r"\b(document|window)\.", 2.0),
+        ],
+    ),
+    (
+        "ts",
+        &[
+            (r"\w\??:\s*(string|number|boolean|any|void|unknown)\b", 3.0),
+            (r"\binterface\s+\w+\s*\{", 2.0),
+            (r"\btype\s+\w+\s*=", 3.0),
+            (r"\b(public|private|readonly)\s+\w+\s*:", 2.0),
+            (r"\bas\s+(string|number|const)\b", 2.0),
+        ],
+    ),
+    (
+        "java",
+        &[
+            (r"\bpublic\s+(static\s+)?(class|void|final)\b", 3.0),
+            (r"\bSystem\.out\.print", 4.0),
+            (r"(?m)^\s*import\s+java\.", 4.0),
+            (r"@Override\b", 3.0),
+            (r"\bString\[\]\s+args\b", 3.0),
+        ],
+    ),
+    (
+        "cs",
+        &[
+            (r"(?m)^\s*using\s+System", 4.0),
+            (r"\bnamespace\s+[\w.]+", 2.0),
+            (r"\bConsole\.Write", 4.0),
+            (r"\{\s*get;\s*(set;)?\s*\}", 4.0),
+            (r"\bvar\s+\w+\s*=", 1.0),
+        ],
+    ),
+    (
+        "rb",
+        &[
+            (r"(?m)^\s*def\s+\w+[?!]?(\(.*\))?\s*$", 3.0),
+            (r"(?m)^\s*end\s*$", 2.0),
+            (r"\bputs\b", 3.0),
+            (r"\.each\s+do\s*\|", 4.0),
+            (r#"\brequire(_relative)?\s+['"]"#, 2.0),
+            (r"\bnil\b", 2.0),
+            (r"\battr_(reader|writer|accessor)\b", 4.0),
+        ],
+    ),
+    (
+        "php",
+        &[
+            (r"<\?php", 10.0),
+            (r"\$\w+\s*=", 2.0),
+            (r"\becho\b", 2.0),
+            (r"\bfunction\s+\w+\s*\(\$", 3.0),
+        ],
+    ),
+    (
+        "sh",
+        &[
+            (r"(?m)^\s*(if|while)\s+\[\[?\s", 4.0),
+            (r"(?m)^\s*(fi|done|esac)\s*$", 4.0),
+            (r"\$\{\w+\}", 1.0),
+            (r"(?m)^\s*(echo|export|cd|sudo|apt-get|apt|curl)\s", 2.0),
+            (r"\s\|\s*(grep|awk|sed|xargs)\b", 3.0),
+        ],
+    ),
+    (
+        "json",
+        &[
+            (r#""[^"\n]+"\s*:"#, 1.0),
+        ],
+    ),
+    (
+        "yaml",
+        &[
+            (r"(?m)^\s*[\w.-]+:(\s|$)", 2.0),
+            (r"(?m)^\s*-\s+[\w.-]+:?", 1.0),
+            (r"(?m)^---\s*$", 4.0),
+        ],
+    ),
+    (
+        "html",
+        &[
+            (r"(?i)<(html|head|body|div|span|p|a|script|!doctype)\b", 3.0),
+            (r"</\w+>", 2.0),
+        ],
+    ),
+    (
+        "css",
+        &[
+            (r"(?m)^\s*[\w-]+\s*:\s*[^;{}\n]+;\s*$", 2.0),
+            (r"@media\b|@import\b", 3.0),
+            (r"\b\d+(px|em|rem|vh|vw)\b", 3.0),
+        ],
+    ),
+    (
+        "sql",
+        &[
+            (r"(?i)\bselect\b.+\bfrom\b", 4.0),
+            (r"(?i)\b(insert\s+into|create\s+table|update\s+\w+\s+set|delete\s+from)\b", 4.0),
+            (r"(?i)\b(where|join|group\s+by|order\s+by)\b", 2.0),
+        ],
+    ),
+    (
+        "md",
+        &[
+            (r"(?m)^#{1,6}\s+\w", 3.0),
+            (r"\[[^\]\n]+\]\([^)\n]+\)", 3.0),
+            (r"(?m)^```", 3.0),
+        ],
+    ),
+    (
+        "kt",
+        &[
+            (r"\bfun\s+\w+\s*\(", 4.0),
+            (r"\bval\s+\w+", 2.0),
+            (r"\bdata\s+class\b", 4.0),
+        ],
+    ),
+    (
+        "swift",
+        &[
+            (r"(?m)^\s*import\s+(Foundation|UIKit|SwiftUI)\b", 5.0),
+            (r"\bguard\s+let\b", 3.0),
+            (r"\bvar\s+\w+\s*:\s*\w+", 1.0),
+        ],
+    ),
+    (
+        "lua",
+        &[
+            (r"\blocal\s+\w+\s*=", 3.0),
+            (r"\bthen\b", 2.0),
+            (r"~=", 2.0),
+        ],
+    ),
+];
+
+lazy_static! {
+    static ref PATTERNS: Vec<(&'static str, Vec<(Regex, f32)>)> = KEYWORDS
+        .iter()
+        .map(|(token, patterns)| {
+            let patterns = patterns
+                .iter()
+                .map(|(pattern, weight)| (Regex::new(pattern).unwrap(), *weight))
+                .collect();
+            (*token, patterns)
+        })
+        .collect();
+}
+
+/// The syntax token for a file extension syntect doesn't know, eg. `mjs`.
+pub fn extension_token(extension: &str) -> Option<&'static str> {
+    EXTENSIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map(|(_, token)| *token)
+}
+
+/// The log2 probability of the languages `code` looks like, by syntax token,
+/// as the detection model would give them. Languages without enough
+/// evidence are left out, so the result may be empty.
+pub fn predict(code: &str) -> Vec<(String, f32)> {
+    if let Some(token) = code.lines().next().and_then(shebang_token) {
+        return vec![(token.to_owned(), SHEBANG_PROBABILITY.log2())];
+    }
+
+    let mut end = code.len().min(MAX_SCANNED);
+    while !code.is_char_boundary(end) {
+        end -= 1;
+    }
+    let code = &code[..end];
+
+    let mut scores: Vec<(&str, f32)> = PATTERNS
+        .iter()
+        .map(|(token, patterns)| {
+            let score = patterns
+                .iter()
+                .map(|(pattern, weight)| pattern.find_iter(code).take(MAX_MATCHES).count() as f32 * weight)
+                .sum();
+            (*token, score)
+        })
+        .collect();
+
+    // A document which parses as JSON is JSON, whatever its keys look like.
+    let trimmed = code.trim_start();
+    if (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(code).is_ok()
+    {
+        if let Some((_, score)) = scores.iter_mut().find(|(token, _)| *token == "json") {
+            *score += 20.0;
+        }
+    }
+
+    scores.retain(|(_, score)| *score >= MIN_SCORE);
+    let total: f32 = scores.iter().map(|(_, score)| score).sum();
+    scores
+        .into_iter()
+        .map(|(token, score)| (token.to_owned(), (score / total * MAX_PROBABILITY).log2()))
+        .collect()
+}
+
+/// The syntax token of the interpreter named by a shebang line, eg.
+/// `#!/usr/bin/env python3`.
+fn shebang_token(line: &str) -> Option<&'static str> {
+    let command = line.strip_prefix("#!")?;
+    let mut words = command.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    SHEBANGS
+        .iter()
+        .find(|(interpreter, _)| *interpreter == program)
+        .map(|(_, token)| *token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The most likely token `predict` gives for `code`.
+    fn top(code: &str) -> Option<String> {
+        predict(code)
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(token, _)| token)
+    }
+
+    #[test]
+    fn shebangs_name_the_interpreter() {
+        assert_eq!(shebang_token("#!/usr/bin/env node"), Some("js"));
+        assert_eq!(shebang_token("#!/usr/bin/python3.11"), Some("py"));
+        assert_eq!(shebang_token("#!/usr/bin/env -S deno run"), Some("js"));
+        assert_eq!(shebang_token("#!/bin/bash -e"), Some("sh"));
+        assert_eq!(shebang_token("#!/usr/bin/env unknown"), None);
+        assert_eq!(shebang_token("# not a shebang"), None);
+    }
+
+    #[test]
+    fn a_shebang_decides_the_language() {
+        let predictions = predict("#!/usr/bin/env node\nputs 'this looks like Ruby'\n");
+        assert_eq!(predictions, vec![("js".to_owned(), SHEBANG_PROBABILITY.log2())]);
+    }
+
+    #[test]
+    fn json_and_yaml_are_told_apart() {
+        assert_eq!(top(r#"{"a": 1}"#).as_deref(), Some("json"));
+        assert_eq!(top("a: 1\nb: [2]\n").as_deref(), Some("yaml"));
+    }
+
+    #[test]
+    fn rust_and_c_are_told_apart() {
+        assert_eq!(top("fn main() { let mut v = Vec::new(); }").as_deref(), Some("rs"));
+        assert_eq!(top(r#"int main(void) { printf("x"); }"#).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn too_little_evidence_predicts_nothing() {
+        assert!(predict("x").is_empty());
+    }
+}
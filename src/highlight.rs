@@ -0,0 +1,67 @@
+//! Highlight groups with their own colors, from `highlight_lines` entries
+//! like `1-3:#2ecc4040`. Silicon draws every highlighted line in the same
+//! style, so colored rows are tinted over the formatter's output here, and
+//! rows without a color are still left to silicon.
+
+use anyhow::Error;
+use image::{DynamicImage, Rgba};
+use silicon::font::FontCollection;
+use std::rc::Rc;
+
+use crate::config::Config;
+use crate::fontcache;
+use crate::watermark;
+
+/// Silicon's padding left of and below the code. It isn't configurable, so
+/// it isn't scaled either.
+pub const CODE_PAD: u32 = 25;
+
+/// Where rows of code are in the bare window out of the formatter.
+///
+/// Rows are laid out from the bottom, since the space above the first row
+/// depends on whether silicon drew a title bar.
+pub struct Layout {
+    pub fonts: Rc<FontCollection>,
+    top: u32,
+    /// Height of a row, including the padding between lines.
+    pub pitch: u32,
+}
+
+impl Layout {
+    /// The layout of `row_count` rows formatted into an image `height`
+    /// pixels tall.
+    pub fn new(height: u32, row_count: u32, conf: &Config) -> Result<Self, Error> {
+        let fonts = fontcache::collection(&conf.title_font())?;
+        let pitch = fonts.get_font_height() + conf.scaled(conf.line_pad);
+        let top = height.saturating_sub(CODE_PAD + pitch * row_count);
+        Ok(Layout { fonts, top, pitch })
+    }
+
+    /// The top of the 1-based `row`.
+    pub fn row_top(&self, row: u32) -> u32 {
+        self.top + self.pitch * row.saturating_sub(1)
+    }
+}
+
+/// Tints each of the 1-based `rows` of `image`, the bare window out of the
+/// formatter, with its color. `row_count` is the number of rows formatted.
+pub fn draw(
+    image: DynamicImage,
+    rows: &[(u32, Rgba<u8>)],
+    row_count: u32,
+    conf: &Config,
+) -> Result<DynamicImage, Error> {
+    let mut image = image.to_rgba8();
+    let layout = Layout::new(image.height(), row_count, conf)?;
+
+    for &(row, color) in rows {
+        let row_top = layout.row_top(row);
+        let alpha = color.0[3] as f32 / 255.0;
+        for y in row_top..(row_top + layout.pitch).min(image.height()) {
+            for x in 0..image.width() {
+                watermark::over(image.get_pixel_mut(x, y), color, alpha);
+            }
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(image))
+}
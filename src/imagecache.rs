@@ -0,0 +1,275 @@
+//! Images fetched for `background_image` and `watermark_image`, cached by
+//! URL, since clients tend to reuse a handful of them. Fetches which failed
+//! are remembered for a short while too, so a dead URL doesn't add a
+//! timeout to every render using it.
+//!
+//! An image is fresh for the configured TTL, or less when the origin's
+//! `Cache-Control` says so, and isn't cached at all with `no-store`. After
+//! that it's revalidated with `If-None-Match` or `If-Modified-Since` when
+//! the origin sent an `ETag` or `Last-Modified`, and fetched again when it
+//! didn't.
+
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api::ApiError;
+use crate::fetch::Fetched;
+
+pub struct ImageCache {
+    max_bytes: usize,
+    ttl: Duration,
+    failure_ttl: Duration,
+    inner: Option<Mutex<Inner>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidations: AtomicU64,
+    failure_hits: AtomicU64,
+}
+
+struct Inner {
+    entries: LruCache<String, Entry>,
+    bytes: usize,
+}
+
+#[derive(Clone)]
+enum Entry {
+    Image(Arc<CachedImage>),
+    Failed { error: ApiError, until: Instant },
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        match self {
+            Entry::Image(image) => image.bytes.len(),
+            Entry::Failed { .. } => 0,
+        }
+    }
+}
+
+/// A fetched image, and what's needed to revalidate it.
+#[derive(Debug)]
+pub struct CachedImage {
+    pub bytes: Vec<u8>,
+    fresh_until: Instant,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl CachedImage {
+    /// The conditional headers to revalidate the image with.
+    pub fn validators(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![];
+        if let Some(etag) = &self.etag {
+            headers.push(("If-None-Match", etag.clone()));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.push(("If-Modified-Since", last_modified.clone()));
+        }
+        headers
+    }
+}
+
+/// What the cache knows about a URL.
+pub enum Lookup {
+    Fresh(Arc<CachedImage>),
+
+    /// Past its freshness, but can be revalidated.
+    Stale(Arc<CachedImage>),
+
+    /// The last fetch failed, recently.
+    Failed(ApiError),
+
+    Missing,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ImageCacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub revalidations: u64,
+    pub failure_hits: u64,
+}
+
+impl ImageCache {
+    /// Creates a cache. A capacity of zero entries or bytes disables caching.
+    pub fn new(max_entries: usize, max_bytes: usize, ttl: Duration, failure_ttl: Duration) -> Self {
+        let inner = NonZeroUsize::new(max_entries)
+            .filter(|_| max_bytes > 0)
+            .map(|cap| {
+                Mutex::new(Inner {
+                    entries: LruCache::new(cap),
+                    bytes: 0,
+                })
+            });
+        ImageCache {
+            max_bytes,
+            ttl,
+            failure_ttl,
+            inner,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            revalidations: AtomicU64::new(0),
+            failure_hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Lookup {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return Lookup::Missing,
+        };
+        let now = Instant::now();
+        let entry = inner.lock().unwrap().entries.get(url).cloned();
+        let lookup = match entry {
+            Some(Entry::Image(image)) if now < image.fresh_until => Lookup::Fresh(image),
+            Some(Entry::Image(image)) if image.etag.is_some() || image.last_modified.is_some() => Lookup::Stale(image),
+            Some(Entry::Failed { error, until }) if now < until => Lookup::Failed(error),
+            _ => Lookup::Missing,
+        };
+        let counter = match &lookup {
+            Lookup::Fresh(_) => &self.hits,
+            Lookup::Stale(_) => &self.revalidations,
+            Lookup::Failed(_) => &self.failure_hits,
+            Lookup::Missing => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        lookup
+    }
+
+    /// Stores the image `fetched` from `url`, unless the origin asked not
+    /// to. A 304 keeps the bytes of `stale`, the image it revalidated.
+    pub fn insert(&self, url: &str, fetched: &Fetched, stale: Option<&CachedImage>) -> Option<Arc<CachedImage>> {
+        let max_age = match cache_control_max_age(fetched.cache_control.as_deref()) {
+            MaxAge::NoStore => {
+                self.remove(url);
+                return None;
+            }
+            MaxAge::Seconds(seconds) => Duration::from_secs(seconds).min(self.ttl),
+            MaxAge::Unspecified => self.ttl,
+        };
+        let bytes = match (fetched.not_modified, stale) {
+            (true, Some(stale)) => stale.bytes.clone(),
+            _ => fetched.bytes.clone(),
+        };
+        let image = Arc::new(CachedImage {
+            bytes,
+            fresh_until: Instant::now() + max_age,
+            etag: fetched.etag.clone().or_else(|| stale.and_then(|stale| stale.etag.clone())),
+            last_modified: fetched
+                .last_modified
+                .clone()
+                .or_else(|| stale.and_then(|stale| stale.last_modified.clone())),
+        });
+        self.put(url, Entry::Image(image.clone()));
+        Some(image)
+    }
+
+    /// Remembers that fetching `url` failed with `error`.
+    pub fn fail(&self, url: &str, error: &ApiError) {
+        if self.failure_ttl.is_zero() {
+            return;
+        }
+        self.put(
+            url,
+            Entry::Failed {
+                error: error.clone(),
+                until: Instant::now() + self.failure_ttl,
+            },
+        );
+    }
+
+    fn put(&self, url: &str, entry: Entry) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let size = entry.size();
+        if size > self.max_bytes {
+            self.remove(url);
+            return;
+        }
+
+        let mut inner = inner.lock().unwrap();
+        inner.bytes += size;
+        if let Some((_, replaced)) = inner.entries.push(url.to_owned(), entry) {
+            inner.bytes -= replaced.size();
+        }
+        while inner.bytes > self.max_bytes {
+            match inner.entries.pop_lru() {
+                Some((_, evicted)) => inner.bytes -= evicted.size(),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&self, url: &str) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            if let Some(removed) = inner.entries.pop(url) {
+                inner.bytes -= removed.size();
+            }
+        }
+    }
+
+    /// Drops every image and remembered failure, returning how many there
+    /// were.
+    pub fn clear(&self) -> usize {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return 0,
+        };
+        let mut inner = inner.lock().unwrap();
+        let cleared = inner.entries.len();
+        inner.entries.clear();
+        inner.bytes = 0;
+        cleared
+    }
+
+    pub fn stats(&self) -> ImageCacheStats {
+        let (entries, bytes) = match &self.inner {
+            Some(inner) => {
+                let inner = inner.lock().unwrap();
+                (inner.entries.len(), inner.bytes)
+            }
+            None => (0, 0),
+        };
+        ImageCacheStats {
+            entries,
+            bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            revalidations: self.revalidations.load(Ordering::Relaxed),
+            failure_hits: self.failure_hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+enum MaxAge {
+    NoStore,
+    Seconds(u64),
+    Unspecified,
+}
+
+/// How long a response may be reused for, by its `Cache-Control` header.
+/// `no-cache` allows storing it, but not reusing it without revalidation.
+fn cache_control_max_age(header: Option<&str>) -> MaxAge {
+    let mut max_age = MaxAge::Unspecified;
+    for directive in header.unwrap_or_default().split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        if directive == "no-store" {
+            return MaxAge::NoStore;
+        } else if directive == "no-cache" {
+            max_age = MaxAge::Seconds(0);
+        } else if let Some(seconds) = directive.strip_prefix("max-age=") {
+            if let (MaxAge::Unspecified, Ok(seconds)) = (&max_age, seconds.trim_matches('"').parse()) {
+                max_age = MaxAge::Seconds(seconds);
+            }
+        }
+    }
+    max_age
+}
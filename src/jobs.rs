@@ -0,0 +1,177 @@
+//! Renders run in the background for `async=true`, whose status and result
+//! are polled at `/jobs/{id}`, for clients with a shorter timeout than a
+//! large render takes.
+//!
+//! Results are kept for the job TTL after the job finishes, then dropped,
+//! and the job is remembered as expired for a while longer so its result
+//! URL answers 410 rather than 404.
+
+use actix_web::http::StatusCode;
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api::{ApiError, ApiVersion};
+use crate::cache::{Rendered, Source};
+use crate::query::ConfigQuery;
+
+/// Result lifetime when `INKIFY_JOB_TTL_SECS` isn't set.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long an expired job is remembered.
+const EXPIRED_KEPT: Duration = Duration::from_secs(60 * 60);
+
+/// The most jobs which may be pending or running at once.
+const MAX_UNFINISHED: usize = 1000;
+
+/// The most bytes of images finished jobs hold. Past it the oldest results
+/// are dropped before their TTL, so polling clients can't pin memory.
+const MAX_RESULT_BYTES: usize = 256 * 1024 * 1024;
+
+/// The most finished jobs remembered, expired ones included. Past it the
+/// oldest are forgotten.
+const MAX_FINISHED: usize = 10_000;
+
+const ID_LEN: usize = 16;
+
+#[derive(Clone)]
+pub enum State {
+    /// Queued, not started yet.
+    Pending,
+
+    /// Fetching the code, or waiting for or holding a render slot.
+    Running,
+
+    /// Rendered, with the resolved request, which decides how the image is
+    /// served.
+    Done {
+        rendered: Arc<Rendered>,
+        source: Source,
+        info: Arc<ConfigQuery>,
+        key: String,
+    },
+
+    /// Failed with the error the synchronous request would have returned.
+    Failed(ApiError),
+
+    /// Finished longer than the TTL ago, so its result was dropped.
+    Expired,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Pending => "pending",
+            State::Running => "running",
+            State::Done { .. } => "done",
+            State::Failed(_) => "failed",
+            State::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Job {
+    pub state: State,
+    pub version: ApiVersion,
+    finished: Option<Instant>,
+}
+
+pub struct Jobs {
+    ttl: Duration,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl Jobs {
+    pub fn new(ttl: Duration) -> Self {
+        Jobs {
+            ttl,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a pending job and returns its ID, or a 503 when too many are
+    /// unfinished.
+    pub fn create(&self, version: ApiVersion) -> Result<String, ApiError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.evict(&mut jobs);
+        if jobs.values().filter(|job| job.finished.is_none()).count() >= MAX_UNFINISHED {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_busy",
+                "Too many render jobs are queued, retry later",
+            )
+            .with_header("Retry-After", "10"));
+        }
+        let id = Alphanumeric.sample_string(&mut rand::thread_rng(), ID_LEN);
+        jobs.insert(
+            id.clone(),
+            Job {
+                state: State::Pending,
+                version,
+                finished: None,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn set(&self, id: &str, state: State) {
+        let finished = matches!(state, State::Done { .. } | State::Failed(_)).then(Instant::now);
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(id) {
+            job.state = state;
+            job.finished = finished;
+        }
+        if finished.is_some() {
+            bound_finished(&mut jobs);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.evict(&mut jobs);
+        jobs.get(id).cloned()
+    }
+
+    /// Drops the results older than the TTL, and forgets the jobs which
+    /// expired long enough ago.
+    fn evict(&self, jobs: &mut HashMap<String, Job>) {
+        let now = Instant::now();
+        jobs.retain(|_, job| {
+            let age = match job.finished {
+                Some(finished) => now.duration_since(finished),
+                None => return true,
+            };
+            if age > self.ttl {
+                job.state = State::Expired;
+            }
+            age <= self.ttl + EXPIRED_KEPT
+        });
+    }
+}
+
+/// Keeps the finished jobs within `MAX_FINISHED` and their images within
+/// `MAX_RESULT_BYTES`, expiring and then forgetting the oldest first.
+fn bound_finished(jobs: &mut HashMap<String, Job>) {
+    let mut finished: Vec<(Instant, String)> = jobs
+        .iter()
+        .filter_map(|(id, job)| Some((job.finished?, id.clone())))
+        .collect();
+    finished.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+    let mut bytes = 0;
+    for (i, (_, id)) in finished.iter().enumerate() {
+        if i >= MAX_FINISHED {
+            jobs.remove(id);
+            continue;
+        }
+        if let Some(job) = jobs.get_mut(id) {
+            if let State::Done { rendered, .. } = &job.state {
+                bytes += rendered.bytes.len();
+                if bytes > MAX_RESULT_BYTES {
+                    job.state = State::Expired;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! The rendering pipeline behind the Inkify server: highlighting code,
+//! drawing it in a window, and encoding the result as a PNG. The server in
+//! `main.rs` adds the HTTP API, caching, storage and limits around it; this
+//! crate is what's left for rendering code to images from Rust.
+//!
+//! ```
+//! use syntect::highlighting::ThemeSet;
+//! use syntect::parsing::SyntaxSet;
+//!
+//! let mut conf = inkify::Config::default();
+//! conf.code = "print('Hello, world!')".to_owned();
+//! conf.window_title = Some("hello.py".to_owned());
+//! let image = inkify::render(&conf, &SyntaxSet::load_defaults_newlines(), &ThemeSet::load_defaults())?;
+//! assert!(image.bytes.starts_with(b"\x89PNG"));
+//! assert_eq!(image.language, "Python");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+#[macro_use]
+extern crate anyhow;
+
+pub mod aliases;
+pub mod ansi;
+pub mod background;
+pub mod badge;
+pub mod config;
+pub mod detector;
+pub mod diff;
+pub mod fontcache;
+pub mod gradient;
+pub mod guides;
+pub mod heuristic;
+pub mod highlight;
+pub mod markdown;
+pub mod notebook;
+pub mod redact;
+pub mod render;
+pub mod rgba;
+pub mod secrets;
+pub mod suggest;
+pub mod watermark;
+pub mod whitespace;
+pub mod window;
+pub mod wrap;
+
+pub use config::Config;
+pub use render::{estimate, render, Estimate, RenderError, RenderedImage};
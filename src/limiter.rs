@@ -0,0 +1,106 @@
+//! Caps on how much CPU bound work runs at once, so a burst of requests
+//! queues up rather than every render slowing down and holding its memory
+//! together. Requests which wait too long for a slot get a 503.
+
+use actix_web::http::StatusCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::api::ApiError;
+
+/// A semaphore which counts the tasks holding and waiting for its permits.
+pub struct Limiter {
+    permits: Semaphore,
+    limit: usize,
+    in_flight: AtomicU64,
+    queued: AtomicU64,
+}
+
+/// Snapshot of a limiter, for `/stats`.
+#[derive(Debug, serde::Serialize)]
+pub struct LimiterStats {
+    pub limit: usize,
+    pub in_flight: u64,
+    pub queued: u64,
+}
+
+impl Limiter {
+    pub fn new(limit: usize) -> Self {
+        Limiter {
+            permits: Semaphore::new(limit),
+            limit,
+            in_flight: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits up to `timeout` for a permit, responding with a 503 and a
+    /// `Retry-After` header when none frees up.
+    pub async fn acquire(&'static self, timeout: Duration) -> Result<Permit, ApiError> {
+        let queued = Queued::new(&self.queued);
+        let permit = actix_web::rt::time::timeout(timeout, self.permits.acquire()).await;
+        drop(queued);
+        match permit {
+            Ok(Ok(permit)) => {
+                self.in_flight.fetch_add(1, Ordering::Relaxed);
+                Ok(Permit {
+                    limiter: self,
+                    _permit: permit,
+                })
+            }
+            Ok(Err(_)) => Err(ApiError::internal("render_failed", "Render pool is closed")),
+            Err(_) => {
+                let secs = timeout.as_secs_f64().ceil().max(1.0) as u64;
+                Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "server_busy",
+                    format!("The server is busy, retry in {} seconds", secs),
+                )
+                .with_header("Retry-After", secs.to_string()))
+            }
+        }
+    }
+
+    /// The number of tasks waiting for a permit.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> LimiterStats {
+        LimiterStats {
+            limit: self.limit,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            queued: self.queued(),
+        }
+    }
+}
+
+/// A slot of a `Limiter`, freed when dropped.
+pub struct Permit {
+    limiter: &'static Limiter,
+    _permit: SemaphorePermit<'static>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a task as queued until dropped, including when the request is
+/// dropped while waiting.
+struct Queued<'a>(&'a AtomicU64);
+
+impl<'a> Queued<'a> {
+    fn new(queued: &'a AtomicU64) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Queued(queued)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
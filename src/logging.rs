@@ -0,0 +1,96 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::Method;
+use std::time::Duration;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Request details which only the handler knows, attached to the response
+/// extensions for the logging middleware. The submitted code is never
+/// recorded here.
+#[derive(Debug, Clone, Default)]
+pub struct LogFields {
+    pub language: Option<String>,
+    pub theme: Option<String>,
+    pub error_code: Option<&'static str>,
+}
+
+/// Installs the global subscriber. The level is controlled by `RUST_LOG`
+/// (default `info`), and `INKIFY_LOG_FORMAT=json` switches to JSON lines.
+/// With the `otel` feature, the same subscriber exports spans when
+/// configured to, and the filter only applies to the logs.
+pub fn init(json: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logs = if json {
+        tracing_subscriber::fmt::layer().json().boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    };
+    let subscriber = tracing_subscriber::registry().with(logs.with_filter(filter));
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(crate::telemetry::layer());
+    subscriber.init();
+}
+
+/// Records what's known of a request once it's been served on its span,
+/// for the exported trace.
+pub fn record_response<B: MessageBody>(span: &tracing::Span, res: &ServiceResponse<B>) {
+    span.record("status", res.status().as_u16());
+    if let BodySize::Sized(size) = res.response().body().size() {
+        span.record("size", size);
+    }
+    if let Some(fields) = res.response().extensions().get::<LogFields>() {
+        if let Some(language) = &fields.language {
+            span.record("language", language.as_str());
+        }
+        if let Some(theme) = &fields.theme {
+            span.record("theme", theme.as_str());
+        }
+    }
+}
+
+/// Logs a completed request. Only the path is logged, never the query
+/// string, since it carries the code being rendered.
+pub fn log_request<B: MessageBody>(
+    method: &Method,
+    path: &str,
+    res: &ServiceResponse<B>,
+    latency: Duration,
+) {
+    let status = res.status();
+    let size = match res.response().body().size() {
+        BodySize::Sized(n) => Some(n),
+        _ => None,
+    };
+    let fields = res
+        .response()
+        .extensions()
+        .get::<LogFields>()
+        .cloned()
+        .unwrap_or_default();
+
+    macro_rules! log {
+        ($level:ident, $msg:expr) => {
+            tracing::$level!(
+                method = %method,
+                path = path,
+                status = status.as_u16(),
+                latency_ms = latency.as_secs_f64() * 1000.0,
+                size = size,
+                language = fields.language.as_deref(),
+                theme = fields.theme.as_deref(),
+                error_code = fields.error_code,
+                $msg
+            )
+        };
+    }
+
+    if status.is_server_error() {
+        log!(error, "request failed");
+    } else if status.is_client_error() {
+        log!(warn, "request rejected");
+    } else {
+        log!(info, "request completed");
+    }
+}
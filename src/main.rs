@@ -2,57 +2,450 @@
 extern crate anyhow;
 
 use clap::Parser;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::dev::Service;
+use actix_web::http::StatusCode;
+use actix_multipart::Multipart;
+use actix_web::{get, head, middleware, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder};
 use anyhow::Error;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{StreamExt, TryStreamExt};
+use tracing::Instrument;
+use api::{ApiError, ApiVersion};
+use cancel::Cancellation;
+use inkify::detector::LanguageDetector;
+use inkify::rgba::parse_str_color;
+use inkify::{
+    aliases, ansi, background, badge, config, detector, diff, heuristic, markdown, notebook, redact, render, rgba,
+    secrets, watermark, whitespace, window, wrap,
+};
 use lazy_static::lazy_static;
 use silicon as si;
-use silicon::utils::ToRgba;
-use tensorflow::Tensor;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::io::Cursor;
 use std::num::ParseIntError;
-use syntect::easy::HighlightLines;
-use syntect::util::LinesWithEndings;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
 
-mod config;
-mod rgba;
+mod api;
+mod assets;
+mod auth;
+mod batch;
+mod cache;
+mod cancel;
+mod coalesce;
+mod cors;
+mod defaults;
+mod derive;
+mod diskcache;
+mod fallback;
+mod families;
+mod fetch;
+mod filename;
+mod fontdir;
+mod imagecache;
+mod jobs;
+mod limiter;
+mod logging;
+mod metadata;
+mod metrics;
+mod oembed;
+mod openapi;
+mod preview;
+mod query;
+mod ratelimit;
+mod rediscache;
+mod requestid;
+mod s3;
+mod settings;
+mod shutdown;
+mod signing;
+mod source;
+mod storage;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tls;
+mod toplist;
+mod uds;
+mod usage;
 
 lazy_static! {
-    static ref HIGHLIGHTING_ASSETS: si::assets::HighlightingAssets =
-        silicon::assets::HighlightingAssets::new();
+    static ref ARGS: CliArgs = CliArgs::parse();
+    static ref HIGHLIGHTING_ASSETS: RwLock<Arc<si::assets::HighlightingAssets>> =
+        RwLock::new(Arc::new(load_assets().unwrap_or_else(|e| settings::fail(e.setting(), e))));
+    static ref SETTINGS: settings::Settings = settings::Settings::from_env();
+    static ref RENDER_CACHE: cache::RenderCache =
+        cache::RenderCache::new(SETTINGS.cache_entries, SETTINGS.cache_max_bytes);
+    static ref IMAGE_CACHE: imagecache::ImageCache = imagecache::ImageCache::new(
+        SETTINGS.image_cache_entries,
+        SETTINGS.image_cache_max_bytes,
+        SETTINGS.image_cache_ttl,
+        SETTINGS.image_cache_failure_ttl,
+    );
+    static ref RATE_LIMITER: Option<ratelimit::RateLimiter> = SETTINGS
+        .rate_limit
+        .map(|(limit, window)| ratelimit::RateLimiter::new(limit, window));
+    static ref DISK_CACHE: Option<diskcache::DiskCache> = open_disk_cache();
+    static ref STORAGE: Option<storage::Storage> = open_storage();
+    static ref S3: Option<s3::S3Store> = s3::S3Store::from_env();
+    static ref JOBS: jobs::Jobs = jobs::Jobs::new(SETTINGS.job_ttl);
+    static ref OPENAPI: serde_json::Value = openapi::document(SETTINGS.admin_token.is_some());
+    static ref HELP: String = serde_json::to_string(&usage::Help::new(
+        &OPENAPI,
+        !SETTINGS.api_keys.is_empty(),
+        SETTINGS.signing_secret.is_some(),
+        defaults::active(),
+    ))
+    .unwrap();
+    static ref REDIS_CACHE: Option<rediscache::RedisCache> = SETTINGS.redis_url.as_deref().map(|url| {
+        rediscache::RedisCache::new(url, SETTINGS.redis_ttl)
+            .unwrap_or_else(|e| settings::fail("INKIFY_REDIS_URL", e))
+    });
+    /// The caches of generated images, fastest first.
+    static ref CACHES: Vec<&'static dyn cache::CacheBackend> = {
+        let mut caches: Vec<&'static dyn cache::CacheBackend> = vec![&*RENDER_CACHE];
+        if let Some(disk) = &*DISK_CACHE {
+            caches.push(disk);
+        }
+        if let Some(redis) = &*REDIS_CACHE {
+            caches.push(redis);
+        }
+        caches
+    };
+    static ref COALESCER: coalesce::Coalescer = coalesce::Coalescer::default();
+    static ref RENDERS: limiter::Limiter = limiter::Limiter::new(SETTINGS.max_blocking_renders);
+    static ref DETECTIONS: limiter::Limiter = limiter::Limiter::new(SETTINGS.max_blocking_detections);
+    static ref WATERMARK_IMAGE: Option<Arc<image::DynamicImage>> =
+        SETTINGS.watermark_path.as_ref().map(|path| {
+            image::open(path)
+                .map(Arc::new)
+                .unwrap_or_else(|e| settings::fail("INKIFY_WATERMARK_PATH", e))
+        });
+    static ref HISTORY: metrics::History =
+        metrics::History::new(SETTINGS.stats_interval, SETTINGS.stats_history_len);
 }
 
-macro_rules! unwrap_or_return {
-    ( $e:expr, $r:expr ) => {
-        match $e {
-            Ok(x) => x,
-            Err(_) => return $r,
-        }
+lazy_static! {
+    static ref DETECTOR: Option<Result<Arc<dyn LanguageDetector>, String>> = load_detector().map(|result| {
+        result.map_err(|e| {
+            tracing::error!(error = %e, "Failed to load language detection model");
+            e.to_string()
+        })
+    });
+    /// The outcome of the model's first prediction, made at startup. A model
+    /// which fails it is treated as unavailable, while requests which don't
+    /// need it are still served.
+    static ref WARMUP: Option<Result<Duration, String>> = match &*DETECTOR {
+        Some(Ok(model)) => Some(match detector::warm_up(model.clone()) {
+            Ok(elapsed) => {
+                tracing::info!(?elapsed, "Warmed up language detection model");
+                Ok(elapsed)
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Language detection model failed to warm up, detection is unavailable");
+                Err(e.to_string())
+            }
+        }),
+        _ => None,
     };
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct CliArgs {
+    #[cfg(feature = "tensorflow")]
     #[arg(short, long)]
     tensorflow_model_dir: Option<String>,
+
+    /// ONNX export of the language detection model, in place of
+    /// --tensorflow-model-dir.
+    #[cfg(feature = "onnx")]
+    #[arg(long)]
+    #[cfg_attr(feature = "tensorflow", arg(conflicts_with = "tensorflow_model_dir"))]
+    onnx_model_path: Option<String>,
+
+    /// Directory of .tmTheme files to make available by name, in addition
+    /// to the bundled themes. Also read from INKIFY_THEMES_DIR.
+    #[arg(long)]
+    themes_dir: Option<String>,
+
+    /// Directory of .sublime-syntax files, searched recursively, to add to
+    /// the bundled syntaxes. Also read from INKIFY_SYNTAXES_DIR.
+    #[arg(long)]
+    syntaxes_dir: Option<String>,
+
+    /// Directory of .ttf, .otf, and .ttc files to make available by family
+    /// name, preferred over installed fonts. Also read from INKIFY_FONTS_DIR.
+    #[arg(long)]
+    fonts_dir: Option<String>,
+
+    /// JSON or TOML file mapping extra language aliases to the language they
+    /// stand for. Also read from INKIFY_LANGUAGE_ALIASES.
+    #[arg(long)]
+    language_aliases: Option<String>,
+
+    /// Address to listen on. Also read from HOST, defaults to 127.0.0.1.
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Port to listen on. Also read from PORT, defaults to 8080.
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Listen on this unix domain socket instead of a TCP port. Also read
+    /// from INKIFY_UNIX_SOCKET.
+    #[arg(long, conflicts_with_all = ["host", "port", "tls_cert"])]
+    unix_socket: Option<PathBuf>,
+
+    /// Octal permissions of --unix-socket. Defaults to 660.
+    #[arg(long, value_parser = uds::parse_mode, requires = "unix_socket")]
+    unix_socket_mode: Option<u32>,
+
+    /// Number of worker threads serving requests. Also read from
+    /// INKIFY_WORKERS, defaults to the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    workers: Option<u16>,
+
+    /// Maximum number of threads each worker runs blocking work, such as
+    /// rendering, on. Also read from INKIFY_MAX_BLOCKING_THREADS, defaults to
+    /// 512 divided among the workers.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    max_blocking_threads: Option<u16>,
+
+    /// Seconds in-flight requests may take to finish after SIGTERM or
+    /// SIGINT before they're aborted. Also read from
+    /// INKIFY_SHUTDOWN_TIMEOUT_SECS, defaults to 30.
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// Directory to also cache rendered images in, so they survive
+    /// restarts. Also read from INKIFY_CACHE_DIR.
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Seconds an image stays in the disk cache. Also read from
+    /// INKIFY_CACHE_TTL_SECS, defaults to a week.
+    #[arg(long, requires = "cache_dir")]
+    cache_ttl: Option<u64>,
+
+    /// Size budget of the disk cache, in bytes. Also read from
+    /// INKIFY_DISK_CACHE_MAX_BYTES, defaults to 1GB.
+    #[arg(long, requires = "cache_dir")]
+    cache_max_bytes: Option<u64>,
+
+    /// Directory to keep images generated with store=true in, served at
+    /// /i/{id}. Storing is disabled without it. Also read from
+    /// INKIFY_STORAGE_DIR.
+    #[arg(long)]
+    storage_dir: Option<PathBuf>,
+
+    /// Seconds a stored image is kept. Also read from
+    /// INKIFY_STORAGE_TTL_SECS, defaults to 30 days.
+    #[arg(long, requires = "storage_dir")]
+    storage_ttl: Option<u64>,
+
+    /// Size budget of the stored images, in bytes. Also read from
+    /// INKIFY_STORAGE_MAX_BYTES, defaults to 1GB.
+    #[arg(long, requires = "storage_dir")]
+    storage_max_bytes: Option<u64>,
+
+    /// PEM certificate chain to serve HTTPS with, along with --tls-key.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Also listen for plain HTTP on this port, redirecting every request to
+    /// HTTPS.
+    #[arg(long, requires = "tls_cert")]
+    tls_redirect_http_port: Option<u16>,
+
+    /// Print this URL, eg. '/generate?code=...', signed with
+    /// INKIFY_SIGNING_SECRET, and exit.
+    #[arg(long)]
+    sign_url: Option<String>,
+
+    /// Seconds the URL given to --sign-url stays valid. Defaults to forever.
+    #[arg(long, requires = "sign_url")]
+    sign_expires_in: Option<u64>,
+}
+
+/// Where and how the server listens, from the command line, the environment,
+/// then the defaults.
+struct Bind {
+    host: String,
+    port: u16,
+    unix_socket: Option<PathBuf>,
+    unix_socket_mode: u32,
+    workers: usize,
+    max_blocking_threads: usize,
+    shutdown_timeout: Duration,
+}
+
+impl Bind {
+    fn resolve() -> Self {
+        let host = ARGS
+            .host
+            .clone()
+            .or_else(|| settings::env_string("HOST"))
+            .unwrap_or_else(|| "127.0.0.1".to_owned());
+        let port = ARGS
+            .port
+            .or_else(|| settings::env_parse_required("PORT"))
+            .unwrap_or(8080);
+        let unix_socket = ARGS
+            .unix_socket
+            .clone()
+            .or_else(|| settings::env_string("INKIFY_UNIX_SOCKET").map(PathBuf::from));
+        #[cfg(not(unix))]
+        if unix_socket.is_some() {
+            settings::fail::<()>("--unix-socket", "unix domain sockets aren't supported on this platform");
+        }
+        let workers = ARGS
+            .workers
+            .map(usize::from)
+            .or_else(|| settings::env_parse_required("INKIFY_WORKERS"))
+            .unwrap_or_else(settings::default_parallelism);
+        if workers == 0 {
+            settings::fail::<()>("INKIFY_WORKERS", "must be at least 1");
+        }
+        let max_blocking_threads = ARGS
+            .max_blocking_threads
+            .map(usize::from)
+            .or_else(|| settings::env_parse_required("INKIFY_MAX_BLOCKING_THREADS"))
+            // Matches actix's own default.
+            .unwrap_or(512 / workers)
+            .max(1);
+        let shutdown_timeout = ARGS
+            .shutdown_timeout
+            .or_else(|| settings::env_parse_required("INKIFY_SHUTDOWN_TIMEOUT_SECS"))
+            .map(Duration::from_secs)
+            .unwrap_or(shutdown::DEFAULT_TIMEOUT);
+        Bind {
+            host,
+            port,
+            unix_socket,
+            unix_socket_mode: ARGS.unix_socket_mode.unwrap_or(uds::DEFAULT_MODE),
+            workers,
+            max_blocking_threads,
+            shutdown_timeout,
+        }
+    }
+}
+
+fn open_disk_cache() -> Option<diskcache::DiskCache> {
+    let dir = ARGS
+        .cache_dir
+        .clone()
+        .or_else(|| settings::env_string("INKIFY_CACHE_DIR").map(PathBuf::from))?;
+    let ttl = ARGS
+        .cache_ttl
+        .or_else(|| settings::env_parse_required("INKIFY_CACHE_TTL_SECS"))
+        .map(Duration::from_secs)
+        .unwrap_or(diskcache::DEFAULT_TTL);
+    let max_bytes = ARGS
+        .cache_max_bytes
+        .or_else(|| settings::env_parse_required("INKIFY_DISK_CACHE_MAX_BYTES"))
+        .unwrap_or(diskcache::DEFAULT_MAX_BYTES);
+    let cache = diskcache::DiskCache::open(&dir, ttl, max_bytes)
+        .unwrap_or_else(|e| settings::fail("--cache-dir", format!("{}: {}", dir.display(), e)));
+    tracing::info!(dir = %dir.display(), ?ttl, max_bytes, "Opened disk cache");
+    Some(cache)
+}
+
+fn open_storage() -> Option<storage::Storage> {
+    let dir = ARGS
+        .storage_dir
+        .clone()
+        .or_else(|| settings::env_string("INKIFY_STORAGE_DIR").map(PathBuf::from))?;
+    let ttl = ARGS
+        .storage_ttl
+        .or_else(|| settings::env_parse_required("INKIFY_STORAGE_TTL_SECS"))
+        .map(Duration::from_secs)
+        .unwrap_or(storage::DEFAULT_TTL);
+    let max_bytes = ARGS
+        .storage_max_bytes
+        .or_else(|| settings::env_parse_required("INKIFY_STORAGE_MAX_BYTES"))
+        .unwrap_or(storage::DEFAULT_MAX_BYTES);
+    let storage = storage::Storage::open(&dir, ttl, max_bytes)
+        .unwrap_or_else(|e| settings::fail("--storage-dir", format!("{}: {}", dir.display(), e)));
+    tracing::info!(dir = %dir.display(), ?ttl, max_bytes, "Opened image storage");
+    Some(storage)
+}
+
+/// Loads the default syntaxes and themes, and those in the configured
+/// directories.
+fn load_assets() -> Result<si::assets::HighlightingAssets, assets::Error> {
+    assets::load(
+        ARGS.themes_dir
+            .clone()
+            .or_else(|| settings::env_string("INKIFY_THEMES_DIR"))
+            .as_deref(),
+        ARGS.syntaxes_dir
+            .clone()
+            .or_else(|| settings::env_string("INKIFY_SYNTAXES_DIR"))
+            .as_deref(),
+    )
+}
+
+/// The syntaxes and themes currently loaded. A request holds on to the ones
+/// it started with, so a reload doesn't change them mid-render.
+fn highlighting_assets() -> Arc<si::assets::HighlightingAssets> {
+    HIGHLIGHTING_ASSETS.read().unwrap().clone()
+}
+
+/// Loads the configured language detection model, if any. At most one
+/// backend can be configured, which clap enforces.
+fn load_detector() -> Option<Result<Arc<dyn LanguageDetector>, Error>> {
+    #[cfg(feature = "tensorflow")]
+    if let Some(dir) = &ARGS.tensorflow_model_dir {
+        return Some(
+            detector::TensorflowDetector::load(dir).map(|model| Arc::new(model) as Arc<dyn LanguageDetector>),
+        );
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(path) = &ARGS.onnx_model_path {
+        return Some(detector::OnnxDetector::load(path).map(|model| Arc::new(model) as Arc<dyn LanguageDetector>));
+    }
+    None
+}
+
+/// The language detection model, if one was configured, loaded, and warmed
+/// up.
+fn language_detector() -> Option<Arc<dyn LanguageDetector>> {
+    match (&*DETECTOR, &*WARMUP) {
+        (Some(Ok(model)), Some(Ok(_))) => Some(model.clone()),
+        _ => None,
+    }
 }
 
-fn parse_font_str(s: &str) -> Vec<(String, f32)> {
+fn parse_font_str(s: &str) -> Result<Vec<(String, f32)>, Error> {
     let mut result = vec![];
     for font in s.split(';') {
         let tmp = font.split('=').collect::<Vec<_>>();
-        let font_name = tmp[0].to_owned();
-        let font_size = tmp
-            .get(1)
-            .map(|s| s.parse::<f32>().unwrap())
-            .unwrap_or(26.0);
+        let font_name = tmp[0].trim().to_owned();
+        let font_size = match tmp.get(1) {
+            Some(size) => size
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format_err!("Invalid font size: `{}`", size))?,
+            None => 26.0,
+        };
         result.push((font_name, font_size));
     }
-    result
+    Ok(result)
 }
 
-fn parse_line_range(s: &str) -> Result<Vec<u32>, ParseIntError> {
+/// Parses lines such as `1-3;7` into the line numbers they cover. Ranges are
+/// cut off at `last_line`, since no line past it exists to act on.
+fn parse_line_range(s: &str, last_line: u32) -> Result<Vec<u32>, ParseIntError> {
     let mut result = vec![];
     for range in s.split(';') {
         let range: Vec<u32> = range
@@ -62,7 +455,7 @@ fn parse_line_range(s: &str) -> Result<Vec<u32>, ParseIntError> {
         if range.len() == 1 {
             result.push(range[0])
         } else {
-            for i in range[0]..=range[1] {
+            for i in range[0]..=range[1].min(last_line) {
                 result.push(i);
             }
         }
@@ -70,294 +463,3264 @@ fn parse_line_range(s: &str) -> Result<Vec<u32>, ParseIntError> {
     Ok(result)
 }
 
-fn parse_str_color(s: &str) -> Result<rgba::Rgba, Error> {
-    let res = s
-        .to_rgba()
-        .map_err(|_| format_err!("Invalid color: `{}`", s));
-    Ok(rgba::Rgba(res?))
+/// Parses `highlight_lines` groups, eg. `1-3:#2ecc4040;7`, into the lines
+/// highlighted in silicon's style and those with their own color. Where
+/// groups overlap, the last one wins. Ranges stop at `last_line`.
+fn parse_highlight_lines(s: &str, last_line: u32) -> Result<(Vec<u32>, Vec<(u32, image::Rgba<u8>)>), ApiError> {
+    let mut colors = std::collections::BTreeMap::new();
+    for group in s.split(';') {
+        let (range, color) = match group.split_once(':') {
+            Some((range, color)) => {
+                let color = parse_str_color(color.trim())
+                    .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?;
+                (range, Some(color.to_rgba()))
+            }
+            None => (group, None),
+        };
+        let lines = parse_line_range(range.trim(), last_line).map_err(|_| {
+            ApiError::bad_request("invalid_line_range", format!("Invalid highlight_lines: `{}`", s))
+        })?;
+        for line in lines {
+            colors.insert(line, color);
+        }
+    }
+    let lines = colors.iter().filter(|(_, color)| color.is_none()).map(|(line, _)| *line).collect();
+    let colored = colors
+        .into_iter()
+        .filter_map(|(line, color)| color.map(|color| (line, color)))
+        .collect();
+    Ok((lines, colored))
 }
 
-#[get("/")]
-async fn help() -> impl Responder {
-    // Respond with some help text for how to use the API,
-    // formatted as JSON since this is an API.
-    let json = r#"
-    {
-        "message": "Hello, world! Welcome to Inkify, a simple API for generating images from code. Think of it like Carbon in API form.",
-        "routes": {
-          "GET /": "This help text. Will always return 200, so you can use it to check if the server is up.",
-          "GET /themes": "Return a list of available syntax themes.",
-          "GET /languages": "Retuns a list of languages which can be parsed.",
-          "GET /fonts": "Returns a list of available fonts.",
-          "GET /detect": {
-            "description": "Detect the language of the given code.",
-            "parameters": {
-                "code": "The code to detect the language of. Required."
-            }
-          },
-          "GET /generate": {
-            "description": "Generate an image from the given code.",
-            "parameters": {
-                "code": "The code to generate an image from. Required.",
-                "language": "The language to use for syntax highlighting. Optional, will attempt to guess if not provided.",
-                "theme": "The theme to use for syntax highlighting. Optional, defaults to Dracula.",
-                "font": "The font to use. Optional.",
-                "shadow_color": "The color of the shadow. Optional, defaults to transparent.",
-                "background": "The background color. Optional, defaults to transparent.",
-                "tab_width": "The tab width. Optional, defaults to 4.",
-                "line_pad": "The line padding. Optional, defaults to 2.",
-                "line_offset": "The line offset. Optional, defaults to 1.",
-                "window_title": "The window title. Optional, defaults to \"Inkify\".",
-                "no_line_number": "Whether to hide the line numbers. Optional, defaults to false.",
-                "no_round_corner": "Whether to round the corners. Optional, defaults to false.",
-                "no_window_controls": "Whether to hide the window controls. Optional, defaults to false.",
-                "shadow_blur_radius": "The shadow blur radius. Optional, defaults to 0.",
-                "shadow_offset_x": "The shadow offset x. Optional, defaults to 0.",
-                "shadow_offset_y": "The shadow offset y. Optional, defaults to 0.",
-                "pad_horiz": "The horizontal padding. Optional, defaults to 80.",
-                "pad_vert": "The vertical padding. Optional, defaults to 100.",
-                "highlight_lines": "The lines to highlight. Optional, defaults to none.",
-                "background_image": "The background image for the padding area as a URL. Optional, defaults to none."
-            }
-          }
+/// Parses `line_range`, eg. `40-80` or `1-5;20-25`, into inclusive ranges.
+fn parse_ranges(s: &str) -> Result<Vec<(u32, u32)>, Error> {
+    s.split(';')
+        .map(|range| {
+            let range = range.trim();
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (start, end) = match (start.trim().parse::<u32>(), end.trim().parse::<u32>()) {
+                (Ok(start), Ok(end)) if start > 0 && start <= end => (start, end),
+                _ => return Err(format_err!("Invalid line range: `{}`", range)),
+            };
+            Ok((start, end))
+        })
+        .collect()
+}
+
+/// Keeps only the lines of `code` within `ranges`, with a separator row
+/// between disjoint ranges. Ranges are clamped to the code, then sorted and
+/// merged, and the ones actually used are returned.
+fn select_lines(code: &str, ranges: &[(u32, u32)]) -> (String, Vec<(u32, u32)>) {
+    let lines: Vec<&str> = code.lines().collect();
+    let total = lines.len() as u32;
+    let mut clamped: Vec<(u32, u32)> = ranges
+        .iter()
+        .filter(|(start, _)| *start <= total)
+        .map(|&(start, end)| (start, end.min(total)))
+        .collect();
+    clamped.sort_unstable();
+
+    let mut merged: Vec<(u32, u32)> = vec![];
+    for (start, end) in clamped {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
         }
-      }
-    "#;
+    }
+
+    let code = merged
+        .iter()
+        .map(|&(start, end)| lines[start as usize - 1..end as usize].join("\n"))
+        .collect::<Vec<_>>()
+        .join(&format!("\n{}\n", config::RANGE_SEPARATOR));
+    (code, merged)
+}
 
+#[get("/")]
+async fn help() -> impl Responder {
+    // Respond with some help text for how to use the API, formatted as
+    // JSON since this is an API.
     HttpResponse::Ok()
         .append_header(("Content-Type", "application/json"))
-        .body(json)
+        .body(HELP.as_str())
 }
 
-#[get("/themes")]
-async fn themes() -> impl Responder {
-    let ha = &*HIGHLIGHTING_ASSETS;
-    let themes = &ha.theme_set.themes;
-    let theme_keys: Vec<String> = themes.keys().map(|s| s.to_string()).collect();
-    HttpResponse::Ok().json(theme_keys)
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct ThemesQuery {
+    /// Return objects with the name, whether the theme is dark, its
+    /// background and foreground colors as hex, and its author when known,
+    /// rather than just the names.
+    detailed: Option<bool>,
+
+    /// Only return 'dark' or 'light' themes, going by the background color.
+    filter: Option<String>,
 }
 
-#[get("/languages")]
-async fn languages() -> impl Responder {
-    let ha = &*HIGHLIGHTING_ASSETS;
-    let syntaxes = &ha.syntax_set.syntaxes();
-    let mut languages = syntaxes
+#[get("/themes")]
+async fn themes(version: ApiVersion, query: web::Query<ThemesQuery>) -> impl Responder {
+    let dark = match query.filter.as_deref().map(|filter| filter.to_ascii_lowercase()) {
+        None => None,
+        Some(filter) if filter == "dark" => Some(true),
+        Some(filter) if filter == "light" => Some(false),
+        Some(filter) => {
+            return ApiError::bad_request(
+                "invalid_filter",
+                format!("Invalid filter `{}`, expected one of: dark, light", filter),
+            )
+            .to_response(version);
+        }
+    };
+
+    let ha = highlighting_assets();
+    let themes = ha
+        .theme_set
+        .themes
         .iter()
-        .map(|s| s.name.to_string())
-        .collect::<Vec<String>>();
-    let unique_languages: HashSet<String> = languages.drain(..).collect();
-    let mut unique_languages: Vec<String> = unique_languages.into_iter().collect();
-    unique_languages.sort();
-    HttpResponse::Ok().json(unique_languages)
+        .map(|(name, theme)| (name, theme, is_dark_theme(theme)))
+        .filter(|(_, _, is_dark)| dark.is_none() || dark == Some(*is_dark));
+    if query.detailed.unwrap_or(false) {
+        let hex = |color: Option<syntect::highlighting::Color>| color.map(|c| derive::hex([c.r, c.g, c.b]));
+        let themes: Vec<serde_json::Value> = themes
+            .map(|(name, theme, is_dark)| {
+                serde_json::json!({
+                    "name": name,
+                    "dark": is_dark,
+                    "background": hex(theme.settings.background),
+                    "foreground": hex(theme.settings.foreground),
+                    "author": theme.author,
+                })
+            })
+            .collect();
+        HttpResponse::Ok().json(themes)
+    } else {
+        let theme_keys: Vec<String> = themes.map(|(name, _, _)| name.to_string()).collect();
+        HttpResponse::Ok().json(theme_keys)
+    }
 }
 
-#[get("/fonts")]
-async fn fonts() -> impl Responder {
-    let source = font_kit::source::SystemSource::new();
-    let fonts = source.all_families().unwrap_or_default();
-    HttpResponse::Ok().json(fonts)
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct PreviewQuery {
+    /// Language of the sample snippet: rs, py, js, go, or rb.
+    language: Option<String>,
+
+    /// Themes to compare, separated by ';', eg. 'Dracula;Nord'. Required
+    /// by /themes/preview, at most 8.
+    themes: Option<String>,
 }
 
-#[get("/detect")]
-async fn detect(info: web::Query<config::ConfigQuery>) -> impl Responder {
-    let args = CliArgs::parse();
-    let ha = &*HIGHLIGHTING_ASSETS;
+#[get("/themes/preview")]
+async fn theme_comparison(req: HttpRequest, version: ApiVersion, query: web::Query<PreviewQuery>) -> impl Responder {
+    let names: Vec<String> = query
+        .themes
+        .as_deref()
+        .unwrap_or_default()
+        .split(';')
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return ApiError::bad_request("missing_themes", "themes parameter is required, eg. themes=Dracula;Nord")
+            .to_response(version);
+    }
+    if names.len() > preview::MAX_THEMES {
+        return ApiError::bad_request(
+            "too_many_themes",
+            format!("At most {} themes can be compared at once", preview::MAX_THEMES),
+        )
+        .to_response(version);
+    }
+    theme_preview_response(&req, version, names, query.language.as_deref()).await
+}
 
-    let (ps, _ts) = (&ha.syntax_set, &ha.theme_set);
+#[get("/themes/{name}/preview")]
+async fn theme_preview(
+    req: HttpRequest,
+    version: ApiVersion,
+    name: web::Path<String>,
+    query: web::Query<PreviewQuery>,
+) -> impl Responder {
+    theme_preview_response(&req, version, vec![name.into_inner()], query.language.as_deref()).await
+}
 
-    let mut conf = config::Config::default();
-    conf.code = info.code.clone();
-    if conf.code.is_empty() {
-        return HttpResponse::BadRequest()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "code parameter is required"}"#);
+/// Renders the sample in `language` with each of the `themes`, stacked top
+/// to bottom, titled with the theme's name. Previews only depend on those,
+/// so they're cached like generated images.
+async fn theme_preview_response(
+    req: &HttpRequest,
+    version: ApiVersion,
+    themes: Vec<String>,
+    language: Option<&str>,
+) -> HttpResponse {
+    let language = language.unwrap_or(preview::DEFAULT_LANGUAGE);
+    let (language, sample) = match preview::sample(language) {
+        Some(sample) => sample,
+        None => {
+            return ApiError::bad_request(
+                "invalid_language",
+                format!(
+                    "No sample for language `{}`, expected one of: {}",
+                    language,
+                    preview::names().join(", ")
+                ),
+            )
+            .to_response(version);
+        }
+    };
+    // Only installed themes can be previewed, never a path to a file.
+    if let Some(unknown) = themes
+        .iter()
+        .find(|name| !highlighting_assets().theme_set.themes.contains_key(name.as_str()))
+    {
+        return ApiError::new(StatusCode::NOT_FOUND, "unknown_theme", format!("Unknown theme `{}`", unknown))
+            .to_response(version);
     }
 
-    if args.tensorflow_model_dir.is_some() {
-        conf.load_tensorflow_model(args.tensorflow_model_dir.unwrap().as_str());
+    let key = cache::preview_key(&themes, language);
+    let etag = format!("\"{}\"", key);
+    let cache_control = format!("public, max-age={}", SETTINGS.http_max_age);
+    if if_none_match(req, &etag) {
+        return HttpResponse::NotModified()
+            .append_header(("ETag", etag))
+            .append_header(("Cache-Control", cache_control))
+            .finish();
     }
 
-    let input_data = Tensor::new(&[1]).with_values(&[conf.code.clone()]).unwrap();
-    let predictions = unwrap_or_return!(
-        conf.predict_language_with_tensorflow(ps, input_data),
-        HttpResponse::BadRequest()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "Failed to detect language."}"#)
-    );
+    let (rendered, cache_hit) = match RENDER_CACHE.get(&key) {
+        Some(rendered) => (rendered, true),
+        None => match run_blocking(&RENDERS, move || render_preview(&themes, language, sample)).await {
+            Ok(rendered) => {
+                let rendered = Arc::new(rendered);
+                RENDER_CACHE.insert(key, rendered.clone());
+                (rendered, false)
+            }
+            Err(e) => return e.to_response(version),
+        },
+    };
+    HttpResponse::Ok()
+        .append_header(("X-Cache", if cache_hit { "HIT" } else { "MISS" }))
+        .append_header(("ETag", etag))
+        .append_header(("Cache-Control", cache_control))
+        .append_header(("Content-Type", rendered.content_type.as_str()))
+        .body(rendered.bytes.clone())
+}
+
+fn render_preview(themes: &[String], language: &str, sample: &str) -> Result<cache::Rendered, ApiError> {
+    let started = Instant::now();
+    let ha = highlighting_assets();
+    let ps = &ha.syntax_set;
+    let syntax = ps.find_syntax_by_token(language).unwrap_or_else(|| ps.find_syntax_plain_text());
 
-    let mut sorted_predictions: Vec<_> = predictions.iter().collect();
-        sorted_predictions.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
+    let mut images = vec![];
+    for name in themes {
+        let mut conf = config::Config::default();
+        conf.code = sample.to_owned();
+        conf.language = Some(language.to_owned());
+        conf.theme = name.clone();
+        conf.window_title = Some(name.clone());
+        let theme = conf
+            .theme(&ha.theme_set, None)
+            .map_err(|e| ApiError::bad_request("invalid_theme", e.to_string()))?;
+        let (rendered, _) = render_png(&conf, syntax, &theme, ps, &Cancellation::default())?;
+        images.push(rendered.bytes);
+    }
+    let bytes = if images.len() == 1 {
+        images.remove(0)
+    } else {
+        let images = images
+            .iter()
+            .map(|png| image::load_from_memory(png))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ApiError::internal("encode_failed", "Failed to read a preview"))?;
+        let mut buffer: Vec<u8> = Vec::new();
+        preview::stack(&images)
+            .write_to(&mut Cursor::new(&mut buffer), image::ImageOutputFormat::Png)
+            .map_err(|_| ApiError::internal("encode_failed", "Failed to write image"))?;
+        buffer
+    };
 
-    let min_score = predictions.iter().map(|(_, score)| *score).fold(f32::INFINITY, f32::min);
-    let max_score = predictions.iter().map(|(_, score)| *score).fold(f32::NEG_INFINITY, f32::max);
+    let (width, height) = png_size(&bytes);
+    Ok(cache::Rendered {
+        bytes,
+        content_type: "image/png".to_owned(),
+        headers: vec![],
+        language: syntax.name.clone(),
+        theme: themes.join(";"),
+        window_title: None,
+        width,
+        height,
+        render_ms: started.elapsed().as_millis() as u64,
+    })
+}
 
-    // Normalize scores and pick top 5
-    let mut normalized_predictions: Vec<_> = predictions.iter().map(|(lang, score)| {
-    let normalized_score = (score - min_score) / (max_score - min_score) * 100.0;
-    (lang, normalized_score)
-    }).collect();
+/// Whether `theme` is dark, going by its background color, or by its
+/// foreground when it has none.
+fn is_dark_theme(theme: &Theme) -> bool {
+    let luminance = |c: syntect::highlighting::Color| derive::luminance([c.r, c.g, c.b]);
+    match (theme.settings.background, theme.settings.foreground) {
+        (Some(background), _) => luminance(background) < 0.5,
+        (None, Some(foreground)) => luminance(foreground) >= 0.5,
+        (None, None) => false,
+    }
+}
 
-    normalized_predictions.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct LanguagesQuery {
+    /// Return objects with the language name, its file extensions, and the
+    /// aliases accepted for it as language, eg. golang for Go, rather than
+    /// just the names.
+    detailed: Option<bool>,
+}
 
-    let response = normalized_predictions
+#[get("/languages")]
+async fn languages(query: web::Query<LanguagesQuery>) -> impl Responder {
+    let ha = highlighting_assets();
+    let syntaxes = &ha.syntax_set.syntaxes();
+    let mut languages = syntaxes
         .iter()
-        // .take(5)
-        .map(|(language, score)| format!("{{\"language\": \"{}\", \"score\": {}}}", language, score))
-        .collect::<Vec<_>>()
-        .join(",");
+        .map(|s| s.name.to_string())
+        .collect::<Vec<String>>();
+    let unique_languages: HashSet<String> = languages.drain(..).collect();
+    let mut unique_languages: Vec<String> = unique_languages.into_iter().collect();
+    unique_languages.sort();
+    if !query.detailed.unwrap_or(false) {
+        return HttpResponse::Ok().json(unique_languages);
+    }
 
-    HttpResponse::Ok()
-        .append_header(("Content-Type", "application/json"))
-        .body(format!("[{}]", response))
+    let detailed: Vec<serde_json::Value> = unique_languages
+        .iter()
+        .filter_map(|name| ha.syntax_set.find_syntax_by_name(name))
+        .map(|syntax| {
+            let aliases: Vec<&str> = aliases::all()
+                .iter()
+                .filter(|(_, token)| {
+                    ha.syntax_set
+                        .find_syntax_by_token(token)
+                        .is_some_and(|target| target.name == syntax.name)
+                })
+                .map(|(alias, _)| alias.as_str())
+                .collect();
+            serde_json::json!({
+                "name": syntax.name,
+                "extensions": syntax.file_extensions,
+                "aliases": aliases,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(detailed)
 }
 
-#[get("/generate")]
-async fn generate(info: web::Query<config::ConfigQuery>) -> impl Responder {
-    let args = CliArgs::parse();
-    let ha = &*HIGHLIGHTING_ASSETS;
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct FontsQuery {
+    /// Return objects with the family name, whether it has a monospace face,
+    /// and the weights and styles of its faces, rather than just the names.
+    detailed: Option<bool>,
 
-    let (ps, ts) = (&ha.syntax_set, &ha.theme_set);
+    /// Only return families with a monospace face, which suit code.
+    monospace: Option<bool>,
+}
 
-    let mut conf = config::Config::default();
-    conf.code = info.code.clone();
-    if conf.code.is_empty() {
-        return HttpResponse::BadRequest()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "code parameter is required"}"#);
+#[get("/fonts")]
+async fn fonts(version: ApiVersion, query: web::Query<FontsQuery>) -> impl Responder {
+    let detailed = query.detailed.unwrap_or(false);
+    let monospace = query.monospace.unwrap_or(false);
+    let mut res = HttpResponse::Ok();
+    res.append_header(("X-Fallback-Fonts", fallback::installed().join(";")));
+    let missing = fallback::missing();
+    if !missing.is_empty() {
+        res.append_header(("X-Missing-Fallback-Fonts", missing.join(";")));
     }
-
-    if args.tensorflow_model_dir.is_some() {
-        conf.load_tensorflow_model(args.tensorflow_model_dir.unwrap().as_str());
+    if !detailed && !monospace {
+        let source = font_kit::source::SystemSource::new();
+        return match source.all_families() {
+            Ok(fonts) => res.json(fonts),
+            Err(e) => ApiError::internal("font_listing_failed", format!("Failed to list fonts: {}", e))
+                .to_response(version),
+        };
     }
 
-    conf.language = info.language.clone();
-    if let Some(theme) = info.theme.clone() {
-        conf.theme = theme;
+    // Loading every face is slow the first time, so it runs on the blocking
+    // pool, though outside the render limit.
+    let families = web::block(|| {
+        families::all()
+            .map_err(|e| ApiError::internal("font_listing_failed", format!("Failed to list fonts: {}", e)))
+    })
+    .await
+    .unwrap_or_else(|_| Err(ApiError::internal("font_listing_failed", "Font listing task failed")));
+    let families = match families {
+        Ok(families) => families.iter().filter(|family| !monospace || family.monospace),
+        Err(e) => return e.to_response(version),
+    };
+    if detailed {
+        res.json(families.collect::<Vec<_>>())
+    } else {
+        res.json(families.map(|family| &family.name).collect::<Vec<_>>())
     }
-    if let Some(font) = info.font.clone() {
-        conf.font = Some(parse_font_str(&font));
+}
+
+/// Rejects unknown query parameters on `/v1` routes when strict validation
+/// is enabled. Legacy routes silently ignore them.
+fn check_strict(query: &str, version: ApiVersion, body_unknown: &[String]) -> Result<(), ApiError> {
+    if version != ApiVersion::V1 || !SETTINGS.v1_strict {
+        return Ok(());
     }
-    if let Some(shadow_color) = info.shadow_color.clone() {
-        conf.shadow_color = parse_str_color(shadow_color.as_str()).unwrap();
+    let mut unknown = query::unknown_params(query);
+    if SETTINGS.signing_secret.is_some() {
+        unknown.retain(|name| name != signing::SIG && name != signing::EXPIRES);
     }
-    if let Some(background) = info.background.clone() {
-        conf.background = parse_str_color(background.as_str()).unwrap();
+    unknown.extend_from_slice(body_unknown);
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(
+            "unknown_parameter",
+            format!("Unknown parameter(s): {}", unknown.join(", ")),
+        ))
     }
-    if let Some(tab_width) = info.tab_width {
-        conf.tab_width = tab_width;
+}
+
+/// Applies the per-IP rate limit, when one is configured.
+fn check_rate_limit(req: &HttpRequest) -> Result<(), ApiError> {
+    let limiter = match &*RATE_LIMITER {
+        Some(limiter) => limiter,
+        None => return Ok(()),
+    };
+    let ip = match ratelimit::client_ip(req, SETTINGS.trust_proxy) {
+        Some(ip) => ip,
+        None => return Ok(()),
+    };
+    limiter.check(ip).map_err(|retry_after| {
+        let secs = retry_after.as_secs_f64().ceil() as u64;
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            format!("Rate limit exceeded, retry in {} seconds", secs),
+        )
+        .with_header("Retry-After", secs.to_string())
+    })
+}
+
+/// Requires a valid API key, when any are configured.
+fn check_api_key(req: &HttpRequest) -> Result<(), ApiError> {
+    if SETTINGS.api_keys.is_empty() {
+        return Ok(());
     }
-    if let Some(line_pad) = info.line_pad {
-        conf.line_pad = line_pad;
+    match auth::presented_key(req) {
+        Some(key) if auth::key_matches(key, &SETTINGS.api_keys) => Ok(()),
+        Some(_) => Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid_api_key", "Invalid API key")
+            .with_header("WWW-Authenticate", "Bearer")),
+        None => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "missing_api_key",
+            "An API key is required, pass it as `Authorization: Bearer <key>` or `X-Api-Key: <key>`",
+        )
+        .with_header("WWW-Authenticate", "Bearer")),
     }
-    if let Some(line_offset) = info.line_offset {
-        conf.line_offset = line_offset;
+}
+
+/// Requires the admin token. Without one configured, the admin endpoints
+/// don't exist.
+fn check_admin(req: &HttpRequest) -> Result<(), ApiError> {
+    let token = match &SETTINGS.admin_token {
+        Some(token) => token,
+        None => return Err(ApiError::not_found("not_found", "Not found")),
+    };
+    match auth::presented_key(req) {
+        Some(key) if auth::constant_time_eq(key.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid_admin_token", "Invalid admin token")
+            .with_header("WWW-Authenticate", "Bearer")),
     }
-    if let Some(window_title) = info.window_title.clone() {
-        conf.window_title = Some(window_title);
+}
+
+/// Requires a valid signature of `path` and `query` when signing is enabled,
+/// unless the request has a valid API key, and otherwise an API key when any
+/// are configured.
+fn check_generate_access(req: &HttpRequest, path: &str, query: &str) -> Result<(), ApiError> {
+    let secret = match &SETTINGS.signing_secret {
+        Some(secret) => secret,
+        None => return check_api_key(req),
+    };
+    let has_key = !SETTINGS.api_keys.is_empty()
+        && auth::presented_key(req).is_some_and(|key| auth::key_matches(key, &SETTINGS.api_keys));
+    if has_key {
+        return Ok(());
     }
-    if let Some(no_line_number) = info.no_line_number {
-        conf.no_line_number = no_line_number;
+    if !matches!(*req.method(), actix_web::http::Method::GET | actix_web::http::Method::HEAD) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "signature_required",
+            "Only GET and HEAD requests can be signed, POST requests need an API key",
+        ));
     }
-    if let Some(no_round_corner) = info.no_round_corner {
-        conf.no_round_corner = no_round_corner;
+    signing::verify(secret, path, query, std::time::SystemTime::now())
+}
+
+#[get("/detect")]
+async fn detect(
+    req: HttpRequest,
+    version: ApiVersion,
+    info: web::Query<query::ConfigQuery>,
+) -> impl Responder {
+    let mut info = info.into_inner();
+    let warning = match validate_detect(&req, version, &mut info) {
+        Ok(warning) => warning,
+        Err(e) => return e.to_response(version),
+    };
+
+    let mut conf = config::Config::default();
+    conf.code = info.code.clone();
+    conf.language = info.language.clone();
+    conf.filename = info.filename.clone();
+    let top_k = info.top_k.unwrap_or(DEFAULT_TOP_K);
+    let min_score = info.min_score.unwrap_or(0.0);
+
+    // Inference is CPU bound, so it runs on the blocking pool rather than
+    // holding up the worker's executor.
+    let result = run_blocking(&DETECTIONS, move || detect_language(conf, top_k, min_score)).await;
+    match result {
+        Ok(predictions) => {
+            let mut res = HttpResponse::Ok();
+            if let Some(warning) = warning {
+                res.append_header(("X-Warning", warning));
+            }
+            res.json(predictions)
+        }
+        Err(e) => e.to_response(version),
     }
-    if let Some(no_window_controls) = info.no_window_controls {
-        conf.no_window_controls = no_window_controls;
+}
+
+/// Checks a /detect request and tidies its code, returning any warning
+/// about the code.
+fn validate_detect(
+    req: &HttpRequest,
+    version: ApiVersion,
+    info: &mut query::ConfigQuery,
+) -> Result<Option<&'static str>, ApiError> {
+    check_rate_limit(req)?;
+    check_api_key(req)?;
+    check_strict(req.query_string(), version, &[])?;
+    source::decode_inline(info)?;
+    let warning = source::tidy(info, &highlighting_assets().syntax_set)?;
+
+    if info.code.is_empty() {
+        return Err(ApiError::bad_request("missing_code", "code parameter is required"));
     }
-    if let Some(shadow_blur_radius) = info.shadow_blur_radius {
-        conf.shadow_blur_radius = shadow_blur_radius;
+    if info.top_k == Some(0) {
+        return Err(ApiError::bad_request("invalid_top_k", "top_k must be at least 1"));
     }
-    if let Some(shadow_offset_x) = info.shadow_offset_x {
-        conf.shadow_offset_x = shadow_offset_x;
+    if let Some(min_score) = info.min_score {
+        if !(0.0..=100.0).contains(&min_score) {
+            return Err(ApiError::bad_request(
+                "invalid_min_score",
+                "min_score must be between 0 and 100",
+            ));
+        }
     }
-    if let Some(shadow_offset_y) = info.shadow_offset_y {
-        conf.shadow_offset_y = shadow_offset_y;
+    Ok(warning)
+}
+
+/// Predictions /detect returns when `top_k` isn't given.
+const DEFAULT_TOP_K: usize = 5;
+
+/// A language predicted by /detect.
+#[derive(Debug, serde::Serialize)]
+struct Prediction {
+    /// The model's class, or the name of the syntax when the model wasn't
+    /// needed.
+    language: String,
+
+    /// The model's score relative to the other classes, or the heuristic's
+    /// estimated probability, from 0 to 100.
+    score: f32,
+
+    /// A token which selects the matching syntax in /generate's `language`,
+    /// or `None` if there's no such syntax.
+    syntax_token: Option<String>,
+
+    /// How the language was chosen: "explicit", "extension", "first-line",
+    /// "model", or "heuristic".
+    source: &'static str,
+}
+
+/// The languages `conf.code` is likely written in. When `language`,
+/// `filename`, or the first line decides it, that's the only prediction,
+/// otherwise the model is asked.
+fn detect_language(conf: config::Config, top_k: usize, min_score: f32) -> Result<Vec<Prediction>, ApiError> {
+    let ha = highlighting_assets();
+
+    let (ps, _ts) = (&ha.syntax_set, &ha.theme_set);
+
+    let decided = conf
+        .language_without_model(ps)
+        .map_err(|e| ApiError::bad_request("invalid_language", e.to_string()))?;
+    if let Some((syntax, detection)) = decided {
+        let prediction = Prediction {
+            language: syntax.name.clone(),
+            score: 100.0,
+            syntax_token: Some(syntax.file_extensions.first().cloned().unwrap_or_else(|| syntax.name.clone())),
+            source: detection.source(),
+        };
+        return Ok(vec![prediction]);
     }
-    if let Some(pad_horiz) = info.pad_horiz {
-        conf.pad_horiz = pad_horiz;
+
+    let predictions = match model_predictions(&conf.code)? {
+        Some(predictions) => scale_model_scores(predictions),
+        // The heuristic's probabilities are shown as they are, so they read
+        // as less certain than the model's.
+        None => heuristic::predict(&conf.code)
+            .into_iter()
+            .map(|(language, score)| (language, score.exp2() * 100.0, "heuristic"))
+            .collect(),
+    };
+
+    // Scores are rounded to two decimal places.
+    let mut predictions: Vec<Prediction> = predictions
+        .into_iter()
+        .map(|(language, score, source)| {
+            let syntax_token = ps.find_syntax_by_token(&language).map(|syntax| {
+                syntax.file_extensions.first().cloned().unwrap_or_else(|| language.clone())
+            });
+            Prediction {
+                language,
+                score: (score * 100.0).round() / 100.0,
+                syntax_token,
+                source,
+            }
+        })
+        .filter(|prediction| prediction.score >= min_score)
+        .collect();
+    predictions.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.language.cmp(&b.language)));
+    predictions.truncate(top_k);
+    Ok(predictions)
+}
+
+/// Normalizes the model's log2 probabilities against their range, from 0
+/// to 100.
+fn scale_model_scores(predictions: Vec<(String, f32)>) -> Vec<(String, f32, &'static str)> {
+    let lowest = predictions.iter().map(|(_, score)| *score).fold(f32::INFINITY, f32::min);
+    let highest = predictions.iter().map(|(_, score)| *score).fold(f32::NEG_INFINITY, f32::max);
+    predictions
+        .into_iter()
+        .map(|(language, score)| {
+            let score = if highest > lowest {
+                (score - lowest) / (highest - lowest) * 100.0
+            } else {
+                100.0
+            };
+            (language, score, "model")
+        })
+        .collect()
+}
+
+/// The model's log2 probability for each language it knows, or `None` when
+/// there's no model, it failed to warm up, or it failed on `code`, and the
+/// heuristic should be used instead.
+fn model_predictions(code: &str) -> Result<Option<Vec<(String, f32)>>, ApiError> {
+    let model = match language_detector() {
+        Some(model) => model,
+        None => return Ok(None),
+    };
+    match detector::predict(model, code, SETTINGS.detection_timeout) {
+        Ok(predictions) => Ok(Some(predictions)),
+        Err(detector::PredictError::Failed(e)) => {
+            tracing::warn!(error = %e, "Language detection failed, using the heuristic");
+            Ok(None)
+        }
+        Err(e) => Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "detection_unavailable",
+            format!("Language detection is unavailable: {}, pass language or filename instead", e),
+        )),
     }
-    if let Some(pad_vert) = info.pad_vert {
-        conf.pad_vert = pad_vert;
+}
+
+#[get("/generate")]
+async fn generate(
+    req: HttpRequest,
+    version: ApiVersion,
+    info: web::Query<query::ConfigQuery>,
+) -> impl Responder {
+    generate_response(&req, version, info.into_inner(), &[]).await
+}
+
+/// The headers `GET /generate` responds with, and the size of the image,
+/// without rendering it unless it's cached.
+#[head("/generate")]
+async fn generate_head(
+    req: HttpRequest,
+    version: ApiVersion,
+    info: web::Query<query::ConfigQuery>,
+) -> HttpResponse {
+    let _in_flight = shutdown::InFlight::start();
+    let mut info = info.into_inner();
+    if let Err(e) = prepare_preflight(&req, version, &mut info).await {
+        return e.to_response(version);
     }
-    if let Some(highlight_lines) = info.highlight_lines.clone() {
-        conf.highlight_lines = Some(parse_line_range(highlight_lines.as_str()).unwrap());
+    let key = cache::cache_key(&info, version);
+    let etag = format!("\"{}\"", key);
+    let cache_control = format!("public, max-age={}", SETTINGS.http_max_age);
+    if if_none_match(&req, &etag) {
+        return HttpResponse::NotModified()
+            .append_header(("ETag", etag))
+            .append_header(("Cache-Control", cache_control))
+            .finish();
     }
-    if let Some(background_image) = info.background_image.clone() {
-        // If a background image is provided, it will be as a URL. We need
-        // to download it and add it to the config as a Vec<u8>.
-        let res = reqwest::get(background_image.as_str()).await;
-        if let Ok(mut res) = res {
-            let mut buf = vec![];
-            while let Ok(Some(chunk)) = res.chunk().await {
-                buf.extend_from_slice(&chunk);
-            }
-            conf.background_image = Some(buf);
-        }
+
+    let size = match image_size(&key, version, &info).await {
+        Ok(size) => size,
+        Err(e) => return e.to_response(version),
+    };
+    let mut res = HttpResponse::Ok();
+    res.append_header(("ETag", etag))
+        .append_header(("Cache-Control", cache_control))
+        .append_header(("X-Image-Width", size.width.to_string()))
+        .append_header(("X-Image-Height", size.height.to_string()))
+        .append_header(("X-Size-Estimated", (!size.exact).to_string()));
+    match size.rendered {
+        // The body of a HEAD response isn't sent, but gives its length.
+        Some(rendered) => res
+            .append_header(("Content-Type", rendered.content_type.as_str()))
+            .body(rendered.bytes.clone()),
+        None => res.append_header(("Content-Type", "image/png")).finish(),
     }
+}
 
-    let syntax = unwrap_or_return!(
-        conf.language(ps),
-        HttpResponse::BadRequest()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "Unable to determine language, please provide one explicitly"}"#)
-    );
+/// The size `GET /generate` would render an image at, as JSON.
+#[get("/estimate")]
+async fn estimate(req: HttpRequest, version: ApiVersion, info: web::Query<query::ConfigQuery>) -> HttpResponse {
+    let _in_flight = shutdown::InFlight::start();
+    estimate_response(&req, version, info.into_inner())
+        .await
+        .unwrap_or_else(|e| e.to_response(version))
+}
 
-    let theme = unwrap_or_return!(
-        conf.theme(ts),
-        HttpResponse::BadRequest()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "Invalid theme"}"#)
-    );
+async fn estimate_response(
+    req: &HttpRequest,
+    version: ApiVersion,
+    mut info: query::ConfigQuery,
+) -> Result<HttpResponse, ApiError> {
+    prepare_preflight(req, version, &mut info).await?;
+    let size = image_size(&cache::cache_key(&info, version), version, &info).await?;
+    Ok(HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-store"))
+        .json(serde_json::json!({
+            "width": size.width,
+            "height": size.height,
+            "bytes": size.bytes,
+            "exact": size.exact,
+        })))
+}
 
-    let mut h = HighlightLines::new(syntax, &theme);
-    let highlight = unwrap_or_return!(
-        LinesWithEndings::from(conf.code.as_ref())
-            .map(|line| h.highlight_line(line, ps))
-            .collect::<Result<Vec<_>, _>>(),
-        HttpResponse::InternalServerError()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "Failed to highlight code"}"#)
-    );
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct OembedQuery {
+    /// The GET /generate URL of the image, on this server.
+    url: Option<String>,
 
-    let mut formatter = unwrap_or_return!(
-        conf.get_formatter(),
-        HttpResponse::InternalServerError()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "Failed to get formatter"}"#)
-    );
+    /// The format of the response. Only 'json' is supported.
+    format: Option<String>,
+}
 
-    let image = formatter.format(&highlight, &theme);
-    let mut buffer: Vec<u8> = Vec::new();
-    unwrap_or_return!(
-        image.write_to(&mut Cursor::new(&mut buffer), image::ImageOutputFormat::Png),
-        HttpResponse::InternalServerError()
-            .append_header(("Content-Type", "application/json"))
-            .body(r#"{"error": "Failed to write image"}"#)
-    );
+/// Describes the image of a `GET /generate` URL of this server as an oEmbed
+/// photo, rendering it as that URL would be, so the size is exact.
+#[get("/oembed")]
+async fn oembed(req: HttpRequest, version: ApiVersion, query: web::Query<OembedQuery>) -> HttpResponse {
+    let _in_flight = shutdown::InFlight::start();
+    oembed_response(&req, query.into_inner())
+        .await
+        .unwrap_or_else(|e| e.to_response(version))
+}
 
-    // Return the image as a PNG.
-    HttpResponse::Ok()
-        .append_header(("Content-Type", "image/png"))
-        .body(buffer)
+async fn oembed_response(req: &HttpRequest, query: OembedQuery) -> Result<HttpResponse, ApiError> {
+    check_rate_limit(req)?;
+    if let Some(format) = query.format.as_deref().filter(|format| !format.eq_ignore_ascii_case("json")) {
+        return Err(ApiError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "unsupported_format",
+            format!("Unsupported format `{}`, only json is supported", format),
+        ));
+    }
+    let url = query
+        .url
+        .ok_or_else(|| ApiError::bad_request("missing_url", "url parameter is required"))?;
+    let (host, scheme) = {
+        let conn = req.connection_info();
+        (conn.host().to_owned(), conn.scheme().to_owned())
+    };
+    let target = oembed::target(&url, &host)?;
+
+    // The same checks as the image request itself will face.
+    // Behind a proxy mounting the routes under a prefix, the path of the
+    // image request as the server receives it is the route alone.
+    check_generate_access(req, &format!("{}/generate", target.version.prefix()), &target.query)?;
+    check_strict(&target.query, target.version, &[])?;
+    let mut info: query::ConfigQuery = serde_urlencoded::from_str(&target.query)
+        .map_err(|e| ApiError::bad_request("invalid_url", format!("Invalid parameters in url: {}", e)))?;
+    validate_params(&info)?;
+    defaults::apply(&mut info);
+    let returns_image = info.store != Some(true)
+        && info.r#async != Some(true)
+        && info.response.as_deref().map_or(true, |mode| mode.eq_ignore_ascii_case("binary"));
+    if !returns_image {
+        return Err(ApiError::bad_request(
+            "invalid_url",
+            "url should return an image, without store, async, or response=json",
+        ));
+    }
+    source::resolve(&mut info, &highlighting_assets().syntax_set).await?;
+
+    let key = cache::cache_key(&info, target.version);
+    let (rendered, _) = generate_image(key, target.version, &info).await?;
+    let provider_url = format!("{}://{}/", scheme, host);
+    Ok(HttpResponse::Ok()
+        .append_header(("Cache-Control", format!("public, max-age={}", SETTINGS.http_max_age)))
+        .json(oembed::document(&url, &rendered, &provider_url, SETTINGS.http_max_age)))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let host = std::env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_owned());
-    let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_owned());
-    let server = HttpServer::new(|| {
-        App::new()
-            .service(help)
-            .service(themes)
-            .service(languages)
-            .service(fonts)
-            .service(detect)
-            .service(generate)
-    })
-    .bind((host.clone(), port.parse::<u16>().unwrap()))?
-    .run();
+/// Same as `GET /generate`, with the parameters passed either as a JSON
+/// object or as `multipart/form-data` fields alongside an uploaded `file`.
+#[post("/generate")]
+async fn generate_post(req: HttpRequest, version: ApiVersion, payload: web::Payload) -> impl Responder {
+    let parsed = if is_multipart(&req) {
+        parse_upload(&req, payload).await
+    } else {
+        read_body(payload).await.and_then(|body| {
+            query::parse_json_query(&body)
+                .map_err(|e| ApiError::bad_request("invalid_body", format!("Invalid JSON body: {}", e)))
+        })
+    };
+    match parsed {
+        Ok((info, unknown)) => generate_response(&req, version, info, &unknown).await,
+        Err(e) => e.to_response(version),
+    }
+}
+
+fn is_multipart(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("multipart/form-data"))
+        .unwrap_or(false)
+}
 
-    println!("Inkify listening on {}:{}", host, port);
-    println!("Visit http://{}:{}/ to get started.", host, port);
-    server.await
+/// Upper bound on a request body, leaving room for the encoding overhead of
+/// `code_b64` and `code_gz` and for the other parameters.
+fn max_body_bytes() -> usize {
+    SETTINGS.max_code_bytes * 2 + 64 * 1024
+}
+
+async fn read_body(mut payload: web::Payload) -> Result<Vec<u8>, ApiError> {
+    let mut body = vec![];
+    while let Some(chunk) = payload
+        .try_next()
+        .await
+        .map_err(|e| ApiError::bad_request("invalid_body", e.to_string()))?
+    {
+        if body.len() + chunk.len() > max_body_bytes() {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "body_too_large",
+                format!("Request body exceeds the {} byte limit", max_body_bytes()),
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Reads a `multipart/form-data` upload. The `file` part supplies the code,
+/// and its file name the default language and window title. Every other part
+/// is read as a parameter, as if it had been passed in the query string.
+async fn parse_upload(
+    req: &HttpRequest,
+    payload: web::Payload,
+) -> Result<(query::ConfigQuery, Vec<String>), ApiError> {
+    let mut multipart = Multipart::new(req.headers(), payload);
+    let mut file: Option<(Option<String>, Vec<u8>)> = None;
+    let mut params = form_urlencoded::Serializer::new(String::new());
+    let mut params_len = 0;
+    while let Some(mut field) = multipart
+        .try_next()
+        .await
+        .map_err(|e| ApiError::bad_request("invalid_multipart", e.to_string()))?
+    {
+        let name = field.name().to_owned();
+        let is_file = name == "file";
+        if is_file && file.is_some() {
+            return Err(ApiError::bad_request(
+                "multiple_files",
+                "Only one `file` part may be uploaded per request",
+            ));
+        }
+
+        let limit = if is_file {
+            SETTINGS.max_code_bytes
+        } else {
+            max_body_bytes() - params_len
+        };
+        let mut buf = vec![];
+        while let Some(chunk) = field
+            .try_next()
+            .await
+            .map_err(|e| ApiError::bad_request("invalid_multipart", e.to_string()))?
+        {
+            if buf.len() + chunk.len() > limit {
+                return Err(if is_file {
+                    ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "code_too_large",
+                        format!("file exceeds the {} byte limit", SETTINGS.max_code_bytes),
+                    )
+                } else {
+                    ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "body_too_large",
+                        format!("Request body exceeds the {} byte limit", max_body_bytes()),
+                    )
+                });
+            }
+            buf.extend_from_slice(&chunk);
+        }
+
+        if is_file {
+            let filename = field
+                .content_disposition()
+                .get_filename()
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_owned());
+            file = Some((filename, buf));
+        } else {
+            let value = String::from_utf8(buf).map_err(|_| {
+                ApiError::bad_request("invalid_multipart", format!("Field `{}` is not UTF-8 text", name))
+            })?;
+            params_len += name.len() + value.len();
+            params.append_pair(&name, &value);
+        }
+    }
+
+    let params = params.finish();
+    let mut info: query::ConfigQuery = serde_urlencoded::from_str(&params)
+        .map_err(|e| ApiError::bad_request("invalid_parameter", e.to_string()))?;
+    let unknown = query::unknown_params(&params);
+
+    if let Some((filename, bytes)) = file {
+        if !info.code.is_empty() {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "code and file are mutually exclusive, pass only one of them",
+            ));
+        }
+        info.code = String::from_utf8(bytes)
+            .map_err(|_| ApiError::bad_request("invalid_file", "file is not UTF-8 text"))?;
+        if let Some(filename) = filename {
+            if info.language.is_none() && info.notebook_cell.is_none() {
+                info.language = config::language_from_filename(&filename, &highlighting_assets().syntax_set);
+            }
+            if info.window_title.is_none() {
+                info.window_title = Some(filename);
+            }
+        }
+    }
+    Ok((info, unknown))
+}
+
+async fn generate_response(
+    req: &HttpRequest,
+    version: ApiVersion,
+    mut info: query::ConfigQuery,
+    body_unknown: &[String],
+) -> HttpResponse {
+    let _in_flight = shutdown::InFlight::start();
+    if let Err(e) = validate_generate(req, version, &info, body_unknown) {
+        return e.to_response(version);
+    }
+    defaults::apply(&mut info);
+    if info.r#async == Some(true) {
+        return enqueue_job(version, info);
+    }
+    let code_source = match source::resolve(&mut info, &highlighting_assets().syntax_set).await {
+        Ok(code_source) => code_source,
+        Err(e) => return e.to_response(version),
+    };
+
+    // The ETag is derived from the same parameter hash as the render cache,
+    // so a matching If-None-Match can be answered without rendering.
+    let key = cache::cache_key(&info, version);
+    let etag = format!("\"{}\"", key);
+    let cache_control = format!("public, max-age={}", SETTINGS.http_max_age);
+    let store = info.store.unwrap_or(false);
+    // Every stored request gets an ID of its own.
+    if !store && if_none_match(req, &etag) {
+        return HttpResponse::NotModified()
+            .append_header(("ETag", etag))
+            .append_header(("Cache-Control", cache_control))
+            .finish();
+    }
+
+    match generate_image(key.clone(), version, &info).await {
+        Ok((rendered, _)) if store => store_response(version, &key, rendered).await,
+        Ok((rendered, source)) => {
+            let mut res = image_response(&rendered, source, &info, etag, cache_control);
+            if let Some(warning) = code_source.warning {
+                res.headers_mut().insert(
+                    actix_web::http::header::HeaderName::from_static("x-warning"),
+                    actix_web::http::header::HeaderValue::from_static(warning),
+                );
+            }
+            res
+        }
+        Err(e) => e.to_response(version),
+    }
+}
+
+/// Responds with a generated image, in the format `info` asks for.
+fn image_response(
+    rendered: &cache::Rendered,
+    source: cache::Source,
+    info: &query::ConfigQuery,
+    etag: String,
+    cache_control: String,
+) -> HttpResponse {
+    let mut res = HttpResponse::Ok();
+    match source {
+        cache::Source::Cache(tier) => {
+            res.append_header(("X-Cache", "HIT"));
+            res.append_header(("X-Cache-Tier", tier));
+        }
+        _ => {
+            res.append_header(("X-Cache", "MISS"));
+        }
+    }
+    if source == cache::Source::Coalesced {
+        res.append_header(("X-Coalesced", "true"));
+    }
+    res.append_header(("ETag", etag));
+    res.append_header(("Cache-Control", cache_control));
+    for (name, value) in &rendered.headers {
+        res.append_header((name.as_str(), value.as_str()));
+    }
+    // Already validated.
+    let mode = info.response.as_deref().and_then(|mode| mode.parse().ok()).unwrap_or_default();
+    let extension = match mode {
+        api::ResponseMode::Binary => "png",
+        api::ResponseMode::Json => "json",
+    };
+    let filename = filename::suggest(
+        info.filename.as_deref(),
+        rendered.window_title.as_deref(),
+        &rendered.language,
+        extension,
+    );
+    res.append_header((
+        "Content-Disposition",
+        filename::content_disposition(&filename, info.download.unwrap_or(false)),
+    ));
+    let mut res = match mode {
+        api::ResponseMode::Binary => res
+            .append_header(("Content-Type", rendered.content_type.as_str()))
+            .body(rendered.bytes.clone()),
+        api::ResponseMode::Json => res.json(image_json(rendered)),
+    };
+    res.extensions_mut().insert(logging::LogFields {
+        language: Some(rendered.language.clone()),
+        theme: Some(rendered.theme.clone()),
+        error_code: None,
+    });
+    res
+}
+
+/// Starts rendering `info` in the background, responding with where to
+/// poll for it.
+fn enqueue_job(version: ApiVersion, mut info: query::ConfigQuery) -> HttpResponse {
+    // Shares the cache key of the same request made synchronously.
+    info.r#async = None;
+    let id = match JOBS.create(version) {
+        Ok(id) => id,
+        Err(e) => return e.to_response(version),
+    };
+    // The job's logs are in the span of the request which started it.
+    actix_web::rt::spawn({
+        let id = id.clone();
+        async move {
+            let _in_flight = shutdown::InFlight::start();
+            JOBS.set(&id, jobs::State::Running);
+            let state = run_job(version, info).await.unwrap_or_else(jobs::State::Failed);
+            JOBS.set(&id, state);
+        }
+        .instrument(tracing::Span::current())
+    });
+    HttpResponse::Accepted()
+        .append_header(("Cache-Control", "no-store"))
+        .json(serde_json::json!({
+            "job_id": id,
+            "status_url": format!("{}/jobs/{}", version.prefix(), id),
+        }))
+}
+
+/// Renders several images, returned as a zip archive when the client
+/// accepts one, and otherwise as a JSON array. Items which fail are
+/// reported alongside the others.
+#[post("/generate/batch")]
+async fn generate_batch(req: HttpRequest, version: ApiVersion, payload: web::Payload) -> HttpResponse {
+    let _in_flight = shutdown::InFlight::start();
+    let checked = check_rate_limit(&req)
+        .and_then(|_| check_generate_access(&req, req.path(), req.query_string()))
+        .and_then(|_| check_strict(req.query_string(), version, &[]));
+    if let Err(e) = checked {
+        return e.to_response(version);
+    }
+    let items = match read_body(payload)
+        .await
+        .and_then(|body| batch::parse(&body, SETTINGS.max_batch_size))
+    {
+        Ok(items) => items,
+        Err(e) => return e.to_response(version),
+    };
+
+    // The code of every item counts against the limit together. Inline code
+    // is counted before anything is fetched, and fetched code as it arrives,
+    // so a batch over the limit is refused without resolving the rest.
+    let too_large = |code_bytes: usize| {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "code_too_large",
+            format!(
+                "The batch's code adds up to {} bytes, over the {} byte limit",
+                code_bytes, SETTINGS.max_code_bytes
+            ),
+        )
+        .to_response(version)
+    };
+    let inline_bytes: usize = items
+        .iter()
+        .filter_map(|item| item.query.as_ref().ok())
+        .map(|(info, _)| info.code_len())
+        .sum();
+    if inline_bytes > SETTINGS.max_code_bytes {
+        return too_large(inline_bytes);
+    }
+
+    // Renders are bounded by the render pool anyway, this keeps one batch
+    // from taking all of its queue.
+    let parallelism = (SETTINGS.max_blocking_renders / 2).max(1);
+    let mut preparing = futures_util::stream::iter(items)
+        .map(|item| async move { (item.id, prepare_batch_item(version, item.query).await) })
+        .buffered(parallelism);
+    let mut prepared: Vec<(String, Result<query::ConfigQuery, ApiError>)> = vec![];
+    let mut code_bytes = 0;
+    while let Some((id, info)) = preparing.next().await {
+        code_bytes += info.as_ref().map_or(0, |info| info.code_len());
+        // Dropping the stream cancels the fetches still in flight.
+        if code_bytes > SETTINGS.max_code_bytes {
+            return too_large(code_bytes);
+        }
+        prepared.push((id, info));
+    }
+    let results: Vec<(String, Result<Arc<cache::Rendered>, ApiError>)> = futures_util::stream::iter(prepared)
+        .map(|(id, info)| async move {
+            let rendered = match info {
+                Ok(info) => generate_image(cache::cache_key(&info, version), version, &info)
+                    .await
+                    .map(|(rendered, _)| rendered),
+                Err(e) => Err(e),
+            };
+            (id, rendered)
+        })
+        .buffered(parallelism)
+        .collect()
+        .await;
+    let failures = results.iter().filter(|(_, rendered)| rendered.is_err()).count();
+
+    let accepts_zip = req
+        .headers()
+        .get_all("Accept")
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/zip"));
+    if !accepts_zip {
+        let items: Vec<serde_json::Value> = results
+            .iter()
+            .map(|(id, rendered)| {
+                let mut item = match rendered {
+                    Ok(rendered) => image_json(rendered),
+                    Err(e) => serde_json::json!({ "error": e.body(version)["error"] }),
+                };
+                item["id"] = id.as_str().into();
+                item
+            })
+            .collect();
+        return HttpResponse::Ok()
+            .append_header(("X-Batch-Failures", failures.to_string()))
+            .json(items);
+    }
+
+    let errors: serde_json::Map<String, serde_json::Value> = results
+        .iter()
+        .filter_map(|(id, rendered)| Some((id.clone(), rendered.as_ref().err()?.body(version)["error"].clone())))
+        .collect();
+    let errors = serde_json::to_vec_pretty(&errors).unwrap_or_default();
+    let mut files: Vec<(String, &[u8])> = results
+        .iter()
+        .filter_map(|(id, rendered)| Some((format!("{}.png", id), rendered.as_ref().ok()?.bytes.as_slice())))
+        .collect();
+    if failures > 0 {
+        files.push(("errors.json".to_owned(), &errors));
+    }
+    match batch::zip(&files) {
+        Ok(zip) => HttpResponse::Ok()
+            .append_header(("Content-Type", "application/zip"))
+            .append_header(("Content-Disposition", "attachment; filename=\"inkify.zip\""))
+            .append_header(("X-Batch-Failures", failures.to_string()))
+            .body(zip),
+        Err(e) => ApiError::internal("render_failed", format!("Failed to build the zip archive: {}", e))
+            .to_response(version),
+    }
+}
+
+/// Validates and resolves an item of a batch, as `generate_response` does
+/// a request.
+async fn prepare_batch_item(
+    version: ApiVersion,
+    query: Result<(query::ConfigQuery, Vec<String>), ApiError>,
+) -> Result<query::ConfigQuery, ApiError> {
+    let (mut info, unknown) = query?;
+    if version == ApiVersion::V1 && SETTINGS.v1_strict && !unknown.is_empty() {
+        return Err(ApiError::bad_request(
+            "unknown_parameter",
+            format!("Unknown parameter(s): {}", unknown.join(", ")),
+        ));
+    }
+    validate_params(&info)?;
+    if info.store == Some(true) || info.r#async == Some(true) {
+        return Err(ApiError::bad_request(
+            "unsupported_parameter",
+            "store and async aren't supported in batches",
+        ));
+    }
+    defaults::apply(&mut info);
+    source::resolve(&mut info, &highlighting_assets().syntax_set).await?;
+    Ok(info)
+}
+
+/// Everything `generate_response` does after validation, for a job.
+async fn run_job(version: ApiVersion, mut info: query::ConfigQuery) -> Result<jobs::State, ApiError> {
+    source::resolve(&mut info, &highlighting_assets().syntax_set).await?;
+    let key = cache::cache_key(&info, version);
+    let (rendered, source) = generate_image(key.clone(), version, &info).await?;
+    Ok(jobs::State::Done {
+        rendered,
+        source,
+        info: Arc::new(info),
+        key,
+    })
+}
+
+/// The status of a job started with `async=true`.
+#[get("/jobs/{id}")]
+async fn job_status(version: ApiVersion, id: web::Path<String>) -> HttpResponse {
+    let id = id.into_inner();
+    let job = match JOBS.get(&id) {
+        Some(job) => job,
+        None => return job_not_found(version),
+    };
+    let mut status = serde_json::json!({
+        "job_id": id,
+        "status": job.state.name(),
+    });
+    match &job.state {
+        jobs::State::Done { .. } => {
+            status["result_url"] = format!("{}/jobs/{}/result", version.prefix(), id).into();
+        }
+        jobs::State::Failed(e) => {
+            status["error"] = e.body(version)["error"].clone();
+        }
+        _ => {}
+    }
+    HttpResponse::Ok().append_header(("Cache-Control", "no-store")).json(status)
+}
+
+/// The image of a finished job, or the error it failed with.
+#[get("/jobs/{id}/result")]
+async fn job_result(version: ApiVersion, id: web::Path<String>) -> HttpResponse {
+    let job = match JOBS.get(&id) {
+        Some(job) => job,
+        None => return job_not_found(version),
+    };
+    match job.state {
+        jobs::State::Done {
+            rendered,
+            source,
+            info,
+            key,
+        } => {
+            let cache_control = format!("public, max-age={}", SETTINGS.http_max_age);
+            image_response(&rendered, source, &info, format!("\"{}\"", key), cache_control)
+        }
+        jobs::State::Failed(e) => e.to_response(version),
+        jobs::State::Pending | jobs::State::Running => ApiError::new(
+            StatusCode::CONFLICT,
+            "job_not_finished",
+            "The job hasn't finished yet, poll its status_url",
+        )
+        .with_header("Retry-After", "1")
+        .to_response(version),
+        jobs::State::Expired => ApiError::new(StatusCode::GONE, "job_expired", "The job's result has expired")
+            .to_response(version),
+    }
+}
+
+fn job_not_found(version: ApiVersion) -> HttpResponse {
+    ApiError::not_found("not_found", "No job with this ID").to_response(version)
+}
+
+/// The body of `response=json`.
+fn image_json(rendered: &cache::Rendered) -> serde_json::Value {
+    serde_json::json!({
+        "image": BASE64.encode(&rendered.bytes),
+        "format": "png",
+        "width": rendered.width,
+        "height": rendered.height,
+        "language": rendered.language,
+        "theme": rendered.theme,
+        "render_ms": rendered.render_ms,
+    })
+}
+
+/// Stores `rendered` and responds with its ID and URL. Uploads to S3 take
+/// precedence over the storage directory, and when they fail the image is
+/// returned inline as with `response=json`, along with a warning.
+async fn store_response(version: ApiVersion, key: &str, rendered: Arc<cache::Rendered>) -> HttpResponse {
+    if let Some(s3) = &*S3 {
+        return match s3.upload(key, &rendered).await {
+            Ok(url) => HttpResponse::Ok()
+                .append_header(("Cache-Control", "no-store"))
+                .json(serde_json::json!({ "id": key, "url": url })),
+            Err(e) => {
+                tracing::warn!(key = %key, error = %e, "Failed to upload image to S3, returning it inline");
+                let mut body = image_json(&rendered);
+                body["warning"] = serde_json::json!("The image couldn't be stored, so it's returned inline");
+                HttpResponse::Ok().append_header(("Cache-Control", "no-store")).json(body)
+            }
+        };
+    }
+
+    // Checked by validate_generate.
+    let storage = STORAGE.as_ref().unwrap();
+    let stored = web::block(move || storage.store(&rendered)).await;
+    match stored {
+        Ok(Ok(id)) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-store"))
+            .json(serde_json::json!({
+                "url": format!("{}/i/{}", version.prefix(), id),
+                "id": id,
+            })),
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "Failed to store image");
+            ApiError::internal("store_failed", "Failed to store the image").to_response(version)
+        }
+        Err(e) => ApiError::internal("store_failed", e.to_string()).to_response(version),
+    }
+}
+
+/// Serves an image stored with `store=true`. IDs are never reused, so the
+/// image can be cached for as long as it's kept.
+#[get("/i/{id}")]
+async fn stored_image(req: HttpRequest, version: ApiVersion, id: web::Path<String>) -> HttpResponse {
+    let id = id.into_inner();
+    let storage = match &*STORAGE {
+        Some(storage) => storage,
+        None => return stored_not_found(version),
+    };
+    let etag = format!("\"{}\"", id);
+    let cache_control = format!("public, max-age={}, immutable", storage.ttl().as_secs());
+    let lookup = id.clone();
+    let rendered = match web::block(move || storage.get(&lookup)).await {
+        Ok(Some(rendered)) => rendered,
+        Ok(None) => return stored_not_found(version),
+        Err(e) => return ApiError::internal("read_failed", e.to_string()).to_response(version),
+    };
+    if if_none_match(&req, &etag) {
+        return HttpResponse::NotModified()
+            .append_header(("ETag", etag))
+            .append_header(("Cache-Control", cache_control))
+            .finish();
+    }
+
+    let mut res = HttpResponse::Ok();
+    res.append_header(("ETag", etag));
+    res.append_header(("Cache-Control", cache_control));
+    for (name, value) in &rendered.headers {
+        res.append_header((name.as_str(), value.as_str()));
+    }
+    let filename = filename::suggest(None, rendered.window_title.as_deref(), &rendered.language, "png");
+    res.append_header(("Content-Disposition", filename::content_disposition(&filename, false)));
+    let mut res = res
+        .append_header(("Content-Type", rendered.content_type.as_str()))
+        .body(rendered.bytes);
+    res.extensions_mut().insert(logging::LogFields {
+        language: Some(rendered.language),
+        theme: Some(rendered.theme),
+        error_code: None,
+    });
+    res
+}
+
+fn stored_not_found(version: ApiVersion) -> HttpResponse {
+    ApiError::not_found("not_found", "No stored image with this ID, or it has expired").to_response(version)
+}
+
+/// Whether the request's `If-None-Match` header matches `etag`, using the
+/// weak comparison required for conditional GETs.
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all("If-None-Match")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Marks the ETag of a compressed response as weak, since its bytes differ
+/// from the uncompressed response's under the same tag. `if_none_match`
+/// compares weakly, so either validates the other.
+fn weaken_encoded_etag(headers: &mut actix_web::http::header::HeaderMap) {
+    use actix_web::http::header::{HeaderValue, CONTENT_ENCODING, ETAG};
+    let encoded = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+    if !encoded {
+        return;
+    }
+    let weak = headers
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+    if let Some(weak) = weak {
+        headers.insert(ETAG, weak);
+    }
+}
+
+/// Largest tmTheme document accepted in `theme_b64`, once decoded.
+const MAX_INLINE_THEME_BYTES: usize = 1024 * 1024;
+
+/// Decodes and parses `theme_b64`.
+fn parse_inline_theme(theme_b64: &str) -> Result<Theme, ApiError> {
+    if !SETTINGS.allow_inline_themes {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "inline_themes_disabled",
+            "Inline themes are disabled on this server, pass a theme name instead",
+        ));
+    }
+    let bytes = source::decode_base64(theme_b64)
+        .ok_or_else(|| ApiError::bad_request("invalid_theme_b64", "theme_b64 is not valid base64"))?;
+    if bytes.len() > MAX_INLINE_THEME_BYTES {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "theme_too_large",
+            format!("theme_b64 decodes to more than {} bytes", MAX_INLINE_THEME_BYTES),
+        ));
+    }
+    ThemeSet::load_from_reader(&mut Cursor::new(bytes))
+        .map_err(|e| ApiError::bad_request("invalid_theme_b64", format!("Invalid theme_b64: {}", e)))
+}
+
+/// Applies the query parameters on top of the default config. Flags are read
+/// in their negative (`no_*`) form on every version; `/v1` additionally
+/// accepts the positive form, which wins when both are given.
+async fn apply_query(
+    conf: &mut config::Config,
+    info: &query::ConfigQuery,
+    version: ApiVersion,
+) -> Result<(), ApiError> {
+    conf.language = info.language.clone();
+    conf.filename = info.filename.clone();
+    if let Some(index) = info.notebook_cell {
+        apply_notebook_cell(conf, info, index)?;
+    }
+    if let Some(theme) = info.theme.clone() {
+        conf.theme = theme;
+    }
+    if let Some(theme_b64) = info.theme_b64.as_deref() {
+        if info.theme.is_some() {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "theme and theme_b64 are mutually exclusive, pass only one of them",
+            ));
+        }
+        let theme = parse_inline_theme(theme_b64)?;
+        conf.theme = theme.name.clone().unwrap_or_else(|| "Inline".to_owned());
+        conf.inline_theme = Some(theme);
+    }
+    if let Some(font) = info.font.clone() {
+        conf.font = Some(
+            parse_font_str(&font).map_err(|e| ApiError::bad_request("invalid_font", e.to_string()))?,
+        );
+    }
+    conf.fallback_fonts = fallback::installed().to_vec();
+    if let Some(shadow_color) = info.shadow_color.clone() {
+        conf.shadow_color = parse_str_color(shadow_color.as_str())
+            .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?;
+    }
+    if let Some(background) = info.background.clone() {
+        conf.background = parse_str_color(background.as_str())
+            .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?;
+    }
+    if let Some(tab_width) = info.tab_width {
+        conf.tab_width = tab_width;
+    }
+    if let Some(line_pad) = info.line_pad {
+        conf.line_pad = line_pad;
+    }
+    if let Some(line_offset) = info.line_offset {
+        conf.line_offset = line_offset;
+    }
+    let diff_language = info.language.as_deref().is_some_and(diff::is_diff_language);
+    if info.diff.unwrap_or(false) || diff_language {
+        if info.line_range.is_some() {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "diff and line_range are mutually exclusive, pass only one of them",
+            ));
+        }
+        conf.diff = true;
+        // The language is that of the changed code, from the file headers
+        // unless given.
+        if conf.language.is_none() || diff_language {
+            let language = diff::language(&conf.code, &highlighting_assets().syntax_set);
+            conf.language = Some(language.unwrap_or_else(|| "txt".to_owned()));
+        }
+    }
+    if info.ansi.unwrap_or(false) {
+        if conf.diff {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "ansi and diff are mutually exclusive, pass only one of them",
+            ));
+        }
+        if info.strip_ansi.unwrap_or(false) {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "ansi and strip_ansi are mutually exclusive, pass only one of them",
+            ));
+        }
+        conf.ansi = true;
+    }
+    if let Some(line_range) = info.line_range.clone() {
+        let ranges = parse_ranges(&line_range)
+            .map_err(|e| ApiError::bad_request("invalid_line_range", e.to_string()))?;
+        let (code, ranges) = select_lines(&conf.code, &ranges);
+        if ranges.is_empty() {
+            return Err(ApiError::bad_request(
+                "invalid_line_range",
+                format!(
+                    "line_range `{}` is past the end of the code ({} lines)",
+                    line_range,
+                    conf.code.lines().count()
+                ),
+            ));
+        }
+        if info.line_offset.is_none() {
+            conf.line_offset = ranges[0].0;
+        }
+        conf.code = code;
+        conf.line_range = Some(ranges);
+    }
+    if let Some(window_title) = info.window_title.clone() {
+        conf.window_title = Some(window_title);
+    }
+    if let Some(no_line_number) = info.no_line_number {
+        conf.no_line_number = no_line_number;
+    }
+    if let Some(no_round_corner) = info.no_round_corner {
+        conf.no_round_corner = no_round_corner;
+    }
+    if let Some(window_style) = info.window_style.as_deref() {
+        conf.window_style = window_style
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_window_style", e.to_string()))?;
+    }
+    if let Some(corner_radius) = info.corner_radius {
+        if corner_radius > config::MAX_CORNER_RADIUS {
+            return Err(ApiError::bad_request(
+                "invalid_corner_radius",
+                format!(
+                    "corner_radius must be between 0 and {}, got {}",
+                    config::MAX_CORNER_RADIUS,
+                    corner_radius
+                ),
+            ));
+        }
+        conf.corner_radius = Some(corner_radius);
+    }
+    if let Some(no_window_controls) = info.no_window_controls {
+        conf.no_window_controls = no_window_controls;
+    }
+    if version == ApiVersion::V1 {
+        if let Some(line_number) = info.line_number {
+            conf.no_line_number = !line_number;
+        }
+        if let Some(round_corner) = info.round_corner {
+            conf.no_round_corner = !round_corner;
+        }
+        if let Some(window_controls) = info.window_controls {
+            conf.no_window_controls = !window_controls;
+        }
+    }
+    if let Some(shadow_blur_radius) = info.shadow_blur_radius {
+        conf.shadow_blur_radius = shadow_blur_radius;
+    }
+    if let Some(shadow_offset_x) = info.shadow_offset_x {
+        conf.shadow_offset_x = shadow_offset_x;
+    }
+    if let Some(shadow_offset_y) = info.shadow_offset_y {
+        conf.shadow_offset_y = shadow_offset_y;
+    }
+    if let Some(pad_horiz) = info.pad_horiz {
+        conf.pad_horiz = pad_horiz;
+    }
+    if let Some(pad_vert) = info.pad_vert {
+        conf.pad_vert = pad_vert;
+    }
+    if let Some(scale) = info.scale {
+        if !(config::MIN_SCALE..=config::MAX_SCALE).contains(&scale) {
+            return Err(ApiError::bad_request(
+                "invalid_scale",
+                format!(
+                    "scale must be between {:.1} and {:.1}, got {}",
+                    config::MIN_SCALE,
+                    config::MAX_SCALE,
+                    scale
+                ),
+            ));
+        }
+        conf.scale = scale;
+    }
+    if let Some(max_width) = info.max_width {
+        if !(wrap::MIN_COLUMNS..=wrap::MAX_COLUMNS).contains(&max_width) {
+            return Err(ApiError::bad_request(
+                "invalid_max_width",
+                format!(
+                    "max_width must be between {} and {} columns, got {}",
+                    wrap::MIN_COLUMNS,
+                    wrap::MAX_COLUMNS,
+                    max_width
+                ),
+            ));
+        }
+        conf.max_width = Some(max_width);
+    }
+    if let Some(highlight_lines) = info.highlight_lines.as_deref() {
+        let (lines, colors) = parse_highlight_lines(highlight_lines, conf.last_line_number())?;
+        conf.highlight_lines = Some(lines);
+        conf.highlight_colors = colors;
+    }
+    if let Some(redact_lines) = info.redact_lines.as_deref() {
+        conf.redact_lines = Some(parse_line_range(redact_lines, conf.last_line_number()).map_err(|_| {
+            ApiError::bad_request("invalid_line_range", format!("Invalid redact_lines: `{}`", redact_lines))
+        })?);
+    }
+    if let Some(redact_pattern) = info.redact_pattern.as_deref().filter(|pattern| !pattern.is_empty()) {
+        conf.redact_pattern = Some(
+            regex::RegexBuilder::new(redact_pattern)
+                .size_limit(redact::MAX_PATTERN_SIZE)
+                .build()
+                .map_err(|e| {
+                    ApiError::bad_request("invalid_redact_pattern", format!("Invalid redact_pattern: {}", e))
+                })?,
+        );
+    }
+    conf.auto_redact = SETTINGS.force_auto_redact || info.auto_redact.unwrap_or(false);
+    if let Some(allow) = info.auto_redact_allow.as_deref() {
+        for name in allow.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            if !secrets::NAMES.contains(&name) {
+                return Err(ApiError::bad_request(
+                    "invalid_auto_redact_allow",
+                    format!(
+                        "Invalid auto_redact_allow rule `{}`, expected one of: {}",
+                        name,
+                        secrets::NAMES.join(", ")
+                    ),
+                ));
+            }
+            // A forced redaction can't be loosened.
+            if !SETTINGS.force_auto_redact {
+                conf.auto_redact_allow.push(name.to_owned());
+            }
+        }
+    }
+    if let Some(strict_language) = info.strict_language {
+        conf.strict_language = strict_language;
+    }
+    if let Some(deterministic) = info.deterministic {
+        conf.deterministic = deterministic;
+    }
+    conf.png_compression = match info.png_compression.as_deref() {
+        Some(png_compression) => png_compression
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_png_compression", e.to_string()))?,
+        None => SETTINGS.png_compression,
+    };
+    if let Some(fallback_language) = info.fallback_language.as_deref() {
+        config::find_language(&highlighting_assets().syntax_set, fallback_language)
+            .map_err(|e| ApiError::bad_request("invalid_fallback_language", e.to_string()))?;
+    }
+    conf.fallback_language = info
+        .fallback_language
+        .clone()
+        .or_else(|| SETTINGS.fallback_language.clone());
+    if let Some(show_whitespace) = info.show_whitespace.as_deref() {
+        conf.show_whitespace = whitespace::Mode::parse(show_whitespace)
+            .map_err(|e| ApiError::bad_request("invalid_show_whitespace", e.to_string()))?;
+    }
+    if let Some(indent_guides) = info.indent_guides {
+        conf.indent_guides = indent_guides;
+    }
+    if let Some(color) = info.indent_guide_color.as_deref() {
+        conf.indent_guide_color = Some(
+            parse_str_color(color)
+                .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?
+                .to_rgba(),
+        );
+    }
+    if let Some(show_language) = info.show_language {
+        conf.show_language = show_language;
+    }
+    if let Some(badge_position) = info.badge_position.as_deref() {
+        conf.badge_position = badge_position
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_badge_position", e.to_string()))?;
+    }
+    if let Some(color) = info.badge_color.as_deref() {
+        conf.badge_color = Some(
+            parse_str_color(color)
+                .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?
+                .to_rgba(),
+        );
+    }
+    if let Some(background_gradient) = info.background_gradient.as_deref() {
+        if info.background_image.is_some() {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "background_gradient and background_image are mutually exclusive, pass only one of them",
+            ));
+        }
+        conf.background_gradient = Some(
+            background_gradient
+                .parse()
+                .map_err(|e: Error| ApiError::bad_request("invalid_gradient", e.to_string()))?,
+        );
+    }
+    conf.watermark = parse_watermark(info).await?;
+    if let Some(caption) = info.caption.as_deref().filter(|caption| !caption.is_empty()) {
+        let lines: Vec<String> = caption
+            .replace("\\n", "\n")
+            .lines()
+            .map(|line| line.to_owned())
+            .collect();
+        if lines.len() > window::MAX_CAPTION_LINES {
+            return Err(ApiError::bad_request(
+                "invalid_caption",
+                format!("caption may have at most {} lines", window::MAX_CAPTION_LINES),
+            ));
+        }
+        let mut caption = window::Caption {
+            lines,
+            color: None,
+            align: window::Align::default(),
+        };
+        if let Some(color) = info.caption_color.as_deref() {
+            caption.color = Some(
+                parse_str_color(color)
+                    .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?
+                    .to_rgba(),
+            );
+        }
+        if let Some(align) = info.caption_align.as_deref() {
+            caption.align = align
+                .parse()
+                .map_err(|e: Error| ApiError::bad_request("invalid_caption_align", e.to_string()))?;
+        }
+        conf.caption = Some(caption);
+    }
+    if let Some(preset) = info.preset.as_deref() {
+        conf.preset = Some(
+            preset
+                .parse()
+                .map_err(|e: Error| ApiError::bad_request("invalid_preset", e.to_string()))?,
+        );
+    }
+    let layout = match info.layout.as_deref() {
+        Some(layout) => layout
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_layout", e.to_string()))?,
+        None => window::Layout::default(),
+    };
+    if info.snippets.is_some() {
+        conf.layout = Some(layout);
+    }
+    if let Some(snippet_gap) = info.snippet_gap {
+        if snippet_gap > window::MAX_SNIPPET_GAP {
+            return Err(ApiError::bad_request(
+                "invalid_snippet_gap",
+                format!(
+                    "snippet_gap must be between 0 and {}, got {}",
+                    window::MAX_SNIPPET_GAP,
+                    snippet_gap
+                ),
+            ));
+        }
+        conf.snippet_gap = snippet_gap;
+    }
+    for (name, max) in [
+        ("max_output_width", info.max_output_width),
+        ("max_output_height", info.max_output_height),
+    ] {
+        if max.is_some_and(|max| !(render::MIN_OUTPUT_SIZE..=render::MAX_OUTPUT_SIZE).contains(&max)) {
+            return Err(ApiError::bad_request(
+                "invalid_max_output_size",
+                format!(
+                    "{} must be between {} and {} pixels, got {}",
+                    name,
+                    render::MIN_OUTPUT_SIZE,
+                    render::MAX_OUTPUT_SIZE,
+                    max.unwrap_or_default()
+                ),
+            ));
+        }
+    }
+    conf.max_output_width = info.max_output_width;
+    conf.max_output_height = info.max_output_height;
+    if let Some(background_fit) = info.background_fit.as_deref() {
+        conf.background_fit = background_fit
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_background_fit", e.to_string()))?;
+    }
+    if let Some(background_position) = info.background_position.as_deref() {
+        conf.background_position = background_position
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_background_position", e.to_string()))?;
+    }
+    if let Some(background_image) = info.background_image.clone() {
+        // If a background image is provided, it will be as a URL or data URI.
+        // We need to load it and add it to the config as a Vec<u8>.
+        conf.background_image = Some(load_image_param("background_image", &background_image).await?);
+    }
+    Ok(())
+}
+
+/// The watermark to draw. A watermark pinned by the server replaces the
+/// requested one, ignoring its style so it can't be hidden.
+async fn parse_watermark(info: &query::ConfigQuery) -> Result<Option<watermark::Watermark>, ApiError> {
+    if let Some(image) = &*WATERMARK_IMAGE {
+        return Ok(Some(watermark::Watermark::new(watermark::Mark::Image(image.clone()))));
+    }
+    if let Some(text) = &SETTINGS.force_watermark {
+        return Ok(Some(watermark::Watermark::new(watermark::Mark::Text(text.clone()))));
+    }
+
+    let text = info.watermark.clone().filter(|text| !text.is_empty());
+    let mark = match (text, info.watermark_image.as_deref()) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "watermark and watermark_image are mutually exclusive, pass only one of them",
+            ))
+        }
+        (Some(text), None) => watermark::Mark::Text(text),
+        (None, Some(url)) => {
+            let bytes = load_image_param("watermark_image", url).await?;
+            let image = image::load_from_memory(&bytes).map_err(|e| {
+                ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_image",
+                    format!("Failed to decode watermark_image: {}", e),
+                )
+            })?;
+            watermark::Mark::Image(Arc::new(image))
+        }
+        (None, None) => return Ok(None),
+    };
+
+    let mut watermark = watermark::Watermark::new(mark);
+    if let Some(position) = info.watermark_position.as_deref() {
+        watermark.position = position
+            .parse()
+            .map_err(|e: Error| ApiError::bad_request("invalid_watermark_position", e.to_string()))?;
+    }
+    if let Some(color) = info.watermark_color.as_deref() {
+        watermark.color = parse_str_color(color)
+            .map_err(|e| ApiError::bad_request("invalid_color", e.to_string()))?
+            .to_rgba();
+    }
+    if let Some(opacity) = info.watermark_opacity {
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(ApiError::bad_request(
+                "invalid_watermark_opacity",
+                format!("watermark_opacity must be between 0.0 and 1.0, got {}", opacity),
+            ));
+        }
+        watermark.opacity = opacity;
+    }
+    if let Some(scale) = info.watermark_scale {
+        if !(0.05..=4.0).contains(&scale) {
+            return Err(ApiError::bad_request(
+                "invalid_watermark_scale",
+                format!("watermark_scale must be between 0.05 and 4.0, got {}", scale),
+            ));
+        }
+        watermark.scale = scale;
+    }
+    if let watermark::Mark::Image(image) = &watermark.mark {
+        let scale = watermark.scale * info.scale.unwrap_or(1.0);
+        let largest = image.width().max(image.height()) as f32 * scale;
+        if largest > watermark::MAX_IMAGE_SIZE as f32 {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "watermark_too_large",
+                format!(
+                    "watermark_image is {}x{} pixels, which exceeds {} pixels when scaled",
+                    image.width(),
+                    image.height(),
+                    watermark::MAX_IMAGE_SIZE
+                ),
+            ));
+        }
+    }
+    Ok(Some(watermark))
+}
+
+/// Loads an image parameter given as a URL or a data URI. URLs are fetched
+/// subject to the fetch policy, and the response must be an image.
+async fn load_image_param(param: &str, url: &str) -> Result<Vec<u8>, ApiError> {
+    if fetch::is_data_uri(url) {
+        return fetch::decode_data_uri(param, url, SETTINGS.max_download_bytes);
+    }
+    let stale = match IMAGE_CACHE.get(url) {
+        imagecache::Lookup::Fresh(image) => return Ok(image.bytes.clone()),
+        imagecache::Lookup::Failed(error) => return Err(error),
+        imagecache::Lookup::Stale(image) => Some(image),
+        imagecache::Lookup::Missing => None,
+    };
+    let validators = stale.as_ref().map(|image| image.validators()).unwrap_or_default();
+    let fetched = fetch::fetch_with_headers(url, &SETTINGS.fetch_policy(), &validators)
+        .instrument(tracing::debug_span!("fetch_image", param, revalidating = stale.is_some()))
+        .await
+        .map_err(|e| e.into_api_error(param, url))
+        .and_then(|fetched| {
+            if fetched.not_modified || (fetched.is_image() && image::guess_format(&fetched.bytes).is_ok()) {
+                return Ok(fetched);
+            }
+            Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_image",
+                format!(
+                    "{} `{}` is not an image (Content-Type: {})",
+                    param,
+                    url,
+                    fetched.content_type.as_deref().unwrap_or("unknown")
+                ),
+            ))
+        });
+    let fetched = match fetched {
+        Ok(fetched) => fetched,
+        Err(error) => {
+            IMAGE_CACHE.fail(url, &error);
+            return Err(error);
+        }
+    };
+    match IMAGE_CACHE.insert(url, &fetched, stale.as_deref()) {
+        Some(image) => Ok(image.bytes.clone()),
+        None if fetched.not_modified => Ok(stale.map(|image| image.bytes.clone()).unwrap_or_default()),
+        None => Ok(fetched.bytes),
+    }
+}
+
+fn validate_generate(
+    req: &HttpRequest,
+    version: ApiVersion,
+    info: &query::ConfigQuery,
+    body_unknown: &[String],
+) -> Result<(), ApiError> {
+    check_rate_limit(req)?;
+    check_generate_access(req, req.path(), req.query_string())?;
+    check_strict(req.query_string(), version, body_unknown)?;
+    validate_params(info)
+}
+
+/// The checks of `validate_generate` which only depend on the parameters.
+fn validate_params(info: &query::ConfigQuery) -> Result<(), ApiError> {
+    if info.code.is_empty()
+        && info.code_b64.is_none()
+        && info.code_gz.is_none()
+        && info.code_url.is_none()
+        && info.gist.is_none()
+        && info.github.is_none()
+        && info.snippets.is_none()
+    {
+        return Err(ApiError::bad_request("missing_code", "code parameter is required"));
+    }
+    if let Some(snippets) = &info.snippets {
+        if snippets.is_empty() {
+            return Err(ApiError::bad_request("missing_code", "snippets is empty"));
+        }
+        if snippets.len() > query::MAX_SNIPPETS {
+            return Err(ApiError::bad_request(
+                "too_many_snippets",
+                format!(
+                    "An image may have at most {} snippets, got {}",
+                    query::MAX_SNIPPETS,
+                    snippets.len()
+                ),
+            ));
+        }
+    }
+    if let Some(response) = info.response.as_deref() {
+        response
+            .parse::<api::ResponseMode>()
+            .map_err(|e| ApiError::bad_request("invalid_response", e.to_string()))?;
+    }
+    if info.store == Some(true) && info.r#async == Some(true) {
+        return Err(ApiError::bad_request(
+            "conflicting_parameters",
+            "store and async are mutually exclusive, pass only one of them",
+        ));
+    }
+    if info.store == Some(true) && STORAGE.is_none() && S3.is_none() {
+        return Err(ApiError::bad_request(
+            "storage_disabled",
+            "This server doesn't store images, pass store=false",
+        ));
+    }
+    Ok(())
+}
+
+/// Renders the requested image, or returns it from the first cache holding
+/// it. A render of the same image already in flight is awaited rather than
+/// repeated.
+async fn generate_image(
+    key: String,
+    version: ApiVersion,
+    info: &query::ConfigQuery,
+) -> Result<(Arc<cache::Rendered>, cache::Source), ApiError> {
+    for (i, tier) in CACHES.iter().enumerate() {
+        if let Some(rendered) = cache_get(*tier, &key).await {
+            for faster in &CACHES[..i] {
+                cache_insert(*faster, &key, &rendered);
+            }
+            return Ok((rendered, cache::Source::Cache(tier.name())));
+        }
+    }
+
+    let info = info.clone();
+    let cache_key = key.clone();
+    let (rendered, coalesced) = COALESCER
+        .render(&key, || async move {
+            let rendered = Arc::new(render_image(version, &info).await?);
+            for tier in CACHES.iter() {
+                cache_insert(*tier, &cache_key, &rendered);
+            }
+            Ok(rendered)
+        })
+        .await;
+    let source = if coalesced {
+        cache::Source::Coalesced
+    } else {
+        cache::Source::Rendered
+    };
+    Ok((rendered?, source))
+}
+
+/// The checks of `GET /generate` for a request which only asks about the
+/// image, which has to be one it would respond with.
+async fn prepare_preflight(
+    req: &HttpRequest,
+    version: ApiVersion,
+    info: &mut query::ConfigQuery,
+) -> Result<(), ApiError> {
+    validate_generate(req, version, info, &[])?;
+    defaults::apply(info);
+    let returns_image = info.store != Some(true)
+        && info.r#async != Some(true)
+        && info.response.as_deref().map_or(true, |mode| mode.eq_ignore_ascii_case("binary"));
+    if !returns_image {
+        return Err(ApiError::bad_request(
+            "conflicting_parameters",
+            "Only the size of an image can be asked for, without store, async, or response=json",
+        ));
+    }
+    source::resolve(info, &highlighting_assets().syntax_set).await?;
+    Ok(())
+}
+
+/// The size of the image for `key`, and the image itself when it's in the
+/// memory cache.
+struct ImageSize {
+    width: u32,
+    height: u32,
+    bytes: usize,
+    /// Whether the size is the image's rather than an estimate.
+    exact: bool,
+    rendered: Option<Arc<cache::Rendered>>,
+}
+
+/// The size of the image for `key`: exact when it's in the memory cache,
+/// and otherwise estimated from the layout of `info` without rendering.
+async fn image_size(key: &str, version: ApiVersion, info: &query::ConfigQuery) -> Result<ImageSize, ApiError> {
+    if let Some(rendered) = RENDER_CACHE.get(key) {
+        return Ok(ImageSize {
+            width: rendered.width,
+            height: rendered.height,
+            bytes: rendered.bytes.len(),
+            exact: true,
+            rendered: Some(rendered),
+        });
+    }
+
+    let mut conf = config::Config::default();
+    conf.code = info.code.clone();
+    apply_query(&mut conf, info, version).await?;
+    check_fonts(&conf, info.strict_font.unwrap_or(false))?;
+    let estimated = run_blocking(&RENDERS, move || {
+        let families = conf.title_font();
+        let families: Vec<&str> = families.iter().map(|(name, _)| name.as_str()).collect();
+        if let Cow::Owned(code) = fallback::replace_missing(&conf.code, &families) {
+            conf.code = code;
+        }
+        // The default title depends on the language, which isn't detected
+        // for an estimate, but only whether there's a title changes the size.
+        let ha = highlighting_assets();
+        match conf.window_title.as_deref() {
+            None => conf.window_title = Some(config::default_window_title(ha.syntax_set.find_syntax_plain_text())),
+            Some("") => conf.window_title = None,
+            Some(_) => {}
+        }
+        Ok(render::estimate(&conf)?)
+    })
+    .await?;
+    Ok(ImageSize {
+        width: estimated.width,
+        height: estimated.height,
+        bytes: estimated.bytes,
+        exact: false,
+        rendered: None,
+    })
+}
+
+/// Replaces the notebook in `conf.code` with its code cell at `index`, tidied
+/// like any code, highlighted in the kernel's language unless one was given,
+/// and with the cell's output below it when `include_output` is set.
+fn apply_notebook_cell(conf: &mut config::Config, info: &query::ConfigQuery, index: usize) -> Result<(), ApiError> {
+    let cell = notebook::cell(&conf.code, index).map_err(|e| match e {
+        notebook::Error::Invalid(_) => ApiError::bad_request("invalid_notebook", e.to_string()),
+        notebook::Error::OutOfRange { .. } => ApiError::bad_request("invalid_notebook_cell", e.to_string()),
+        notebook::Error::NotCode { .. } => ApiError::bad_request("not_a_code_cell", e.to_string()),
+    })?;
+    if cell.source.trim().is_empty() {
+        return Err(ApiError::bad_request("missing_code", format!("notebook_cell {} is empty", index)));
+    }
+    if conf.language.is_none() {
+        let ps = &highlighting_assets().syntax_set;
+        conf.language = cell
+            .language
+            .filter(|language| ps.find_syntax_by_token(aliases::resolve(language)).is_some());
+    }
+    conf.code = source::tidy_lines(info, cell.source);
+    if info.include_output == Some(true) {
+        conf.output = cell.output;
+    }
+    Ok(())
+}
+
+/// Looks `key` up in `cache`, on the blocking pool when it blocks.
+async fn cache_get(cache: &'static dyn cache::CacheBackend, key: &str) -> Option<Arc<cache::Rendered>> {
+    if !cache.blocks() {
+        return cache.get(key, "image/png");
+    }
+    let key = key.to_owned();
+    web::block(move || cache.get(&key, "image/png")).await.ok().flatten()
+}
+
+/// Stores `rendered` in `cache`, in the background when it blocks, since
+/// the response doesn't depend on it.
+fn cache_insert(cache: &'static dyn cache::CacheBackend, key: &str, rendered: &Arc<cache::Rendered>) {
+    if !cache.blocks() {
+        cache.insert(key, rendered.clone());
+        return;
+    }
+    let (key, rendered) = (key.to_owned(), rendered.clone());
+    actix_web::rt::spawn(web::block(move || cache.insert(&key, rendered)));
+}
+
+/// Renders the requested image.
+async fn render_image(version: ApiVersion, info: &query::ConfigQuery) -> Result<cache::Rendered, ApiError> {
+    let ha = highlighting_assets();
+
+    let mut conf = config::Config::default();
+    conf.code = info.code.clone();
+
+    conf.detector = language_detector();
+    conf.detection_timeout = SETTINGS.detection_timeout;
+
+    apply_query(&mut conf, info, version).await?;
+
+    let font_fallback = check_fonts(&conf, info.strict_font.unwrap_or(false))?;
+
+    // Redacted code is never embedded, since that would undo the redaction.
+    let document = SETTINGS.embed_params.then(|| {
+        let include_code = !conf.redacts()
+            && (info.embed_code.unwrap_or(false) || info.code_len() <= metadata::MAX_EMBEDDED_CODE);
+        metadata::document(info, include_code)
+    });
+    let fallback_requested = info.fallback_language.is_some();
+    if info.snippets.is_some() {
+        let snippets = snippet_configs(&conf, info)?;
+        return render_snippets(conf, snippets, font_fallback, document, fallback_requested).await;
+    }
+
+    // Language detection, highlighting, formatting, and encoding are all CPU
+    // bound, so they run on the blocking pool.
+    run_render(move |cancellation| {
+        let started = Instant::now();
+        let (ps, ts) = (&ha.syntax_set, &ha.theme_set);
+        let mut conf = conf;
+        let (syntax, detection) = pick_syntax(&conf, ps, fallback_requested)?;
+        cancellation.check()?;
+
+        let families = conf.title_font();
+        let families: Vec<&str> = families.iter().map(|(name, _)| name.as_str()).collect();
+        if let Cow::Owned(code) = fallback::replace_missing(&conf.code, &families) {
+            conf.code = code;
+        }
+        cancellation.check()?;
+
+        // An empty title asks for none, rather than the default.
+        match conf.window_title.as_deref() {
+            None => conf.window_title = Some(config::default_window_title(syntax)),
+            Some("") => conf.window_title = None,
+            Some(_) => {}
+        }
+
+        let theme = conf
+            .theme(ts, SETTINGS.theme_files_dir.as_deref())
+            .map_err(|e| ApiError::bad_request("invalid_theme", e.to_string()))?;
+
+        let (rendered, mut headers) = render_png(&conf, syntax, &theme, ps, cancellation)?;
+        let mut bytes = rendered.bytes;
+        if let Some(document) = &document {
+            bytes = metadata::embed(bytes, document);
+        }
+        headers.push(("X-Language".to_owned(), syntax.name.clone()));
+        if detection != config::Detection::Given {
+            headers.push((
+                "X-Detected-Language".to_owned(),
+                format!("{}; source={}", syntax.name, detection.source()),
+            ));
+        }
+        if let config::Detection::Model { confidence } | config::Detection::Heuristic { confidence } = detection {
+            headers.push(("X-Detection-Confidence".to_owned(), format!("{:.3}", confidence)));
+        }
+        if let config::Detection::Unavailable { reason } = detection {
+            headers.push(("X-Detection-Fallback".to_owned(), reason.to_owned()));
+        }
+        if detection != config::Detection::Given {
+            let fallback_used = matches!(
+                detection,
+                config::Detection::Fallback | config::Detection::Unavailable { .. }
+            );
+            headers.push(("X-Fallback-Language-Used".to_owned(), fallback_used.to_string()));
+        }
+        if let Some(title) = &conf.window_title {
+            let title: String = title.chars().filter(|c| !c.is_control()).collect();
+            headers.push(("X-Window-Title".to_owned(), title));
+        }
+        if let Some(fallback) = font_fallback {
+            headers.push(("X-Font-Fallback".to_owned(), fallback));
+        }
+        if let Some(ranges) = &conf.line_range {
+            let ranges: Vec<String> = ranges
+                .iter()
+                .map(|(start, end)| format!("{}-{}", start, end))
+                .collect();
+            headers.push(("X-Line-Range".to_owned(), ranges.join(";")));
+        }
+
+        let render_ms = started.elapsed().as_millis() as u64;
+        headers.push((
+            "X-Render-Time".to_owned(),
+            format!(
+                "total={}ms, draw={}ms, encode={}ms",
+                render_ms,
+                rendered.draw_time.as_millis(),
+                rendered.encode_time.as_millis()
+            ),
+        ));
+
+        Ok(cache::Rendered {
+            bytes,
+            content_type: "image/png".to_owned(),
+            headers,
+            language: syntax.name.clone(),
+            theme: conf.theme.clone(),
+            window_title: conf.window_title.clone(),
+            width: rendered.width,
+            height: rendered.height,
+            render_ms,
+        })
+    })
+    .await
+}
+
+/// The config of each of `info.snippets`: a copy of `conf`, the image's,
+/// with the snippet's code, language, window title, and highlighted lines.
+/// A language given for the whole image is the default of every snippet.
+fn snippet_configs(conf: &config::Config, info: &query::ConfigQuery) -> Result<Vec<config::Config>, ApiError> {
+    let snippets = info.snippets.as_deref().unwrap_or_default();
+    snippets
+        .iter()
+        .map(|snippet| {
+            let mut snippet_conf = conf.clone();
+            snippet_conf.code = snippet.code.clone();
+            let language = snippet.language.clone().or_else(|| info.language.clone());
+            // The language of a diff is that of the changed code, from its
+            // own file headers.
+            snippet_conf.language = if conf.diff && language.as_deref().map_or(true, diff::is_diff_language) {
+                let language = diff::language(&snippet.code, &highlighting_assets().syntax_set);
+                Some(language.unwrap_or_else(|| "txt".to_owned()))
+            } else {
+                language
+            };
+            snippet_conf.window_title = snippet.window_title.clone();
+            (snippet_conf.highlight_lines, snippet_conf.highlight_colors) = match snippet.highlight_lines.as_deref() {
+                Some(highlight_lines) => {
+                    let (lines, colors) = parse_highlight_lines(highlight_lines, snippet_conf.last_line_number())?;
+                    (Some(lines), colors)
+                }
+                None => (None, vec![]),
+            };
+            Ok(snippet_conf)
+        })
+        .collect()
+}
+
+/// `render_image` for an image of several snippets, whose languages are
+/// detected and titles chosen each on its own.
+async fn render_snippets(
+    conf: config::Config,
+    snippets: Vec<config::Config>,
+    font_fallback: Option<String>,
+    document: Option<String>,
+    fallback_requested: bool,
+) -> Result<cache::Rendered, ApiError> {
+    let ha = highlighting_assets();
+    run_render(move |cancellation| {
+        let started = Instant::now();
+        let (ps, ts) = (&ha.syntax_set, &ha.theme_set);
+        let families = conf.title_font();
+        let families: Vec<&str> = families.iter().map(|(name, _)| name.as_str()).collect();
+        let mut detected = vec![];
+        let mut picked = Vec::with_capacity(snippets.len());
+        for mut snippet in snippets {
+            let (syntax, detection) = pick_syntax(&snippet, ps, fallback_requested)?;
+            cancellation.check()?;
+            if detection != config::Detection::Given {
+                detected.push(format!("{}; source={}", syntax.name, detection.source()));
+            }
+            if let Cow::Owned(code) = fallback::replace_missing(&snippet.code, &families) {
+                snippet.code = code;
+            }
+            match snippet.window_title.as_deref() {
+                None => snippet.window_title = Some(config::default_window_title(syntax)),
+                Some("") => snippet.window_title = None,
+                Some(_) => {}
+            }
+            picked.push((snippet, syntax));
+        }
+
+        let theme = conf
+            .theme(ts, SETTINGS.theme_files_dir.as_deref())
+            .map_err(|e| ApiError::bad_request("invalid_theme", e.to_string()))?;
+        let rendered = render::draw_snippets(&conf, &picked, &theme, ps, &|| cancellation.is_cancelled())?;
+        let mut headers = render_headers(&conf, &rendered);
+        let mut bytes = rendered.bytes;
+        if let Some(document) = &document {
+            bytes = metadata::embed(bytes, document);
+        }
+        headers.push(("X-Language".to_owned(), rendered.language.clone()));
+        if !detected.is_empty() {
+            headers.push(("X-Detected-Language".to_owned(), detected.join(", ")));
+        }
+        if let Some(fallback) = font_fallback {
+            headers.push(("X-Font-Fallback".to_owned(), fallback));
+        }
+
+        let render_ms = started.elapsed().as_millis() as u64;
+        headers.push((
+            "X-Render-Time".to_owned(),
+            format!(
+                "total={}ms, draw={}ms, encode={}ms",
+                render_ms,
+                rendered.draw_time.as_millis(),
+                rendered.encode_time.as_millis()
+            ),
+        ));
+
+        Ok(cache::Rendered {
+            bytes,
+            content_type: "image/png".to_owned(),
+            headers,
+            language: rendered.language,
+            theme: conf.theme.clone(),
+            window_title: None,
+            width: rendered.width,
+            height: rendered.height,
+            render_ms,
+        })
+    })
+    .await
+}
+
+/// Detects the language of `conf` unless it's given. A guess which isn't
+/// confident is replaced by the fallback language when the request asks for
+/// one, or else refused under `strict_language`.
+fn pick_syntax<'a>(
+    conf: &config::Config,
+    ps: &'a SyntaxSet,
+    fallback_requested: bool,
+) -> Result<(&'a SyntaxReference, config::Detection), ApiError> {
+    let detect_span = tracing::debug_span!(
+        "detect_language",
+        lines = conf.code.lines().count(),
+        language = tracing::field::Empty,
+        source = tracing::field::Empty,
+    );
+    let (syntax, detection) = detect_span
+        .in_scope(|| conf.detect_language(ps))
+        .map_err(|e| ApiError::bad_request("unknown_language", e.to_string()))?;
+    detect_span.record("language", syntax.name.as_str());
+    detect_span.record("source", detection.source());
+    let confident = match detection {
+        config::Detection::Model { confidence } | config::Detection::Heuristic { confidence } => {
+            confidence >= SETTINGS.strict_language_threshold
+        }
+        config::Detection::Fallback | config::Detection::Unavailable { .. } => false,
+        _ => true,
+    };
+    // A fallback language the request asks for replaces a guess, rather
+    // than the guess being used or refused.
+    if fallback_requested && !confident {
+        if let config::Detection::Model { .. } | config::Detection::Heuristic { .. } = detection {
+            return Ok((conf.fallback_syntax(ps), config::Detection::Fallback));
+        }
+    } else if conf.strict_language && !confident {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "ambiguous_language",
+            "Unable to detect the language confidently, please pass language explicitly",
+        ));
+    }
+    Ok((syntax, detection))
+}
+
+/// Checks that the families in the font list are installed. Returns the
+/// `X-Font-Fallback` header for those which aren't, naming the font used in
+/// their place, or with `strict` an error.
+fn check_fonts(conf: &config::Config, strict: bool) -> Result<Option<String>, ApiError> {
+    let font = match &conf.font {
+        Some(font) => font,
+        None => return Ok(None),
+    };
+    let (found, missing): (Vec<&str>, Vec<&str>) = font
+        .iter()
+        .map(|(name, _)| name.trim())
+        .partition(|name| families::is_installed(name));
+    if missing.is_empty() {
+        return Ok(None);
+    }
+    if strict {
+        return Err(ApiError::bad_request(
+            "missing_font",
+            format!(
+                "Font(s) not installed: {}. See /fonts for the available families",
+                missing.join(", ")
+            ),
+        ));
+    }
+
+    let fallback = found.first().copied().unwrap_or(config::DEFAULT_FONT);
+    let substitutions: Vec<String> = missing
+        .iter()
+        .map(|name| format!("{}->{}", name, fallback))
+        .collect();
+    Ok(Some(substitutions.join(", ").chars().filter(|c| !c.is_control()).collect()))
+}
+
+/// The width and height in a PNG's header.
+fn png_size(png: &[u8]) -> (u32, u32) {
+    let read = |at: usize| {
+        png.get(at..at + 4)
+            .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    (read(16), read(20))
+}
+
+/// Runs CPU bound work on the blocking thread pool. The number of tasks
+/// running at once is capped by `limiter` so a burst of renders can't
+/// exhaust the pool.
+async fn run_blocking<F, T>(limiter: &'static limiter::Limiter, f: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = limiter.acquire(SETTINGS.queue_timeout).await?;
+    let span = tracing::Span::current();
+    web::block(move || span.in_scope(f))
+        .await
+        .map_err(|_| ApiError::internal("render_failed", "Render task failed"))?
+}
+
+/// Same as `run_blocking` under the render limit, but gives up on the render after
+/// `INKIFY_RENDER_TIMEOUT_MS`. The render is then cancelled at its next
+/// stage, and holds its permit until it stops.
+async fn run_render<F, T>(f: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&Cancellation) -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    let permit = RENDERS.acquire(SETTINGS.queue_timeout).await?;
+    let cancellation = Cancellation::default();
+    let started = Instant::now();
+    let task = web::block({
+        let cancellation = cancellation.clone();
+        let span = tracing::Span::current();
+        move || {
+            let _permit = permit;
+            span.in_scope(|| f(&cancellation))
+        }
+    });
+    match actix_web::rt::time::timeout(SETTINGS.render_timeout, task).await {
+        Ok(result) => result.map_err(|_| ApiError::internal("render_failed", "Render task failed"))?,
+        Err(_) => {
+            cancellation.cancel();
+            metrics::METRICS.render_timeouts.fetch_add(1, Ordering::Relaxed);
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+            tracing::warn!(elapsed_ms, "Render timed out");
+            Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "render_timeout",
+                format!(
+                    "Rendering took longer than {}ms, try a smaller snippet",
+                    SETTINGS.render_timeout.as_millis()
+                ),
+            )
+            .with_detail("elapsed_ms", elapsed_ms))
+        }
+    }
+}
+
+/// Renders `conf` as a PNG with the library's pipeline, checking
+/// `cancellation` between stages. Also returns headers describing how it was
+/// drawn, such as whether the window was scaled down to fit `conf.preset`.
+fn render_png(
+    conf: &config::Config,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    ps: &SyntaxSet,
+    cancellation: &Cancellation,
+) -> Result<(render::RenderedImage, Vec<(String, String)>), ApiError> {
+    let rendered = render::draw(conf, syntax, theme, ps, &|| cancellation.is_cancelled())?;
+    let headers = render_headers(conf, &rendered);
+    Ok((rendered, headers))
+}
+
+/// The headers describing how `rendered` was drawn from `conf`.
+fn render_headers(conf: &config::Config, rendered: &render::RenderedImage) -> Vec<(String, String)> {
+    let mut headers = vec![];
+    if conf.redacts() {
+        headers.push(("X-Redactions".to_owned(), rendered.redactions.to_string()));
+    }
+    if let Some(scale) = rendered.preset_scale {
+        headers.push(("X-Preset-Downscaled".to_owned(), (scale < 1.0).to_string()));
+        headers.push(("X-Preset-Scale".to_owned(), format!("{:.3}", scale)));
+    }
+    if conf.max_output_width.is_some() || conf.max_output_height.is_some() {
+        headers.push((
+            "X-Original-Size".to_owned(),
+            format!("{}x{}", rendered.original_width, rendered.original_height),
+        ));
+        headers.push(("X-Output-Size".to_owned(), format!("{}x{}", rendered.width, rendered.height)));
+    }
+    headers
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct DeriveQuery {
+    /// URL of the image to fetch instead of uploading one.
+    url: Option<String>,
+
+    /// Name for the derived theme.
+    name: Option<String>,
+}
+
+/// Reads the `image` field of a multipart/form-data upload.
+async fn read_image_field(req: &HttpRequest, payload: web::Payload) -> Result<Vec<u8>, ApiError> {
+    let mut multipart = Multipart::new(req.headers(), payload);
+    let mut image = None;
+    while let Some(mut field) = multipart
+        .try_next()
+        .await
+        .map_err(|e| ApiError::bad_request("invalid_multipart", e.to_string()))?
+    {
+        if field.name() != "image" {
+            continue;
+        }
+        let mut buf = vec![];
+        while let Some(chunk) = field
+            .try_next()
+            .await
+            .map_err(|e| ApiError::bad_request("invalid_multipart", e.to_string()))?
+        {
+            extend_image(&mut buf, &chunk)?;
+        }
+        image = Some(buf);
+    }
+    image.ok_or_else(|| ApiError::bad_request("missing_image", "multipart field `image` is required"))
+}
+
+/// Appends `chunk` to an uploaded image, which may be at most
+/// `INKIFY_MAX_DOWNLOAD_BYTES` long like a fetched one.
+fn extend_image(buf: &mut Vec<u8>, chunk: &[u8]) -> Result<(), ApiError> {
+    if buf.len() + chunk.len() > SETTINGS.max_download_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "image_too_large",
+            format!("image exceeds the {} byte limit", SETTINGS.max_download_bytes),
+        ));
+    }
+    buf.extend_from_slice(chunk);
+    Ok(())
+}
+
+#[post("/decode")]
+async fn decode(req: HttpRequest, version: ApiVersion, payload: web::Payload) -> impl Responder {
+    match decode_response(&req, payload).await {
+        Ok(body) => HttpResponse::Ok().json(body),
+        Err(e) => e.to_response(version),
+    }
+}
+
+/// Reads the generation parameters back out of a PNG made by /generate,
+/// uploaded as the raw body or as the `image` field of a multipart upload.
+async fn decode_response(req: &HttpRequest, mut payload: web::Payload) -> Result<serde_json::Value, ApiError> {
+    let bytes = if is_multipart(req) {
+        read_image_field(req, payload).await?
+    } else {
+        let mut buf = vec![];
+        while let Some(chunk) = payload
+            .try_next()
+            .await
+            .map_err(|e| ApiError::bad_request("invalid_body", e.to_string()))?
+        {
+            extend_image(&mut buf, &chunk)?;
+        }
+        buf
+    };
+    if bytes.is_empty() {
+        return Err(ApiError::bad_request(
+            "missing_image",
+            "Upload the PNG as the request body or as a multipart/form-data field named `image`",
+        ));
+    }
+
+    let document = metadata::extract(&bytes, max_body_bytes()).map_err(|e| {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_image", format!("Failed to read image: {}", e))
+    })?;
+    let not_inkify = || {
+        ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "not_inkify_image",
+            "The image has no Inkify parameters: it wasn't generated by Inkify, its metadata was stripped, or the server had embedding disabled",
+        )
+    };
+    serde_json::from_str(&document.ok_or_else(not_inkify)?).map_err(|_| not_inkify())
+}
+
+/// Largest image /themes/derive decodes, in pixels along either side and in
+/// bytes of decoded pixel data, so a small compressed upload can't expand
+/// into gigabytes.
+const MAX_DERIVE_IMAGE_SIZE: u32 = render::MAX_OUTPUT_SIZE;
+const MAX_DERIVE_IMAGE_ALLOC: u64 = 256 * 1024 * 1024;
+
+fn decode_image(bytes: &[u8]) -> Result<image::DynamicImage, ApiError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_image", format!("Failed to decode image: {}", e))
+    };
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_DERIVE_IMAGE_SIZE);
+    limits.max_image_height = Some(MAX_DERIVE_IMAGE_SIZE);
+    limits.max_alloc = Some(MAX_DERIVE_IMAGE_ALLOC);
+
+    let mut reader = image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| invalid(&e))?;
+    reader.limits(limits);
+    reader.decode().map_err(|e| invalid(&e))
+}
+
+#[post("/themes/derive")]
+async fn derive_theme(
+    req: HttpRequest,
+    version: ApiVersion,
+    query: web::Query<DeriveQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+    match derive_theme_response(&req, &query, payload).await {
+        Ok(body) => HttpResponse::Ok().json(body),
+        Err(e) => e.to_response(version),
+    }
+}
+
+async fn derive_theme_response(
+    req: &HttpRequest,
+    query: &DeriveQuery,
+    payload: web::Payload,
+) -> Result<serde_json::Value, ApiError> {
+    check_rate_limit(req)?;
+    check_api_key(req)?;
+
+    let bytes = if is_multipart(req) {
+        read_image_field(req, payload).await?
+    } else if let Some(url) = &query.url {
+        fetch::fetch(url, &SETTINGS.fetch_policy())
+            .await
+            .map_err(|e| e.into_api_error("url", url))?
+            .bytes
+    } else {
+        return Err(ApiError::bad_request(
+            "missing_image",
+            "Upload an `image` as multipart/form-data or pass a `url` parameter",
+        ));
+    };
+
+    let name = query.name.clone().unwrap_or_else(|| derive::DEFAULT_NAME.to_owned());
+    let (derived, preview) = run_blocking(&RENDERS, move || {
+        let image = decode_image(&bytes)?;
+        let derived = derive::derive_theme(&image, &name)
+            .map_err(|e| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "derive_failed", e.to_string()))?;
+
+        let ha = highlighting_assets();
+        let ps = &ha.syntax_set;
+        let mut conf = config::Config::default();
+        conf.code = derive::SAMPLE_CODE.to_owned();
+        conf.window_title = Some(name);
+        let syntax = ps.find_syntax_by_token("rs").unwrap_or_else(|| ps.find_syntax_plain_text());
+        let (preview, _) = render_png(&conf, syntax, &derived.theme, ps, &Cancellation::default())?;
+        Ok((derived, preview.bytes))
+    })
+    .await?;
+
+    Ok(serde_json::json!({
+        "name": derived.name,
+        "dark": derived.dark,
+        "palette": derived.palette,
+        "adjustments": derived.adjustments,
+        "tm_theme": derived.tm_theme,
+        "theme_b64": BASE64.encode(derived.tm_theme.as_bytes()),
+        "preview": BASE64.encode(preview),
+    }))
+}
+
+/// Liveness probe. Responds as soon as the server can serve requests.
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness probe. Fails until the highlighting assets and, when
+/// configured, the language detection model are loaded.
+#[get("/readyz")]
+async fn readyz() -> impl Responder {
+    let ha = highlighting_assets();
+    let reason = if shutdown::begun() {
+        Some("shutting down".to_owned())
+    } else if ha.syntax_set.syntaxes().is_empty() || ha.theme_set.themes.is_empty() {
+        Some("highlighting assets failed to load".to_owned())
+    } else {
+        match &*DETECTOR {
+            Some(Err(e)) => Some(format!("Language detection model failed to load: {}", e)),
+            _ => None,
+        }
+    };
+
+    // A model which failed to warm up only degrades detection, so the
+    // server is still ready.
+    let detection = match &*WARMUP {
+        None => "disabled",
+        Some(Ok(_)) => "ready",
+        Some(Err(_)) => "unavailable",
+    };
+
+    match reason {
+        None => HttpResponse::Ok().json(serde_json::json!({ "status": "ready", "detection": detection })),
+        Some(reason) => HttpResponse::ServiceUnavailable()
+            .json(serde_json::json!({ "status": "unavailable", "reason": reason })),
+    }
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+struct HistoryQuery {
+    /// Only return samples taken after this unix timestamp (seconds).
+    since: Option<u64>,
+}
+
+#[get("/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(&*OPENAPI)
+}
+
+#[get("/docs")]
+async fn docs(version: ApiVersion) -> HttpResponse {
+    if !SETTINGS.swagger_ui {
+        return ApiError::not_found("not_found", "Swagger UI is disabled on this server").to_response(version);
+    }
+    HttpResponse::Ok()
+        .append_header(("Content-Type", "text/html; charset=utf-8"))
+        .body(openapi::DOCS_HTML)
+}
+
+/// The playground page, which only uses relative URLs so it works under any
+/// prefix the routes are mounted at.
+const PLAYGROUND_HTML: &str = include_str!("playground.html");
+
+#[get("/playground")]
+async fn playground(version: ApiVersion) -> HttpResponse {
+    if !SETTINGS.playground {
+        return ApiError::not_found("not_found", "The playground is disabled on this server").to_response(version);
+    }
+    HttpResponse::Ok()
+        .append_header(("Content-Type", "text/html; charset=utf-8"))
+        .body(PLAYGROUND_HTML)
+}
+
+/// Requires whatever `INKIFY_STATS` asks of the stats endpoints.
+fn check_stats_access(req: &HttpRequest) -> Result<(), ApiError> {
+    match SETTINGS.stats {
+        metrics::StatsAccess::Public => Ok(()),
+        metrics::StatsAccess::Admin => check_admin(req),
+        metrics::StatsAccess::Disabled => Err(ApiError::not_found("not_found", "Stats are disabled on this server")),
+    }
+}
+
+/// The usage since startup: requests by route, images served, bytes sent,
+/// how well the memory caches do, and the most requested themes and
+/// languages.
+fn usage_stats() -> serde_json::Value {
+    let usage = &metrics::USAGE;
+    let hits = metrics::METRICS.cache_hits.load(Ordering::Relaxed);
+    let misses = metrics::METRICS.cache_misses.load(Ordering::Relaxed);
+    let top = |list: &toplist::TopList| -> Vec<serde_json::Value> {
+        list.top(metrics::TOP_LIST_LEN)
+            .into_iter()
+            .map(|(name, count)| serde_json::json!({ "name": name, "count": count }))
+            .collect()
+    };
+    serde_json::json!({
+        "uptime_secs": usage.started.elapsed().as_secs(),
+        "requests": metrics::METRICS.requests.load(Ordering::Relaxed),
+        "endpoints": usage
+            .endpoints()
+            .into_iter()
+            .map(|(endpoint, count)| (endpoint, count.into()))
+            .collect::<serde_json::Map<_, _>>(),
+        "images": usage.images.load(Ordering::Relaxed),
+        "bytes_served": usage.bytes_served.load(Ordering::Relaxed),
+        "cache_hit_rate": if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 },
+        "cache_memory_bytes": {
+            "renders": metrics::METRICS.memory_bytes.load(Ordering::Relaxed),
+            "images": IMAGE_CACHE.stats().bytes,
+        },
+        "top_themes": top(&usage.themes),
+        "top_languages": top(&usage.languages),
+    })
+}
+
+#[get("/stats")]
+async fn stats(req: HttpRequest, version: ApiVersion) -> HttpResponse {
+    if let Err(e) = check_stats_access(&req) {
+        return e.to_response(version);
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "usage": usage_stats(),
+        "renders": RENDERS.stats(),
+        "detections": DETECTIONS.stats(),
+        "render_timeouts": metrics::METRICS.render_timeouts.load(Ordering::Relaxed),
+        "disk_cache": DISK_CACHE.as_ref().map(|_| serde_json::json!({
+            "hits": metrics::METRICS.disk_cache_hits.load(Ordering::Relaxed),
+            "misses": metrics::METRICS.disk_cache_misses.load(Ordering::Relaxed),
+            "bytes": metrics::METRICS.disk_cache_bytes.load(Ordering::Relaxed),
+        })),
+        "image_cache": IMAGE_CACHE.stats(),
+        "redis": REDIS_CACHE.as_ref().map(|_| serde_json::json!({
+            "hits": metrics::METRICS.redis_hits.load(Ordering::Relaxed),
+            "misses": metrics::METRICS.redis_misses.load(Ordering::Relaxed),
+            "errors": metrics::METRICS.redis_errors.load(Ordering::Relaxed),
+        })),
+    }))
+}
+
+#[get("/stats/history")]
+async fn stats_history(req: HttpRequest, version: ApiVersion, query: web::Query<HistoryQuery>) -> HttpResponse {
+    if let Err(e) = check_stats_access(&req) {
+        return e.to_response(version);
+    }
+    HttpResponse::Ok().json(HISTORY.series(query.since))
+}
+
+/// Reloads the syntaxes and themes from the configured directories, swapping
+/// them in once they've loaded. Renders already running finish with the
+/// ones they started with, and a directory which can't be read leaves the
+/// loaded ones in place. Language aliases aren't reloaded.
+#[post("/admin/reload")]
+async fn admin_reload(req: HttpRequest, version: ApiVersion) -> HttpResponse {
+    if let Err(e) = check_admin(&req) {
+        return e.to_response(version);
+    }
+    let started = Instant::now();
+    let loaded = match web::block(load_assets).await {
+        Ok(Ok(loaded)) => loaded,
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "Failed to reload highlighting assets");
+            return ApiError::internal("reload_failed", e.to_string()).to_response(version);
+        }
+        Err(_) => return ApiError::internal("reload_failed", "Reload task failed").to_response(version),
+    };
+    let (themes, syntaxes) = (loaded.theme_set.themes.len(), loaded.syntax_set.syntaxes().len());
+    *HIGHLIGHTING_ASSETS.write().unwrap() = Arc::new(loaded);
+    let elapsed = started.elapsed();
+    tracing::info!(themes, syntaxes, ?elapsed, "Reloaded highlighting assets");
+    HttpResponse::Ok().append_header(("Cache-Control", "no-store")).json(serde_json::json!({
+        "themes": themes,
+        "syntaxes": syntaxes,
+        "elapsed_ms": elapsed.as_millis() as u64,
+    }))
+}
+
+/// Empties the in-memory caches of rendered and fetched images. The disk
+/// and Redis caches are left alone.
+#[post("/admin/cache/flush")]
+async fn admin_cache_flush(req: HttpRequest, version: ApiVersion) -> HttpResponse {
+    if let Err(e) = check_admin(&req) {
+        return e.to_response(version);
+    }
+    let renders = RENDER_CACHE.clear();
+    let images = IMAGE_CACHE.clear();
+    tracing::info!(renders, images, "Flushed caches");
+    HttpResponse::Ok().append_header(("Cache-Control", "no-store")).json(serde_json::json!({
+        "renders": renders,
+        "images": images,
+    }))
+}
+
+/// Samples the metrics into the history on a fixed interval.
+fn spawn_history_sampler() {
+    actix_web::rt::spawn(async {
+        let interval = HISTORY.interval;
+        let mut sampler = metrics::Sampler::new();
+        let mut ticker = actix_web::rt::time::interval(interval);
+        // The first tick completes immediately.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            HISTORY.push(sampler.sample(interval));
+        }
+    });
+}
+
+/// Registers every route. Called once per mounted API version; the version
+/// itself reaches the handlers through the request extensions.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(help)
+        .service(healthz)
+        .service(readyz)
+        .service(themes)
+        .service(theme_comparison)
+        .service(theme_preview)
+        .service(derive_theme)
+        .service(decode)
+        .service(languages)
+        .service(fonts)
+        .service(detect)
+        .service(generate)
+        .service(generate_head)
+        .service(estimate)
+        .service(generate_post)
+        .service(generate_batch)
+        .service(stored_image)
+        .service(job_status)
+        .service(job_result)
+        .service(stats)
+        .service(stats_history)
+        .service(admin_reload)
+        .service(admin_cache_flush)
+        .service(openapi_json)
+        .service(docs)
+        .service(playground)
+        .service(oembed);
+}
+
+/// Checks the `INKIFY_DEFAULT_*` parameters the way a request's are, first
+/// one by one, so the error names the variable, then together, so a bad
+/// default stops startup rather than failing every render.
+async fn validate_defaults() {
+    let defaults = defaults::active();
+    let mut checks: Vec<(String, serde_json::Map<String, serde_json::Value>)> = defaults
+        .iter()
+        .map(|(name, value)| (defaults::variable(name), std::iter::once((name.clone(), value.clone())).collect()))
+        .collect();
+    if defaults.len() > 1 {
+        checks.push((defaults::variable("*"), defaults.clone()));
+    }
+    for (variable, params) in checks {
+        let info: query::ConfigQuery = serde_json::from_value(serde_json::Value::Object(params))
+            .unwrap_or_else(|e| settings::fail(&variable, e));
+        if let Err(e) = check_defaults(&info).await {
+            settings::fail::<()>(&variable, e);
+        }
+    }
+}
+
+async fn check_defaults(info: &query::ConfigQuery) -> Result<(), ApiError> {
+    let ha = highlighting_assets();
+    let mut conf = config::Config::default();
+    apply_query(&mut conf, info, ApiVersion::V1).await?;
+    conf.theme(&ha.theme_set, SETTINGS.theme_files_dir.as_deref())
+        .map_err(|e| ApiError::bad_request("invalid_theme", e.to_string()))?;
+    if let Some(language) = info.language.as_deref() {
+        config::find_language(&ha.syntax_set, language)
+            .map_err(|e| ApiError::bad_request("unknown_language", e.to_string()))?;
+    }
+    check_fonts(&conf, true)?;
+    Ok(())
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    lazy_static::initialize(&ARGS);
+    if let Some(url) = &ARGS.sign_url {
+        let secret = SETTINGS
+            .signing_secret
+            .as_deref()
+            .unwrap_or_else(|| settings::fail("INKIFY_SIGNING_SECRET", "required by --sign-url"));
+        let expires = ARGS.sign_expires_in.map(|secs| {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            now.as_secs() + secs
+        });
+        println!("{}", signing::sign_url(secret, url, expires));
+        return Ok(());
+    }
+    let bind = Bind::resolve();
+    logging::init(SETTINGS.log_json);
+
+    // Fonts are looked up lazily, so this has to happen before the first
+    // render or /fonts request.
+    let fonts_dir = ARGS.fonts_dir.clone().or_else(|| settings::env_string("INKIFY_FONTS_DIR"));
+    if let Some(dir) = fonts_dir {
+        let count = fontdir::install(Path::new(&dir)).unwrap_or_else(|e| settings::fail("INKIFY_FONTS_DIR", e));
+        tracing::info!(count, dir = %dir, "Installed custom fonts");
+    }
+    // Logs the fallback fonts which aren't installed.
+    fallback::installed();
+
+    // Load the assets and model up front, rather than on the first request.
+    let ha = highlighting_assets();
+    let aliases_path = ARGS
+        .language_aliases
+        .clone()
+        .or_else(|| settings::env_string("INKIFY_LANGUAGE_ALIASES"));
+    let aliases = aliases::load(aliases_path.as_deref().map(Path::new), &ha.syntax_set)
+        .unwrap_or_else(|e| settings::fail("INKIFY_LANGUAGE_ALIASES", e));
+    aliases::set(aliases);
+    if let Some(language) = &SETTINGS.fallback_language {
+        config::find_language(&ha.syntax_set, language)
+            .unwrap_or_else(|e| settings::fail("INKIFY_FALLBACK_LANGUAGE", e));
+    }
+    validate_defaults().await;
+    lazy_static::initialize(&DETECTOR);
+    lazy_static::initialize(&WARMUP);
+    lazy_static::initialize(&WATERMARK_IMAGE);
+    if let Some(disk) = &*DISK_CACHE {
+        disk.spawn_sweeper();
+    }
+    lazy_static::initialize(&CACHES);
+    if let Some(storage) = &*STORAGE {
+        storage.spawn_sweeper();
+    }
+    lazy_static::initialize(&S3);
+    lazy_static::initialize(&metrics::USAGE);
+    spawn_history_sampler();
+
+    let tls = match (&ARGS.tls_cert, &ARGS.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load(cert, key)),
+        _ => None,
+    };
+
+    let server = HttpServer::new(|| {
+        let mut legacy_headers = middleware::DefaultHeaders::new();
+        if let Some(sunset) = &SETTINGS.legacy_sunset {
+            legacy_headers = legacy_headers.add(("Sunset", sunset.as_str()));
+        }
+
+        App::new()
+            // Compress skips images, whose formats are compressed already,
+            // except SVG.
+            .wrap(middleware::Condition::new(SETTINGS.compression, middleware::Compress::default()))
+            .wrap_fn(|req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    weaken_encoded_etag(res.headers_mut());
+                    Ok(res)
+                }
+            })
+            .wrap(middleware::Condition::new(
+                !SETTINGS.cors_origins.is_empty(),
+                cors::middleware(&SETTINGS.cors_origins),
+            ))
+            .wrap_fn(|req, srv| {
+                let start = Instant::now();
+                let method = req.method().clone();
+                let path = req.path().to_owned();
+                let ids = requestid::RequestIds::from_request(&req);
+                let span = tracing::info_span!(
+                    "request",
+                    request_id = %ids.request_id,
+                    trace_id = ids.trace_id.as_deref(),
+                    parent_span_id = ids.parent_span_id.as_deref(),
+                    method = %method,
+                    status = tracing::field::Empty,
+                    size = tracing::field::Empty,
+                    language = tracing::field::Empty,
+                    theme = tracing::field::Empty,
+                );
+                #[cfg(feature = "otel")]
+                telemetry::set_parent(&span, req.headers());
+                let fut = span.in_scope(|| srv.call(req));
+                let request_id = ids.request_id;
+                requestid::scope(request_id.clone(), async move {
+                    let mut res = fut.await?;
+                    let latency = start.elapsed();
+                    if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&request_id) {
+                        res.headers_mut().insert(requestid::HEADER, value);
+                    }
+                    metrics::METRICS.record_request(res.status(), latency);
+                    metrics::USAGE.record_response(&res);
+                    logging::record_response(&tracing::Span::current(), &res);
+                    logging::log_request(&method, &path, &res, latency);
+                    Ok(res)
+                }
+                .instrument(span))
+            })
+            // The /v1 scope has to be registered first, since the legacy
+            // scope is mounted at the root and would otherwise swallow it.
+            .service(
+                web::scope("/v1")
+                    .wrap_fn(|req, srv| {
+                        req.extensions_mut().insert(ApiVersion::V1);
+                        srv.call(req)
+                    })
+                    .app_data(web::QueryConfig::default().error_handler(api::v1_query_error))
+                    .configure(routes)
+                    .default_service(web::to(api::v1_not_found)),
+            )
+            .service(
+                web::scope("")
+                    .wrap(legacy_headers)
+                    .wrap_fn(|req, srv| {
+                        req.extensions_mut().insert(ApiVersion::Legacy);
+                        srv.call(req)
+                    })
+                    .configure(routes),
+            )
+    })
+    .workers(bind.workers)
+    .worker_max_blocking_threads(bind.max_blocking_threads)
+    .disable_signals()
+    .shutdown_timeout(bind.shutdown_timeout.as_secs());
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let mut server = server;
+    #[cfg(unix)]
+    if let Some(path) = &bind.unix_socket {
+        uds::remove_stale(path).unwrap_or_else(|e| settings::fail("--unix-socket", e));
+        server = server.bind_uds(path)?;
+        uds::set_mode(path, bind.unix_socket_mode)?;
+    }
+    if bind.unix_socket.is_none() {
+        server = match tls {
+            Some(config) => server.bind_rustls_021((bind.host.as_str(), bind.port), config)?,
+            None => server.bind((bind.host.as_str(), bind.port))?,
+        };
+    }
+    let server = server.run();
+    let redirect = match ARGS.tls_redirect_http_port {
+        Some(port) => Some(tls::redirect_server(&bind.host, port, bind.port)?),
+        None => None,
+    };
+
+    let mut handles = vec![server.handle()];
+    handles.extend(redirect.as_ref().map(|redirect| redirect.handle()));
+    shutdown::spawn(handles, bind.shutdown_timeout);
+
+    match &bind.unix_socket {
+        Some(path) => println!("Inkify listening on unix:{}", path.display()),
+        None => println!("Inkify listening on {}://{}:{}", scheme, bind.host, bind.port),
+    }
+    if let Some(port) = ARGS.tls_redirect_http_port {
+        println!("Redirecting http://{}:{} to HTTPS.", bind.host, port);
+    }
+    println!(
+        "Using {} workers, each with up to {} blocking threads.",
+        bind.workers, bind.max_blocking_threads
+    );
+    if bind.unix_socket.is_none() {
+        println!("Visit {}://{}:{}/ to get started.", scheme, bind.host, bind.port);
+    }
+    let result = match redirect {
+        Some(redirect) => futures_util::future::try_join(server, redirect).await.map(|_| ()),
+        None => server.await,
+    };
+    if let Some(path) = &bind.unix_socket {
+        uds::remove(path);
+    }
+    #[cfg(feature = "otel")]
+    telemetry::shutdown();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_ranges_are_expanded() {
+        assert_eq!(parse_line_range("1-3;7", 10).unwrap(), vec![1, 2, 3, 7]);
+        assert!(parse_line_range("1-x", 10).is_err());
+    }
+
+    #[test]
+    fn highlight_ranges_stop_at_the_last_line() {
+        let (lines, colors) = parse_highlight_lines("2-4294967295;1:#ff0000", 4).unwrap();
+        assert_eq!(lines, vec![2, 3, 4]);
+        assert_eq!(colors.iter().map(|(line, _)| *line).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn redact_ranges_stop_at_the_last_line() {
+        let mut conf = config::Config::default();
+        conf.code = "let a = 1;\nlet b = 2;\n".to_owned();
+        assert_eq!(parse_line_range("1-4294967295", conf.last_line_number()).unwrap(), vec![1, 2]);
+
+        conf.line_offset = 10;
+        assert_eq!(parse_line_range("1-4294967295", conf.last_line_number()).unwrap().len(), 11);
+    }
 }
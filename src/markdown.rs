@@ -0,0 +1,89 @@
+//! Fenced code blocks in Markdown, for the `markdown` parameter, so a whole
+//! message can be sent and only the code in it rendered.
+//!
+//! Fences are found as CommonMark describes them, of three or more
+//! backticks or tildes, closed by a fence of the same character at least as
+//! long, or by the end of the text. Fences indented inside list items are
+//! found too, and the indentation of the opening fence is removed from the
+//! lines of the block.
+
+/// A fenced code block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// The info string after the opening fence, eg. `rust,ignore`.
+    pub info: String,
+    pub code: String,
+}
+
+impl CodeBlock {
+    /// The language the info string names, eg. `rust` for `rust,ignore` or
+    /// `{.rust}`.
+    pub fn language(&self) -> Option<&str> {
+        self.info
+            .split(|c: char| c.is_whitespace() || c == ',' || c == '{' || c == '}')
+            .map(|word| word.trim_start_matches('.'))
+            .find(|word| !word.is_empty())
+    }
+}
+
+/// An opening fence: its character, length, and indentation.
+struct Fence {
+    marker: char,
+    len: usize,
+    indent: usize,
+}
+
+/// The fenced code blocks of `text`, in order.
+pub fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    let mut open: Option<(Fence, CodeBlock)> = None;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        match open.take() {
+            Some((fence, mut block)) => {
+                if closes(content, &fence) {
+                    blocks.push(block);
+                } else {
+                    block.code.push_str(unindent(line, fence.indent));
+                    open = Some((fence, block));
+                }
+            }
+            None => open = opening_fence(content),
+        }
+    }
+    // An unclosed block runs to the end of the text.
+    blocks.extend(open.map(|(_, block)| block));
+    blocks
+}
+
+/// The fence `line` opens, if it's one, with an empty block.
+fn opening_fence(line: &str) -> Option<(Fence, CodeBlock)> {
+    let rest = line.trim_start_matches([' ', '\t']);
+    let indent = line.len() - rest.len();
+    let marker = rest.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = rest.len() - rest.trim_start_matches(marker).len();
+    let info = rest[len..].trim();
+    // A backtick fence's info string can't contain backticks, or it's
+    // inline code.
+    if len < 3 || (marker == '`' && info.contains('`')) {
+        return None;
+    }
+    let fence = Fence { marker, len, indent };
+    let block = CodeBlock {
+        info: info.to_owned(),
+        code: String::new(),
+    };
+    Some((fence, block))
+}
+
+fn closes(line: &str, fence: &Fence) -> bool {
+    let rest = line.trim_start_matches([' ', '\t']);
+    let len = rest.len() - rest.trim_start_matches(fence.marker).len();
+    len >= fence.len && rest[len..].trim().is_empty()
+}
+
+/// Removes up to `indent` columns of leading whitespace from `line`.
+fn unindent(line: &str, indent: usize) -> &str {
+    let whitespace = line.len() - line.trim_start_matches([' ', '\t']).len();
+    &line[whitespace.min(indent)..]
+}
@@ -0,0 +1,127 @@
+//! Generation parameters embedded in the PNGs, so an image can be turned
+//! back into the request which produced it with /decode.
+//!
+//! The parameters are stored as JSON in a compressed `zTXt` chunk right
+//! after the header, alongside a `Software` `tEXt` chunk naming Inkify.
+
+use anyhow::Error;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use std::io::{Read, Write};
+
+use crate::query::ConfigQuery;
+
+/// Keyword of the chunk holding the parameters.
+const KEYWORD: &str = "inkify";
+
+const SOFTWARE: &str = "Inkify";
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Code larger than this, in bytes, is left out of the parameters unless
+/// `embed_code` is passed.
+pub const MAX_EMBEDDED_CODE: usize = 4096;
+
+/// Parameters which only affect how the image is returned.
+const SKIPPED: &[&str] = &["response", "embed_code", "store", "async", "download", "filename"];
+
+/// The JSON document embedded for `info`, the resolved request. Unset
+/// parameters are dropped, and so is the code, and that of any snippets,
+/// unless `include_code`.
+pub fn document(info: &ConfigQuery, include_code: bool) -> String {
+    let mut params = serde_json::to_value(info).unwrap_or_default();
+    if let Some(params) = params.as_object_mut() {
+        params.retain(|name, value| !value.is_null() && !SKIPPED.contains(&name.as_str()));
+        if !include_code {
+            params.remove("code");
+            let snippets = params.get_mut("snippets").and_then(|snippets| snippets.as_array_mut());
+            for snippet in snippets.into_iter().flatten() {
+                if let Some(snippet) = snippet.as_object_mut() {
+                    snippet.remove("code");
+                }
+            }
+        }
+    }
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "code_included": include_code,
+        "params": params,
+    })
+    .to_string()
+}
+
+/// Inserts `document` into `png` after its header. A buffer which isn't a
+/// PNG is returned as is.
+pub fn embed(png: Vec<u8>, document: &str) -> Vec<u8> {
+    // The signature followed by the IHDR chunk, which must come first.
+    let header_end = SIGNATURE.len() + 8 + 13 + 4;
+    if !png.starts_with(SIGNATURE) || png.len() < header_end {
+        return png;
+    }
+
+    let mut compressed = ZlibEncoder::new(vec![], Compression::default());
+    compressed.write_all(document.as_bytes()).ok();
+    let compressed = compressed.finish().unwrap_or_default();
+    let mut ztxt = Vec::with_capacity(KEYWORD.len() + 2 + compressed.len());
+    ztxt.extend_from_slice(KEYWORD.as_bytes());
+    ztxt.extend_from_slice(&[0, 0]);
+    ztxt.extend_from_slice(&compressed);
+    let mut text = b"Software\0".to_vec();
+    text.extend_from_slice(SOFTWARE.as_bytes());
+
+    let mut out = Vec::with_capacity(png.len() + ztxt.len() + text.len() + 24);
+    out.extend_from_slice(&png[..header_end]);
+    write_chunk(&mut out, b"tEXt", &text);
+    write_chunk(&mut out, b"zTXt", &ztxt);
+    out.extend_from_slice(&png[header_end..]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// Finds the embedded document in `png`. Returns `None` when there's none,
+/// and an error when `png` isn't a PNG. The document is decompressed to at
+/// most `max_bytes`.
+pub fn extract(png: &[u8], max_bytes: usize) -> Result<Option<String>, Error> {
+    if !png.starts_with(SIGNATURE) {
+        return Err(format_err!("Not a PNG image"));
+    }
+    let mut at = SIGNATURE.len();
+    while let Some(header) = png.get(at..at + 8) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..8];
+        let data = png
+            .get(at + 8..at + 8 + len)
+            .ok_or_else(|| format_err!("Truncated PNG image"))?;
+        if kind == b"IEND" {
+            break;
+        }
+        let keyword_end = data.iter().position(|&b| b == 0);
+        if let Some(end) = keyword_end.filter(|&end| data[..end] == *KEYWORD.as_bytes()) {
+            match kind {
+                b"tEXt" => return Ok(Some(String::from_utf8(data[end + 1..].to_vec())?)),
+                // The byte after the keyword is the compression method,
+                // where 0 (zlib) is the only one defined.
+                b"zTXt" if data.get(end + 1) == Some(&0) => {
+                    let mut text = vec![];
+                    ZlibDecoder::new(&data[end + 2..])
+                        .take(max_bytes as u64)
+                        .read_to_end(&mut text)?;
+                    return Ok(Some(String::from_utf8(text)?));
+                }
+                _ => {}
+            }
+        }
+        at += 8 + len + 4;
+    }
+    Ok(None)
+}
@@ -0,0 +1,313 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use anyhow::Error;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::logging::LogFields;
+use crate::toplist::TopList;
+
+/// Latencies kept per sampling interval for the p95 computation. Requests
+/// beyond this within one interval are counted but their latency is dropped.
+const MAX_LATENCIES_PER_INTERVAL: usize = 10_000;
+
+/// How many themes and languages are counted for the top lists, of which
+/// the top `TOP_LIST_LEN` are reported.
+const TOP_LIST_CAPACITY: usize = 100;
+pub const TOP_LIST_LEN: usize = 10;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+    pub static ref USAGE: Usage = Usage::default();
+}
+
+/// Process-wide counters and gauges, updated from the handlers and
+/// middleware. Counters are monotonic; rates are derived by the sampler.
+#[derive(Default)]
+pub struct Metrics {
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub memory_bytes: AtomicU64,
+    pub render_timeouts: AtomicU64,
+    pub disk_cache_hits: AtomicU64,
+    pub disk_cache_misses: AtomicU64,
+    pub disk_cache_bytes: AtomicU64,
+    pub redis_hits: AtomicU64,
+    pub redis_misses: AtomicU64,
+    pub redis_errors: AtomicU64,
+    latencies: Mutex<Vec<u32>>,
+}
+
+impl Metrics {
+    pub fn record_request(&self, status: StatusCode, latency: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_client_error() || status.is_server_error() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.len() < MAX_LATENCIES_PER_INTERVAL {
+            latencies.push(latency.as_millis().min(u32::MAX as u128) as u32);
+        }
+    }
+
+    fn take_latencies(&self) -> Vec<u32> {
+        std::mem::take(&mut *self.latencies.lock().unwrap())
+    }
+}
+
+/// Who may see `/stats` and `/stats/history`, from `INKIFY_STATS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatsAccess {
+    #[default]
+    Public,
+    Admin,
+    Disabled,
+}
+
+impl StatsAccess {
+    pub const NAMES: &'static [&'static str] = &["public", "admin", "disabled"];
+}
+
+impl std::str::FromStr for StatsAccess {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "public" => Ok(StatsAccess::Public),
+            "admin" => Ok(StatsAccess::Admin),
+            "disabled" => Ok(StatsAccess::Disabled),
+            _ => Err(Error::msg(format!(
+                "Invalid stats access `{}`, expected one of: {}",
+                s,
+                StatsAccess::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+/// What the server has been used for since startup: requests by route,
+/// images served and bytes sent, and the themes and languages most asked
+/// for.
+pub struct Usage {
+    pub started: Instant,
+
+    /// Requests by route pattern, the same for the legacy and `/v1` routes.
+    /// Patterns are a fixed set, so this doesn't grow with traffic.
+    endpoints: RwLock<HashMap<String, AtomicU64>>,
+    pub images: AtomicU64,
+    pub bytes_served: AtomicU64,
+    pub themes: TopList,
+    pub languages: TopList,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Usage {
+            started: Instant::now(),
+            endpoints: RwLock::default(),
+            images: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            themes: TopList::new(TOP_LIST_CAPACITY),
+            languages: TopList::new(TOP_LIST_CAPACITY),
+        }
+    }
+}
+
+impl Usage {
+    /// Counts a completed request. Responses with an image carry the theme
+    /// and language it was rendered with, in their `LogFields`.
+    pub fn record_response<B: MessageBody>(&self, res: &ServiceResponse<B>) {
+        let endpoint = match res.request().match_pattern() {
+            Some(pattern) => pattern.strip_prefix("/v1").map(str::to_owned).unwrap_or(pattern),
+            None => "unmatched".to_owned(),
+        };
+        let counted = self
+            .endpoints
+            .read()
+            .unwrap()
+            .get(&endpoint)
+            .map(|count| count.fetch_add(1, Ordering::Relaxed))
+            .is_some();
+        if !counted {
+            self.endpoints
+                .write()
+                .unwrap()
+                .entry(endpoint)
+                .or_default()
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let BodySize::Sized(size) = res.response().body().size() {
+            self.bytes_served.fetch_add(size, Ordering::Relaxed);
+        }
+        if !res.status().is_success() {
+            return;
+        }
+        if let Some(fields) = res.response().extensions().get::<LogFields>() {
+            self.images.fetch_add(1, Ordering::Relaxed);
+            if let Some(theme) = &fields.theme {
+                self.themes.record(theme);
+            }
+            if let Some(language) = &fields.language {
+                self.languages.record(language);
+            }
+        }
+    }
+
+    /// Requests by route pattern.
+    pub fn endpoints(&self) -> Vec<(String, u64)> {
+        self.endpoints
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, count)| (endpoint.clone(), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub timestamp: u64,
+    pub request_rate: f32,
+    pub error_rate: f32,
+    pub p95_latency_ms: u32,
+    pub queue_depth: u64,
+    pub cache_hit_ratio: f32,
+    pub memory_bytes: u64,
+    pub render_timeouts: u64,
+}
+
+/// Fixed-size ring buffer of samples. A sample is 48 bytes, so the default
+/// of 360 samples (one hour at 10 second resolution) stays under 20KB.
+pub struct History {
+    pub interval: Duration,
+    capacity: usize,
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryResponse {
+    pub interval_secs: u64,
+    pub capacity: usize,
+    pub timestamps: Vec<u64>,
+    pub request_rate: Vec<f32>,
+    pub error_rate: Vec<f32>,
+    pub p95_latency_ms: Vec<u32>,
+    pub queue_depth: Vec<u64>,
+    pub cache_hit_ratio: Vec<f32>,
+    pub memory_bytes: Vec<u64>,
+    pub render_timeouts: Vec<u64>,
+}
+
+impl History {
+    pub fn new(interval: Duration, capacity: usize) -> Self {
+        History {
+            interval,
+            capacity: capacity.max(1),
+            samples: Mutex::new(VecDeque::with_capacity(capacity.max(1))),
+        }
+    }
+
+    pub fn push(&self, sample: Sample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Returns the samples taken strictly after `since` (a unix timestamp in
+    /// seconds), as one array per series.
+    pub fn series(&self, since: Option<u64>) -> HistoryResponse {
+        let samples = self.samples.lock().unwrap();
+        let selected: Vec<&Sample> = samples
+            .iter()
+            .filter(|s| since.map(|since| s.timestamp > since).unwrap_or(true))
+            .collect();
+        HistoryResponse {
+            interval_secs: self.interval.as_secs(),
+            capacity: self.capacity,
+            timestamps: selected.iter().map(|s| s.timestamp).collect(),
+            request_rate: selected.iter().map(|s| s.request_rate).collect(),
+            error_rate: selected.iter().map(|s| s.error_rate).collect(),
+            p95_latency_ms: selected.iter().map(|s| s.p95_latency_ms).collect(),
+            queue_depth: selected.iter().map(|s| s.queue_depth).collect(),
+            cache_hit_ratio: selected.iter().map(|s| s.cache_hit_ratio).collect(),
+            memory_bytes: selected.iter().map(|s| s.memory_bytes).collect(),
+            render_timeouts: selected.iter().map(|s| s.render_timeouts).collect(),
+        }
+    }
+}
+
+/// Computes interval rates from the monotonic counters.
+pub struct Sampler {
+    last_requests: u64,
+    last_errors: u64,
+    last_hits: u64,
+    last_misses: u64,
+    last_render_timeouts: u64,
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+        Sampler {
+            last_requests: METRICS.requests.load(Ordering::Relaxed),
+            last_errors: METRICS.errors.load(Ordering::Relaxed),
+            last_hits: METRICS.cache_hits.load(Ordering::Relaxed),
+            last_misses: METRICS.cache_misses.load(Ordering::Relaxed),
+            last_render_timeouts: METRICS.render_timeouts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn sample(&mut self, interval: Duration) -> Sample {
+        let requests = METRICS.requests.load(Ordering::Relaxed);
+        let errors = METRICS.errors.load(Ordering::Relaxed);
+        let hits = METRICS.cache_hits.load(Ordering::Relaxed);
+        let misses = METRICS.cache_misses.load(Ordering::Relaxed);
+        let render_timeouts = METRICS.render_timeouts.load(Ordering::Relaxed);
+
+        let d_requests = requests - self.last_requests;
+        let d_errors = errors - self.last_errors;
+        let d_hits = hits - self.last_hits;
+        let d_misses = misses - self.last_misses;
+        self.last_requests = requests;
+        self.last_errors = errors;
+        self.last_hits = hits;
+        self.last_misses = misses;
+        let d_render_timeouts = render_timeouts - self.last_render_timeouts;
+        self.last_render_timeouts = render_timeouts;
+
+        let mut latencies = METRICS.take_latencies();
+        latencies.sort_unstable();
+        let p95_latency_ms = if latencies.is_empty() {
+            0
+        } else {
+            latencies[((latencies.len() - 1) as f32 * 0.95).round() as usize]
+        };
+
+        let secs = interval.as_secs_f32().max(f32::EPSILON);
+        Sample {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            request_rate: d_requests as f32 / secs,
+            error_rate: if d_requests == 0 { 0.0 } else { d_errors as f32 / d_requests as f32 },
+            p95_latency_ms,
+            queue_depth: crate::RENDERS.queued(),
+            cache_hit_ratio: if d_hits + d_misses == 0 {
+                0.0
+            } else {
+                d_hits as f32 / (d_hits + d_misses) as f32
+            },
+            memory_bytes: METRICS.memory_bytes.load(Ordering::Relaxed),
+            render_timeouts: d_render_timeouts,
+        }
+    }
+}
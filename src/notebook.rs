@@ -0,0 +1,180 @@
+//! Cells of Jupyter notebooks, for the `notebook_cell` parameter, so an
+//! `.ipynb` file can be rendered a cell at a time. Notebooks in the current
+//! format (nbformat 4) are read, with the language of the notebook's kernel.
+
+use serde::Deserialize;
+
+use crate::ansi;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid notebook: {0}")]
+    Invalid(#[from] serde_json::Error),
+
+    #[error("notebook_cell {index} is out of range, the notebook has {count} cells")]
+    OutOfRange { index: usize, count: usize },
+
+    /// The cell isn't a code cell. `code_cells` are the indices of those
+    /// which are.
+    #[error("notebook_cell {index} is a {kind} cell, not a code cell{}", hint(.code_cells))]
+    NotCode {
+        index: usize,
+        kind: String,
+        code_cells: Vec<usize>,
+    },
+}
+
+fn hint(code_cells: &[usize]) -> String {
+    if code_cells.is_empty() {
+        return ", and the notebook has none".to_owned();
+    }
+    let shown: Vec<String> = code_cells.iter().take(10).map(|index| index.to_string()).collect();
+    let more = if code_cells.len() > shown.len() { ", ..." } else { "" };
+    format!(", code cells are {}{}", shown.join(", "), more)
+}
+
+/// A code cell, with the language of the notebook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub source: String,
+    /// The text the cell printed and evaluated to, when it has any.
+    pub output: Option<String>,
+    /// The name of the kernel's language, eg. `python`.
+    pub language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Notebook {
+    cells: Vec<RawCell>,
+    #[serde(default)]
+    metadata: Metadata,
+}
+
+#[derive(Deserialize, Default)]
+struct Metadata {
+    language_info: Option<LanguageInfo>,
+    kernelspec: Option<Kernelspec>,
+}
+
+#[derive(Deserialize)]
+struct LanguageInfo {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Kernelspec {
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawCell {
+    cell_type: String,
+    #[serde(default)]
+    source: Text,
+    #[serde(default)]
+    outputs: Vec<Output>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    output_type: String,
+    /// The text of a stream.
+    text: Option<Text>,
+    /// The representations of a result or display.
+    data: Option<Data>,
+    ename: Option<String>,
+    evalue: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Data {
+    #[serde(rename = "text/plain")]
+    text_plain: Option<Text>,
+}
+
+/// Multiline text, which notebooks store either whole or as a list of
+/// lines.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Text {
+    Whole(String),
+    Lines(Vec<String>),
+}
+
+impl Default for Text {
+    fn default() -> Self {
+        Text::Whole(String::new())
+    }
+}
+
+impl Text {
+    fn into_string(self) -> String {
+        match self {
+            Text::Whole(text) => text,
+            Text::Lines(lines) => lines.concat(),
+        }
+    }
+}
+
+/// The code cell at `index`, counting every cell from 0, of the notebook
+/// `json`.
+pub fn cell(json: &str, index: usize) -> Result<Cell, Error> {
+    let notebook: Notebook = serde_json::from_str(json)?;
+    let count = notebook.cells.len();
+    let language = notebook
+        .metadata
+        .language_info
+        .and_then(|info| info.name)
+        .or_else(|| notebook.metadata.kernelspec.and_then(|spec| spec.language))
+        .filter(|name| !name.is_empty());
+    let code_cells: Vec<usize> = notebook
+        .cells
+        .iter()
+        .enumerate()
+        .filter(|(_, cell)| cell.cell_type == "code")
+        .map(|(index, _)| index)
+        .collect();
+    let cell = notebook
+        .cells
+        .into_iter()
+        .nth(index)
+        .ok_or(Error::OutOfRange { index, count })?;
+    if cell.cell_type != "code" {
+        return Err(Error::NotCode {
+            index,
+            kind: cell.cell_type,
+            code_cells,
+        });
+    }
+    Ok(Cell {
+        source: cell.source.into_string(),
+        output: output_text(cell.outputs),
+        language,
+    })
+}
+
+/// The plain text of a cell's outputs, in order. Tracebacks are colored
+/// with ANSI escapes, which are removed.
+fn output_text(outputs: Vec<Output>) -> Option<String> {
+    let mut text = String::new();
+    for output in outputs {
+        let part = match output.output_type.as_str() {
+            "stream" => output.text.map(Text::into_string),
+            "execute_result" | "display_data" => output.data.and_then(|data| data.text_plain).map(Text::into_string),
+            "error" => Some(format!(
+                "{}: {}",
+                output.ename.unwrap_or_default(),
+                output.evalue.unwrap_or_default()
+            )),
+            _ => None,
+        };
+        if let Some(part) = part.filter(|part| !part.is_empty()) {
+            text.push_str(&ansi::strip(&part));
+            if !text.ends_with('\n') {
+                text.push('\n');
+            }
+        }
+    }
+    let text = text.trim_end();
+    (!text.is_empty()).then(|| text.to_owned())
+}
@@ -0,0 +1,66 @@
+//! `GET /oembed`, which describes a `/generate` URL of this server as an
+//! oEmbed photo, so chat and forum software which speaks oEmbed can unfurl
+//! links to images. Only URLs of this server are described, and the size is
+//! the one of the actual render, which lands in the render cache for the
+//! image request which follows.
+
+use serde_json::{json, Value};
+
+use crate::api::{ApiError, ApiVersion};
+use crate::cache::Rendered;
+
+/// The `/generate` URL an oEmbed request asks about.
+pub struct Target {
+    pub version: ApiVersion,
+    pub query: String,
+}
+
+/// Parses `url`, which must be a `/generate` URL on `host`, the host the
+/// request was made to. The routes may be mounted under a prefix, so any
+/// path ending in `/generate` is accepted.
+pub fn target(url: &str, host: &str) -> Result<Target, ApiError> {
+    let invalid = |message: String| ApiError::bad_request("invalid_url", message);
+    let parsed = reqwest::Url::parse(url).map_err(|e| invalid(format!("Invalid url `{}`: {}", url, e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid(format!("url `{}` should be an http or https URL", url)));
+    }
+    let authority = match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => String::new(),
+    };
+    if !authority.eq_ignore_ascii_case(host) {
+        return Err(ApiError::bad_request(
+            "external_url",
+            format!("Only URLs of this server, {}, can be embedded", host),
+        ));
+    }
+    let path = parsed.path().trim_end_matches('/');
+    let version = if path.ends_with("/v1/generate") {
+        ApiVersion::V1
+    } else if path.ends_with("/generate") {
+        ApiVersion::Legacy
+    } else {
+        return Err(invalid(format!("url `{}` should be a /generate URL", url)));
+    };
+    Ok(Target {
+        version,
+        query: parsed.query().unwrap_or_default().to_owned(),
+    })
+}
+
+/// The oEmbed photo document for the image `rendered` from `url`.
+pub fn document(url: &str, rendered: &Rendered, provider_url: &str, cache_age: u64) -> Value {
+    let title = rendered.window_title.as_deref().unwrap_or(&rendered.language);
+    json!({
+        "version": "1.0",
+        "type": "photo",
+        "url": url,
+        "width": rendered.width,
+        "height": rendered.height,
+        "title": title,
+        "provider_name": "Inkify",
+        "provider_url": provider_url,
+        "cache_age": cache_age,
+    })
+}
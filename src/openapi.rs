@@ -0,0 +1,455 @@
+//! The OpenAPI 3 document served at `/openapi.json`. Parameters come from
+//! the query structs, through `utoipa::ToSchema`, so their names, types, and
+//! descriptions follow the code, and the values of the enumerated
+//! parameters come from the same `NAMES` their parsers accept.
+
+use serde_json::{json, Map, Value};
+use utoipa::ToSchema;
+
+use crate::config::{PngCompression, WindowStyle};
+use crate::query::ConfigQuery;
+use crate::{api, background, badge, watermark, whitespace, window};
+
+/// Swagger UI for the document, served at `/docs`. The assets come from
+/// unpkg at an exact version, so a new release can't change what's served.
+pub const DOCS_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Inkify API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui.css" crossorigin="anonymous">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui-bundle.js" crossorigin="anonymous"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"#;
+
+/// The values accepted by the parameters parsed into enums.
+fn enums() -> Vec<(&'static str, &'static [&'static str])> {
+    vec![
+        ("response", api::ResponseMode::NAMES),
+        ("window_style", WindowStyle::NAMES),
+        ("png_compression", PngCompression::NAMES),
+        ("show_whitespace", whitespace::Mode::NAMES),
+        ("badge_position", badge::Position::NAMES),
+        ("background_fit", background::Fit::NAMES),
+        ("background_position", background::Position::NAMES),
+        ("watermark_position", watermark::Position::NAMES),
+        ("caption_align", window::Align::NAMES),
+        ("layout", window::Layout::NAMES),
+    ]
+}
+
+/// The schema of `T`, with the enumerated values filled in.
+fn schema<'s, T: ToSchema<'s>>() -> Value {
+    let mut schema = serde_json::to_value(T::schema().1).unwrap_or_default();
+    for (name, values) in enums() {
+        if let Some(property) = schema.pointer_mut(&format!("/properties/{}", name)) {
+            property["enum"] = json!(values);
+        }
+    }
+    schema
+}
+
+/// The query parameters of `T`, or only those named in `only` when given.
+fn query_params<'s, T: ToSchema<'s>>(only: Option<&[&str]>) -> Vec<Value> {
+    let schema = schema::<T>();
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let properties = match schema["properties"].as_object() {
+        Some(properties) => properties.clone(),
+        None => Map::new(),
+    };
+    properties
+        .into_iter()
+        .filter(|(name, _)| only.map_or(true, |only| only.contains(&name.as_str())))
+        .map(|(name, mut property)| {
+            let description = property.as_object_mut().and_then(|property| property.remove("description"));
+            json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&name.as_str()),
+                "description": description,
+                "schema": property,
+            })
+        })
+        .collect()
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "description": description, "schema": { "type": "string" } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+fn png_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "image/png": { "schema": { "type": "string", "format": "binary" } } }
+    })
+}
+
+fn image_response() -> Value {
+    json!({
+        "description": "The image, or its base64 and metadata with response=json, or its ID with store=true, or a job with async=true.",
+        "content": {
+            "image/png": { "schema": { "type": "string", "format": "binary" } },
+            "application/json": {
+                "schema": {
+                    "oneOf": [
+                        { "$ref": "#/components/schemas/ImageJson" },
+                        { "$ref": "#/components/schemas/Stored" }
+                    ]
+                }
+            }
+        }
+    })
+}
+
+fn error_ref() -> Value {
+    json!({ "$ref": "#/components/responses/Error" })
+}
+
+/// Responses shared by every operation, besides the successful one.
+fn responses(ok: Value) -> Value {
+    json!({ "200": ok, "4XX": error_ref(), "5XX": error_ref() })
+}
+
+/// The document for the server's routes, reachable under both the legacy
+/// and the `/v1` prefix. The admin routes are only described when `admin`
+/// is set, since they don't exist otherwise.
+pub fn document(admin: bool) -> Value {
+    let mut generate_params = query_params::<ConfigQuery>(None);
+    // Snippets can only be passed in a JSON body.
+    generate_params.retain(|param| param["name"] != "snippets");
+    let detect_params = query_params::<ConfigQuery>(Some(&[
+        "code",
+        "code_b64",
+        "code_gz",
+        "code_url",
+        "gist",
+        "gist_file",
+        "github",
+        "filename",
+        "language",
+        "markdown",
+        "block_index",
+        "dedent",
+        "trim",
+        "strip_ansi",
+        "raw",
+        "top_k",
+        "min_score",
+    ]));
+    let mut generate_get = json!({
+        "summary": "Generate an image from code",
+        "description": "Generate an image from the given code.",
+        "parameters": generate_params,
+        "responses": responses(image_response()),
+    });
+    generate_get["responses"]["202"] = json_response(
+        "The render was started in the background, with async=true.",
+        json!({ "$ref": "#/components/schemas/JobCreated" }),
+    );
+    let mut generate_post = json!({
+        "summary": "Generate an image from code, with the parameters in the body",
+        "description": format!("Generate an image from the given code, taking the parameters of GET /generate as a JSON object, or as multipart/form-data fields with the code uploaded as a `file` part whose name sets the default language and window title. A JSON body may instead pass snippets, a list of up to {} objects with their own code, language, window_title, and highlight_lines, to draw them side by side in one image, arranged by layout and snippet_gap.", crate::query::MAX_SNIPPETS),
+        "requestBody": {
+            "required": true,
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/ConfigQuery" } },
+                "multipart/form-data": {
+                    "schema": {
+                        "type": "object",
+                        "properties": { "file": { "type": "string", "format": "binary" } },
+                        "additionalProperties": { "type": "string" }
+                    }
+                }
+            }
+        },
+        "responses": responses(image_response()),
+    });
+    generate_post["responses"]["202"] = generate_get["responses"]["202"].clone();
+    let generate_head = json!({
+        "summary": "The headers and size of an image",
+        "description": "The headers GET /generate would respond with, without rendering the image unless it's in the memory cache: Content-Type, the ETag, which a matching If-None-Match answers with 304, and the size in X-Image-Width and X-Image-Height. The size is estimated from the layout of the code, to within a couple of pixels, unless X-Size-Estimated is false, when the Content-Length is the image's too. store, async, and response=json aren't supported.",
+        "parameters": generate_params.clone(),
+        "responses": responses(json!({ "description": "The headers of the image, without a body." })),
+    });
+
+    let mut preview_params = vec![path_param("name", "The theme.")];
+    preview_params.extend(query_params::<crate::PreviewQuery>(Some(&["language"])));
+
+    let mut paths = Map::new();
+    paths.insert("/".to_owned(), json!({ "get": {
+        "summary": "Help text, and a liveness check",
+        "description": "This help text. Will always return 200, so you can use it to check if the server is up. Parameter defaults the server overrides are listed under defaults.",
+        "responses": { "200": json_response("The help text.", json!({ "type": "object" })) },
+    } }));
+    paths.insert("/healthz".to_owned(), json!({ "get": {
+        "summary": "Liveness probe",
+        "description": "Liveness probe. Returns a small JSON document with status 200 as soon as the server can serve requests.",
+        "responses": { "200": json_response("The server is up.", json!({ "type": "object" })) },
+    } }));
+    paths.insert("/readyz".to_owned(), json!({ "get": {
+        "summary": "Readiness probe",
+        "description": "Readiness probe. Returns 200 once the highlighting assets and language detection model (when configured) are loaded, and 503 with a reason otherwise, including once shutdown has begun. The detection field is ready, unavailable (the model failed to warm up at startup), or disabled.",
+        "responses": responses(json_response("The server is ready.", json!({ "type": "object" }))),
+    } }));
+    paths.insert("/themes".to_owned(), json!({ "get": {
+        "summary": "List the themes",
+        "description": "Return a list of available syntax themes.",
+        "parameters": query_params::<crate::ThemesQuery>(None),
+        "responses": responses(json_response("The themes.", json!({ "type": "array", "items": {} }))),
+    } }));
+    paths.insert("/themes/{name}/preview".to_owned(), json!({ "get": {
+        "summary": "Preview a theme",
+        "description": "Render a fixed sample snippet with the named theme, as a PNG. Responds with 404 for unknown themes.",
+        "parameters": preview_params,
+        "responses": responses(png_response("The sample rendered with the theme.")),
+    } }));
+    paths.insert("/themes/preview".to_owned(), json!({ "get": {
+        "summary": "Compare themes",
+        "description": "Render the sample snippet with several themes, stacked top to bottom in one PNG for comparison, each titled with the theme's name.",
+        "parameters": query_params::<crate::PreviewQuery>(None),
+        "responses": responses(png_response("The sample rendered with each theme.")),
+    } }));
+    paths.insert("/themes/derive".to_owned(), json!({ "post": {
+        "summary": "Derive a theme from an image",
+        "description": "Derive a theme from the dominant colors of an image. Returns the palette, any contrast adjustments, the theme as tmTheme XML (also base64 encoded), and a base64 PNG preview. The image is uploaded as the image field of a multipart/form-data body, unless url is given.",
+        "parameters": query_params::<crate::DeriveQuery>(None),
+        "requestBody": { "content": { "multipart/form-data": { "schema": {
+            "type": "object",
+            "properties": { "image": { "type": "string", "format": "binary" } }
+        } } } },
+        "responses": responses(json_response("The derived theme.", json!({ "type": "object" }))),
+    } }));
+    paths.insert("/decode".to_owned(), json!({ "post": {
+        "summary": "Read the parameters embedded in an image",
+        "description": "Read back the parameters embedded in a PNG generated by Inkify, as a JSON object with the Inkify version, whether the code was included, and the params, which can be passed to POST /generate. Responds with 422 not_inkify_image when the image has none. The PNG is the raw request body, or the image field of a multipart/form-data body.",
+        "requestBody": { "required": true, "content": {
+            "image/png": { "schema": { "type": "string", "format": "binary" } },
+            "multipart/form-data": { "schema": {
+                "type": "object",
+                "properties": { "image": { "type": "string", "format": "binary" } }
+            } }
+        } },
+        "responses": responses(json_response("The embedded parameters.", json!({ "type": "object" }))),
+    } }));
+    paths.insert("/languages".to_owned(), json!({ "get": {
+        "summary": "List the languages",
+        "description": "Returns a list of languages which can be parsed.",
+        "parameters": query_params::<crate::LanguagesQuery>(None),
+        "responses": responses(json_response("The languages.", json!({ "type": "array", "items": {} }))),
+    } }));
+    paths.insert("/fonts".to_owned(), json!({ "get": {
+        "summary": "List the font families",
+        "description": "Returns a list of available font families. The configured fallback fonts which are installed are listed in the X-Fallback-Fonts header, and those which aren't in X-Missing-Fallback-Fonts.",
+        "parameters": query_params::<crate::FontsQuery>(None),
+        "responses": responses(json_response("The font families.", json!({ "type": "array", "items": {} }))),
+    } }));
+    paths.insert("/detect".to_owned(), json!({ "get": {
+        "summary": "Detect the language of code",
+        "description": "Detect the language of the given code. Returns [{\"language\", \"score\", \"syntax_token\", \"source\"}], sorted by descending score, where score is relative to the other languages, from 0 to 100, syntax_token can be passed as /generate's language, and source is how the language was chosen: explicit, extension, first-line, model, or heuristic. Only the model and heuristic give more than one prediction; without a model, the heuristic's scores are estimated probabilities, at most 50.",
+        "parameters": detect_params,
+        "responses": responses(json_response("The likeliest languages.", json!({ "type": "object" }))),
+    } }));
+    paths.insert(
+        "/generate".to_owned(),
+        json!({ "get": generate_get, "head": generate_head, "post": generate_post }),
+    );
+    paths.insert("/estimate".to_owned(), json!({ "get": {
+        "summary": "Estimate the size of an image",
+        "description": "The size GET /generate would render the image at, as {\"width\", \"height\", \"bytes\", \"exact\"}, without rendering it. The width and height are estimated from the layout of the code, to within a couple of pixels, and bytes is a rough guess at the size of the PNG. When the image is in the memory cache, they're its own, and exact is true. Takes the parameters of GET /generate, and faces the same checks, but not store, async, or response=json.",
+        "parameters": generate_params.clone(),
+        "responses": responses(json_response("The size of the image.", json!({
+            "type": "object",
+            "properties": {
+                "width": { "type": "integer" },
+                "height": { "type": "integer" },
+                "bytes": { "type": "integer" },
+                "exact": { "type": "boolean" }
+            }
+        }))),
+    } }));
+    paths.insert("/generate/batch".to_owned(), json!({ "post": {
+        "summary": "Generate several images",
+        "description": "Render several images in one request. The body is a JSON array of objects taking the parameters of POST /generate, each with an id of 1 to 100 letters, digits, '.', '_', or '-'. Responds with a zip archive of <id>.png files when the Accept header includes application/zip, plus errors.json mapping the ids of failed items to their errors, and otherwise with a JSON array of {\"id\", ...} objects like the ones of response=json, or with an error. Items fail on their own, and the number which failed is returned in X-Batch-Failures. At most INKIFY_MAX_BATCH_SIZE items (default 50), and the code of all items counts against the code size limit together. store and async aren't supported.",
+        "requestBody": { "required": true, "content": { "application/json": { "schema": {
+            "type": "array",
+            "items": { "allOf": [
+                { "$ref": "#/components/schemas/ConfigQuery" },
+                { "type": "object", "required": ["id"], "properties": { "id": { "type": "string" } } }
+            ] }
+        } } } },
+        "responses": responses(json!({
+            "description": "The images, as a zip archive with Accept: application/zip.",
+            "content": {
+                "application/zip": { "schema": { "type": "string", "format": "binary" } },
+                "application/json": { "schema": { "type": "array", "items": { "type": "object" } } }
+            }
+        })),
+    } }));
+    paths.insert("/oembed".to_owned(), json!({ "get": {
+        "summary": "Describe an image as an oEmbed photo",
+        "description": "Describe the image of a GET /generate URL of this server as an oEmbed photo, for chat and forum software to unfurl links to images: {\"version\", \"type\": \"photo\", \"url\", \"width\", \"height\", \"title\", \"provider_name\", \"provider_url\", \"cache_age\"}. The image is rendered as the URL would render it, so the size is exact and the image request which follows is a cache hit. The URL faces the same API key, signature, and parameter checks as the image request. Responds with 400 external_url for URLs of other hosts, 400 invalid_url for URLs which aren't of an image, and 501 unsupported_format for formats other than json.",
+        "parameters": query_params::<crate::OembedQuery>(None),
+        "responses": responses(json_response("The oEmbed document.", json!({ "$ref": "#/components/schemas/Oembed" }))),
+    } }));
+    paths.insert("/i/{id}".to_owned(), json!({ "get": {
+        "summary": "A stored image",
+        "description": "An image stored by GET /generate with store=true. Responds with 404 not_found once it has expired, or for unknown IDs.",
+        "parameters": [path_param("id", "The ID returned with store=true.")],
+        "responses": responses(png_response("The stored image.")),
+    } }));
+    paths.insert("/jobs/{id}".to_owned(), json!({ "get": {
+        "summary": "The status of a job",
+        "description": "The status of a render started with async=true: {\"job_id\", \"status\"}, where status is pending, running, done (with a result_url), failed (with the error the request would have failed with), or expired once the result is gone. Responds with 404 not_found for unknown IDs.",
+        "parameters": [path_param("id", "The job_id returned with async=true.")],
+        "responses": responses(json_response("The job.", json!({ "$ref": "#/components/schemas/Job" }))),
+    } }));
+    paths.insert("/jobs/{id}/result".to_owned(), json!({ "get": {
+        "summary": "The result of a job",
+        "description": "The image of a job which is done, as GET /generate would have returned it, or the error of a job which failed. Responds with 409 job_not_finished while it's pending or running, and 410 job_expired once the result has been dropped, INKIFY_JOB_TTL_SECS (default 600) after the job finished.",
+        "parameters": [path_param("id", "The job_id returned with async=true.")],
+        "responses": responses(image_response()),
+    } }));
+    paths.insert("/stats".to_owned(), json!({ "get": {
+        "summary": "Current load and usage",
+        "description": "Current load: the limit, in-flight, and queued counts of renders and of detections, the number of renders which timed out since startup, and the counters of the caches. Also the usage since startup: requests by route, images served, bytes sent, the memory cache's hit rate and the caches' memory use, and the ten most requested themes and languages. Disabled, or requiring INKIFY_ADMIN_TOKEN, depending on INKIFY_STATS.",
+        "responses": { "200": json_response("The load and usage.", json!({ "type": "object" })) },
+    } }));
+    paths.insert("/stats/history".to_owned(), json!({ "get": {
+        "summary": "Recent load",
+        "description": "Recent server metrics (request rate, error rate, p95 latency, queue depth, cache hit ratio, memory, renders timed out per interval) as one array per series.",
+        "parameters": query_params::<crate::HistoryQuery>(None),
+        "responses": { "200": json_response("The samples.", json!({ "type": "object" })) },
+    } }));
+    if admin {
+        paths.insert("/admin/reload".to_owned(), json!({ "post": {
+            "summary": "Reload syntaxes and themes",
+            "description": "Loads the syntaxes and themes again from INKIFY_SYNTAXES_DIR and INKIFY_THEMES_DIR and swaps them in, returning how many there are. Requires INKIFY_ADMIN_TOKEN as a bearer token.",
+            "responses": responses(json_response("The counts of themes and syntaxes loaded.", json!({ "type": "object" }))),
+        } }));
+        paths.insert("/admin/cache/flush".to_owned(), json!({ "post": {
+            "summary": "Flush the caches",
+            "description": "Empties the in-memory caches of rendered images and of fetched background and watermark images, returning how many entries each held. Requires INKIFY_ADMIN_TOKEN as a bearer token.",
+            "responses": responses(json_response("The counts of entries flushed.", json!({ "type": "object" }))),
+        } }));
+    }
+    paths.insert("/openapi.json".to_owned(), json!({ "get": {
+        "summary": "This document",
+        "description": "The OpenAPI 3 document describing the routes, with the types, defaults, and accepted values of their parameters, generated from the same definitions the server parses them with.",
+        "responses": { "200": json_response("The document.", json!({ "type": "object" })) },
+    } }));
+    paths.insert("/playground".to_owned(), json!({ "get": {
+        "summary": "An interactive playground",
+        "description": "An HTML page to try out GET /generate, with a live preview, the themes, languages, and monospace fonts to pick from, and the equivalent URL and curl command, unless the server disables it.",
+        "responses": responses(json!({
+            "description": "The playground page.",
+            "content": { "text/html": { "schema": { "type": "string" } } }
+        })),
+    } }));
+    paths.insert("/docs".to_owned(), json!({ "get": {
+        "summary": "Swagger UI for this document",
+        "description": "Swagger UI for /openapi.json, unless the server disables it.",
+        "responses": responses(json!({
+            "description": "The Swagger UI page.",
+            "content": { "text/html": { "schema": { "type": "string" } } }
+        })),
+    } }));
+    let mut components = json!({
+        "schemas": {
+            "ConfigQuery": schema::<ConfigQuery>(),
+            "ImageJson": {
+                "type": "object",
+                "properties": {
+                    "image": { "type": "string", "format": "byte" },
+                    "format": { "type": "string", "enum": ["png"] },
+                    "width": { "type": "integer" },
+                    "height": { "type": "integer" },
+                    "language": { "type": "string" },
+                    "theme": { "type": "string" },
+                    "render_ms": { "type": "integer" }
+                }
+            },
+            "Stored": {
+                "type": "object",
+                "properties": { "id": { "type": "string" }, "url": { "type": "string" } }
+            },
+            "JobCreated": {
+                "type": "object",
+                "properties": { "job_id": { "type": "string" }, "status_url": { "type": "string" } }
+            },
+            "Job": {
+                "type": "object",
+                "properties": {
+                    "job_id": { "type": "string" },
+                    "status": { "type": "string", "enum": ["pending", "running", "done", "failed", "expired"] },
+                    "result_url": { "type": "string" },
+                    "error": { "$ref": "#/components/schemas/ErrorObject" }
+                }
+            },
+            "ErrorObject": {
+                "type": "object",
+                "properties": {
+                    "code": { "type": "string" },
+                    "message": { "type": "string" },
+                    "status": { "type": "integer" }
+                }
+            }
+        },
+        "responses": {
+            "Error": json_response(
+                "An error, as {\"error\": {...}} under /v1, and {\"error\": \"message\"} on the legacy routes.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "error": { "oneOf": [{ "$ref": "#/components/schemas/ErrorObject" }, { "type": "string" }] }
+                    }
+                })
+            )
+        }
+    });
+
+    components["schemas"]["Oembed"] = json!({
+        "type": "object",
+        "properties": {
+            "version": { "type": "string", "enum": ["1.0"] },
+            "type": { "type": "string", "enum": ["photo"] },
+            "url": { "type": "string" },
+            "width": { "type": "integer" },
+            "height": { "type": "integer" },
+            "title": { "type": "string" },
+            "provider_name": { "type": "string" },
+            "provider_url": { "type": "string" },
+            "cache_age": { "type": "integer" }
+        }
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Inkify",
+            "description": "Generate images from code. Every route is served both under /v1, with structured errors and strict parameters, and at the root, with the legacy behavior.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [
+            { "url": "/v1", "description": "Versioned routes" },
+            { "url": "/", "description": "Legacy routes" }
+        ],
+        "paths": paths,
+        "components": components,
+    })
+}
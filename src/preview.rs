@@ -0,0 +1,134 @@
+//! Theme previews: fixed sample snippets to render with a theme, and the
+//! stacking of several previews into one comparison image.
+
+use image::{imageops, DynamicImage, RgbaImage};
+
+/// Most themes compared in one image.
+pub const MAX_THEMES: usize = 8;
+
+/// Language of the sample when none is asked for.
+pub const DEFAULT_LANGUAGE: &str = "rs";
+
+/// The samples, by the token their syntax is found with, followed by the
+/// other names accepted for it.
+const SAMPLES: &[(&str, &[&str], &str)] = &[
+    (
+        "rs",
+        &["rust"],
+        r#"use std::collections::HashMap;
+
+/// Counts how often each word appears.
+fn count_words(text: &str) -> HashMap<&str, usize> {
+    let mut counts = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    counts
+}
+
+fn main() {
+    let counts = count_words("the quick brown fox jumps over the lazy dog");
+    println!("{} distinct words, {:?}", counts.len(), counts.get("the"));
+}"#,
+    ),
+    (
+        "py",
+        &["python"],
+        r#"from collections import Counter
+
+# Counts how often each word appears.
+def count_words(text: str) -> Counter:
+    counts = Counter()
+    for word in text.split():
+        counts[word.lower()] += 1
+    return counts
+
+if __name__ == "__main__":
+    counts = count_words("the quick brown fox jumps over the lazy dog")
+    print(f"{len(counts)} distinct words, {counts['the']} x 'the'")"#,
+    ),
+    (
+        "js",
+        &["javascript"],
+        r#"// Counts how often each word appears.
+function countWords(text) {
+  const counts = new Map();
+  for (const word of text.split(/\s+/)) {
+    counts.set(word, (counts.get(word) ?? 0) + 1);
+  }
+  return counts;
+}
+
+const counts = countWords("the quick brown fox jumps over the lazy dog");
+console.log(`${counts.size} distinct words, ${counts.get("the")} x "the"`);"#,
+    ),
+    (
+        "go",
+        &["golang"],
+        r#"package main
+
+import (
+	"fmt"
+	"strings"
+)
+
+// countWords counts how often each word appears.
+func countWords(text string) map[string]int {
+	counts := make(map[string]int)
+	for _, word := range strings.Fields(text) {
+		counts[word]++
+	}
+	return counts
+}
+
+func main() {
+	counts := countWords("the quick brown fox jumps over the lazy dog")
+	fmt.Printf("%d distinct words, %d x \"the\"\n", len(counts), counts["the"])
+}"#,
+    ),
+    (
+        "rb",
+        &["ruby"],
+        r##"# Counts how often each word appears.
+def count_words(text)
+  counts = Hash.new(0)
+  text.split.each do |word|
+    counts[word.downcase] += 1
+  end
+  counts
+end
+
+counts = count_words("the quick brown fox jumps over the lazy dog")
+puts "#{counts.size} distinct words, #{counts['the']} x 'the'""##,
+    ),
+];
+
+/// The names accepted by `sample`.
+pub fn names() -> Vec<&'static str> {
+    SAMPLES.iter().map(|(token, _, _)| *token).collect()
+}
+
+/// The syntax token and code of the sample in `language`, matched by token
+/// or name.
+pub fn sample(language: &str) -> Option<(&'static str, &'static str)> {
+    let language = language.to_ascii_lowercase();
+    SAMPLES
+        .iter()
+        .find(|(token, aliases, _)| *token == language || aliases.contains(&language.as_str()))
+        .map(|(token, _, code)| (*token, *code))
+}
+
+/// Stacks `images` top to bottom, each centered horizontally, on a
+/// transparent canvas as wide as the widest.
+pub fn stack(images: &[DynamicImage]) -> DynamicImage {
+    let width = images.iter().map(|image| image.width()).max().unwrap_or(0);
+    let height = images.iter().map(|image| image.height()).sum();
+    let mut canvas = RgbaImage::new(width, height);
+    let mut y = 0;
+    for image in images {
+        let x = (width - image.width()) / 2;
+        imageops::overlay(&mut canvas, &image.to_rgba8(), x as i64, y as i64);
+        y += image.height();
+    }
+    DynamicImage::ImageRgba8(canvas)
+}
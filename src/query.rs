@@ -0,0 +1,393 @@
+//! The parameters of `/generate`, as they're passed in a query string, a
+//! JSON body, or form fields, before they're parsed into a `Config`.
+
+/// Query parameters for the /generate endpoint, using Option to make all options
+/// with defaults optional.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct ConfigQuery {
+    /// The background image for the padding area as a URL, or inline as a
+    /// `data:image/<type>;base64,<data>` URI which is decoded without any network access.
+    pub background_image: Option<String>,
+
+    /// The background color.
+    pub background: Option<String>,
+
+    /// The code to generate an image from. Required unless code_b64, code_gz, code_url, gist, or
+    /// github is given.
+    #[serde(default)]
+    pub code: String,
+
+    /// The code as standard or URL-safe base64, avoiding query string encoding issues with #, %, +,
+    /// newlines, and unicode. Mutually exclusive with code.
+    pub code_b64: Option<String>,
+
+    /// The code gzip or deflate compressed, then base64 encoded, for large snippets. Mutually
+    /// exclusive with code.
+    pub code_gz: Option<String>,
+
+    /// URL of a file to fetch the code from instead of passing code. The language is inferred from
+    /// the file extension when not given. Mutually exclusive with code.
+    pub code_url: Option<String>,
+
+    /// A GitHub gist ID or URL to render instead of passing code. The file name is used to infer
+    /// the language and as the window title.
+    pub gist: Option<String>,
+
+    /// The file to render from a multi-file gist. Defaults to the first file.
+    pub gist_file: Option<String>,
+
+    /// A GitHub file permalink such as
+    /// `https://github.com/owner/repo/blob/<ref>/path/file.rs#L10-L42`. Only the selected lines are
+    /// rendered, numbered as in the original file, and the path becomes the window title.
+    pub github: Option<String>,
+
+    /// The font to use.
+    pub font: Option<String>,
+
+    /// The lines to highlight, eg. 1-3;7. Each group may take its own RGBA color, eg.
+    /// 1-3:#2ecc4040;7:#f1c40f40, and groups without one use the default highlight. Where groups
+    /// overlap, the last one wins.
+    pub highlight_lines: Option<String>,
+
+    /// Lines to cover with a solid block, eg. 3;7-9. The text is removed before rendering, so it
+    /// can't be recovered from the image.
+    pub redact_lines: Option<String>,
+
+    /// A regular expression whose matches in each line are covered with a solid block, eg.
+    /// `sk_live_[0-9a-zA-Z]+`.
+    pub redact_pattern: Option<String>,
+
+    /// Cover well-known secrets, such as AWS keys, GitHub and Slack tokens, private keys, and
+    /// password or api_key assignments, with a solid block. Only the secret's value is covered, and
+    /// the number of redactions is returned in the X-Redactions header.
+    pub auto_redact: Option<bool>,
+
+    /// Comma separated auto_redact rules to skip: aws_access_key, aws_secret_key, github_token,
+    /// slack_token, private_key, or generic_assignment.
+    pub auto_redact_allow: Option<String>,
+
+    /// The language to use for syntax highlighting, by name, extension, or alias, eg. golang or
+    /// c++. Guessed when not given. Unknown languages are rejected with suggestions.
+    pub language: Option<String>,
+
+    /// The line padding.
+    pub line_pad: Option<u32>,
+
+    /// The line offset.
+    pub line_offset: Option<u32>,
+
+    /// Whether to hide the window controls.
+    pub no_window_controls: Option<bool>,
+
+    /// Whether to show the window controls. /v1 only, takes precedence over no_window_controls.
+    pub window_controls: Option<bool>,
+
+    /// The window title. Defaults to the file name when the code came from a file, or else eg.
+    /// snippet.rs for the language, or "Inkify" for plain text. Pass an empty value for no title.
+    /// The title used is returned in the X-Window-Title header.
+    pub window_title: Option<String>,
+
+    /// Whether to hide the line numbers.
+    pub no_line_number: Option<bool>,
+
+    /// Whether to show the line numbers. /v1 only, takes precedence over no_line_number.
+    pub line_number: Option<bool>,
+
+    /// Deprecated, use corner_radius=0 instead. Whether to not round the corners.
+    pub no_round_corner: Option<bool>,
+
+    /// Whether to round the corners. /v1 only, takes precedence over no_round_corner.
+    pub round_corner: Option<bool>,
+
+    /// The horizontal padding.
+    pub pad_horiz: Option<u32>,
+
+    /// The vertical padding.
+    pub pad_vert: Option<u32>,
+
+    /// The color of the shadow.
+    pub shadow_color: Option<String>,
+
+    /// The shadow blur radius.
+    pub shadow_blur_radius: Option<f32>,
+
+    /// The shadow offset y.
+    pub shadow_offset_y: Option<i32>,
+
+    /// The shadow offset x.
+    pub shadow_offset_x: Option<i32>,
+
+    /// The tab width.
+    pub tab_width: Option<u8>,
+
+    /// The theme to use for syntax highlighting.
+    pub theme: Option<String>,
+
+    /// A tmTheme XML document, base64 encoded and at most 1MB decoded, to use for this render
+    /// instead of a named theme. Mutually exclusive with theme.
+    pub theme_b64: Option<String>,
+
+    /// Multiplier for high-DPI output between 1.0 and 4.0, applied to font sizes (including
+    /// explicit ones), paddings, and shadows before rendering.
+    pub scale: Option<f32>,
+
+    /// Wrap lines longer than this many columns (20 to 1000), after expanding tabs. Continuation
+    /// rows keep the line's indentation and are marked with ↪ instead of a line number. Defaults to
+    /// no wrapping.
+    pub max_width: Option<usize>,
+
+    /// Render only these lines of the code, eg. 40-80, or 1-5;20-25 for several ranges with a
+    /// separator row between them. line_offset defaults to the start of the first range, and
+    /// highlight_lines refers to the numbers shown. Ranges are clamped to the code, and the
+    /// effective ranges are returned in the X-Line-Range header.
+    pub line_range: Option<String>,
+
+    /// Treat the code as Markdown and render only its first fenced code block, or the one at
+    /// block_index. The fence's info string, eg. rust for ```rust,ignore, is the language unless
+    /// one is given. Without any fenced block, the input is rendered as is, with an X-Warning
+    /// header.
+    pub markdown: Option<bool>,
+
+    /// Which fenced code block to render with markdown=true, from 0 for the first.
+    pub block_index: Option<usize>,
+
+    /// Treat the code as a Jupyter notebook (.ipynb JSON) and render its cell at this index, from 0
+    /// for the first, which must be a code cell. The notebook's kernel language is the language
+    /// unless one is given.
+    pub notebook_cell: Option<usize>,
+
+    /// Draw the text output of the notebook_cell below its code, set apart from it.
+    pub include_output: Option<bool>,
+
+    /// Strip the indentation common to all non-blank lines, expanding tabs to tab_width first.
+    pub dedent: Option<bool>,
+
+    /// Drop leading and trailing blank lines.
+    pub trim: Option<bool>,
+
+    /// Draw the code, eg. the output of a command, in the colors and styles of its ANSI escape
+    /// sequences instead of highlighting it: the 16 and 256 color palettes, truecolor, bold,
+    /// italic, underline, and inverse. Other sequences are dropped, and text without a color is
+    /// drawn in the theme's. Can't be combined with diff or strip_ansi.
+    pub ansi: Option<bool>,
+
+    /// Remove ANSI escape sequences, like the colors of pasted terminal output, before detecting
+    /// and highlighting the code.
+    pub strip_ansi: Option<bool>,
+
+    /// Leave the code's bytes untouched, rather than turning CRLF and CR line endings into LF,
+    /// stripping a leading byte order mark, and ending the last line with a newline.
+    pub raw: Option<bool>,
+
+    /// The number of predictions /detect returns.
+    pub top_k: Option<usize>,
+
+    /// The lowest normalized score, from 0 to 100, of a prediction /detect
+    /// returns.
+    pub min_score: Option<f32>,
+
+    /// Render the code as a unified diff, with removed and added lines tinted and old and new line
+    /// numbers. Also enabled by language=diff. language sets the language of the changed code, and
+    /// defaults to the one of the file in the +++ header. Can't be combined with line_range.
+    pub diff: Option<bool>,
+
+    /// When the language isn't given, respond with 422 ambiguous_language instead of guessing if
+    /// the detection model's confidence is below the server's threshold.
+    pub strict_language: Option<bool>,
+
+    /// The language to use when the language isn't given and can't be detected, or only below the
+    /// server's confidence threshold, instead of guessing or failing with strict_language. Defaults
+    /// to the server's INKIFY_FALLBACK_LANGUAGE, or plain text, which are only used when detection
+    /// finds nothing. X-Fallback-Language-Used says whether it was used.
+    pub fallback_language: Option<String>,
+
+    /// How the image is returned: binary (the PNG itself) or json ({"image": base64 PNG, "format":
+    /// "png", "width", "height", "language", "theme", "render_ms"}). render_ms is the time of the
+    /// original render, also on cache hits.
+    pub response: Option<String>,
+
+    /// Respond with 400 missing_font, listing the families of font which aren't installed, instead
+    /// of falling back to another font. Without it, substitutions are reported in the
+    /// X-Font-Fallback header, eg. JetBrains Mono->Hack.
+    pub strict_font: Option<bool>,
+
+    /// Render byte-identical images for identical inputs, for caching and regression tests: the
+    /// language is detected with the heuristic instead of the detection model, which may time out.
+    pub deterministic: Option<bool>,
+
+    /// How hard the PNG encoder works: fast, default, or best, trading size for encoding time.
+    /// Defaults to the server's INKIFY_PNG_COMPRESSION, or default.
+    pub png_compression: Option<String>,
+
+    /// Embed the code in the PNG's metadata with the other parameters even when it's over 4096
+    /// bytes. Redacted code is never embedded.
+    pub embed_code: Option<bool>,
+
+    /// Store the image and respond with {"id", "url"} instead, where url is the path of GET /i/{id}
+    /// serving it, so links don't have to carry every parameter. When the server uploads to S3, the
+    /// id is the parameter hash and the url the object's public or presigned URL; if the upload
+    /// fails, the response is the one of response=json with a warning field added. Responds with
+    /// 400 storage_disabled when the server doesn't store images.
+    pub store: Option<bool>,
+
+    /// Render in the background and respond at once with 202 and {"job_id", "status_url"}, for
+    /// renders slower than the client's timeout. Poll GET /jobs/{id}, then fetch GET
+    /// /jobs/{id}/result. The parameters are validated before the job starts, and the job fails
+    /// with the error the request would have. Can't be combined with store.
+    pub r#async: Option<bool>,
+
+    /// Send the image as an attachment, so browsers save it instead of displaying it.
+    pub download: Option<bool>,
+
+    /// The code's file name, eg. main.rs. Its extension picks the language when language isn't
+    /// given, before the first line or the detection model are consulted. It's also the file name
+    /// suggested in the Content-Disposition header, with the extension swapped for the response
+    /// format's, path separators, quotes, and control characters removed, and cut to 100
+    /// characters. The suggested name defaults to the window title with its extension swapped, or
+    /// else the language, eg. main.png.
+    pub filename: Option<String>,
+
+    /// Draw spaces as a faint · and tabs as a faint → padded to tab_width: true, false, or trailing
+    /// to mark only whitespace at the end of lines.
+    pub show_whitespace: Option<String>,
+
+    /// Draw a faint vertical line at every tab_width columns of each line's indentation.
+    pub indent_guides: Option<bool>,
+
+    /// Color of the indentation guides. Defaults to the theme's foreground at low opacity.
+    pub indent_guide_color: Option<String>,
+
+    /// Draw the language's name, whether given or detected, in a pill in a corner of the window.
+    pub show_language: Option<bool>,
+
+    /// Corner of the language badge: top-left, top-right, bottom-left, or bottom-right.
+    pub badge_position: Option<String>,
+
+    /// Background color of the language badge. Defaults to the theme's foreground at low opacity.
+    pub badge_color: Option<String>,
+
+    /// Style of the window around the code: mac (traffic lights, centered title), windows (buttons
+    /// on the right, left-aligned title), bar (centered title, no buttons), or none (no controls or
+    /// title area).
+    pub window_style: Option<String>,
+
+    /// Radius of the window's corners in pixels, from 0 to 50. 0 disables rounding.
+    pub corner_radius: Option<u32>,
+
+    /// A gradient for the padding area, as `linear,<angle>deg,<color>,<color>[,...]` or
+    /// `radial,<color>,<color>[,...]`, eg. linear,45deg,#ff5f6d,#ffc371. Mutually exclusive with
+    /// background_image.
+    pub background_gradient: Option<String>,
+
+    /// How background_image is fitted to the image: cover (scaled and cropped), contain (scaled to
+    /// fit, over the background color), tile, or stretch.
+    pub background_fit: Option<String>,
+
+    /// Which part of a covering background_image is kept, or where a contained one is placed:
+    /// center, top, bottom, left, or right.
+    pub background_position: Option<String>,
+
+    /// Text to draw in the padding area, eg. for attribution.
+    pub watermark: Option<String>,
+
+    /// Where the watermark is drawn: top-left, top-right, bottom-left, bottom-right, or
+    /// bottom-center.
+    pub watermark_position: Option<String>,
+
+    /// Color of the watermark.
+    pub watermark_color: Option<String>,
+
+    /// A logo to draw in the padding area instead of watermark text, as a URL or data URI. Fetched
+    /// like background_image. Mutually exclusive with watermark.
+    pub watermark_image: Option<String>,
+
+    /// Multiplier for the size of watermark_image, from 0.05 to 4.0.
+    pub watermark_scale: Option<f32>,
+
+    /// Opacity of the watermark, from 0.0 to 1.0.
+    pub watermark_opacity: Option<f32>,
+
+    /// A caption beneath the window, eg. the file and commit. Long lines are truncated with an
+    /// ellipsis, and \n separates up to 5 lines.
+    pub caption: Option<String>,
+
+    /// Color of the caption. Defaults to the theme's foreground.
+    pub caption_color: Option<String>,
+
+    /// Alignment of the caption under the window: left, center, or right.
+    pub caption_align: Option<String>,
+
+    /// Centers the window on a fixed size canvas: og (1200x630), twitter (1200x675), square
+    /// (1080x1080), or WIDTHxHEIGHT from 200 to 4096. Overrides pad_horiz and pad_vert, and the
+    /// window is scaled down if it doesn't fit.
+    pub preset: Option<String>,
+
+    /// Scale the final image down, keeping its aspect ratio, when it's wider than this many pixels,
+    /// from 16 to 8192. Combined with scale, text is rendered at a higher resolution and downscaled
+    /// for crisper output.
+    pub max_output_width: Option<u32>,
+
+    /// Scale the final image down, keeping its aspect ratio, when it's taller than this many
+    /// pixels, from 16 to 8192.
+    pub max_output_height: Option<u32>,
+
+    /// Up to 4 snippets to draw in one image instead of code, each in a window of its own, eg. for
+    /// a before and after. JSON bodies only. The other parameters apply to every snippet, and the
+    /// windows share the background, shadow, and caption.
+    #[schema(inline)]
+    pub snippets: Option<Vec<Snippet>>,
+
+    /// How the windows of snippets are arranged: vertical, stacked top to bottom, or horizontal,
+    /// side by side. Defaults to vertical.
+    pub layout: Option<String>,
+
+    /// Space between the windows of snippets in pixels, from 0 to 400, before scaling. Defaults to
+    /// 40.
+    pub snippet_gap: Option<u32>,
+}
+
+impl ConfigQuery {
+    /// The size of the code in bytes, with that of any snippets.
+    pub fn code_len(&self) -> usize {
+        let snippets = self.snippets.iter().flatten();
+        self.code.len() + snippets.map(|snippet| snippet.code.len()).sum::<usize>()
+    }
+}
+
+/// The most snippets an image may have.
+pub const MAX_SNIPPETS: usize = 4;
+
+/// One of the `snippets` of an image.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct Snippet {
+    /// The code of the snippet.
+    pub code: String,
+
+    /// The language, detected from the snippet's own code when not given.
+    pub language: Option<String>,
+
+    /// The snippet's window title, which defaults as window_title does.
+    pub window_title: Option<String>,
+
+    /// The lines of the snippet to highlight, as in highlight_lines.
+    pub highlight_lines: Option<String>,
+}
+
+/// Returns the names of any parameters in `query` which are not fields of
+/// `ConfigQuery`, used for strict validation on `/v1` routes.
+pub fn unknown_params(query: &str) -> Vec<String> {
+    let mut unknown = vec![];
+    let de = serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+    let _: Result<ConfigQuery, _> = serde_ignored::deserialize(de, |path| unknown.push(path.to_string()));
+    unknown
+}
+
+/// Parses a JSON request body into a `ConfigQuery`, also returning the names
+/// of any unknown fields.
+pub fn parse_json_query(body: &[u8]) -> Result<(ConfigQuery, Vec<String>), serde_json::Error> {
+    let mut unknown = vec![];
+    let mut de = serde_json::Deserializer::from_slice(body);
+    let query = serde_ignored::deserialize(&mut de, |path| unknown.push(path.to_string()))?;
+    de.end()?;
+    Ok((query, unknown))
+}
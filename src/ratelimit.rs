@@ -0,0 +1,248 @@
+use actix_web::HttpRequest;
+use lru::LruCache;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most clients tracked at once. Past it the least recently seen are
+/// forgotten, so a flood of addresses can't grow memory without bound.
+const MAX_CLIENTS: usize = 100_000;
+
+/// Per-client sliding window rate limiter. Each client keeps the counts of
+/// the current and previous fixed windows, and the previous count is
+/// weighted by how much of it still overlaps the sliding window. This keeps
+/// memory per client constant regardless of the limit. IPv6 clients are
+/// limited by their /64, see `client_key`.
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    clients: Mutex<LruCache<IpAddr, Window>>,
+}
+
+struct Window {
+    start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            clients: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_CLIENTS).unwrap())),
+        }
+    }
+
+    /// Records a request from `ip`. Returns `Err` with the time to wait
+    /// before retrying when the client is over the limit.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        // Clients are ordered by their last request, so idle ones gather at
+        // the end and sweeping them costs only the ones dropped.
+        while clients
+            .peek_lru()
+            .is_some_and(|(_, w)| now.duration_since(w.start) >= self.window * 2)
+        {
+            clients.pop_lru();
+        }
+
+        let window = clients.get_or_insert_mut(client_key(ip), || Window {
+            start: now,
+            current: 0,
+            previous: 0,
+        });
+
+        let elapsed = now.duration_since(window.start);
+        if elapsed >= self.window * 2 {
+            *window = Window {
+                start: now,
+                current: 0,
+                previous: 0,
+            };
+        } else if elapsed >= self.window {
+            window.start += self.window;
+            window.previous = window.current;
+            window.current = 0;
+        }
+
+        let into_window = now.duration_since(window.start).as_secs_f64() / self.window.as_secs_f64();
+        let weighted = window.previous as f64 * (1.0 - into_window) + window.current as f64;
+        if weighted + 1.0 > self.limit as f64 {
+            let retry_after = self.window.saturating_sub(now.duration_since(window.start));
+            return Err(retry_after.max(Duration::from_secs(1)));
+        }
+
+        window.current += 1;
+        Ok(())
+    }
+}
+
+/// Parses a rate such as `60/minute`, `10/second`, or `1000/hour`.
+pub fn parse_rate(s: &str) -> Result<(u32, Duration), anyhow::Error> {
+    let (limit, unit) = s
+        .split_once('/')
+        .ok_or_else(|| format_err!("Invalid rate `{}`, expected eg. `60/minute`", s))?;
+    let limit = limit
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format_err!("Invalid rate limit `{}`", limit))?;
+    let window = match unit.trim() {
+        "s" | "sec" | "second" => Duration::from_secs(1),
+        "m" | "min" | "minute" => Duration::from_secs(60),
+        "h" | "hour" => Duration::from_secs(3600),
+        "d" | "day" => Duration::from_secs(86400),
+        other => return Err(format_err!("Invalid rate unit `{}`", other)),
+    };
+    if limit == 0 {
+        return Err(format_err!("Rate limit must be greater than 0"));
+    }
+    Ok((limit, window))
+}
+
+/// The address a client is limited by: its IPv4 address, or the /64 prefix
+/// of its IPv6 address, since a single host is commonly given a whole /64
+/// and can rotate through it.
+pub fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => IpAddr::V4(mapped),
+            None => {
+                let [a, b, c, d, ..] = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+/// Returns the client's IP address. Forwarding headers are only consulted
+/// when `trust_proxy` is set, since any client can send them. Even then only
+/// their rightmost entry is used: the proxy appends the address it saw to
+/// whatever the client sent, so entries to its left can be forged.
+pub fn client_ip(req: &HttpRequest, trust_proxy: bool) -> Option<IpAddr> {
+    if trust_proxy {
+        let last_entry = |name: &str| {
+            req.headers()
+                .get_all(name)
+                .filter_map(|v| v.to_str().ok())
+                .last()
+                .and_then(|v| v.rsplit(',').next())
+                .map(str::trim)
+                .map(str::to_owned)
+        };
+
+        let forwarded_for = last_entry("X-Forwarded-For").and_then(|ip| ip.parse::<IpAddr>().ok());
+        if forwarded_for.is_some() {
+            return forwarded_for;
+        }
+
+        let forwarded = last_entry("Forwarded").and_then(|element| {
+            element
+                .split(';')
+                .map(|pair| pair.trim())
+                .find_map(|pair| pair.strip_prefix("for=").and_then(parse_forwarded_node))
+        });
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+/// Parses a `Forwarded` node such as `192.0.2.1`, `"[2001:db8::1]:4711"`, or
+/// `192.0.2.1:4711`.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':').and_then(|(ip, _)| ip.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn peer() -> std::net::SocketAddr {
+        "10.0.0.1:4711".parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn clients_are_limited_separately() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.check(ip("192.0.2.1")).is_ok());
+        assert!(limiter.check(ip("192.0.2.1")).is_ok());
+        assert!(limiter.check(ip("192.0.2.1")).is_err());
+        assert!(limiter.check(ip("192.0.2.2")).is_ok());
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_by_their_64() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.check(ip("2001:db8:1:2::1")).is_ok());
+        assert!(limiter.check(ip("2001:db8:1:2:ffff::9")).is_ok());
+        assert!(limiter.check(ip("2001:db8:1:2:abcd::42")).is_err());
+        assert!(limiter.check(ip("2001:db8:1:3::1")).is_ok());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_keyed_as_ipv4() {
+        assert_eq!(client_key(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(client_key(ip("192.0.2.1")), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn idle_clients_are_swept() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(20));
+        assert!(limiter.check(ip("192.0.2.1")).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(limiter.check(ip("192.0.2.2")).is_ok());
+        assert_eq!(limiter.clients.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forwarding_headers_are_ignored_without_trust_proxy() {
+        let req = TestRequest::default()
+            .peer_addr(peer())
+            .insert_header(("X-Forwarded-For", "192.0.2.1"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, false), Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn x_forwarded_for_uses_the_entry_added_by_the_proxy() {
+        let req = TestRequest::default()
+            .peer_addr(peer())
+            .insert_header(("X-Forwarded-For", "203.0.113.9, 198.51.100.7 , 192.0.2.1"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, true), Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn forwarded_uses_the_element_added_by_the_proxy() {
+        let req = TestRequest::default()
+            .peer_addr(peer())
+            .insert_header(("Forwarded", "for=203.0.113.9, for=\"[2001:db8::1]:4711\";proto=https"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, true), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn unparsable_forwarding_headers_fall_back_to_the_peer() {
+        let req = TestRequest::default()
+            .peer_addr(peer())
+            .insert_header(("X-Forwarded-For", "192.0.2.1, unknown"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, true), Some("10.0.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,142 @@
+//! Redaction of lines and pattern matches, for the `redact_lines`,
+//! `redact_pattern`, and `auto_redact` parameters.
+//!
+//! Redacted text is replaced with spaces before highlighting, so nothing of
+//! it reaches the image, and a solid block is then drawn over where it was.
+//! The blocks are placed by measuring the text before them in each row, so
+//! the gutter must be drawn as part of the code.
+
+use anyhow::Error;
+use image::{DynamicImage, Rgba};
+use regex::Regex;
+use std::ops::Range;
+use syntect::highlighting::Style;
+
+use crate::config::Config;
+use crate::highlight::{Layout, CODE_PAD};
+
+/// Largest compiled size of `redact_pattern`, in bytes.
+pub const MAX_PATTERN_SIZE: usize = 1 << 16;
+
+const BLOCK_COLOR: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// Replaces the 1-based `lines` of `code`, and any matches of `patterns`,
+/// with as many spaces as they had characters. Only the `secret` group of a
+/// pattern which has one is replaced. Returns the new code and the byte
+/// ranges of it which were redacted.
+pub fn blank(code: &str, lines: &[u32], patterns: &[&Regex]) -> (String, Vec<Range<usize>>) {
+    let mut out = String::with_capacity(code.len());
+    let mut redacted = vec![];
+    for (i, line) in code.split_inclusive('\n').enumerate() {
+        let content = line.trim_end_matches(['\n', '\r']);
+        let mut spans: Vec<Range<usize>> = if lines.contains(&(i as u32 + 1)) {
+            vec![0..content.len()]
+        } else {
+            patterns.iter().flat_map(|pattern| matches(pattern, content)).collect()
+        };
+        spans.sort_by_key(|span| span.start);
+
+        let mut last = 0;
+        for span in merge(spans) {
+            out.push_str(&content[last..span.start]);
+            let blanked = content[span.clone()].chars().count();
+            redacted.push(out.len()..out.len() + blanked);
+            out.extend(std::iter::repeat(' ').take(blanked));
+            last = span.end;
+        }
+        out.push_str(&line[last..]);
+    }
+    (out, redacted)
+}
+
+/// Merges overlapping and adjacent `spans`, sorted by start, dropping empty
+/// ones.
+fn merge(spans: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    let mut merged: Vec<Range<usize>> = vec![];
+    for span in spans.into_iter().filter(|span| !span.is_empty()) {
+        match merged.last_mut() {
+            Some(last) if span.start <= last.end => last.end = last.end.max(span.end),
+            _ => merged.push(span),
+        }
+    }
+    merged
+}
+
+/// The byte ranges of `content` matched by `pattern`, or by its `secret`
+/// group when it has one.
+fn matches(pattern: &Regex, content: &str) -> Vec<Range<usize>> {
+    pattern
+        .captures_iter(content)
+        .filter_map(|captures| captures.name("secret").or_else(|| captures.get(0)))
+        .map(|m| m.range())
+        .collect()
+}
+
+/// A block to draw over redacted text.
+pub struct Block {
+    /// The 1-based row it's on.
+    pub row: u32,
+    /// The text of the row before it, including any gutter.
+    pub before: String,
+    /// The redacted spaces it covers.
+    pub text: String,
+}
+
+/// Finds where the `redacted` byte ranges of `code` ended up in `lines`, the
+/// rows passed to the formatter. Text in the rows which isn't part of
+/// `code`, like the gutter and repeated indentation, is never redacted.
+pub fn blocks(lines: &[Vec<(Style, &str)>], code: &str, redacted: &[Range<usize>]) -> Vec<Block> {
+    let mut blocks = vec![];
+    for (i, row) in lines.iter().enumerate() {
+        let mut before = String::new();
+        for (_, segment) in row {
+            if let Some(offset) = offset_in(code, segment) {
+                for range in redacted {
+                    let from = range.start.max(offset);
+                    let to = range.end.min(offset + segment.len());
+                    if from < to {
+                        blocks.push(Block {
+                            row: i as u32 + 1,
+                            before: format!("{}{}", before, &segment[..from - offset]),
+                            text: segment[from - offset..to - offset].to_owned(),
+                        });
+                    }
+                }
+            }
+            before.push_str(segment);
+        }
+    }
+    blocks
+}
+
+/// The byte offset of `s` in `code`, when it's a slice of it rather than
+/// text added around the code.
+pub fn offset_in(code: &str, s: &str) -> Option<usize> {
+    let start = code.as_ptr() as usize;
+    let at = s.as_ptr() as usize;
+    (start..start + code.len()).contains(&at).then(|| at - start)
+}
+
+/// Whether the byte at `offset` in the code is in one of the `redacted`
+/// ranges.
+pub fn is_redacted(redacted: &[Range<usize>], offset: usize) -> bool {
+    redacted.iter().any(|range| range.contains(&offset))
+}
+
+/// Draws `blocks` onto `image`, the bare window out of the formatter.
+/// `row_count` is the number of rows formatted.
+pub fn draw(image: DynamicImage, blocks: &[Block], row_count: u32, conf: &Config) -> Result<DynamicImage, Error> {
+    let mut image = image.to_rgba8();
+    let layout = Layout::new(image.height(), row_count, conf)?;
+    for block in blocks {
+        let left = CODE_PAD + layout.fonts.get_text_len(&block.before);
+        let right = (left + layout.fonts.get_text_len(&block.text)).min(image.width());
+        let top = layout.row_top(block.row);
+        for y in top..(top + layout.pitch).min(image.height()) {
+            for x in left..right {
+                image.put_pixel(x, y, BLOCK_COLOR);
+            }
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(image))
+}
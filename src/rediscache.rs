@@ -0,0 +1,125 @@
+//! Rendered images cached in Redis, for `INKIFY_REDIS_URL`, so replicas
+//! behind a load balancer share their renders. Redis being unreachable only
+//! costs the cache: failures are logged and counted, and Redis is skipped
+//! for a while rather than slowing down every request.
+
+use anyhow::Error;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cache::{CacheBackend, Rendered};
+use crate::metrics::METRICS;
+
+/// Entry lifetime when `INKIFY_REDIS_TTL_SECS` isn't set.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long connecting, and each command, may take.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long Redis is skipped after a failure.
+const BACKOFF: Duration = Duration::from_secs(10);
+
+const KEY_PREFIX: &str = "inkify:render:";
+
+pub struct RedisCache {
+    client: redis::Client,
+    ttl: Duration,
+    /// Idle connections, reused between requests.
+    idle: Mutex<Vec<redis::Connection>>,
+    /// Redis is skipped until then after a failure.
+    backoff_until: Mutex<Option<Instant>>,
+}
+
+impl RedisCache {
+    /// Parses `url`. Nothing is connected until the first request.
+    pub fn new(url: &str, ttl: Duration) -> Result<Self, Error> {
+        Ok(RedisCache {
+            client: redis::Client::open(url)?,
+            ttl,
+            idle: Mutex::new(vec![]),
+            backoff_until: Mutex::new(None),
+        })
+    }
+
+    /// Runs `command` on an idle connection or a new one. After a failure,
+    /// the connection is dropped and Redis is skipped for a while.
+    fn with_connection<T>(
+        &self,
+        command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    ) -> Option<T> {
+        if self.backoff_until.lock().unwrap().is_some_and(|until| Instant::now() < until) {
+            return None;
+        }
+        let idle = self.idle.lock().unwrap().pop();
+        let result = match idle {
+            Some(connection) => Ok(connection),
+            None => self.connect(),
+        }
+        .and_then(|mut connection| command(&mut connection).map(|value| (value, connection)));
+        match result {
+            Ok((value, connection)) => {
+                self.idle.lock().unwrap().push(connection);
+                Some(value)
+            }
+            Err(e) => {
+                METRICS.redis_errors.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(error = %e, backoff = ?BACKOFF, "Redis cache unavailable, rendering locally");
+                *self.backoff_until.lock().unwrap() = Some(Instant::now() + BACKOFF);
+                None
+            }
+        }
+    }
+
+    fn connect(&self) -> redis::RedisResult<redis::Connection> {
+        let connection = self.client.get_connection_with_timeout(TIMEOUT)?;
+        connection.set_read_timeout(Some(TIMEOUT))?;
+        connection.set_write_timeout(Some(TIMEOUT))?;
+        Ok(connection)
+    }
+}
+
+impl CacheBackend for RedisCache {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn get(&self, key: &str, content_type: &str) -> Option<Arc<Rendered>> {
+        let encoded: Option<Vec<u8>> = self.with_connection(|connection| {
+            redis::cmd("GET").arg(format!("{}{}", KEY_PREFIX, key)).query(connection)
+        })?;
+        let rendered = match encoded.map(|encoded| Rendered::decode(&encoded)) {
+            Some(Ok(rendered)) if rendered.content_type == content_type => Some(Arc::new(rendered)),
+            Some(Ok(_)) => None,
+            Some(Err(e)) => {
+                tracing::warn!(key = %key, error = %e, "Ignoring corrupted Redis cache entry");
+                None
+            }
+            None => None,
+        };
+        if rendered.is_some() {
+            METRICS.redis_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            METRICS.redis_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        rendered
+    }
+
+    fn insert(&self, key: &str, rendered: Arc<Rendered>) {
+        let encoded = match rendered.encode() {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                tracing::warn!(key = %key, error = %e, "Failed to encode Redis cache entry");
+                return;
+            }
+        };
+        self.with_connection(|connection| {
+            redis::cmd("SET")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .arg(encoded)
+                .arg("EX")
+                .arg(self.ttl.as_secs().max(1))
+                .query::<()>(connection)
+        });
+    }
+}
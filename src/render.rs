@@ -0,0 +1,556 @@
+//! Rendering code to a PNG, the pipeline behind `/generate` without any of
+//! the server around it: a `Config` in, the encoded image and what was
+//! decided while drawing it out.
+
+use image::codecs::png::{self, CompressionType, PngEncoder};
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, ImageEncoder};
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Style, Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::config::{Config, PngCompression, WindowStyle, DEFAULT_CODE_PAD_RIGHT};
+use crate::{ansi, badge, diff, fontcache, guides, highlight, redact, watermark, whitespace, window, wrap};
+
+/// An encoded image, with what the render decided along the way.
+#[derive(Debug, Clone)]
+pub struct RenderedImage {
+    /// The PNG.
+    pub bytes: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+
+    /// The name of the language the code was highlighted as, or those of
+    /// several snippets, joined by commas.
+    pub language: String,
+
+    /// How many spans were covered by redaction.
+    pub redactions: usize,
+
+    /// How much the window was scaled to fit a preset canvas, if one was
+    /// used.
+    pub preset_scale: Option<f32>,
+
+    /// Dimensions of the image before it was scaled down to fit
+    /// `max_output_width` and `max_output_height`, the same as `width` and
+    /// `height` when it wasn't.
+    pub original_width: u32,
+    pub original_height: u32,
+
+    /// How long highlighting and drawing took, and then encoding the PNG.
+    pub draw_time: Duration,
+    pub encode_time: Duration,
+}
+
+/// Limits of `max_output_width` and `max_output_height`, in pixels.
+pub const MIN_OUTPUT_SIZE: u32 = 16;
+pub const MAX_OUTPUT_SIZE: u32 = 8192;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    /// The language isn't known, or couldn't be detected.
+    #[error("{0}")]
+    UnknownLanguage(String),
+
+    /// The theme isn't known, or failed to load.
+    #[error("{0}")]
+    InvalidTheme(String),
+
+    #[error("Failed to highlight code")]
+    Highlight,
+
+    /// A stage of drawing the image failed.
+    #[error("{0}")]
+    Draw(&'static str),
+
+    #[error("Failed to write image")]
+    Encode,
+
+    /// The render was cancelled between stages.
+    #[error("The render was cancelled")]
+    Cancelled,
+}
+
+/// Renders `conf` with the syntaxes in `ps` and the themes in `ts`. The
+/// language is detected when `conf` doesn't give one, and the window title
+/// is drawn only when `conf` gives one.
+///
+/// ```
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let mut conf = inkify::Config::default();
+/// conf.code = "fn main() {}".to_owned();
+/// conf.language = Some("rs".to_owned());
+/// let image = inkify::render(&conf, &SyntaxSet::load_defaults_newlines(), &ThemeSet::load_defaults())?;
+/// assert_eq!(image.language, "Rust");
+/// assert!(image.width > 0 && image.height > 0);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn render(conf: &Config, ps: &SyntaxSet, ts: &ThemeSet) -> Result<RenderedImage, RenderError> {
+    let (syntax, _) = conf
+        .detect_language(ps)
+        .map_err(|e| RenderError::UnknownLanguage(e.to_string()))?;
+    let theme = conf
+        .theme(ts, None)
+        .map_err(|e| RenderError::InvalidTheme(e.to_string()))?;
+    draw(conf, syntax, &theme, ps, &|| false)
+}
+
+/// Renders `conf` as `syntax` with `theme`, checking `cancelled` between
+/// stages and giving up once it returns true.
+pub fn draw(
+    conf: &Config,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    ps: &SyntaxSet,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<RenderedImage, RenderError> {
+    let started = Instant::now();
+    let (mut image, redactions) = draw_code(conf, syntax, theme, ps, cancelled)?;
+    let mut preset_scale = None;
+    if conf.is_composited() {
+        let (decorated, scale) =
+            window::decorate(image, conf, theme).map_err(|_| RenderError::Draw("Failed to draw the window"))?;
+        image = decorated;
+        preset_scale = scale;
+    }
+    finish(image, conf, started, syntax.name.clone(), redactions, preset_scale, cancelled)
+}
+
+/// Renders several snippets, each with a config of its own and highlighted
+/// as its syntax, in windows arranged by `conf.layout` on one canvas. `conf`
+/// gives what they share, like the background, caption, and watermark, and
+/// every snippet's config is expected to share its theme and fonts.
+pub fn draw_snippets(
+    conf: &Config,
+    snippets: &[(Config, &SyntaxReference)],
+    theme: &Theme,
+    ps: &SyntaxSet,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<RenderedImage, RenderError> {
+    let started = Instant::now();
+    let mut windows = Vec::with_capacity(snippets.len());
+    let mut redactions = 0;
+    for (snippet, syntax) in snippets {
+        let (image, redacted) = draw_code(snippet, syntax, theme, ps, cancelled)?;
+        let window = window::frame(image, snippet, theme).map_err(|_| RenderError::Draw("Failed to draw the window"))?;
+        windows.push(window);
+        redactions += redacted;
+    }
+    let (image, preset_scale) =
+        window::arrange(windows, conf, theme).map_err(|_| RenderError::Draw("Failed to arrange the snippets"))?;
+    let languages: Vec<&str> = snippets.iter().map(|(_, syntax)| syntax.name.as_str()).collect();
+    finish(image, conf, started, languages.join(", "), redactions, preset_scale, cancelled)
+}
+
+/// Highlights and formats the code of `conf`, with everything drawn over
+/// it, but not the window around it. Also returns how many spans were
+/// redacted.
+fn draw_code(
+    conf: &Config,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    ps: &SyntaxSet,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<(DynamicImage, usize), RenderError> {
+    let check = || if cancelled() { Err(RenderError::Cancelled) } else { Ok(()) };
+    // Tabs are expanded before wrapping, redacting, or drawing guides so
+    // columns can be counted and measured. Visible whitespace expands them itself, after
+    // marking them.
+    // Terminal output is parsed first, leaving the text its escapes color,
+    // with its tabs already expanded.
+    let (source, ansi_runs) = if conf.ansi {
+        let parsed = ansi::parse(&conf.code, conf.tab_width);
+        (Cow::Owned(parsed.text), parsed.runs)
+    } else {
+        (Cow::Borrowed(conf.code.as_str()), vec![])
+    };
+    let measured = conf.max_width.is_some() || conf.redacts() || conf.indent_guides;
+    let code = if measured && conf.show_whitespace.is_none() {
+        wrap::expand_tabs(&source, conf.tab_width)
+    } else {
+        source.into_owned()
+    };
+    let (code, redacted) = if conf.redacts() {
+        redact::blank(&code, &conf.redacted_rows(), &conf.redact_patterns())
+    } else {
+        (code, vec![])
+    };
+    let diff_lines = if conf.diff { diff::parse(&code) } else { vec![] };
+    let highlight = {
+        let _span = tracing::debug_span!("highlight", language = %syntax.name, lines = code.lines().count()).entered();
+        if conf.ansi {
+            ansi::highlight(&code, &ansi_runs, theme)
+        } else if conf.diff {
+            diff::highlight(&diff_lines, syntax, theme, ps).map_err(|_| RenderError::Highlight)?
+        } else {
+            let mut h = HighlightLines::new(syntax, theme);
+            LinesWithEndings::from(code.as_ref())
+                .map(|line| {
+                    check()?;
+                    h.highlight_line(line, ps).map_err(|_| RenderError::Highlight)
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    check()?;
+    // Columns at the start of each line which aren't code, like a diff's
+    // prefix.
+    let skip = if conf.diff { 1 } else { 0 };
+    let mut highlight = match conf.show_whitespace {
+        Some(mode) => whitespace::mark(highlight, mode, conf.tab_width, skip, theme, &code, &redacted),
+        None => highlight,
+    };
+    if let Some(output) = &conf.output {
+        highlight.extend(output_rows(output, wrap::gutter_style(theme)));
+    }
+
+    let mut builder = conf
+        .get_formatter_builder()
+        .map_err(|_| RenderError::Draw("Failed to get formatter"))?;
+
+    let rows = wrap::wrap(&highlight, conf.max_width.unwrap_or(usize::MAX));
+    let own_gutter = owns_gutter(conf, &rows);
+    let gutter = gutter(conf, own_gutter, &rows, &diff_lines);
+    let guide_rows: Vec<usize> = if conf.indent_guides {
+        let indents = guides::indents(&code, conf.tab_width, skip);
+        rows.row_lines().iter().map(|line| indents.get(*line).copied().unwrap_or(0)).collect()
+    } else {
+        vec![]
+    };
+    let highlight_lines = conf.highlighted_rows();
+    let mut colored_rows = diff::tints(&diff_lines);
+    colored_rows.extend(conf.colored_rows());
+    let (lines, formatter_rows) = if own_gutter {
+        colored_rows = colored_rows
+            .into_iter()
+            .flat_map(|(line, color)| rows.highlight_lines(&[line]).into_iter().map(move |row| (row, color)))
+            .collect();
+        let formatter_rows = rows.highlight_lines(&highlight_lines);
+        builder = builder.line_number(false).highlight_lines(formatter_rows.clone());
+        (rows.into_lines(&gutter, wrap::gutter_style(theme)), formatter_rows)
+    } else {
+        builder = builder.highlight_lines(highlight_lines.clone());
+        (highlight, highlight_lines)
+    };
+    let blocks = redact::blocks(&lines, &code, &redacted);
+    check()?;
+
+    let key = conf
+        .formatter_key()
+        .map(|key| format!("{} {} {:?}", key, own_gutter, formatter_rows));
+    let mut image = tracing::debug_span!("format", theme = %conf.theme, rows = lines.len())
+        .in_scope(|| fontcache::with_formatter(key, || builder.build(), |formatter| formatter.format(&lines, theme)))
+        .map_err(|_| RenderError::Draw("Failed to get formatter"))?;
+    check()?;
+    if !colored_rows.is_empty() {
+        image = highlight::draw(image, &colored_rows, lines.len() as u32, conf)
+            .map_err(|_| RenderError::Draw("Failed to highlight lines"))?;
+    }
+    if conf.indent_guides {
+        let foreground = theme.settings.foreground.unwrap_or(syntect::highlighting::Color::WHITE);
+        let color = conf.indent_guide_color.unwrap_or(image::Rgba([
+            foreground.r,
+            foreground.g,
+            foreground.b,
+            guides::DEFAULT_OPACITY,
+        ]));
+        image = guides::draw(image, &guide_rows, &gutter, skip, color, conf)
+            .map_err(|_| RenderError::Draw("Failed to draw indent guides"))?;
+    }
+    if !blocks.is_empty() {
+        image = redact::draw(image, &blocks, lines.len() as u32, conf)
+            .map_err(|_| RenderError::Draw("Failed to redact lines"))?;
+    }
+    if conf.show_language {
+        image = badge::draw(image, &syntax.name, lines.len() as u32, conf, theme)
+            .map_err(|_| RenderError::Draw("Failed to draw the language badge"))?;
+    }
+    Ok((image, redacted.len()))
+}
+
+/// Draws the watermark over the finished `image`, then fits and encodes it.
+fn finish(
+    mut image: DynamicImage,
+    conf: &Config,
+    started: Instant,
+    language: String,
+    redactions: usize,
+    preset_scale: Option<f32>,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<RenderedImage, RenderError> {
+    if let Some(mark) = &conf.watermark {
+        image = watermark::draw(image, mark, conf).map_err(|_| RenderError::Draw("Failed to draw the watermark"))?;
+    }
+    if cancelled() {
+        return Err(RenderError::Cancelled);
+    }
+    let (original_width, original_height) = (image.width(), image.height());
+    let image = fit(image, conf.max_output_width, conf.max_output_height);
+    let (width, height) = (image.width(), image.height());
+    let draw_time = started.elapsed();
+
+    let started = Instant::now();
+    let encode_span = tracing::debug_span!("encode", width, height, bytes = tracing::field::Empty);
+    let bytes = encode_span.in_scope(|| encode(&image, conf.png_compression))?;
+    encode_span.record("bytes", bytes.len());
+    let encode_time = started.elapsed();
+
+    Ok(RenderedImage {
+        bytes,
+        width,
+        height,
+        language,
+        redactions,
+        preset_scale,
+        original_width,
+        original_height,
+        draw_time,
+        encode_time,
+    })
+}
+
+/// The size of an image `estimate` works out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Estimate {
+    pub width: u32,
+    pub height: u32,
+    /// A rough guess at the size of the PNG, which depends on the pixels.
+    pub bytes: usize,
+}
+
+/// Silicon's layout of the bare window, mirrored by `estimate`: the space
+/// either side of the line numbers, the space its title bar takes above the
+/// code, and the least width of a window.
+const LINE_NUMBER_PAD: u32 = 6;
+const TITLE_BAR_PAD: u32 = 50;
+const MIN_WINDOW_WIDTH: u32 = 150;
+
+/// Works out the size `conf` renders at from the font metrics, without
+/// highlighting or drawing anything, to within a pixel or two. Like
+/// `render`, the window title is drawn only when `conf` gives one.
+pub fn estimate(conf: &Config) -> Result<Estimate, RenderError> {
+    let fonts = fontcache::collection(&conf.title_font()).map_err(|_| RenderError::Draw("Failed to load fonts"))?;
+    // Silicon replaces each tab with spaces, where `draw` expands them to
+    // the next tab stop itself when measuring.
+    let measured = conf.max_width.is_some() || conf.redacts() || conf.indent_guides;
+    let code = if conf.ansi {
+        ansi::parse(&conf.code, conf.tab_width).text
+    } else if measured || conf.show_whitespace.is_some() {
+        wrap::expand_tabs(&conf.code, conf.tab_width)
+    } else {
+        conf.code.replace('\t', &" ".repeat(conf.tab_width as usize))
+    };
+    let diff_lines = if conf.diff { diff::parse(&code) } else { vec![] };
+    let plain = Style::default();
+    let mut text: Vec<Vec<(Style, &str)>> = if conf.diff {
+        diff_lines
+            .iter()
+            .map(|line| match line.kind {
+                diff::Kind::Meta => vec![(plain, line.text)],
+                _ => vec![(plain, line.prefix), (plain, line.text)],
+            })
+            .collect()
+    } else {
+        LinesWithEndings::from(&code).map(|line| vec![(plain, line)]).collect()
+    };
+
+    if let Some(output) = &conf.output {
+        text.extend(output_rows(output, plain));
+    }
+    let rows = wrap::wrap(&text, conf.max_width.unwrap_or(usize::MAX));
+    let own_gutter = owns_gutter(conf, &rows);
+    let gutter = gutter(conf, own_gutter, &rows, &diff_lines);
+    let lines = if own_gutter { rows.into_lines(&gutter, plain) } else { text };
+    let row_count = lines.len() as u32;
+
+    let left = highlight::CODE_PAD
+        + if own_gutter || conf.no_line_number {
+            0
+        } else {
+            let digits = (((row_count + conf.line_offset) as f32).log10() + 1.0).floor() as usize;
+            2 * LINE_NUMBER_PAD + fonts.get_text_len(&format!("{:>width$}", 0, width = digits))
+        };
+    let code_width = lines
+        .iter()
+        .map(|row| {
+            let text: String = row.iter().map(|(_, token)| token.trim_end_matches('\n')).collect();
+            left + fonts.get_text_len(&text)
+        })
+        .max()
+        .unwrap_or(left);
+    let title_bar = conf.window_style == WindowStyle::Mac && (!conf.no_window_controls || conf.window_title.is_some());
+    let pitch = fonts.get_font_height() + conf.scaled(conf.line_pad);
+    let window = (
+        (code_width + conf.scaled(DEFAULT_CODE_PAD_RIGHT)).max(MIN_WINDOW_WIDTH),
+        pitch * row_count + 2 * highlight::CODE_PAD + if title_bar { TITLE_BAR_PAD } else { 0 },
+    );
+
+    let (width, height) = if conf.is_composited() {
+        window::decorated_size(window, conf).map_err(|_| RenderError::Draw("Failed to draw the window"))?
+    } else {
+        conf.canvas_size(window.0, window.1)
+    };
+    let (width, height) = fit_size(width, height, conf.max_output_width, conf.max_output_height);
+    Ok(Estimate {
+        width,
+        height,
+        bytes: estimate_bytes(width, height, conf),
+    })
+}
+
+/// Bytes per pixel of typical renders at each compression level, over a
+/// solid background and over a gradient or image, which compress worse.
+fn estimate_bytes(width: u32, height: u32, conf: &Config) -> usize {
+    let per_pixel = match conf.png_compression {
+        PngCompression::Fast => 0.5,
+        PngCompression::Default => 0.35,
+        PngCompression::Best => 0.3,
+    };
+    let busy = conf.background_gradient.is_some() || conf.background_image.is_some();
+    let per_pixel = if busy { per_pixel * 3.0 } else { per_pixel };
+    (width as f64 * height as f64 * per_pixel).round() as usize
+}
+
+/// Whether the gutter is drawn as part of the code rather than by silicon.
+/// Silicon numbers every row it's given consecutively, so it is when lines
+/// are wrapped, ranges of lines skipped, a diff numbered on both sides, or
+/// output drawn below the code.
+/// So it is when redacting or drawing guides, to measure where the code
+/// starts.
+fn owns_gutter(conf: &Config, rows: &wrap::Wrapped) -> bool {
+    let skips_lines = conf.line_range.as_ref().is_some_and(|ranges| ranges.len() > 1);
+    let measures = conf.redacts() || conf.indent_guides;
+    conf.diff || measures || rows.is_wrapped() || skips_lines || conf.output.is_some()
+}
+
+/// The rows of `output`, all in `style`, after a blank row setting them
+/// apart from the code.
+fn output_rows(output: &str, style: Style) -> impl Iterator<Item = Vec<(Style, &str)>> {
+    std::iter::once(vec![(style, "\n")]).chain(LinesWithEndings::from(output).map(move |line| vec![(style, line)]))
+}
+
+/// The gutter of each row, when it's drawn as part of the code.
+fn gutter(conf: &Config, own_gutter: bool, rows: &wrap::Wrapped, diff_lines: &[diff::Line]) -> Vec<String> {
+    if !own_gutter || conf.no_line_number {
+        vec![]
+    } else if conf.diff {
+        rows.gutter_with(&diff::gutter(diff_lines))
+    } else {
+        rows.gutter(&conf.line_numbers())
+    }
+}
+
+/// Scales `image` down with Lanczos3, keeping its aspect ratio, until it's
+/// no wider than `max_width` and no taller than `max_height`. Images which
+/// already fit are left alone. Combined with `scale`, this renders text at
+/// a higher resolution than it's delivered at, which makes it crisper.
+fn fit(image: DynamicImage, max_width: Option<u32>, max_height: Option<u32>) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let (fitted_width, fitted_height) = fit_size(width, height, max_width, max_height);
+    if (fitted_width, fitted_height) == (width, height) {
+        return image;
+    }
+    image.resize_exact(fitted_width, fitted_height, FilterType::Lanczos3)
+}
+
+/// The size `fit` scales an image `width` by `height` to.
+fn fit_size(width: u32, height: u32, max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    let factor = [
+        max_width.map(|max| max as f64 / width as f64),
+        max_height.map(|max| max as f64 / height as f64),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+    if factor >= 1.0 {
+        return (width, height);
+    }
+    let fitted_width = ((width as f64 * factor).round() as u32).max(1);
+    let fitted_height = ((height as f64 * factor).round() as u32).max(1);
+    (fitted_width, fitted_height)
+}
+
+/// Encodes `image` as a PNG. The encoder's settings are always given
+/// explicitly, rather than left to the image crate's defaults, so the same
+/// pixels encode to the same bytes across versions of it.
+fn encode(image: &DynamicImage, compression: PngCompression) -> Result<Vec<u8>, RenderError> {
+    let (compression, filter) = match compression {
+        PngCompression::Fast => (CompressionType::Fast, png::FilterType::Sub),
+        PngCompression::Default => (CompressionType::Default, png::FilterType::Adaptive),
+        PngCompression::Best => (CompressionType::Best, png::FilterType::Adaptive),
+    };
+    let pixels = match image.as_rgba8() {
+        Some(pixels) => Cow::Borrowed(pixels),
+        None => Cow::Owned(image.to_rgba8()),
+    };
+    // Sized for a compressed image of around a byte per pixel, so growing
+    // the buffer while encoding is rare without reserving the raw size.
+    let mut bytes = Vec::with_capacity(pixels.width() as usize * pixels.height() as usize);
+    PngEncoder::new_with_quality(&mut bytes, compression, filter)
+        .write_image(pixels.as_raw(), pixels.width(), pixels.height(), ColorType::Rgba8)
+        .map_err(|_| RenderError::Encode)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assets() -> (SyntaxSet, ThemeSet) {
+        (SyntaxSet::load_defaults_newlines(), ThemeSet::load_defaults())
+    }
+
+    fn conf(code: &str, language: Option<&str>) -> Config {
+        let mut conf = Config::default();
+        conf.code = code.to_owned();
+        conf.language = language.map(str::to_owned);
+        conf
+    }
+
+    #[test]
+    fn render_encodes_a_png_of_its_size() {
+        let (ps, ts) = assets();
+        let image = render(&conf("fn main() {}\n", Some("rs")), &ps, &ts).unwrap();
+        assert!(image.bytes.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let decoded = image::load_from_memory(&image.bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (image.width, image.height));
+        assert_eq!(image.language, "Rust");
+    }
+
+    #[test]
+    fn render_detects_the_language() {
+        let (ps, ts) = assets();
+        let image = render(&conf("#!/usr/bin/env python3\nprint('hi')\n", None), &ps, &ts).unwrap();
+        assert_eq!(image.language, "Python");
+    }
+
+    #[test]
+    fn render_refuses_unknown_languages_and_themes() {
+        let (ps, ts) = assets();
+        let unknown = render(&conf("x", Some("not-a-language")), &ps, &ts);
+        assert!(matches!(unknown, Err(RenderError::UnknownLanguage(_))));
+
+        let mut themed = conf("x", Some("txt"));
+        themed.theme = "Not A Theme".to_owned();
+        assert!(matches!(render(&themed, &ps, &ts), Err(RenderError::InvalidTheme(_))));
+    }
+
+    #[test]
+    fn window_decorations_grow_the_image() {
+        let (ps, ts) = assets();
+        let mut bare = conf("let x = 1;\n", Some("rs"));
+        bare.pad_horiz = 0;
+        bare.pad_vert = 0;
+        let mut padded = bare.clone();
+        padded.pad_horiz = 80;
+        padded.pad_vert = 100;
+        let bare = render(&bare, &ps, &ts).unwrap();
+        let padded = render(&padded, &ps, &ts).unwrap();
+        assert!(padded.width > bare.width && padded.height > bare.height);
+    }
+}
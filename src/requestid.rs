@@ -0,0 +1,92 @@
+//! An ID for every request, to correlate what a client saw with the server's
+//! logs. It's taken from the request's `X-Request-Id` when it sends a usable
+//! one, then from the trace ID of a W3C `traceparent`, so the logs line up
+//! with the upstream trace, and is otherwise generated as a UUIDv7, which
+//! sorts by time.
+//!
+//! The ID is a field of the span every log line of the request is written
+//! in, is returned in the `X-Request-Id` header, and is available to the
+//! request's task, where `/v1` error bodies include it.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderName;
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The longest incoming ID accepted, beyond which one is generated instead.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The IDs of a request, and of the trace it's part of, if any.
+#[derive(Debug, Clone)]
+pub struct RequestIds {
+    pub request_id: String,
+    pub trace_id: Option<String>,
+    pub parent_span_id: Option<String>,
+}
+
+impl RequestIds {
+    pub fn from_request(req: &ServiceRequest) -> Self {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+        let traceparent = header("traceparent").and_then(parse_traceparent);
+        let request_id = header(HEADER.as_str())
+            .map(str::trim)
+            .filter(|id| is_valid(id))
+            .map(str::to_owned)
+            .or_else(|| traceparent.as_ref().map(|(trace_id, _)| trace_id.clone()))
+            .unwrap_or_else(generate);
+        let (trace_id, parent_span_id) = traceparent.unzip();
+        RequestIds {
+            request_id,
+            trace_id,
+            parent_span_id,
+        }
+    }
+}
+
+/// A new, time-ordered ID.
+pub fn generate() -> String {
+    uuid::Uuid::now_v7().to_string()
+}
+
+/// Runs `fut` with `id` as the current request's ID.
+pub async fn scope<F: std::future::Future>(id: String, fut: F) -> F::Output {
+    REQUEST_ID.scope(id, fut).await
+}
+
+/// The ID of the request whose task this is, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Incoming IDs are logged and echoed, so they're kept short and printable.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// The trace ID and parent span ID of a `traceparent` header, in the
+/// `00-<trace-id>-<parent-id>-<flags>` format, or `None` when it's
+/// malformed or the IDs are all zeros, as the spec requires. Versions past
+/// `00` may add fields, which are ignored.
+fn parse_traceparent(header: &str) -> Option<(String, String)> {
+    let mut parts = header.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    if version.len() != 2 || !is_hex(version) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    let valid = |id: &str, len: usize| id.len() == len && is_hex(id) && id.bytes().any(|b| b != b'0');
+    if !valid(trace_id, 32) || !valid(parent_id, 16) || flags.len() != 2 || !is_hex(flags) {
+        return None;
+    }
+    Some((trace_id.to_owned(), parent_id.to_owned()))
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
@@ -38,8 +38,9 @@ impl std::fmt::Display for Rgba {
     }
 }
 
-fn parse_str_color(s: &str) -> Result<Rgba, Error> {
+/// Parses a hex color, eg. `#282a36` or `#00000000`.
+pub fn parse_str_color(s: &str) -> Result<Rgba, Error> {
     let rgba = s.to_rgba()
-        .map_err(|e| Error::msg(format!("Invalid color: {}", e)))?;
+        .map_err(|_| Error::msg(format!("Invalid color: `{}`", s)))?;
     Ok(Rgba(rgba))
 }
\ No newline at end of file
@@ -0,0 +1,251 @@
+//! Uploads of images generated with `store=true` to an S3 compatible bucket,
+//! enabled by `INKIFY_S3_BUCKET`. Objects are named after the parameter
+//! hash, so identical requests overwrite the same object rather than adding
+//! another, and their URL is either under `INKIFY_S3_PUBLIC_URL`, eg. a CDN
+//! in front of the bucket, or presigned.
+//!
+//! Requests are signed with AWS Signature Version 4 and use path-style
+//! addressing, which AWS, MinIO, R2, and the like all accept.
+
+use anyhow::Error;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cache::Rendered;
+use crate::settings::{env_parse_required, env_string, fail};
+
+/// Lifetime of presigned URLs when `INKIFY_S3_PRESIGN_EXPIRY_SECS` isn't set.
+const DEFAULT_PRESIGN_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// The longest S3 accepts.
+const MAX_PRESIGN_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+pub struct S3Store {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    public_url: Option<String>,
+    presign_expiry: Duration,
+}
+
+impl S3Store {
+    /// Configures the bucket from the `INKIFY_S3_*` variables, if
+    /// `INKIFY_S3_BUCKET` is set. Incomplete or invalid settings stop
+    /// startup.
+    pub fn from_env() -> Option<Self> {
+        let bucket = env_string("INKIFY_S3_BUCKET")?;
+        let region = env_string("INKIFY_S3_REGION").unwrap_or_else(|| "us-east-1".to_owned());
+        let endpoint = env_string("INKIFY_S3_ENDPOINT").unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = reqwest::Url::parse(&endpoint)
+            .map_err(Error::from)
+            .and_then(|url| match url.host_str() {
+                Some(_) if url.query().is_none() => Ok(url),
+                _ => Err(format_err!("`{}` should be a URL with a host and no query", endpoint)),
+            })
+            .unwrap_or_else(|e| fail("INKIFY_S3_ENDPOINT", e));
+        let access_key_id = env_string("INKIFY_S3_ACCESS_KEY_ID")
+            .or_else(|| env_string("AWS_ACCESS_KEY_ID"))
+            .unwrap_or_else(|| fail("INKIFY_S3_ACCESS_KEY_ID", "required with INKIFY_S3_BUCKET"));
+        let secret_access_key = env_string("INKIFY_S3_SECRET_ACCESS_KEY")
+            .or_else(|| env_string("AWS_SECRET_ACCESS_KEY"))
+            .unwrap_or_else(|| fail("INKIFY_S3_SECRET_ACCESS_KEY", "required with INKIFY_S3_BUCKET"));
+        let presign_expiry = env_parse_required("INKIFY_S3_PRESIGN_EXPIRY_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_PRESIGN_EXPIRY);
+        if presign_expiry.is_zero() || presign_expiry > MAX_PRESIGN_EXPIRY {
+            fail::<()>("INKIFY_S3_PRESIGN_EXPIRY_SECS", "should be between 1 and 604800");
+        }
+        let client = reqwest::Client::builder()
+            .timeout(UPLOAD_TIMEOUT)
+            .build()
+            .unwrap_or_else(|e| fail("INKIFY_S3_ENDPOINT", e));
+        tracing::info!(endpoint = %endpoint, bucket = %bucket, "Storing images in S3");
+        Some(S3Store {
+            client,
+            endpoint,
+            bucket,
+            prefix: env_string("INKIFY_S3_PREFIX").unwrap_or_default(),
+            region,
+            access_key_id,
+            secret_access_key,
+            public_url: env_string("INKIFY_S3_PUBLIC_URL").map(|url| url.trim_end_matches('/').to_owned()),
+            presign_expiry,
+        })
+    }
+
+    /// Uploads `rendered` as the object for the parameter hash `key`, and
+    /// returns the URL it can be fetched from.
+    pub async fn upload(&self, key: &str, rendered: &Rendered) -> Result<String, Error> {
+        let object = format!("{}{}.png", self.prefix, key);
+        let path = self.path(&object);
+        let host = host(&self.endpoint);
+        let payload_hash = hex(&Sha256::digest(&rendered.bytes));
+        let now = SystemTime::now();
+        let (date, timestamp) = amz_date(now);
+        // The object only ever holds this image, so it can be cached for good.
+        let cache_control = "public, max-age=31536000, immutable";
+
+        let headers = [
+            ("cache-control", cache_control),
+            ("content-type", rendered.content_type.as_str()),
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", timestamp.as_str()),
+        ];
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "PUT\n{}\n\n{}\n{}\n{}",
+            path, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = self.scope(&date);
+        let signature = self.sign(&date, &timestamp, &scope, &canonical_request);
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.access_key_id, scope, signed_headers, signature
+        );
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let response = self
+            .client
+            .put(url)
+            .header("Authorization", authorization)
+            .header("Cache-Control", cache_control)
+            .header("Content-Type", rendered.content_type.as_str())
+            .header("X-Amz-Content-Sha256", payload_hash)
+            .header("X-Amz-Date", timestamp)
+            .body(rendered.bytes.clone())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format_err!("{} uploading {}: {}", status, object, body.trim()));
+        }
+
+        Ok(match &self.public_url {
+            Some(public_url) => format!("{}/{}", public_url, encode_path(&object)),
+            None => self.presign(&path, now),
+        })
+    }
+
+    /// A presigned GET URL of the object at `path`.
+    fn presign(&self, path: &str, now: SystemTime) -> String {
+        let host = host(&self.endpoint);
+        let (date, timestamp) = amz_date(now);
+        let scope = self.scope(&date);
+        // Already in the sorted order the signature requires.
+        let query = format!(
+            "X-Amz-Algorithm={}&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            ALGORITHM,
+            encode(&format!("{}/{}", self.access_key_id, scope), true),
+            timestamp,
+            self.presign_expiry.as_secs()
+        );
+        let canonical_request = format!("GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", path, query, host);
+        let signature = self.sign(&date, &timestamp, &scope, &canonical_request);
+        let mut url = self.endpoint.clone();
+        url.set_path(path);
+        url.set_query(Some(&format!("{}&X-Amz-Signature={}", query, signature)));
+        url.to_string()
+    }
+
+    /// The encoded path of `object`, below any path of the endpoint.
+    fn path(&self, object: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            encode(&self.bucket, true),
+            encode_path(object)
+        )
+    }
+
+    fn scope(&self, date: &str) -> String {
+        format!("{}/{}/s3/aws4_request", date, self.region)
+    }
+
+    fn sign(&self, date: &str, timestamp: &str, scope: &str, canonical_request: &str) -> String {
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_access_key).into_bytes(), |key, part| {
+                hmac(&key, part.as_bytes())
+            });
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    }
+}
+
+/// The endpoint's host, with the port when it isn't the scheme's default.
+fn host(url: &reqwest::Url) -> String {
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => String::new(),
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes everything but the unreserved characters, and slashes
+/// too unless `slash` is false, as Signature Version 4 requires.
+fn encode(s: &str, slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b'/' if !slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn encode_path(object: &str) -> String {
+    encode(object, false)
+}
+
+/// The date, eg. 20240131, and timestamp, eg. 20240131T120000Z, of `time`
+/// in UTC.
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Converts days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!("{}T{:02}{:02}{:02}Z", date, secs / 3600, secs % 3600 / 60, secs % 60);
+    (date, timestamp)
+}
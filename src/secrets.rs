@@ -0,0 +1,67 @@
+//! Well-known secret formats, for the `auto_redact` parameter. Each rule
+//! matches the secret's value in a `secret` group, so only the value is
+//! redacted and not the key or the rest of the line.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// Names of the rules, for `auto_redact_allow`. `private_key` is handled by
+/// `private_key_lines`, since a key spans several lines.
+pub const NAMES: &[&str] = &[
+    "aws_access_key",
+    "aws_secret_key",
+    "github_token",
+    "slack_token",
+    "private_key",
+    "generic_assignment",
+];
+
+lazy_static! {
+    static ref RULES: Vec<(&'static str, Regex)> = [
+        ("aws_access_key", r"\b(?P<secret>(?:AKIA|ASIA)[0-9A-Z]{16})\b"),
+        (
+            "aws_secret_key",
+            r#"(?i)aws_?secret_?access_?key["']?\s*[:=]\s*["']?(?P<secret>[A-Za-z0-9/+=]{40})"#,
+        ),
+        (
+            "github_token",
+            r"\b(?P<secret>gh[pousr]_[A-Za-z0-9]{36,255}|github_pat_[A-Za-z0-9_]{22,255})\b",
+        ),
+        ("slack_token", r"\b(?P<secret>xox[abposr]-[A-Za-z0-9-]{10,})\b"),
+        (
+            "generic_assignment",
+            r#"(?i)\b(?:password|passwd|pwd|secret|api_?key|access_?token|auth_?token)["']?\s*[:=]\s*["']?(?P<secret>[^\s"'`,;]{4,})"#,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| (name, Regex::new(pattern).unwrap()))
+    .collect();
+    static ref PRIVATE_KEY_BEGIN: Regex = Regex::new(r"-----BEGIN (?:[A-Z0-9]+ )*PRIVATE KEY( BLOCK)?-----").unwrap();
+    static ref PRIVATE_KEY_END: Regex = Regex::new(r"-----END (?:[A-Z0-9]+ )*PRIVATE KEY( BLOCK)?-----").unwrap();
+}
+
+/// The single line patterns of the rules not in `allow`.
+pub fn patterns(allow: &[String]) -> Vec<&'static Regex> {
+    RULES
+        .iter()
+        .filter(|(name, _)| !allow.iter().any(|allowed| allowed == name))
+        .map(|(_, pattern)| pattern)
+        .collect()
+}
+
+/// The 1-based lines of `code` between the header and footer of a private
+/// key, which hold the key itself. The header and footer are left visible.
+pub fn private_key_lines(code: &str) -> Vec<u32> {
+    let mut lines = vec![];
+    let mut in_key = false;
+    for (i, line) in code.lines().enumerate() {
+        if PRIVATE_KEY_BEGIN.is_match(line) {
+            in_key = true;
+        } else if PRIVATE_KEY_END.is_match(line) {
+            in_key = false;
+        } else if in_key {
+            lines.push(i as u32 + 1);
+        }
+    }
+    lines
+}
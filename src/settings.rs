@@ -0,0 +1,334 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::batch;
+use crate::config::PngCompression;
+use crate::cors::AllowedOrigin;
+use crate::detector;
+use crate::fetch::FetchPolicy;
+use crate::jobs;
+use crate::metrics::StatsAccess;
+use crate::ratelimit;
+use crate::rediscache;
+
+/// Fallback fonts used when `INKIFY_FALLBACK_FONTS` isn't set.
+const DEFAULT_FALLBACK_FONTS: &[&str] = &["Noto Sans CJK SC", "Noto Color Emoji"];
+
+/// Server-side settings, read once from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Value for the `Sunset` header sent on legacy (unprefixed) routes, eg.
+    /// 'Sat, 31 Dec 2024 23:59:59 GMT'. No header is sent when unset.
+    pub legacy_sunset: Option<String>,
+
+    /// Reject unknown query parameters on `/v1` routes.
+    pub v1_strict: bool,
+
+    /// Timeout for fetching remote resources such as `background_image`.
+    pub fetch_timeout: Duration,
+
+    /// Maximum size of a fetched remote resource, in bytes.
+    pub max_download_bytes: usize,
+
+    /// Allow fetching URLs which resolve to private or internal addresses.
+    pub allow_private_urls: bool,
+
+    /// How often the metrics history is sampled.
+    pub stats_interval: Duration,
+
+    /// Number of samples kept in the metrics history.
+    pub stats_history_len: usize,
+
+    /// Who may see /stats and /stats/history.
+    pub stats: StatsAccess,
+
+    /// Maximum number of rendered images kept in memory. 0 disables caching.
+    pub cache_entries: usize,
+
+    /// Maximum total size of the rendered images kept in memory, in bytes.
+    pub cache_max_bytes: usize,
+
+    /// Bounds of the cache of fetched background and watermark images.
+    pub image_cache_entries: usize,
+    pub image_cache_max_bytes: usize,
+
+    /// How long a fetched image is reused before it's revalidated, at most.
+    pub image_cache_ttl: Duration,
+
+    /// How long a failed fetch is remembered, and answered with the same
+    /// error without fetching again.
+    pub image_cache_failure_ttl: Duration,
+
+    /// `max-age` of the Cache-Control header sent with generated images.
+    pub http_max_age: u64,
+
+    /// Requests allowed per client IP and the window they are counted over,
+    /// eg. '60/minute'. Applies to /generate and /detect.
+    pub rate_limit: Option<(u32, Duration)>,
+
+    /// Read the client IP from `X-Forwarded-For`/`Forwarded` headers.
+    pub trust_proxy: bool,
+
+    /// API keys accepted by /generate and /detect. Authentication is
+    /// disabled when empty.
+    pub api_keys: Vec<String>,
+
+    /// Secret /generate URLs are signed with. Signing is disabled without
+    /// it.
+    pub signing_secret: Option<String>,
+
+    /// Token required by the /admin endpoints, which aren't served without
+    /// it.
+    pub admin_token: Option<String>,
+
+    /// Log requests as JSON lines rather than human readable text.
+    pub log_json: bool,
+
+    /// Maximum number of renders running on the blocking thread pool at
+    /// once.
+    pub max_blocking_renders: usize,
+
+    /// Maximum number of `/detect` predictions running at once, separate
+    /// from the renders.
+    pub max_blocking_detections: usize,
+
+    /// How long a request waits for a free render or detection slot before
+    /// a 503.
+    pub queue_timeout: Duration,
+
+    /// Maximum size of the code to render, in bytes.
+    pub max_code_bytes: usize,
+
+    /// Token used for GitHub API requests, raising the API rate limit.
+    pub github_token: Option<String>,
+
+    /// Watermark text drawn on every image, replacing any requested one.
+    pub force_watermark: Option<String>,
+
+    /// Image file drawn as the watermark on every image, replacing any
+    /// requested one, including `force_watermark`.
+    pub watermark_path: Option<String>,
+
+    /// How long the language detection model may take on one snippet.
+    pub detection_timeout: Duration,
+
+    /// Lowest confidence of the detection model, from 0 to 1, accepted
+    /// when `strict_language` is passed.
+    pub strict_language_threshold: f32,
+
+    /// Redact well-known secrets in every image, as if `auto_redact` were
+    /// always passed.
+    pub force_auto_redact: bool,
+
+    /// Embed the generation parameters in every PNG, so /decode can read
+    /// them back.
+    pub embed_params: bool,
+
+    /// Accept themes given inline with `theme_b64`, parsing them per request.
+    pub allow_inline_themes: bool,
+
+    /// Directory `theme` may name .tmTheme files in, canonicalized. Themes
+    /// are only read from the filesystem when it's set.
+    pub theme_files_dir: Option<PathBuf>,
+
+    /// Font families appended to every font list, for text the requested
+    /// fonts can't draw. Only those installed are used.
+    pub fallback_fonts: Vec<String>,
+
+    /// Language used when detection finds none and the request doesn't pass
+    /// `fallback_language`. Plain text when unset.
+    pub fallback_language: Option<String>,
+
+    /// How long a render may take before it's abandoned with a 503.
+    pub render_timeout: Duration,
+
+    /// How hard the PNG encoder works when the request doesn't pass
+    /// `png_compression`.
+    pub png_compression: PngCompression,
+
+    /// Serve Swagger UI at /docs.
+    pub swagger_ui: bool,
+
+    /// Serve the playground at /playground.
+    pub playground: bool,
+
+    /// The most items of a batch.
+    pub max_batch_size: usize,
+
+    /// How long the results of async jobs are kept.
+    pub job_ttl: Duration,
+
+    /// Redis server to share rendered images through, eg.
+    /// 'redis://127.0.0.1:6379/0'.
+    pub redis_url: Option<String>,
+
+    /// How long images stay in Redis.
+    pub redis_ttl: Duration,
+
+    /// Compress responses other than images for clients which accept it.
+    pub compression: bool,
+
+    /// Origins allowed to make cross-origin requests. No CORS headers are
+    /// sent when empty.
+    pub cors_origins: Vec<AllowedOrigin>,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Settings {
+            legacy_sunset: env_string("INKIFY_LEGACY_SUNSET"),
+            v1_strict: env_parse("INKIFY_V1_STRICT").unwrap_or(true),
+            fetch_timeout: Duration::from_millis(
+                env_parse("INKIFY_FETCH_TIMEOUT_MS").unwrap_or(10_000),
+            ),
+            max_download_bytes: env_parse("INKIFY_MAX_DOWNLOAD_BYTES").unwrap_or(5 * 1024 * 1024),
+            allow_private_urls: env_parse("INKIFY_ALLOW_PRIVATE_URLS").unwrap_or(false),
+            stats_interval: Duration::from_secs(
+                env_parse::<u64>("INKIFY_STATS_INTERVAL_SECS").unwrap_or(10).max(1),
+            ),
+            stats_history_len: env_parse("INKIFY_STATS_HISTORY_LEN").unwrap_or(360),
+            stats: match env_parse_required("INKIFY_STATS").unwrap_or_default() {
+                StatsAccess::Admin if env_string("INKIFY_ADMIN_TOKEN").is_none() => {
+                    fail("INKIFY_STATS", "`admin` requires INKIFY_ADMIN_TOKEN to be set")
+                }
+                access => access,
+            },
+            cache_entries: env_parse("INKIFY_CACHE_ENTRIES").unwrap_or(256),
+            cache_max_bytes: env_parse("INKIFY_CACHE_MAX_BYTES").unwrap_or(64 * 1024 * 1024),
+            image_cache_entries: env_parse("INKIFY_IMAGE_CACHE_ENTRIES").unwrap_or(64),
+            image_cache_max_bytes: env_parse("INKIFY_IMAGE_CACHE_MAX_BYTES").unwrap_or(64 * 1024 * 1024),
+            image_cache_ttl: Duration::from_secs(env_parse("INKIFY_IMAGE_CACHE_TTL_SECS").unwrap_or(3600)),
+            image_cache_failure_ttl: Duration::from_secs(
+                env_parse("INKIFY_IMAGE_CACHE_FAILURE_TTL_SECS").unwrap_or(60),
+            ),
+            http_max_age: env_parse("INKIFY_HTTP_MAX_AGE").unwrap_or(86400),
+            rate_limit: env_string("INKIFY_RATE_LIMIT").map(|rate| {
+                ratelimit::parse_rate(&rate).unwrap_or_else(|e| fail("INKIFY_RATE_LIMIT", e))
+            }),
+            trust_proxy: env_parse("INKIFY_TRUST_PROXY").unwrap_or(false),
+            api_keys: env_list("INKIFY_API_KEYS"),
+            signing_secret: env_string("INKIFY_SIGNING_SECRET"),
+            admin_token: env_string("INKIFY_ADMIN_TOKEN"),
+            log_json: env_string("INKIFY_LOG_FORMAT")
+                .map(|format| format.eq_ignore_ascii_case("json"))
+                .unwrap_or(false),
+            max_blocking_renders: env_parse::<usize>("INKIFY_MAX_BLOCKING_RENDERS")
+                .unwrap_or_else(default_parallelism)
+                .max(1),
+            max_blocking_detections: env_parse::<usize>("INKIFY_MAX_BLOCKING_DETECTIONS")
+                .unwrap_or_else(|| default_parallelism() / 2)
+                .max(1),
+            queue_timeout: Duration::from_millis(env_parse("INKIFY_QUEUE_TIMEOUT_MS").unwrap_or(10_000)),
+            max_code_bytes: env_parse("INKIFY_MAX_CODE_BYTES").unwrap_or(1024 * 1024),
+            github_token: env_string("INKIFY_GITHUB_TOKEN"),
+            force_watermark: env_string("INKIFY_FORCE_WATERMARK"),
+            watermark_path: env_string("INKIFY_WATERMARK_PATH"),
+            detection_timeout: env_parse("INKIFY_DETECTION_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(detector::DEFAULT_TIMEOUT),
+            strict_language_threshold: env_parse::<f32>("INKIFY_STRICT_LANGUAGE_THRESHOLD")
+                .unwrap_or(0.5)
+                .clamp(0.0, 1.0),
+            force_auto_redact: env_parse_required("INKIFY_FORCE_AUTO_REDACT").unwrap_or(false),
+            embed_params: env_parse("INKIFY_EMBED_PARAMS").unwrap_or(true),
+            allow_inline_themes: env_parse("INKIFY_ALLOW_INLINE_THEMES").unwrap_or(true),
+            fallback_language: env_string("INKIFY_FALLBACK_LANGUAGE"),
+            render_timeout: Duration::from_millis(env_parse("INKIFY_RENDER_TIMEOUT_MS").unwrap_or(15_000)),
+            png_compression: env_parse_required("INKIFY_PNG_COMPRESSION").unwrap_or_default(),
+            swagger_ui: env_parse("INKIFY_SWAGGER_UI").unwrap_or(true),
+            playground: env_parse("INKIFY_PLAYGROUND").unwrap_or(true),
+            max_batch_size: env_parse::<usize>("INKIFY_MAX_BATCH_SIZE")
+                .unwrap_or(batch::DEFAULT_MAX_SIZE)
+                .max(1),
+            job_ttl: env_parse("INKIFY_JOB_TTL_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(jobs::DEFAULT_TTL),
+            redis_url: env_string("INKIFY_REDIS_URL"),
+            redis_ttl: env_parse("INKIFY_REDIS_TTL_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(rediscache::DEFAULT_TTL),
+            compression: env_parse("INKIFY_COMPRESSION").unwrap_or(true),
+            cors_origins: env_list("INKIFY_CORS_ORIGINS")
+                .iter()
+                .map(|origin| AllowedOrigin::parse(origin).unwrap_or_else(|e| fail("INKIFY_CORS_ORIGINS", e)))
+                .collect(),
+            fallback_fonts: match env_string("INKIFY_FALLBACK_FONTS") {
+                Some(fonts) if fonts.eq_ignore_ascii_case("none") => vec![],
+                Some(fonts) => fonts
+                    .split(';')
+                    .map(|font| font.trim().to_owned())
+                    .filter(|font| !font.is_empty())
+                    .collect(),
+                None => DEFAULT_FALLBACK_FONTS.iter().map(|font| font.to_string()).collect(),
+            },
+            theme_files_dir: env_string("INKIFY_THEME_FILES_DIR").map(|dir| {
+                std::fs::canonicalize(dir).unwrap_or_else(|e| fail("INKIFY_THEME_FILES_DIR", e))
+            }),
+        }
+    }
+
+    pub fn fetch_policy(&self) -> FetchPolicy {
+        FetchPolicy {
+            timeout: self.fetch_timeout,
+            max_bytes: self.max_download_bytes,
+            allow_private: self.allow_private_urls,
+        }
+    }
+}
+
+pub fn env_string(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+}
+
+/// Reads a comma-separated list, dropping empty entries.
+pub fn env_list(name: &str) -> Vec<String> {
+    env_string(name)
+        .map(|s| {
+            s.split(',')
+                .map(|item| item.trim().to_owned())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    let value = env_string(name)?;
+    match value.parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            eprintln!("Ignoring invalid value for {}: `{}`", name, value);
+            None
+        }
+    }
+}
+
+/// Like `env_parse`, but a value which doesn't parse stops startup.
+pub fn env_parse_required<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = env_string(name)?;
+    Some(
+        value
+            .parse::<T>()
+            .unwrap_or_else(|e| fail(name, format!("`{}`: {}", value, e))),
+    )
+}
+
+/// Aborts startup for a setting which is present but invalid, where silently
+/// falling back to a default would be surprising.
+pub fn fail<T>(name: &str, err: impl std::fmt::Display) -> T {
+    eprintln!("Invalid value for {}: {}", name, err);
+    std::process::exit(1)
+}
+
+pub fn default_parallelism() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
@@ -0,0 +1,91 @@
+//! Graceful shutdown. On SIGTERM or SIGINT the server stops accepting
+//! connections and `/readyz` starts failing, then in-flight renders get a
+//! grace period to finish before the workers are stopped.
+
+use actix_web::dev::ServerHandle;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// How long in-flight requests may take to finish when
+/// `--shutdown-timeout` isn't given.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+static BEGUN: AtomicBool = AtomicBool::new(false);
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a shutdown signal has been received.
+pub fn begun() -> bool {
+    BEGUN.load(Ordering::Relaxed)
+}
+
+/// Counts a render as in flight while it's alive.
+pub struct InFlight(());
+
+impl InFlight {
+    pub fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        InFlight(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Stops `servers` gracefully on the first SIGTERM or SIGINT. Their own
+/// signal handling has to be disabled, and their shutdown timeout set to
+/// `timeout`, after which the renders still running are aborted.
+pub fn spawn(servers: Vec<ServerHandle>, timeout: Duration) {
+    actix_web::rt::spawn(async move {
+        let signal = wait_for_signal().await;
+        BEGUN.store(true, Ordering::Relaxed);
+        tracing::info!(
+            signal,
+            in_flight = IN_FLIGHT.load(Ordering::Relaxed),
+            ?timeout,
+            "Shutting down, draining in-flight requests"
+        );
+
+        let stop = futures_util::future::join_all(servers.iter().map(|server| server.stop(true)));
+        futures_util::pin_mut!(stop);
+        if actix_web::rt::time::timeout(timeout, &mut stop).await.is_err() {
+            tracing::warn!(
+                aborted = IN_FLIGHT.load(Ordering::Relaxed),
+                "Aborting renders which outlived the shutdown grace period"
+            );
+            stop.await;
+        }
+        tracing::info!("Shut down");
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    use futures_util::future::Either;
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to listen for SIGTERM");
+            let _ = actix_web::rt::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    let sigterm = terminate.recv();
+    let sigint = actix_web::rt::signal::ctrl_c();
+    futures_util::pin_mut!(sigterm, sigint);
+    match futures_util::future::select(sigterm, sigint).await {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = actix_web::rt::signal::ctrl_c().await;
+    "SIGINT"
+}
@@ -0,0 +1,196 @@
+//! Signed `/generate` URLs, enabled by `INKIFY_SIGNING_SECRET`, so URLs
+//! embedded in public pages can't be altered to render other images.
+//!
+//! The signature is the hex HMAC-SHA256, keyed with the secret, of the
+//! request path without trailing slashes, a newline, and the canonical
+//! query string: every parameter but `sig`, decoded, sorted by name then
+//! value, and re-encoded as `name=value` pairs joined by `&`, with every
+//! byte but `A-Z a-z 0-9 - _ . ~` percent-encoded in uppercase, including
+//! spaces as `%20`. Signing the path keeps a URL signed for one route, eg.
+//! `/generate`, from working on another like `/v1/generate` or `/estimate`.
+//! An optional `expires` parameter, a unix timestamp, is signed like any
+//! other and bounds how long the URL works.
+
+use actix_web::http::StatusCode;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::ApiError;
+use crate::auth::constant_time_eq;
+
+/// The parameter holding the signature.
+pub const SIG: &str = "sig";
+
+/// The parameter holding the unix timestamp the URL expires at.
+pub const EXPIRES: &str = "expires";
+
+/// The path `signature` is computed over: `path` without trailing slashes.
+pub fn canonical_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    }
+}
+
+/// The query string `signature` is computed over.
+pub fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| name != SIG)
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The signature of a request for `path` with `query`, with `secret`, in
+/// lowercase hex.
+pub fn signature(secret: &str, path: &str, query: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(canonical_path(path).as_bytes());
+    mac.update(b"\n");
+    mac.update(canonical_query(query).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Signs `url`, eg. `/generate?code=fn%20main()`, appending `expires` when
+/// given and then `sig`. Any `sig` already there is replaced. An absolute
+/// URL is signed for its path.
+pub fn sign_url(secret: &str, url: &str, expires: Option<u64>) -> String {
+    let (target, query) = url.split_once('?').unwrap_or((url, ""));
+    let path = match target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |at| &rest[at..]),
+        None => target,
+    };
+    let mut params = form_urlencoded::Serializer::new(String::new());
+    for (name, value) in form_urlencoded::parse(query.as_bytes()).filter(|(name, _)| name != SIG) {
+        params.append_pair(&name, &value);
+    }
+    if let Some(expires) = expires {
+        params.append_pair(EXPIRES, &expires.to_string());
+    }
+    let query = params.finish();
+    let sig = signature(secret, path, &query);
+    if query.is_empty() {
+        format!("{}?{}={}", target, SIG, sig)
+    } else {
+        format!("{}?{}&{}={}", target, query, SIG, sig)
+    }
+}
+
+/// Checks the signature and expiry of a request for `path` with `query`,
+/// responding with a 403 when either is missing or invalid.
+pub fn verify(secret: &str, path: &str, query: &str, now: SystemTime) -> Result<(), ApiError> {
+    let param = |wanted: &str| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == wanted)
+            .map(|(_, value)| value.into_owned())
+    };
+    let sig = param(SIG).ok_or_else(|| {
+        ApiError::new(StatusCode::FORBIDDEN, "missing_signature", "This server requires signed URLs, pass sig")
+    })?;
+    if !constant_time_eq(sig.to_ascii_lowercase().as_bytes(), signature(secret, path, query).as_bytes()) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "invalid_signature", "The signature doesn't match the URL"));
+    }
+    if let Some(expires) = param(EXPIRES) {
+        let expires: u64 = expires.parse().map_err(|_| {
+            ApiError::new(StatusCode::FORBIDDEN, "invalid_signature", "expires should be a unix timestamp")
+        })?;
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now > expires {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "expired_signature", "The signed URL has expired"));
+        }
+    }
+    Ok(())
+}
+
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SECRET: &str = "secret";
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn split(url: &str) -> (&str, &str) {
+        url.split_once('?').unwrap()
+    }
+
+    fn code(result: Result<(), ApiError>) -> String {
+        let body = result.unwrap_err().body(crate::api::ApiVersion::V1);
+        body["error"]["code"].as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn canonical_query_sorts_and_encodes() {
+        assert_eq!(canonical_query("theme=Nord&code=fn+main()&sig=abc"), "code=fn%20main%28%29&theme=Nord");
+    }
+
+    #[test]
+    fn signed_urls_verify() {
+        let url = sign_url(SECRET, "/generate?code=fn+main()&theme=Nord", None);
+        let (path, query) = split(&url);
+        assert_eq!(path, "/generate");
+        verify(SECRET, path, query, at(0)).unwrap();
+        verify(SECRET, "/generate/", query, at(0)).unwrap();
+    }
+
+    #[test]
+    fn absolute_urls_are_signed_for_their_path() {
+        let url = sign_url(SECRET, "https://example.com/v1/generate?code=x", None);
+        assert!(url.starts_with("https://example.com/v1/generate?code=x&sig="));
+        let (_, query) = split(&url);
+        verify(SECRET, "/v1/generate", query, at(0)).unwrap();
+    }
+
+    #[test]
+    fn expired_urls_are_refused() {
+        let url = sign_url(SECRET, "/generate?code=x", Some(100));
+        let (path, query) = split(&url);
+        verify(SECRET, path, query, at(100)).unwrap();
+        assert_eq!(code(verify(SECRET, path, query, at(101))), "expired_signature");
+    }
+
+    #[test]
+    fn tampered_urls_are_refused() {
+        let url = sign_url(SECRET, "/generate?code=x", Some(100));
+        let (path, query) = split(&url);
+        let edited = query.replace("code=x", "code=y");
+        assert_eq!(code(verify(SECRET, path, &edited, at(0))), "invalid_signature");
+        let extended = query.replace("expires=100", "expires=200");
+        assert_eq!(code(verify(SECRET, path, &extended, at(0))), "invalid_signature");
+        assert_eq!(code(verify("other", path, query, at(0))), "invalid_signature");
+        assert_eq!(code(verify(SECRET, path, "code=x", at(0))), "missing_signature");
+    }
+
+    #[test]
+    fn signatures_only_work_on_the_signed_path() {
+        let url = sign_url(SECRET, "/generate?code=x", None);
+        let (_, query) = split(&url);
+        for path in ["/v1/generate", "/estimate", "/generate/batch", "/"] {
+            assert_eq!(code(verify(SECRET, path, query, at(0))), "invalid_signature", "{}", path);
+        }
+    }
+}
@@ -0,0 +1,678 @@
+//! Resolves the code to render from the parameters which can supply it.
+
+use actix_web::http::StatusCode;
+use std::borrow::Cow;
+use std::io::Read;
+use syntect::parsing::SyntaxSet;
+
+use crate::api::ApiError;
+use crate::config::language_from_filename;
+use crate::fetch;
+use crate::query::ConfigQuery;
+use crate::{aliases, ansi, markdown};
+
+/// What is known about where the code came from, beyond its contents.
+#[derive(Debug, Default, Clone)]
+pub struct CodeSource {
+    /// File name of the code, when it came from a remote file.
+    pub filename: Option<String>,
+
+    /// Something about the code the client should know, returned in the
+    /// `X-Warning` header.
+    pub warning: Option<&'static str>,
+}
+
+/// Fills in `info.code` from whichever source parameter was given, and infers
+/// `info.language` from the source's file name when it wasn't given. The
+/// code of `info.snippets` is tidied in their place instead.
+pub async fn resolve(info: &mut ConfigQuery, ps: &SyntaxSet) -> Result<CodeSource, ApiError> {
+    let settings = &*crate::SETTINGS;
+    let mut source = CodeSource::default();
+
+    let given: Vec<&str> = [
+        ("code", !info.code.is_empty()),
+        ("code_b64", info.code_b64.is_some()),
+        ("code_gz", info.code_gz.is_some()),
+        ("code_url", info.code_url.is_some()),
+        ("gist", info.gist.is_some()),
+        ("github", info.github.is_some()),
+        ("snippets", info.snippets.is_some()),
+    ]
+    .iter()
+    .filter(|(_, given)| *given)
+    .map(|(name, _)| *name)
+    .collect();
+    if given.len() > 1 {
+        return Err(ApiError::bad_request(
+            "conflicting_parameters",
+            format!("{} are mutually exclusive, pass only one of them", given.join(" and ")),
+        ));
+    }
+
+    decode_inline(info)?;
+    if info.snippets.is_some() {
+        tidy_snippets(info)?;
+        return Ok(source);
+    }
+
+    if let Some(code_url) = info.code_url.clone() {
+        let mut policy = settings.fetch_policy();
+        policy.max_bytes = policy.max_bytes.min(settings.max_code_bytes);
+        let fetched = fetch::fetch(&code_url, &policy)
+            .await
+            .map_err(|e| e.into_api_error("code_url", &code_url))?;
+        info.code = String::from_utf8(fetched.bytes).map_err(|_| {
+            ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_code",
+                format!("code_url `{}` did not return UTF-8 text", code_url),
+            )
+        })?;
+        source.filename = filename_from_url(&code_url);
+        if info.window_title.is_none() {
+            info.window_title = source.filename.clone();
+        }
+    }
+
+    if let Some(gist) = info.gist.clone() {
+        let (filename, content) = fetch_gist(&gist, info.gist_file.as_deref()).await?;
+        info.code = content;
+        if info.window_title.is_none() {
+            info.window_title = Some(filename.clone());
+        }
+        source.filename = Some(filename);
+    }
+
+    if let Some(github) = info.github.clone() {
+        let permalink = parse_github_permalink(&github).ok_or_else(|| {
+            ApiError::bad_request(
+                "invalid_github_url",
+                format!(
+                    "`{}` is not a GitHub file URL, expected https://github.com/<owner>/<repo>/blob/<ref>/<path>[#L<start>[-L<end>]]",
+                    github
+                ),
+            )
+        })?;
+        let code = fetch_github_file(&permalink, &github).await?;
+        info.code = match permalink.lines {
+            Some((start, end)) => {
+                let total = code.lines().count();
+                if start > total {
+                    return Err(ApiError::bad_request(
+                        "invalid_line_range",
+                        format!("Line {} is past the end of the file ({} lines)", start, total),
+                    ));
+                }
+                if info.line_offset.is_none() {
+                    info.line_offset = Some(start as u32);
+                }
+                code.lines()
+                    .skip(start - 1)
+                    .take(end.min(total) + 1 - start)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            None => code,
+        };
+        if info.window_title.is_none() {
+            info.window_title = Some(permalink.path.clone());
+        }
+        source.filename = permalink.path.rsplit('/').next().map(|name| name.to_owned());
+    }
+
+    source.warning = tidy(info, ps)?;
+
+    if info.code.is_empty() {
+        return Err(ApiError::bad_request("missing_code", "code parameter is required"));
+    }
+    if info.code.len() > settings.max_code_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "code_too_large",
+            format!("code exceeds the {} byte limit", settings.max_code_bytes),
+        ));
+    }
+
+    // A notebook's language is the kernel's, not that of its `.ipynb` name.
+    if info.language.is_none() && info.notebook_cell.is_none() {
+        if let Some(filename) = &source.filename {
+            info.language = language_from_filename(filename, ps);
+        }
+    }
+
+    Ok(source)
+}
+
+/// Decodes code passed inline in an encoded form into `info.code`. Shared by
+/// /generate and /detect.
+pub fn decode_inline(info: &mut ConfigQuery) -> Result<(), ApiError> {
+    if let Some(code_b64) = info.code_b64.take() {
+        if !info.code.is_empty() {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "code and code_b64 are mutually exclusive, pass only one of them",
+            ));
+        }
+        let bytes = decode_base64(&code_b64).ok_or_else(|| {
+            ApiError::bad_request("invalid_code_b64", "code_b64 is not valid base64")
+        })?;
+        info.code = String::from_utf8(bytes).map_err(|_| {
+            ApiError::bad_request("invalid_code_b64", "code_b64 does not decode to UTF-8 text")
+        })?;
+    }
+    if let Some(code_gz) = info.code_gz.take() {
+        if !info.code.is_empty() {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                "code and code_gz are mutually exclusive, pass only one of them",
+            ));
+        }
+        let bytes = decode_base64(&code_gz).ok_or_else(|| {
+            ApiError::bad_request("invalid_code_gz", "code_gz is not valid base64")
+        })?;
+        let bytes = inflate(&bytes, crate::SETTINGS.max_code_bytes)?;
+        info.code = String::from_utf8(bytes).map_err(|_| {
+            ApiError::bad_request("invalid_code_gz", "code_gz does not decompress to UTF-8 text")
+        })?;
+    }
+    Ok(())
+}
+
+/// Applies the `strip_ansi`, `markdown`, `dedent` and `trim` parameters to
+/// `info.code`. Unless `raw` is set, line endings are normalized first, and
+/// the last line is ended with a newline, so every line is highlighted
+/// alike. Shared by /generate and /detect.
+///
+/// Returns a warning for the response when Markdown had no code block to
+/// extract.
+pub fn tidy(info: &mut ConfigQuery, ps: &SyntaxSet) -> Result<Option<&'static str>, ApiError> {
+    if info.strip_ansi == Some(true) {
+        if let Cow::Owned(code) = ansi::strip(&info.code) {
+            info.code = code;
+        }
+    }
+    let raw = info.raw == Some(true);
+    if !raw {
+        if let Cow::Owned(code) = normalize(&info.code) {
+            info.code = code;
+        }
+    }
+    if info.markdown == Some(true) && info.notebook_cell.is_some() {
+        return Err(ApiError::bad_request(
+            "conflicting_parameters",
+            "markdown and notebook_cell are mutually exclusive, pass only one of them",
+        ));
+    }
+    let warning = if info.markdown == Some(true) {
+        extract_code_block(info, ps)?
+    } else {
+        None
+    };
+    info.code = tidy_lines(info, std::mem::take(&mut info.code));
+    Ok(warning)
+}
+
+/// Tidies the code of each of `info.snippets` as `tidy` does `info.code`,
+/// and checks that every snippet has some, within the limit on all of it.
+fn tidy_snippets(info: &mut ConfigQuery) -> Result<(), ApiError> {
+    for (name, given) in [
+        ("markdown", info.markdown == Some(true)),
+        ("notebook_cell", info.notebook_cell.is_some()),
+        ("line_range", info.line_range.is_some()),
+    ] {
+        if given {
+            return Err(ApiError::bad_request(
+                "conflicting_parameters",
+                format!("snippets and {} are mutually exclusive, pass only one of them", name),
+            ));
+        }
+    }
+    let mut snippets = info.snippets.take().unwrap_or_default();
+    for (i, snippet) in snippets.iter_mut().enumerate() {
+        let mut code = std::mem::take(&mut snippet.code);
+        if info.strip_ansi == Some(true) {
+            if let Cow::Owned(stripped) = ansi::strip(&code) {
+                code = stripped;
+            }
+        }
+        snippet.code = tidy_lines(info, code);
+        if snippet.code.trim().is_empty() {
+            return Err(ApiError::bad_request("missing_code", format!("Snippet {} has no code", i)));
+        }
+    }
+    info.snippets = Some(snippets);
+
+    let max_bytes = crate::SETTINGS.max_code_bytes;
+    if info.code_len() > max_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "code_too_large",
+            format!(
+                "The snippets' code adds up to {} bytes, over the {} byte limit",
+                info.code_len(),
+                max_bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Applies the `dedent` and `trim` parameters to `code`, and ends its last
+/// line unless `raw` is set. Also used for the cells `notebook_cell` picks
+/// out of a notebook once it's been tidied.
+pub fn tidy_lines(info: &ConfigQuery, mut code: String) -> String {
+    let raw = info.raw == Some(true);
+    if !raw {
+        if let Cow::Owned(normalized) = normalize(&code) {
+            code = normalized;
+        }
+    }
+    if info.dedent == Some(true) {
+        code = dedent(&code, info.tab_width.unwrap_or(4));
+    }
+    if info.trim == Some(true) {
+        code = trim_blank_lines(&code).to_owned();
+    }
+    if !raw && !code.is_empty() && !code.ends_with('\n') {
+        code.push('\n');
+    }
+    code
+}
+
+/// Replaces Markdown in `info.code` with its `block_index`th fenced code
+/// block, whose info string gives the language unless one was given and
+/// the syntax set knows it. Without any block, the Markdown is left to be
+/// rendered as it is, with a warning.
+fn extract_code_block(info: &mut ConfigQuery, ps: &SyntaxSet) -> Result<Option<&'static str>, ApiError> {
+    let mut blocks = markdown::code_blocks(&info.code);
+    if blocks.is_empty() {
+        return Ok(Some("No fenced code block found, rendering the input as is"));
+    }
+    let index = info.block_index.unwrap_or(0);
+    if index >= blocks.len() {
+        return Err(ApiError::bad_request(
+            "invalid_block_index",
+            format!(
+                "block_index {} is out of range, the Markdown has {} fenced code block{}",
+                index,
+                blocks.len(),
+                if blocks.len() == 1 { "" } else { "s" }
+            ),
+        ));
+    }
+    let block = blocks.swap_remove(index);
+    if info.language.is_none() {
+        info.language = block
+            .language()
+            .filter(|language| ps.find_syntax_by_token(aliases::resolve(language)).is_some())
+            .map(str::to_owned);
+    }
+    info.code = block.code;
+    Ok(None)
+}
+
+/// Strips a leading byte order mark and turns `\r\n` and lone `\r` line
+/// endings into `\n`, as in code pasted from Windows or old Mac files, so
+/// carriage returns aren't drawn and the first line is seen by detection.
+fn normalize(code: &str) -> Cow<'_, str> {
+    let stripped = code.strip_prefix('\u{feff}').unwrap_or(code);
+    if stripped.len() == code.len() && !stripped.contains('\r') {
+        return Cow::Borrowed(code);
+    }
+    Cow::Owned(stripped.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// Strips the indentation common to every non-blank line. Tabs in the
+/// indentation are expanded to `tab_width` first, so mixed tabs and spaces
+/// are compared by width.
+fn dedent(code: &str, tab_width: u8) -> String {
+    let lines: Vec<String> = code.lines().map(|line| expand_indent(line, tab_width)).collect();
+    let common = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or(0);
+    let mut out = lines
+        .iter()
+        .map(|line| line.get(common..).filter(|_| !line.trim().is_empty()).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if code.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+
+/// Replaces the tabs in a line's indentation with spaces.
+fn expand_indent(line: &str, tab_width: u8) -> String {
+    let tab_width = tab_width.max(1) as usize;
+    let rest = line.trim_start_matches([' ', '\t']);
+    let mut out = String::with_capacity(line.len());
+    for c in line[..line.len() - rest.len()].chars() {
+        match c {
+            '\t' => out.extend(std::iter::repeat(' ').take(tab_width - out.len() % tab_width)),
+            c => out.push(c),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Drops blank lines from the start and end of `code`.
+fn trim_blank_lines(code: &str) -> &str {
+    let mut start = 0;
+    for line in code.split_inclusive('\n') {
+        if !line.trim().is_empty() {
+            break;
+        }
+        start += line.len();
+    }
+    code[start..].trim_end()
+}
+
+/// Decompresses a gzip, zlib, or raw deflate stream, detected from its
+/// header. Reading stops just past `max_bytes`, so a small payload can't
+/// inflate into an arbitrarily large allocation.
+fn inflate(bytes: &[u8], max_bytes: usize) -> Result<Vec<u8>, ApiError> {
+    use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+    let limit = max_bytes as u64 + 1;
+    let mut out = vec![];
+    let read = match bytes {
+        [0x1f, 0x8b, ..] => GzDecoder::new(bytes).take(limit).read_to_end(&mut out),
+        [0x78, ..] => ZlibDecoder::new(bytes).take(limit).read_to_end(&mut out),
+        _ => DeflateDecoder::new(bytes).take(limit).read_to_end(&mut out),
+    };
+    read.map_err(|_| {
+        ApiError::bad_request("invalid_code_gz", "code_gz is not a valid gzip or deflate stream")
+    })?;
+    if out.len() > max_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "code_too_large",
+            format!("code_gz decompresses to more than {} bytes", max_bytes),
+        ));
+    }
+    Ok(out)
+}
+
+/// Decodes standard or URL-safe base64, with or without padding. Spaces are
+/// read as `+`, since an unescaped `+` in a query string decodes to a space.
+pub fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+    use base64::Engine;
+
+    let s: String = s
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('+'),
+            '\n' | '\r' | '\t' => None,
+            c => Some(c),
+        })
+        .collect();
+    [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD]
+        .iter()
+        .find_map(|engine| engine.decode(&s).ok())
+}
+
+/// Returns the last path segment of `url`, if it has one.
+pub fn filename_from_url(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    url.path_segments()?
+        .last()
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_owned())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Gist {
+    files: std::collections::BTreeMap<String, GistFile>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct GistFile {
+    filename: String,
+    raw_url: String,
+    content: Option<String>,
+    #[serde(default)]
+    truncated: bool,
+}
+
+/// Extracts the gist ID from either a bare ID or a gist URL such as
+/// `https://gist.github.com/user/0123abcd`.
+pub fn parse_gist_id(gist: &str) -> Option<String> {
+    let gist = gist.trim().trim_end_matches('/');
+    let id = gist.rsplit('/').next()?;
+    let id = id.split(|c| c == '#' || c == '?').next()?;
+    let id = id.trim_end_matches(".git");
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(id.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Fetches a gist through the GitHub API, returning the selected file's name
+/// and contents. Defaults to the first file, in the order GitHub lists them.
+async fn fetch_gist(gist: &str, file: Option<&str>) -> Result<(String, String), ApiError> {
+    let settings = &*crate::SETTINGS;
+    let id = parse_gist_id(gist).ok_or_else(|| {
+        ApiError::bad_request("invalid_gist", format!("`{}` is not a gist ID or URL", gist))
+    })?;
+
+    let mut headers = vec![("Accept", "application/vnd.github+json".to_owned())];
+    if let Some(token) = &settings.github_token {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+    let url = format!("https://api.github.com/gists/{}", id);
+    let fetched = fetch::fetch_with_headers(&url, &settings.fetch_policy(), &headers)
+        .await
+        .map_err(|e| match e {
+            fetch::FetchError::Status(404) => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "gist_not_found",
+                format!("Gist `{}` does not exist or is not accessible", id),
+            ),
+            fetch::FetchError::Status(403) | fetch::FetchError::Status(429) => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "github_rate_limited",
+                "The GitHub API rate limit was exceeded, try again later",
+            ),
+            e => e.into_api_error("gist", gist),
+        })?;
+
+    let gist: Gist = serde_json::from_slice(&fetched.bytes).map_err(|e| {
+        ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_gist",
+            format!("Unexpected response from the GitHub API: {}", e),
+        )
+    })?;
+
+    let selected = match file {
+        Some(name) => gist.files.get(name).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "gist_file_not_found",
+                format!(
+                    "Gist `{}` has no file `{}`, available files: {}",
+                    id,
+                    name,
+                    gist.files.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            )
+        })?,
+        None => gist.files.values().next().ok_or_else(|| {
+            ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_gist", format!("Gist `{}` has no files", id))
+        })?,
+    };
+
+    // Large files are truncated in the API response and have to be fetched
+    // from their raw URL.
+    let content = match &selected.content {
+        Some(content) if !selected.truncated => content.clone(),
+        _ => {
+            let mut policy = settings.fetch_policy();
+            policy.max_bytes = policy.max_bytes.min(settings.max_code_bytes);
+            let raw = fetch::fetch(&selected.raw_url, &policy)
+                .await
+                .map_err(|e| e.into_api_error("gist", gist_url(&id).as_str()))?;
+            String::from_utf8(raw.bytes).map_err(|_| {
+                ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_code",
+                    format!("Gist file `{}` is not UTF-8 text", selected.filename),
+                )
+            })?
+        }
+    };
+
+    Ok((selected.filename.clone(), content))
+}
+
+fn gist_url(id: &str) -> String {
+    format!("https://gist.github.com/{}", id)
+}
+
+/// A parsed `https://github.com/<owner>/<repo>/blob/<ref>/<path>#L<a>-L<b>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GithubPermalink {
+    pub owner: String,
+    pub repo: String,
+    pub reference: String,
+    pub path: String,
+    /// Inclusive, 1-based line range from the fragment.
+    pub lines: Option<(usize, usize)>,
+}
+
+impl GithubPermalink {
+    pub fn raw_url(&self) -> String {
+        format!(
+            "https://raw.githubusercontent.com/{}/{}/{}/{}",
+            self.owner, self.repo, self.reference, self.path
+        )
+    }
+}
+
+pub fn parse_github_permalink(url: &str) -> Option<GithubPermalink> {
+    let url = reqwest::Url::parse(url.trim()).ok()?;
+    if url.host_str()? != "github.com" && url.host_str()? != "www.github.com" {
+        return None;
+    }
+    let segments: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+    // owner / repo / blob / ref / path...
+    if segments.len() < 5 || segments[2] != "blob" {
+        return None;
+    }
+
+    let lines = match url.fragment() {
+        Some(fragment) if !fragment.is_empty() => Some(parse_line_fragment(fragment)?),
+        _ => None,
+    };
+
+    Some(GithubPermalink {
+        owner: segments[0].to_owned(),
+        repo: segments[1].to_owned(),
+        reference: segments[3].to_owned(),
+        path: segments[4..].join("/"),
+        lines,
+    })
+}
+
+/// Parses `L10`, `L10-L42`, or the column form `L10C5-L42C3`.
+fn parse_line_fragment(fragment: &str) -> Option<(usize, usize)> {
+    let line = |s: &str| -> Option<usize> {
+        let s = s.strip_prefix('L')?;
+        let digits = s.split('C').next()?;
+        digits.parse::<usize>().ok().filter(|n| *n > 0)
+    };
+    match fragment.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (line(start)?, line(end)?);
+            (start <= end).then_some((start, end))
+        }
+        None => line(fragment).map(|n| (n, n)),
+    }
+}
+
+async fn fetch_github_file(permalink: &GithubPermalink, github: &str) -> Result<String, ApiError> {
+    let settings = &*crate::SETTINGS;
+    let mut headers = vec![];
+    if let Some(token) = &settings.github_token {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+    let mut policy = settings.fetch_policy();
+    policy.max_bytes = policy.max_bytes.min(settings.max_code_bytes);
+    let fetched = fetch::fetch_with_headers(&permalink.raw_url(), &policy, &headers)
+        .await
+        .map_err(|e| match e {
+            fetch::FetchError::Status(404) => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "github_file_not_found",
+                format!("`{}` does not exist or is not accessible", github),
+            ),
+            e => e.into_api_error("github", github),
+        })?;
+    String::from_utf8(fetched.bytes).map_err(|_| {
+        ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_code",
+            format!("`{}` is not UTF-8 text", permalink.path),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: serde_json::Value) -> ConfigQuery {
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[test]
+    fn normalize_leaves_unix_text_borrowed() {
+        assert!(matches!(normalize("a\nb\n"), Cow::Borrowed("a\nb\n")));
+    }
+
+    #[test]
+    fn normalize_turns_crlf_into_lf() {
+        assert_eq!(normalize("fn main() {\r\n}\r\n"), "fn main() {\n}\n");
+    }
+
+    #[test]
+    fn normalize_turns_lone_cr_into_lf() {
+        assert_eq!(normalize("a\rb\r"), "a\nb\n");
+        assert_eq!(normalize("a\r\rb"), "a\n\nb");
+        assert_eq!(normalize("a\r\n\rb"), "a\n\nb");
+    }
+
+    #[test]
+    fn normalize_strips_a_leading_bom() {
+        assert_eq!(normalize("\u{feff}#!/bin/sh\n"), "#!/bin/sh\n");
+        assert_eq!(normalize("\u{feff}a\r\nb"), "a\nb");
+        // Only a leading one is a byte order mark.
+        assert_eq!(normalize("a\u{feff}b"), "a\u{feff}b");
+    }
+
+    #[test]
+    fn tidy_lines_normalizes_and_ends_the_last_line() {
+        let info = query(serde_json::json!({}));
+        assert_eq!(tidy_lines(&info, "\u{feff}a\r\nb".to_owned()), "a\nb\n");
+        assert_eq!(tidy_lines(&info, "a\rb\r".to_owned()), "a\nb\n");
+        assert_eq!(tidy_lines(&info, String::new()), "");
+    }
+
+    #[test]
+    fn tidy_lines_leaves_raw_code_alone() {
+        let info = query(serde_json::json!({ "raw": true }));
+        assert_eq!(tidy_lines(&info, "\u{feff}a\r\nb".to_owned()), "\u{feff}a\r\nb");
+    }
+
+    #[test]
+    fn tidy_lines_dedents_and_trims_crlf_code() {
+        let info = query(serde_json::json!({ "dedent": true, "trim": true }));
+        assert_eq!(tidy_lines(&info, "\r\n    a\r\n      b\r\n\r\n".to_owned()), "a\n  b\n");
+    }
+}
@@ -0,0 +1,173 @@
+//! Images stored under short random IDs by `store=true`, for `--storage-dir`,
+//! so a link can point at `/i/{id}` rather than carry every parameter.
+//! Entries expire `--storage-ttl` after they were stored, and the oldest are
+//! evicted to stay under `--storage-max-bytes`.
+//!
+//! Each entry is a file named after its ID, holding the image as serialized
+//! by `Rendered::encode`.
+
+use anyhow::Error;
+use rand::Rng;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cache::Rendered;
+
+/// Entry lifetime when `--storage-ttl` isn't given.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Size budget when `--storage-max-bytes` isn't given.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How often expired and over-budget entries are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const EXTENSION: &str = "inkify";
+
+const ID_LEN: usize = 8;
+
+const ID_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Attempts at picking an ID which isn't taken.
+const ID_ATTEMPTS: usize = 5;
+
+pub struct Storage {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+}
+
+impl Storage {
+    /// Opens the storage in `dir`, creating it if needed.
+    pub fn open(dir: &Path, ttl: Duration, max_bytes: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Storage {
+            dir: dir.to_owned(),
+            ttl,
+            max_bytes,
+        })
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Stores `rendered` under a new ID, which is returned. Blocks on IO.
+    pub fn store(&self, rendered: &Rendered) -> Result<String, Error> {
+        let encoded = rendered.encode()?;
+        for _ in 0..ID_ATTEMPTS {
+            let id = new_id();
+            let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+            file.write_all(&encoded)?;
+            match file.persist_noclobber(self.path(&id)) {
+                Ok(_) => return Ok(id),
+                Err(e) if e.error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.error.into()),
+            }
+        }
+        Err(format_err!("No free ID after {} attempts", ID_ATTEMPTS))
+    }
+
+    /// The image stored under `id`, unless it's unknown or expired. Blocks
+    /// on IO.
+    pub fn get(&self, id: &str) -> Option<Rendered> {
+        if !valid_id(id) {
+            return None;
+        }
+        let path = self.path(id);
+        let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+        if self.expired(modified, SystemTime::now()) {
+            remove(&path);
+            return None;
+        }
+        let read = std::fs::read(&path)
+            .map_err(Error::from)
+            .and_then(|encoded| Rendered::decode(&encoded));
+        match read {
+            Ok(rendered) => Some(rendered),
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Failed to read stored image");
+                None
+            }
+        }
+    }
+
+    /// Deletes expired entries, then the oldest until the rest fit the
+    /// budget. Blocks on IO.
+    pub fn sweep(&self) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let modified = metadata.modified().unwrap_or(now);
+            if self.expired(modified, now) {
+                remove(&path);
+            } else {
+                entries.push((modified, metadata.len(), path));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            remove(&path);
+            total -= len;
+        }
+        Ok(())
+    }
+
+    /// Sweeps the storage on a thread of its own, every minute.
+    pub fn spawn_sweeper(&'static self) {
+        let spawned = std::thread::Builder::new()
+            .name("inkify-storage-sweep".to_owned())
+            .spawn(move || loop {
+                if let Err(e) = self.sweep() {
+                    tracing::warn!(dir = %self.dir.display(), error = %e, "Failed to sweep the image storage");
+                }
+                std::thread::sleep(SWEEP_INTERVAL);
+            });
+        if let Err(e) = spawned {
+            tracing::warn!(error = %e, "Failed to start the image storage sweeper");
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, EXTENSION))
+    }
+
+    fn expired(&self, modified: SystemTime, now: SystemTime) -> bool {
+        now.duration_since(modified).is_ok_and(|age| age > self.ttl)
+    }
+}
+
+fn new_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ID_LEN)
+        .map(|_| ID_CHARS[rng.gen_range(0..ID_CHARS.len())] as char)
+        .collect()
+}
+
+/// Whether `id` could have been made by `new_id`, which also keeps it from
+/// naming a path outside the storage.
+fn valid_id(id: &str) -> bool {
+    id.len() == ID_LEN && id.bytes().all(|b| ID_CHARS.contains(&b))
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(path = %path.display(), error = %e, "Failed to remove stored image");
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! "Did you mean" suggestions for misspelled names.
+
+/// Most suggestions given.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// The `candidates` closest to `name`, ignoring case, closest first. Only
+/// those within a few edits, relative to the length of `name`, are given.
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let name = name.trim().to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(2);
+    let mut scored: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .map(|candidate| (distance(&name, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    scored.sort();
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// `message` followed by the suggestions for `name`, if any.
+pub fn with_suggestions<'a>(message: String, name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    let suggestions = closest(name, candidates);
+    if suggestions.is_empty() {
+        message
+    } else {
+        format!("{}, did you mean: {}?", message, suggestions.join(", "))
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`, in characters.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
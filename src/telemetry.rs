@@ -0,0 +1,89 @@
+//! Exporting the spans of requests over OTLP, eg. to Tempo or Jaeger, with
+//! the `otel` feature. Export is configured with the standard `OTEL_*`
+//! variables, and is on when an OTLP endpoint is set, unless
+//! `OTEL_SDK_DISABLED=true` or `OTEL_TRACES_EXPORTER=none`.
+//!
+//! The spans are those of the `tracing` subscriber the logs are written
+//! with: the request, and at `debug` level its stages. Only the log output
+//! is filtered by `RUST_LOG`, so without an exporter the stages' spans are
+//! disabled where they're declared and cost next to nothing.
+
+use actix_web::http::header::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::settings::{self, env_string};
+
+/// Whether spans are exported, from the `OTEL_*` variables.
+pub fn enabled() -> bool {
+    let disabled = env_string("OTEL_SDK_DISABLED").is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let exporter = env_string("OTEL_TRACES_EXPORTER");
+    let endpoint = env_string("OTEL_EXPORTER_OTLP_ENDPOINT").or_else(|| env_string("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"));
+    !disabled
+        && match exporter.as_deref() {
+            Some(exporter) => exporter.eq_ignore_ascii_case("otlp"),
+            None => endpoint.is_some(),
+        }
+}
+
+/// The layer exporting spans, when export is enabled. An exporter which
+/// can't be set up stops startup. It has to be called on the runtime the
+/// spans are sent from.
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if !enabled() {
+        return None;
+    }
+    // The service is named `inkify` unless the environment names it.
+    let mut resource = Resource::default();
+    if env_string("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.merge(&Resource::new([KeyValue::new("service.name", "inkify")]));
+    }
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)
+        .unwrap_or_else(|e| settings::fail("OTEL_EXPORTER_OTLP_ENDPOINT", e));
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_target("inkify", LevelFilter::DEBUG)),
+    )
+}
+
+/// Makes `span` a child of the trace in the `traceparent` of `headers`, if
+/// there's one.
+pub fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&Headers(headers)));
+    span.set_parent(context);
+}
+
+/// Sends the spans still buffered, on shutdown.
+pub fn shutdown() {
+    if enabled() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
@@ -0,0 +1,80 @@
+//! HTTPS with rustls, for `--tls-cert` and `--tls-key`, and the plain HTTP
+//! listener for `--tls-redirect-http-port` which sends clients to it.
+
+use actix_web::dev::Server;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::Error;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::settings;
+
+/// Loads the certificate chain and private key, both PEM. A file which
+/// can't be read or holds no usable PEM stops startup, naming the file.
+pub fn load(cert_path: &Path, key_path: &Path) -> ServerConfig {
+    let certs = read_certs(cert_path).unwrap_or_else(|e| fail("--tls-cert", cert_path, e));
+    let key = read_key(key_path).unwrap_or_else(|e| fail("--tls-key", key_path, e));
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap_or_else(|e| fail("--tls-key", key_path, e))
+}
+
+fn fail<T>(flag: &str, path: &Path, err: impl std::fmt::Display) -> T {
+    settings::fail(flag, format!("{}: {}", path.display(), err))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?.into_iter().map(Certificate).collect();
+    if certs.is_empty() {
+        return Err(format_err!("No PEM certificates found"));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(format_err!("No PEM private key found"))
+}
+
+/// A plain HTTP server on `port` which redirects every request to the same
+/// host and path on `https_port`.
+pub fn redirect_server(host: &str, port: u16, https_port: u16) -> std::io::Result<Server> {
+    Ok(HttpServer::new(move || {
+        App::new().default_service(web::to(move |req: HttpRequest| redirect(req, https_port)))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind((host, port))?
+    .run())
+}
+
+async fn redirect(req: HttpRequest, https_port: u16) -> HttpResponse {
+    let info = req.connection_info();
+    // Drop the port the client connected to, leaving IPv6 addresses alone.
+    let host = match info.host().rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => info.host(),
+    };
+    let authority = if https_port == 443 {
+        host.to_owned()
+    } else {
+        format!("{}:{}", host, https_port)
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    HttpResponse::MovedPermanently()
+        .append_header(("Location", format!("https://{}{}", authority, path)))
+        .finish()
+}
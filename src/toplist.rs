@@ -0,0 +1,57 @@
+//! The most frequent values of a stream, like the themes requested, in
+//! bounded memory. Only `capacity` values are counted: a new value replaces
+//! the least counted one and takes over its count, plus one (the
+//! space-saving algorithm). Counts of values seen often are exact, or over
+//! by at most what the value they replaced had, so the top of a list much
+//! shorter than its capacity is reliable.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub struct TopList {
+    capacity: usize,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl TopList {
+    pub fn new(capacity: usize) -> Self {
+        TopList {
+            capacity: capacity.max(1),
+            counts: Mutex::new(HashMap::with_capacity(capacity.max(1))),
+        }
+    }
+
+    pub fn record(&self, value: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(value) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if counts.len() >= self.capacity {
+            let least = counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(value, count)| (value.clone(), *count));
+            if let Some((least, least_count)) = least {
+                counts.remove(&least);
+                count += least_count;
+            }
+        }
+        counts.insert(value.to_owned(), count);
+    }
+
+    /// The `n` most counted values, most counted first.
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<(String, u64)> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(value, count)| (value.clone(), *count))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
@@ -0,0 +1,54 @@
+//! Listening on a unix domain socket, for `--unix-socket`, eg. behind a
+//! reverse proxy on the same host.
+
+use std::path::Path;
+
+/// Permissions of the socket when `--unix-socket-mode` isn't given, letting
+/// the server's user and group connect.
+pub const DEFAULT_MODE: u32 = 0o660;
+
+/// Parses an octal file mode, eg. `660` or `0o600`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("`{}` isn't an octal file mode, eg. 660", mode)),
+    }
+}
+
+/// Removes the socket a previous run left behind at `path`. Fails when
+/// another server is still listening on it, or `path` isn't a socket.
+#[cfg(unix)]
+pub fn remove_stale(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and isn't a socket", path.display()));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("{} is in use by another server", path.display()));
+    }
+    std::fs::remove_file(path).map_err(|e| e.to_string())?;
+    tracing::info!(path = %path.display(), "Removed stale socket");
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+/// Removes the socket on shutdown.
+pub fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::warn!(path = %path.display(), error = %e, "Failed to remove socket");
+    }
+}
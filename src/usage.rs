@@ -0,0 +1,263 @@
+//! The help text served at `/`, generated from the OpenAPI document so it
+//! lists the same routes and parameters, with their types and accepted
+//! values, and the built-in defaults taken from `Config::default()` and the
+//! types the parameters are parsed into.
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::config::{self, Config, PngCompression, WindowStyle};
+use crate::rgba::Rgba;
+use crate::watermark::{self, Mark, Watermark};
+use crate::{api, background, badge, derive, preview, whitespace, window};
+
+const MESSAGE: &str = "Hello, world! Welcome to Inkify, a simple API for generating images from code. Think of it like Carbon in API form.";
+
+const OPENAPI: &str = "/openapi.json describes every route, parameter, and response as an OpenAPI 3 document.";
+
+const LEGACY: &str = "Legacy routes. Frozen behavior, errors are returned as {\"error\": \"message\"}.";
+
+const V1: &str = "Versioned routes. Errors are returned as {\"error\": {\"code\", \"message\", \"status\"}}, unknown parameters are rejected, and positive flags (line_number, round_corner, window_controls) are accepted.";
+
+const AUTHENTICATION: &str = "API key authentication is enabled. GET /generate and GET /detect require an `Authorization: Bearer <key>` or `X-Api-Key: <key>` header, and respond with 401 otherwise.";
+
+/// The syntax of the parameters whose values are more than a name or a
+/// number, referred to by each parameter's `grammar`.
+const GRAMMARS: &[(&str, &str)] = &[
+    ("color", "A hex color with a leading #: #RGB, #RGBA, #RRGGBB, or #RRGGBBAA, eg. #282a36, or #00000000 for transparent."),
+    ("font_list", "Font families separated by ;, each optionally followed by =<size> in points (26 when omitted), eg. Hack; SimSun=31. Later families draw the characters earlier ones can't."),
+    ("line_ranges", "Line numbers or inclusive ranges of them, counted from 1 and separated by ;, eg. 1-3;7."),
+    ("highlighted_lines", "Like line_ranges, but each group may end with :<color> to highlight it in that color, eg. 1-3:#2ecc4040;7."),
+    ("gradient", "linear,<angle>deg,<color>,<color>[,...] or radial,<color>,<color>[,...], with two or more colors, eg. linear,45deg,#ff5f6d,#ffc371."),
+];
+
+/// The grammar of each parameter which has one.
+const PARAMETER_GRAMMARS: &[(&str, &str)] = &[
+    ("background", "color"),
+    ("shadow_color", "color"),
+    ("watermark_color", "color"),
+    ("caption_color", "color"),
+    ("indent_guide_color", "color"),
+    ("badge_color", "color"),
+    ("font", "font_list"),
+    ("highlight_lines", "highlighted_lines"),
+    ("redact_lines", "line_ranges"),
+    ("line_range", "line_ranges"),
+    ("background_gradient", "gradient"),
+];
+
+#[derive(Serialize)]
+pub struct Help {
+    message: &'static str,
+    openapi: &'static str,
+    versions: BTreeMap<&'static str, &'static str>,
+    grammars: BTreeMap<&'static str, &'static str>,
+    routes: BTreeMap<String, Route>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authentication: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signing: Option<Value>,
+    /// The defaults set by the server, which replace the built-in ones.
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    defaults: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+struct Route {
+    description: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    parameters: BTreeMap<String, Parameter>,
+}
+
+#[derive(Serialize)]
+struct Parameter {
+    #[serde(rename = "type")]
+    kind: String,
+    description: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grammar: Option<&'static str>,
+}
+
+impl Help {
+    /// The help for the routes of `openapi`, noting the server's
+    /// authentication, signing, and `defaults`.
+    pub fn new(openapi: &Value, api_keys: bool, signing: bool, defaults: &Map<String, Value>) -> Self {
+        let mut routes = BTreeMap::new();
+        for (path, operations) in openapi["paths"].as_object().into_iter().flatten() {
+            for (method, operation) in operations.as_object().into_iter().flatten() {
+                let key = format!("{} {}", method.to_uppercase(), path);
+                let route = route(&key, operation);
+                routes.insert(key, route);
+            }
+        }
+        Help {
+            message: MESSAGE,
+            openapi: OPENAPI,
+            versions: BTreeMap::from([("/", LEGACY), ("/v1/", V1)]),
+            grammars: GRAMMARS.iter().copied().collect(),
+            routes,
+            authentication: api_keys.then_some(AUTHENTICATION),
+            signing: signing.then(signing_help),
+            defaults: defaults.clone(),
+        }
+    }
+}
+
+fn route(key: &str, operation: &Value) -> Route {
+    let description = operation["description"]
+        .as_str()
+        .or_else(|| operation["summary"].as_str())
+        .unwrap_or_default()
+        .to_owned();
+    let defaults = built_in_defaults(key);
+    let parameters = operation["parameters"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|param| {
+            let name = param["name"].as_str()?;
+            let schema = &param["schema"];
+            let kind = schema["type"].as_str().unwrap_or("string").to_owned();
+            // Flags are off unless given, save for the ones with a default.
+            let default = defaults
+                .get(name)
+                .cloned()
+                .or_else(|| (kind == "boolean").then_some(Value::Bool(false)));
+            let parameter = Parameter {
+                description: param["description"].as_str().unwrap_or_default().to_owned(),
+                required: param["required"].as_bool().unwrap_or(false),
+                default: default.filter(|_| param["in"] == "query"),
+                values: schema.get("enum").cloned(),
+                grammar: PARAMETER_GRAMMARS
+                    .iter()
+                    .find(|(grammar_param, _)| *grammar_param == name)
+                    .map(|(_, grammar)| *grammar),
+                kind,
+            };
+            Some((name.to_owned(), parameter))
+        })
+        .collect();
+    Route { description, parameters }
+}
+
+/// The defaults of the parameters of the route `key`, eg. `GET /generate`,
+/// which have a fixed one.
+fn built_in_defaults(key: &str) -> Map<String, Value> {
+    let defaults = match key.split_once(' ').map_or(key, |(_, path)| path) {
+        "/generate" => generate_defaults(),
+        "/detect" => json!({
+            "top_k": crate::DEFAULT_TOP_K,
+            "min_score": 0,
+        }),
+        "/themes/preview" | "/themes/{name}/preview" => json!({ "language": preview::DEFAULT_LANGUAGE }),
+        "/themes/derive" => json!({ "name": derive::DEFAULT_NAME }),
+        _ => json!({}),
+    };
+    match defaults {
+        Value::Object(defaults) => defaults,
+        _ => Map::new(),
+    }
+}
+
+fn generate_defaults() -> Value {
+    let config = Config::default();
+    let watermark = Watermark::new(Mark::Text(String::new()));
+    json!({
+        "theme": config.theme,
+        "background": config.background.to_string(),
+        "shadow_color": config.shadow_color.to_string(),
+        "tab_width": config.tab_width,
+        "line_pad": config.line_pad,
+        "line_offset": config.line_offset,
+        "no_line_number": config.no_line_number,
+        "no_round_corner": config.no_round_corner,
+        "no_window_controls": config.no_window_controls,
+        "line_number": !config.no_line_number,
+        "round_corner": !config.no_round_corner,
+        "window_controls": !config.no_window_controls,
+        "corner_radius": config.corner_radius.unwrap_or(config::DEFAULT_CORNER_RADIUS),
+        "shadow_blur_radius": float(config.shadow_blur_radius),
+        "shadow_offset_x": config.shadow_offset_x,
+        "shadow_offset_y": config.shadow_offset_y,
+        "pad_horiz": config.pad_horiz,
+        "pad_vert": config.pad_vert,
+        "scale": float(config.scale),
+        "window_style": name_of(WindowStyle::NAMES, config.window_style),
+        "png_compression": name_of(PngCompression::NAMES, config.png_compression),
+        "show_whitespace": whitespace::Mode::NAMES
+            .iter()
+            .find(|name| whitespace::Mode::parse(name).is_ok_and(|mode| mode == config.show_whitespace)),
+        "badge_position": name_of(badge::Position::NAMES, config.badge_position),
+        "background_fit": name_of(background::Fit::NAMES, config.background_fit),
+        "background_position": name_of(background::Position::NAMES, config.background_position),
+        "watermark_position": name_of(watermark::Position::NAMES, watermark.position),
+        "watermark_color": Rgba(watermark.color).to_string(),
+        "watermark_opacity": float(watermark.opacity),
+        "watermark_scale": float(watermark.scale),
+        "caption_align": name_of(window::Align::NAMES, window::Align::default()),
+        "response": name_of(api::ResponseMode::NAMES, api::ResponseMode::default()),
+    })
+}
+
+/// The name in `names` which parses to `value`.
+fn name_of<T: FromStr + PartialEq>(names: &[&'static str], value: T) -> Option<&'static str> {
+    names.iter().copied().find(|name| name.parse::<T>().is_ok_and(|parsed| parsed == value))
+}
+
+/// `value` as JSON, without the noise of widening it to an f64, eg. 0.6
+/// rather than 0.6000000238418579.
+fn float(value: f32) -> Value {
+    value.to_string().parse::<f64>().map_or(Value::Null, |value| json!(value))
+}
+
+fn signing_help() -> Value {
+    json!({
+        "description": "Signed URLs are required. GET /generate responds with 403 missing_signature, invalid_signature, or expired_signature unless its query string carries a valid sig, or the request a valid API key. POST /generate requires an API key, since its body can't be signed.",
+        "sig": "The lowercase hex HMAC-SHA256, keyed with the server's secret, of the request path without trailing slashes (eg. /generate or /v1/generate, so the signature only works on that route), a newline, and the canonical query string. To canonicalize the query string, decode the parameters (+ and %XX), drop sig, sort them by name then value bytewise, percent-encode every byte of names and values except A-Z a-z 0-9 - _ . ~ as uppercase %XX (so a space is %20), and join the name=value pairs with &.",
+        "expires": "A unix timestamp after which the URL is rejected. Optional, and signed like any other parameter."
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::ConfigQuery;
+    use utoipa::ToSchema;
+
+    fn help() -> Value {
+        let help = Help::new(&crate::openapi::document(true), true, true, &Map::new());
+        serde_json::from_str(&serde_json::to_string(&help).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn help_is_json_with_every_section() {
+        let help = help();
+        for section in ["message", "openapi", "versions", "grammars", "routes", "authentication", "signing"] {
+            assert!(help.get(section).is_some(), "help is missing `{}`", section);
+        }
+    }
+
+    #[test]
+    fn help_describes_every_generate_parameter() {
+        let help = help();
+        let schema = serde_json::to_value(ConfigQuery::schema().1).unwrap();
+        let params = &help["routes"]["GET /generate"]["parameters"];
+        let body = help["routes"]["POST /generate"]["description"].as_str().unwrap();
+        for name in schema["properties"].as_object().unwrap().keys() {
+            // Snippets can only be passed in a JSON body.
+            if name == "snippets" {
+                assert!(body.contains(name.as_str()), "POST /generate help doesn't mention `{}`", name);
+            } else {
+                assert!(params.get(name).is_some(), "GET /generate help is missing `{}`", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Watermarks drawn onto the final image, after the shadow and background,
+//! so they sit in the padding area rather than over the code.
+
+use anyhow::Error;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use silicon::font::{FontCollection, FontStyle};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::fontcache;
+
+/// Font size of a text watermark, before scaling.
+const TEXT_SIZE: f32 = 14.0;
+
+/// Distance from the edges of the image, before scaling.
+const MARGIN: u32 = 16;
+
+/// Where a watermark is placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    BottomCenter,
+}
+
+impl Position {
+    pub const NAMES: &'static [&'static str] =
+        &["top-left", "top-right", "bottom-left", "bottom-right", "bottom-center"];
+}
+
+impl std::str::FromStr for Position {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "top-left" => Ok(Position::TopLeft),
+            "top-right" => Ok(Position::TopRight),
+            "bottom-left" => Ok(Position::BottomLeft),
+            "bottom-right" => Ok(Position::BottomRight),
+            "bottom-center" => Ok(Position::BottomCenter),
+            _ => Err(format_err!(
+                "Invalid watermark_position `{}`, expected one of: {}",
+                s,
+                Position::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Largest width or height of a watermark image, after scaling.
+pub const MAX_IMAGE_SIZE: u32 = 2048;
+
+/// What a watermark draws.
+#[derive(Debug, Clone)]
+pub enum Mark {
+    Text(String),
+    Image(Arc<DynamicImage>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    pub mark: Mark,
+    pub position: Position,
+    /// Color of a text mark.
+    pub color: Rgba<u8>,
+    /// From 0 (invisible) to 1.
+    pub opacity: f32,
+    /// Multiplier for the natural size of an image mark.
+    pub scale: f32,
+}
+
+impl Watermark {
+    /// A watermark with the default style.
+    pub fn new(mark: Mark) -> Self {
+        Watermark {
+            mark,
+            position: Position::default(),
+            color: Rgba([255, 255, 255, 255]),
+            opacity: 0.6,
+            scale: 1.0,
+        }
+    }
+}
+
+/// Draws `watermark` onto `image`, centered in the padding band along the
+/// edge it's placed at.
+pub fn draw(image: DynamicImage, watermark: &Watermark, conf: &Config) -> Result<DynamicImage, Error> {
+    let mut image = image.to_rgba8();
+    match &watermark.mark {
+        Mark::Text(text) => {
+            let fonts = fontcache::collection(&conf.font_at(TEXT_SIZE))?;
+            let size = (fonts.get_text_len(text), fonts.get_font_height());
+            let (x, y) = place(&image, watermark.position, size, conf);
+            draw_text(&mut image, &fonts, text, x, y, watermark.color, watermark.opacity);
+        }
+        Mark::Image(mark) => {
+            let scale = watermark.scale * conf.scale;
+            let width = ((mark.width() as f32 * scale).round() as u32).clamp(1, MAX_IMAGE_SIZE);
+            let height = ((mark.height() as f32 * scale).round() as u32).clamp(1, MAX_IMAGE_SIZE);
+            let mark = mark.resize_exact(width, height, FilterType::Triangle).to_rgba8();
+            let (x, y) = place(&image, watermark.position, (width, height), conf);
+            draw_image(&mut image, &mark, x, y, watermark.opacity);
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+/// Top left corner of an item of `size` at `position`. Vertically it's
+/// centered in the padding, horizontally kept a margin from the edge.
+fn place(image: &RgbaImage, position: Position, size: (u32, u32), conf: &Config) -> (u32, u32) {
+    let (width, height) = image.dimensions();
+    let margin = conf.scaled(MARGIN);
+    let pad_vert = conf.scaled(conf.pad_vert);
+    let top = pad_vert.saturating_sub(size.1) / 2;
+    let bottom = height.saturating_sub(pad_vert) + top;
+    let left = margin;
+    let right = width.saturating_sub(size.0 + margin);
+    match position {
+        Position::TopLeft => (left, top),
+        Position::TopRight => (right, top),
+        Position::BottomLeft => (left, bottom),
+        Position::BottomRight => (right, bottom),
+        Position::BottomCenter => (width.saturating_sub(size.0) / 2, bottom),
+    }
+}
+
+/// Draws `text` with its top left at `x`, `y`, blended over the image at
+/// `opacity`. The glyphs are rendered to a coverage mask first, since
+/// silicon draws text opaquely.
+pub fn draw_text(
+    image: &mut RgbaImage,
+    fonts: &FontCollection,
+    text: &str,
+    x: u32,
+    y: u32,
+    color: Rgba<u8>,
+    opacity: f32,
+) {
+    let mut mask = RgbaImage::from_pixel(image.width(), image.height(), Rgba([0, 0, 0, 255]));
+    fonts.draw_text_mut(&mut mask, Rgba([255, 255, 255, 255]), x, y, FontStyle::REGULAR, text);
+    blend(image, &mask, color, opacity);
+}
+
+/// Blends `color` over `image` wherever `mask` (white on black) is lit.
+fn blend(image: &mut RgbaImage, mask: &RgbaImage, color: Rgba<u8>, opacity: f32) {
+    let strength = opacity.clamp(0.0, 1.0) * color.0[3] as f32 / 255.0;
+    for (x, y, coverage) in mask.enumerate_pixels() {
+        let alpha = coverage.0[0] as f32 / 255.0 * strength;
+        over(image.get_pixel_mut(x, y), color, alpha);
+    }
+}
+
+/// Blends `mark` over `image` with its top left at `x`, `y`.
+fn draw_image(image: &mut RgbaImage, mark: &RgbaImage, x: u32, y: u32, opacity: f32) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    for (mx, my, color) in mark.enumerate_pixels() {
+        let (px, py) = (x + mx, y + my);
+        if px < image.width() && py < image.height() {
+            let alpha = color.0[3] as f32 / 255.0 * opacity;
+            over(image.get_pixel_mut(px, py), *color, alpha);
+        }
+    }
+}
+
+/// Composites `color` at `alpha` over `pixel`.
+pub fn over(pixel: &mut Rgba<u8>, color: Rgba<u8>, alpha: f32) {
+    if alpha <= 0.0 {
+        return;
+    }
+    let below = pixel.0[3] as f32 / 255.0 * (1.0 - alpha);
+    let out_alpha = alpha + below;
+    let mix = |dst: u8, src: u8| ((src as f32 * alpha + dst as f32 * below) / out_alpha).round() as u8;
+    *pixel = Rgba([
+        mix(pixel.0[0], color.0[0]),
+        mix(pixel.0[1], color.0[1]),
+        mix(pixel.0[2], color.0[2]),
+        (out_alpha * 255.0).round() as u8,
+    ]);
+}
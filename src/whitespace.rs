@@ -0,0 +1,137 @@
+//! Visible whitespace, for the `show_whitespace` parameter. Spaces become a
+//! faint `·` and tabs a faint `→` padded to the next tab stop.
+//!
+//! The markers are swapped in after highlighting, so they don't change the
+//! detected language or the highlighting of the code around them.
+
+use anyhow::Error;
+use lazy_static::lazy_static;
+use std::ops::Range;
+use syntect::highlighting::{Color, FontStyle, Style, Theme};
+
+use crate::redact;
+
+const SPACE: &str = "·";
+const TAB: &str = "→";
+
+/// Opacity of the markers over the background.
+const OPACITY: f32 = 0.35;
+
+lazy_static! {
+    /// Padding after a tab marker is sliced from this.
+    static ref SPACES: String = " ".repeat(u8::MAX as usize);
+}
+
+/// Which whitespace is marked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    All,
+    /// Only whitespace at the end of a line.
+    Trailing,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["true", "false", "trailing"];
+
+    /// Parses `show_whitespace`, which is `None` when disabled.
+    pub fn parse(s: &str) -> Result<Option<Mode>, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "true" | "all" => Ok(Some(Mode::All)),
+            "false" => Ok(None),
+            "trailing" => Ok(Some(Mode::Trailing)),
+            _ => Err(format_err!(
+                "Invalid show_whitespace `{}`, expected one of: {}",
+                s,
+                Mode::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Replaces whitespace in highlighted `lines` with markers. Tabs are
+/// expanded to `tab_width` whether marked or not, so columns can be counted
+/// as characters afterwards. The first `skip` columns of each line, such as
+/// a diff's prefix, and text in the `redacted` ranges of `code` are left
+/// alone.
+pub fn mark<'a>(
+    lines: Vec<Vec<(Style, &'a str)>>,
+    mode: Mode,
+    tab_width: u8,
+    skip: usize,
+    theme: &Theme,
+    code: &str,
+    redacted: &[Range<usize>],
+) -> Vec<Vec<(Style, &'a str)>> {
+    let tab_width = tab_width.max(1) as usize;
+    let faint = |style: Style| Style {
+        foreground: mix(style.background, theme.settings.foreground.unwrap_or(style.foreground)),
+        background: style.background,
+        font_style: FontStyle::empty(),
+    };
+
+    lines
+        .into_iter()
+        .map(|line| {
+            let text: String = line.iter().map(|(_, s)| *s).collect();
+            let trailing = text.trim_end_matches(['\n', '\r']).trim_end_matches([' ', '\t']).len();
+
+            let mut out = vec![];
+            let (mut pos, mut col) = (0, 0);
+            for (style, s) in line {
+                let offset = redact::offset_in(code, s);
+                let is_redacted = |i: usize| {
+                    offset.is_some_and(|offset| redact::is_redacted(redacted, offset + i))
+                };
+                let mut run = 0;
+                for (i, c) in s.char_indices() {
+                    if c != ' ' && c != '\t' {
+                        col += 1;
+                        continue;
+                    }
+                    let marked = col >= skip
+                        && (mode == Mode::All || pos + i >= trailing)
+                        && !is_redacted(i);
+                    let width = match c {
+                        '\t' => tab_width - col % tab_width,
+                        _ => 1,
+                    };
+                    if !marked && c == ' ' {
+                        col += 1;
+                        continue;
+                    }
+
+                    if run < i {
+                        out.push((style, &s[run..i]));
+                    }
+                    match (marked, c) {
+                        (true, ' ') => out.push((faint(style), SPACE)),
+                        (true, _) => {
+                            out.push((faint(style), TAB));
+                            out.push((style, &SPACES[..width - 1]));
+                        }
+                        (false, _) => out.push((style, &SPACES[..width])),
+                    }
+                    run = i + 1;
+                    col += width;
+                }
+                if run < s.len() {
+                    out.push((style, &s[run..]));
+                }
+                pos += s.len();
+            }
+            out.retain(|(_, s)| !s.is_empty());
+            out
+        })
+        .collect()
+}
+
+/// `color` at `OPACITY` over `background`.
+fn mix(background: Color, color: Color) -> Color {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * OPACITY).round() as u8;
+    Color {
+        r: mix(background.r, color.r),
+        g: mix(background.g, color.g),
+        b: mix(background.b, color.b),
+        a: 255,
+    }
+}
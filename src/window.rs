@@ -0,0 +1,516 @@
+//! Window styles, corner radii, captions, and preset canvases which silicon
+//! doesn't draw itself. The formatter renders only the code, and any title
+//! bar, the rounded corners, the shadow, and any caption are added around it
+//! here.
+
+use anyhow::Error;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use silicon::font::{FontCollection, FontStyle};
+use silicon::utils::Background;
+use std::rc::Rc;
+use syntect::highlighting::{Color, Theme};
+
+use crate::config::{Config, WindowStyle};
+use crate::fontcache;
+use crate::watermark;
+
+/// Height of the title bar, before scaling.
+const BAR_HEIGHT: u32 = 40;
+
+/// Horizontal padding of a left-aligned title, before scaling.
+const TITLE_PAD: u32 = 16;
+
+/// Width of each Windows caption button and size of its glyph, before scaling.
+const BUTTON_WIDTH: u32 = 46;
+const GLYPH_SIZE: u32 = 10;
+
+/// Size of the caption font relative to the code's.
+const CAPTION_FONT_RATIO: f32 = 0.8;
+
+/// Space between the window's shadow and the caption, and between caption
+/// lines, before scaling.
+const CAPTION_GAP: u32 = 16;
+const CAPTION_LINE_GAP: u32 = 4;
+
+/// Most lines a caption may have.
+pub const MAX_CAPTION_LINES: usize = 5;
+
+/// Text drawn beneath the window.
+#[derive(Debug, Clone)]
+pub struct Caption {
+    pub lines: Vec<String>,
+    /// Defaults to the theme's foreground.
+    pub color: Option<Rgba<u8>>,
+    pub align: Align,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Align {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+impl Align {
+    pub const NAMES: &'static [&'static str] = &["left", "center", "right"];
+}
+
+impl std::str::FromStr for Align {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "left" => Ok(Align::Left),
+            "center" => Ok(Align::Center),
+            "right" => Ok(Align::Right),
+            _ => Err(format_err!(
+                "Invalid caption_align `{}`, expected one of: {}",
+                s,
+                Align::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// How the windows of several snippets are arranged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Stacked top to bottom.
+    #[default]
+    Vertical,
+    /// Side by side, left to right.
+    Horizontal,
+}
+
+impl Layout {
+    pub const NAMES: &'static [&'static str] = &["vertical", "horizontal"];
+}
+
+impl std::str::FromStr for Layout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vertical" => Ok(Layout::Vertical),
+            "horizontal" => Ok(Layout::Horizontal),
+            _ => Err(format_err!(
+                "Invalid layout `{}`, expected one of: {}",
+                s,
+                Layout::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Space between the windows of snippets, before scaling, when
+/// `snippet_gap` isn't given.
+pub const DEFAULT_SNIPPET_GAP: u32 = 40;
+
+/// Largest space between the windows of snippets, before scaling.
+pub const MAX_SNIPPET_GAP: u32 = 400;
+
+/// Smallest and largest width or height of a custom preset, in pixels.
+pub const MIN_PRESET_SIZE: u32 = 200;
+pub const MAX_PRESET_SIZE: u32 = 4096;
+
+/// Least space between the window's shadow and the edges of a preset
+/// canvas, in pixels.
+const PRESET_MARGIN: u32 = 32;
+
+/// A fixed size canvas the window is centered on, for social media cards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preset {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Preset {
+    pub const NAMES: &'static [&'static str] = &["og", "twitter", "square"];
+}
+
+impl std::str::FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = match s.to_ascii_lowercase().as_str() {
+            "og" => (1200, 630),
+            "twitter" => (1200, 675),
+            "square" => (1080, 1080),
+            size => size
+                .split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .ok_or_else(|| {
+                    format_err!(
+                        "Invalid preset `{}`, expected one of: {}, or WIDTHxHEIGHT",
+                        s,
+                        Preset::NAMES.join(", ")
+                    )
+                })?,
+        };
+        let sizes = MIN_PRESET_SIZE..=MAX_PRESET_SIZE;
+        if !sizes.contains(&width) || !sizes.contains(&height) {
+            return Err(format_err!(
+                "Invalid preset `{}`, width and height must be between {} and {}",
+                s,
+                MIN_PRESET_SIZE,
+                MAX_PRESET_SIZE
+            ));
+        }
+        Ok(Preset { width, height })
+    }
+}
+
+/// Draws the title bar for `conf.window_style` above `code` when silicon
+/// didn't, then rounds the corners and adds the shadow and background. The
+/// corners are cut before the background is added, so it shows through them.
+///
+/// Also returns the factor the window was scaled by to fit a preset canvas,
+/// when one is set.
+pub fn decorate(code: DynamicImage, conf: &Config, theme: &Theme) -> Result<(DynamicImage, Option<f32>), Error> {
+    let image = frame(code, conf, theme)?;
+    if conf.caption.is_none() && conf.preset.is_none() {
+        let shadow_adder = conf.get_shadow_adder(Some(image.dimensions()))?;
+        return Ok((shadow_adder.apply_to(&DynamicImage::ImageRgba8(image)), None));
+    }
+
+    // The window, its shadow, and any caption are drawn over a transparent
+    // background first, since the final size depends on them.
+    let pads = layer_pads(conf);
+    let window = image.dimensions();
+    let layer = conf
+        .shadow_adder_with(Background::Solid(Rgba([0, 0, 0, 0])), pads.0, pads.1)
+        .apply_to(&DynamicImage::ImageRgba8(image))
+        .to_rgba8();
+    finish(layer, window, pads, conf, theme)
+}
+
+/// Draws the title bar for `conf.window_style` above `code` when silicon
+/// didn't, and rounds the corners: the window, without its shadow or
+/// background.
+pub fn frame(code: DynamicImage, conf: &Config, theme: &Theme) -> Result<RgbaImage, Error> {
+    let mut image = code.to_rgba8();
+    if conf.window_style.is_composited() {
+        image = draw_title_bar(image, conf, theme)?;
+    }
+    round_corners(&mut image, conf.corner_radius());
+    Ok(image)
+}
+
+/// Arranges the `windows` of several snippets, each out of `frame`, by
+/// `conf.layout` with `conf.snippet_gap` between them, on one background.
+/// Every shadow is drawn before any window, so the shadow of one never
+/// falls over the next, and any caption goes beneath them all.
+///
+/// Also returns the factor the windows were scaled by to fit a preset
+/// canvas, when one is set.
+pub fn arrange(windows: Vec<RgbaImage>, conf: &Config, theme: &Theme) -> Result<(DynamicImage, Option<f32>), Error> {
+    let gap = conf.scaled(conf.snippet_gap);
+    let vertical = conf.layout != Some(Layout::Horizontal);
+    let mut offsets = Vec::with_capacity(windows.len());
+    let (mut width, mut height) = (0, 0);
+    for window in &windows {
+        let (window_width, window_height) = window.dimensions();
+        if vertical {
+            let y = if offsets.is_empty() { 0 } else { height + gap };
+            offsets.push((0, y));
+            width = width.max(window_width);
+            height = y + window_height;
+        } else {
+            let x = if offsets.is_empty() { 0 } else { width + gap };
+            offsets.push((x, 0));
+            width = x + window_width;
+            height = height.max(window_height);
+        }
+    }
+
+    let (pad_horiz, pad_vert) = layer_pads(conf);
+    let mut layer = RgbaImage::new(width + 2 * pad_horiz, height + 2 * pad_vert);
+    let shadow_adder = conf.shadow_adder_with(Background::Solid(Rgba([0, 0, 0, 0])), pad_horiz, pad_vert);
+    for (window, (x, y)) in windows.iter().zip(&offsets) {
+        let shadow = shadow_adder.apply_to(&DynamicImage::ImageRgba8(window.clone()));
+        image::imageops::overlay(&mut layer, &shadow, *x as i64, *y as i64);
+    }
+    for (window, (x, y)) in windows.iter().zip(&offsets) {
+        image::imageops::overlay(&mut layer, window, (x + pad_horiz) as i64, (y + pad_vert) as i64);
+    }
+    finish(layer, (width, height), (pad_horiz, pad_vert), conf, theme)
+}
+
+/// The padding around the windows on a transparent layer. A preset canvas
+/// replaces the padding, so the windows only need room for their shadow.
+fn layer_pads(conf: &Config) -> (u32, u32) {
+    match conf.preset {
+        Some(_) => shadow_extent(conf),
+        None => (conf.scaled(conf.pad_horiz), conf.scaled(conf.pad_vert)),
+    }
+}
+
+/// Adds any caption beneath the windows `window` big on `layer`, then puts
+/// the layer on its background or preset canvas.
+fn finish(
+    mut layer: RgbaImage,
+    window: (u32, u32),
+    pads: (u32, u32),
+    conf: &Config,
+    theme: &Theme,
+) -> Result<(DynamicImage, Option<f32>), Error> {
+    if let Some(caption) = &conf.caption {
+        layer = draw_caption(layer, window, pads, caption, conf, theme)?;
+    }
+
+    match conf.preset {
+        Some(preset) => {
+            let (image, factor) = center_on(layer, preset, conf)?;
+            Ok((image, Some(factor)))
+        }
+        None => {
+            let mut image = conf.background_at(layer.width(), layer.height())?;
+            image::imageops::overlay(&mut image, &layer, 0, 0);
+            Ok((DynamicImage::ImageRgba8(image), None))
+        }
+    }
+}
+
+/// How far the shadow reaches beyond the window horizontally and
+/// vertically, in pixels.
+fn shadow_extent(conf: &Config) -> (u32, u32) {
+    let blur = (conf.shadow_blur_radius * conf.scale).ceil().max(0.0) as u32;
+    let offset = |offset: i32| (offset as f32 * conf.scale).round().abs() as u32;
+    (blur + offset(conf.shadow_offset_x), blur + offset(conf.shadow_offset_y))
+}
+
+/// Centers `layer` on the preset canvas over the background, scaling it
+/// down when it doesn't fit inside the margin. Returns the canvas and the
+/// factor `layer` was scaled by.
+fn center_on(layer: RgbaImage, preset: Preset, conf: &Config) -> Result<(DynamicImage, f32), Error> {
+    let (width, height) = (preset.width, preset.height);
+    let room = |size: u32| size.saturating_sub(2 * PRESET_MARGIN).max(1) as f32;
+    let factor = (room(width) / layer.width() as f32)
+        .min(room(height) / layer.height() as f32)
+        .min(1.0);
+    let layer = if factor < 1.0 {
+        let scaled_width = ((layer.width() as f32 * factor).round() as u32).max(1);
+        let scaled_height = ((layer.height() as f32 * factor).round() as u32).max(1);
+        image::imageops::resize(&layer, scaled_width, scaled_height, FilterType::Lanczos3)
+    } else {
+        layer
+    };
+
+    let mut image = conf.background_at(width, height)?;
+    let x = (width.saturating_sub(layer.width()) / 2) as i64;
+    let y = (height.saturating_sub(layer.height()) / 2) as i64;
+    image::imageops::overlay(&mut image, &layer, x, y);
+    Ok((DynamicImage::ImageRgba8(image), factor))
+}
+
+/// The size `decorate` gives a window `code` big, out of the formatter,
+/// without drawing it.
+pub fn decorated_size(code: (u32, u32), conf: &Config) -> Result<(u32, u32), Error> {
+    let (width, mut height) = code;
+    if conf.window_style.is_composited() {
+        height += conf.scaled(BAR_HEIGHT);
+    }
+    if let Some(preset) = conf.preset {
+        return Ok((preset.width, preset.height));
+    }
+    let (layer_width, layer_height) = conf.canvas_size(width, height);
+    match &conf.caption {
+        Some(caption) => {
+            let pad_vert = conf.scaled(conf.pad_vert);
+            let caption = CaptionLayout::new((width, height), pad_vert, caption, conf)?;
+            Ok((layer_width, layer_height.max(caption.bottom(pad_vert))))
+        }
+        None => Ok((layer_width, layer_height)),
+    }
+}
+
+/// Where a caption goes beneath a window, with its lines shortened to fit.
+struct CaptionLayout {
+    fonts: Rc<FontCollection>,
+    lines: Vec<String>,
+    line_height: u32,
+    top: u32,
+}
+
+impl CaptionLayout {
+    fn new(window: (u32, u32), pad_vert: u32, caption: &Caption, conf: &Config) -> Result<Self, Error> {
+        let (window_width, window_height) = window;
+        let font: Vec<(String, f32)> = conf
+            .title_font()
+            .into_iter()
+            .map(|(name, size)| (name, size * CAPTION_FONT_RATIO))
+            .collect();
+        let fonts = fontcache::collection(&font)?;
+        let lines: Vec<String> = caption
+            .lines
+            .iter()
+            .map(|line| truncate(&fonts, line, window_width))
+            .collect();
+        let line_height = fonts.get_font_height() + conf.scaled(CAPTION_LINE_GAP);
+
+        let shadow_below = (conf.shadow_blur_radius * conf.scale).ceil().max(0.0) as u32
+            + (conf.shadow_offset_y as f32 * conf.scale).round().max(0.0) as u32;
+        let top = pad_vert + window_height + shadow_below + conf.scaled(CAPTION_GAP);
+        Ok(CaptionLayout {
+            fonts,
+            lines,
+            line_height,
+            top,
+        })
+    }
+
+    /// The least height of the layer, to fit the caption with half the
+    /// padding below it.
+    fn bottom(&self, pad_vert: u32) -> u32 {
+        self.top + self.line_height * self.lines.len() as u32 + pad_vert / 2
+    }
+}
+
+/// Draws the caption beneath the window on `layer`, the window and its
+/// shadow over a transparent background. The bottom padding grows as needed
+/// to fit the caption below the shadow.
+fn draw_caption(
+    layer: RgbaImage,
+    window: (u32, u32),
+    (pad_horiz, pad_vert): (u32, u32),
+    caption: &Caption,
+    conf: &Config,
+    theme: &Theme,
+) -> Result<RgbaImage, Error> {
+    let window_width = window.0;
+    let layout = CaptionLayout::new(window, pad_vert, caption, conf)?;
+    let height = layer.height().max(layout.bottom(pad_vert));
+
+    let mut image = RgbaImage::new(layer.width(), height);
+    image::imageops::overlay(&mut image, &layer, 0, 0);
+
+    let foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+    let color = caption.color.unwrap_or_else(|| to_rgba(foreground));
+    for (i, line) in layout.lines.iter().enumerate() {
+        let text_width = layout.fonts.get_text_len(line);
+        let x = match caption.align {
+            Align::Left => pad_horiz,
+            Align::Center => pad_horiz + window_width.saturating_sub(text_width) / 2,
+            Align::Right => pad_horiz + window_width.saturating_sub(text_width),
+        };
+        let y = layout.top + layout.line_height * i as u32;
+        watermark::draw_text(&mut image, &layout.fonts, line, x, y, color, 1.0);
+    }
+    Ok(image)
+}
+
+/// Shortens `text` with an ellipsis until it fits in `max_width` pixels.
+fn truncate(fonts: &FontCollection, text: &str, max_width: u32) -> String {
+    if fonts.get_text_len(text) <= max_width {
+        return text.to_owned();
+    }
+    let mut chars: Vec<char> = text.chars().collect();
+    while chars.pop().is_some() {
+        let shortened = format!("{}…", chars.iter().collect::<String>().trim_end());
+        if fonts.get_text_len(&shortened) <= max_width {
+            return shortened;
+        }
+    }
+    "…".to_owned()
+}
+
+fn draw_title_bar(code: RgbaImage, conf: &Config, theme: &Theme) -> Result<RgbaImage, Error> {
+    let bar_height = conf.scaled(BAR_HEIGHT);
+    let background = theme.settings.background.unwrap_or(Color::BLACK);
+    let foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+
+    let mut image = RgbaImage::from_pixel(
+        code.width(),
+        code.height() + bar_height,
+        to_rgba(shade(background)),
+    );
+    image::imageops::overlay(&mut image, &code, 0, bar_height as i64);
+
+    let buttons = conf.window_style == WindowStyle::Windows && !conf.no_window_controls;
+    if buttons {
+        draw_caption_buttons(&mut image, conf, bar_height, to_rgba(foreground));
+    }
+
+    if let Some(title) = conf.window_title.as_deref().filter(|title| !title.is_empty()) {
+        let fonts = fontcache::collection(&conf.title_font())?;
+        let text_width = fonts.get_text_len(title);
+        let y = bar_height.saturating_sub(fonts.get_font_height()) / 2;
+        let x = match conf.window_style {
+            WindowStyle::Windows => conf.scaled(TITLE_PAD),
+            _ => image.width().saturating_sub(text_width) / 2,
+        };
+        fonts.draw_text_mut(&mut image, to_rgba(foreground), x, y, FontStyle::REGULAR, title);
+    }
+
+    Ok(image)
+}
+
+/// Draws minimize, maximize, and close glyphs at the right of the title bar.
+fn draw_caption_buttons(image: &mut RgbaImage, conf: &Config, bar_height: u32, color: Rgba<u8>) {
+    let button_width = conf.scaled(BUTTON_WIDTH);
+    let glyph = conf.scaled(GLYPH_SIZE);
+    let stroke = conf.scaled(1).max(1);
+    let top = bar_height.saturating_sub(glyph) / 2;
+
+    for (i, button) in ["minimize", "maximize", "close"].iter().enumerate() {
+        let right = image.width().saturating_sub(button_width * (2 - i as u32));
+        let left = right.saturating_sub(button_width) + button_width.saturating_sub(glyph) / 2;
+        for dy in 0..glyph {
+            for dx in 0..glyph {
+                let on = match *button {
+                    "minimize" => dy.abs_diff(glyph / 2) < stroke,
+                    "maximize" => dx < stroke || dy < stroke || glyph - dx <= stroke || glyph - dy <= stroke,
+                    _ => dx.abs_diff(dy) < stroke || dx.abs_diff(glyph - 1 - dy) < stroke,
+                };
+                if on && left + dx < image.width() {
+                    image.put_pixel(left + dx, top + dy, color);
+                }
+            }
+        }
+    }
+}
+
+/// Makes the pixels outside each rounded corner transparent, blending the
+/// edge for a little anti-aliasing.
+fn round_corners(image: &mut RgbaImage, radius: u32) {
+    let (width, height) = image.dimensions();
+    let radius = radius.min(width / 2).min(height / 2);
+    let r = radius as f32;
+    for y in 0..radius {
+        for x in 0..radius {
+            let (dx, dy) = (r - x as f32 - 0.5, r - y as f32 - 0.5);
+            let coverage = (r - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0);
+            if coverage >= 1.0 {
+                continue;
+            }
+            for (px, py) in [
+                (x, y),
+                (width - 1 - x, y),
+                (x, height - 1 - y),
+                (width - 1 - x, height - 1 - y),
+            ] {
+                let pixel = image.get_pixel_mut(px, py);
+                pixel.0[3] = (pixel.0[3] as f32 * coverage) as u8;
+            }
+        }
+    }
+}
+
+/// The title bar color: the background, lightened slightly on dark themes
+/// and darkened on light ones so the bar stands out from the code.
+fn shade(color: Color) -> Color {
+    let dark = (color.r as u32 + color.g as u32 + color.b as u32) < 384;
+    let shift = |c: u8| if dark { c.saturating_add(12) } else { c.saturating_sub(12) };
+    Color {
+        r: shift(color.r),
+        g: shift(color.g),
+        b: shift(color.b),
+        a: color.a,
+    }
+}
+
+fn to_rgba(color: Color) -> Rgba<u8> {
+    Rgba([color.r, color.g, color.b, color.a])
+}
@@ -0,0 +1,219 @@
+//! Soft wrapping of highlighted lines for the `max_width` parameter, and the
+//! gutter drawn in place of silicon's when rows aren't numbered consecutively.
+//!
+//! Lines are highlighted whole and only then split into rows, so a wrapped
+//! string or comment keeps its highlighting and `highlight_lines` keeps
+//! referring to the logical line.
+
+use syntect::highlighting::{Color, FontStyle, Style, Theme};
+
+/// Bounds of the `max_width` parameter, in columns.
+pub const MIN_COLUMNS: usize = 20;
+pub const MAX_COLUMNS: usize = 1000;
+
+/// Marks continuation rows in the gutter.
+const CONTINUATION: &str = "↪";
+
+/// Indentation is repeated on continuation rows by slicing this, so it's
+/// also the longest indentation which is preserved.
+const SPACES: &str = "                                                                ";
+
+/// Replaces tabs with spaces up to the next multiple of `tab_width`, so
+/// columns can be counted as characters.
+pub fn expand_tabs(code: &str, tab_width: u8) -> String {
+    let tab_width = tab_width.max(1) as usize;
+    let mut out = String::with_capacity(code.len());
+    let mut col = 0;
+    for c in code.chars() {
+        match c {
+            '\t' => {
+                let n = tab_width - col % tab_width;
+                out.extend(std::iter::repeat(' ').take(n));
+                col += n;
+            }
+            '\n' => {
+                out.push(c);
+                col = 0;
+            }
+            c => {
+                out.push(c);
+                col += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Highlighted code split into rows of at most a given number of columns.
+pub struct Wrapped<'a> {
+    /// Each row, with the index of the logical line it belongs to.
+    rows: Vec<(usize, Vec<(Style, &'a str)>)>,
+}
+
+/// Splits each highlighted line which is longer than `max_columns` into
+/// several rows, preferring to break after a space. Continuation rows repeat
+/// the line's indentation, unless it would take up more than half the row.
+pub fn wrap<'a>(lines: &[Vec<(Style, &'a str)>], max_columns: usize) -> Wrapped<'a> {
+    let mut rows = vec![];
+    for (index, ranges) in lines.iter().enumerate() {
+        let text: String = ranges.iter().map(|(_, s)| *s).collect();
+        let indent = text.chars().take_while(|c| *c == ' ').count();
+        let indent = if indent <= max_columns / 2 && indent <= SPACES.len() {
+            indent
+        } else {
+            0
+        };
+
+        let breaks = break_points(&text, max_columns, indent);
+        for (i, row) in split_ranges(ranges, &breaks).into_iter().enumerate() {
+            if i > 0 && indent > 0 {
+                let style = row.first().map(|(style, _)| *style).unwrap_or_default();
+                let mut indented = vec![(style, &SPACES[..indent])];
+                indented.extend(row);
+                rows.push((index, indented));
+            } else {
+                rows.push((index, row));
+            }
+        }
+    }
+    Wrapped { rows }
+}
+
+/// Byte offsets in `text` at which continuation rows start.
+fn break_points(text: &str, max_columns: usize, indent: usize) -> Vec<usize> {
+    let mut breaks = vec![];
+    let mut width = max_columns;
+    let mut row_start = 0;
+    let mut col = 0;
+    let mut last_space = None;
+    let mut past_indent = false;
+    for (i, c) in text.char_indices() {
+        if c == '\n' || c == '\r' {
+            break;
+        }
+        if col == width {
+            width = max_columns - indent;
+            // Carry the last word over to the next row, if it will fit.
+            let at = match last_space {
+                Some(space) if space > row_start && text[space..i].chars().count() < width => space,
+                _ => i,
+            };
+            breaks.push(at);
+            col = text[at..i].chars().count();
+            row_start = at;
+            last_space = None;
+        }
+        col += 1;
+        if c != ' ' {
+            past_indent = true;
+        } else if past_indent {
+            last_space = Some(i + c.len_utf8());
+        }
+    }
+    breaks
+}
+
+/// Splits styled ranges into rows at the given byte offsets of their
+/// concatenated text. Offsets always fall on character boundaries.
+fn split_ranges<'a>(ranges: &[(Style, &'a str)], breaks: &[usize]) -> Vec<Vec<(Style, &'a str)>> {
+    let mut rows = vec![vec![]];
+    let mut breaks = breaks.iter().peekable();
+    let mut offset = 0;
+    for &(style, mut s) in ranges {
+        while let Some(&&at) = breaks.peek() {
+            if at >= offset + s.len() {
+                break;
+            }
+            let (head, tail) = s.split_at(at - offset);
+            if !head.is_empty() {
+                rows.last_mut().unwrap().push((style, head));
+            }
+            rows.push(vec![]);
+            offset = at;
+            s = tail;
+            breaks.next();
+        }
+        if !s.is_empty() {
+            rows.last_mut().unwrap().push((style, s));
+        }
+        offset += s.len();
+    }
+    rows
+}
+
+impl<'a> Wrapped<'a> {
+    /// Whether any line was split.
+    pub fn is_wrapped(&self) -> bool {
+        self.rows.windows(2).any(|w| w[0].0 == w[1].0)
+    }
+
+    /// The index of the logical line each row belongs to.
+    pub fn row_lines(&self) -> Vec<usize> {
+        self.rows.iter().map(|(index, _)| *index).collect()
+    }
+
+    /// Maps 1-based logical line numbers to the rows they were split into.
+    pub fn highlight_lines(&self, lines: &[u32]) -> Vec<u32> {
+        self.rows
+            .iter()
+            .enumerate()
+            .filter(|(_, (index, _))| lines.contains(&(*index as u32 + 1)))
+            .map(|(row, _)| row as u32 + 1)
+            .collect()
+    }
+
+    /// Gutter text for each row: the number of its line on a line's first
+    /// row, and a continuation marker on the rest. `numbers` holds the number
+    /// shown for each line, or `None` for a separator row.
+    pub fn gutter(&self, numbers: &[Option<u32>]) -> Vec<String> {
+        let labels: Vec<String> = numbers
+            .iter()
+            .map(|n| n.map(|n| n.to_string()).unwrap_or_default())
+            .collect();
+        self.gutter_with(&labels)
+    }
+
+    /// Gutter text for each row from a label for each line, such as the two
+    /// line numbers of a diff, with continuation rows marked as for `gutter`.
+    pub fn gutter_with(&self, labels: &[String]) -> Vec<String> {
+        let width = labels.iter().map(|label| label.chars().count()).max().unwrap_or(0).max(1);
+        let mut previous = None;
+        self.rows
+            .iter()
+            .map(|(index, _)| {
+                let label = if previous == Some(*index) {
+                    CONTINUATION
+                } else {
+                    labels.get(*index).map_or("", |label| label.as_str())
+                };
+                previous = Some(*index);
+                format!("{:>width$}  ", label, width = width)
+            })
+            .collect()
+    }
+
+    /// The rows, each prefixed with its gutter text when `gutter` isn't empty.
+    pub fn into_lines(self, gutter: &'a [String], style: Style) -> Vec<Vec<(Style, &'a str)>> {
+        self.rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, (_, row))| match gutter.get(i) {
+                Some(label) => std::iter::once((style, label.as_str())).chain(row).collect(),
+                None => row,
+            })
+            .collect()
+    }
+}
+
+/// Style of the gutter drawn for wrapped code, matching the theme's.
+pub fn gutter_style(theme: &Theme) -> Style {
+    Style {
+        foreground: theme
+            .settings
+            .gutter_foreground
+            .or(theme.settings.foreground)
+            .unwrap_or(Color::WHITE),
+        background: theme.settings.background.unwrap_or(Color::BLACK),
+        font_style: FontStyle::empty(),
+    }
+}
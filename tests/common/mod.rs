@@ -0,0 +1,16 @@
+//! Setup shared by the integration tests which render.
+
+use inkify::Config;
+
+pub use silicon::assets::HighlightingAssets;
+
+/// A deterministic `Config` rendering `code` as `language`, with
+/// `configure` applied last.
+pub fn config(code: &str, language: &str, configure: impl FnOnce(&mut Config)) -> Config {
+    let mut conf = Config::default();
+    conf.code = code.to_owned();
+    conf.language = Some(language.to_owned());
+    conf.deterministic = true;
+    configure(&mut conf);
+    conf
+}
@@ -0,0 +1,74 @@
+//! Checks that `inkify::estimate` agrees with the size `inkify::render`
+//! draws at, or HEAD requests and `/estimate` report the wrong size.
+
+mod common;
+
+use common::HighlightingAssets;
+use inkify::Config;
+
+/// How many pixels the estimated width or height may be off by.
+const TOLERANCE: u32 = 2;
+
+const CODE: &str = "fn main() {\n    let greeting = \"Hello, world!\";\n    println!(\"{}\", greeting);\n}\n";
+
+/// Renders `conf`, after `configure`, and compares its size with the
+/// estimate.
+fn check(configure: fn(&mut Config)) {
+    let assets = HighlightingAssets::new();
+    let conf = common::config(CODE, "rs", configure);
+
+    let rendered = inkify::render(&conf, &assets.syntax_set, &assets.theme_set).unwrap();
+    let decoded = image::load_from_memory(&rendered.bytes).unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (rendered.width, rendered.height));
+
+    let estimate = inkify::estimate(&conf).unwrap();
+    assert!(
+        estimate.width.abs_diff(rendered.width) <= TOLERANCE && estimate.height.abs_diff(rendered.height) <= TOLERANCE,
+        "estimated {}x{}, rendered {}x{}",
+        estimate.width,
+        estimate.height,
+        rendered.width,
+        rendered.height
+    );
+}
+
+#[test]
+fn defaults() {
+    check(|_| {});
+}
+
+#[test]
+fn with_a_window_title() {
+    check(|conf| conf.window_title = Some("main.rs".to_owned()));
+}
+
+#[test]
+fn without_line_numbers_or_window_controls() {
+    check(|conf| {
+        conf.no_line_number = true;
+        conf.no_window_controls = true;
+    });
+}
+
+#[test]
+fn with_padding_and_a_line_offset() {
+    check(|conf| {
+        conf.pad_horiz = 20;
+        conf.pad_vert = 120;
+        conf.line_pad = 8;
+        conf.line_offset = 998;
+    });
+}
+
+#[test]
+fn wrapped() {
+    check(|conf| conf.max_width = Some(20));
+}
+
+#[test]
+fn scaled() {
+    check(|conf| {
+        conf.scale = 2.0;
+        conf.window_title = Some("main.rs".to_owned());
+    });
+}
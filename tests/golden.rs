@@ -0,0 +1,162 @@
+//! Renders a set of reference snippets across a few themes in deterministic
+//! mode and compares them with the golden PNGs checked in under
+//! `tests/golden`, so changes to the pipeline which move pixels are caught.
+//!
+//! Run `cargo test --test golden -- --ignored` to compare, and
+//! `INKIFY_BLESS=1 cargo test --test golden -- --ignored` to regenerate the
+//! goldens after an intended change. The output depends on the fonts
+//! installed, so the tests are ignored by default, and goldens should be
+//! regenerated and compared in the Docker image.
+
+mod common;
+
+use common::HighlightingAssets;
+use inkify::Config;
+use std::path::PathBuf;
+
+/// How far apart a channel of a pixel may be before the pixel differs.
+const TOLERANCE: u8 = 2;
+
+/// The fraction of pixels which may differ before a render fails.
+const MAX_DIFFERING: f64 = 0.001;
+
+const THEMES: &[&str] = &["Dracula", "GitHub", "OneHalfDark", "Solarized (light)"];
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn blessing() -> bool {
+    std::env::var("INKIFY_BLESS").is_ok_and(|value| value == "1")
+}
+
+/// How many pixels of `actual` differ from `expected` by more than
+/// `TOLERANCE` in any channel, or `None` when the sizes differ.
+fn differing(actual: &image::RgbaImage, expected: &image::RgbaImage) -> Option<usize> {
+    if actual.dimensions() != expected.dimensions() {
+        return None;
+    }
+    let count = actual
+        .pixels()
+        .zip(expected.pixels())
+        .filter(|(a, e)| a.0.iter().zip(e.0.iter()).any(|(a, e)| a.abs_diff(*e) > TOLERANCE))
+        .count();
+    Some(count)
+}
+
+fn file_name(name: &str, theme: &str) -> String {
+    let theme: String = theme
+        .chars()
+        .filter_map(|c| match c {
+            'a'..='z' | '0'..='9' => Some(c),
+            'A'..='Z' => Some(c.to_ascii_lowercase()),
+            ' ' | '-' => Some('-'),
+            _ => None,
+        })
+        .collect();
+    format!("{}-{}.png", name, theme)
+}
+
+/// Renders `code` as `language` in every theme, with `configure` applied,
+/// and compares each render with its golden, or overwrites the golden when
+/// blessing. Panics listing every render which failed.
+fn check(name: &str, language: &str, code: &str, configure: fn(&mut Config)) {
+    let assets = HighlightingAssets::new();
+    let dir = golden_dir();
+    if blessing() {
+        std::fs::create_dir_all(&dir).unwrap();
+    }
+
+    let mut failures = vec![];
+    for theme in THEMES {
+        let conf = common::config(code, language, |conf| {
+            conf.theme = (*theme).to_owned();
+            conf.window_title = Some(name.to_owned());
+            configure(conf);
+        });
+
+        let path = dir.join(file_name(name, theme));
+        let rendered = match inkify::render(&conf, &assets.syntax_set, &assets.theme_set) {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                failures.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+        if blessing() {
+            std::fs::write(&path, &rendered.bytes).unwrap();
+            continue;
+        }
+
+        let expected = match image::open(&path) {
+            Ok(expected) => expected.to_rgba8(),
+            Err(e) => {
+                failures.push(format!("{}: {}, run with INKIFY_BLESS=1 to create it", path.display(), e));
+                continue;
+            }
+        };
+        let actual = image::load_from_memory(&rendered.bytes).unwrap().to_rgba8();
+        match differing(&actual, &expected) {
+            None => failures.push(format!(
+                "{}: rendered {}x{}, expected {}x{}",
+                path.display(),
+                actual.width(),
+                actual.height(),
+                expected.width(),
+                expected.height()
+            )),
+            Some(count) if count as f64 > MAX_DIFFERING * (actual.width() * actual.height()) as f64 => {
+                failures.push(format!("{}: {} pixels differ", path.display(), count));
+            }
+            Some(_) => {}
+        }
+    }
+    assert!(failures.is_empty(), "renders differ from their goldens:\n{}", failures.join("\n"));
+}
+
+#[test]
+#[ignore = "depends on the installed fonts, run in the Docker image"]
+fn rust() {
+    check(
+        "rust",
+        "rs",
+        "fn main() {\n    let greeting = \"Hello, world!\";\n    println!(\"{}\", greeting);\n}\n",
+        |_| {},
+    );
+}
+
+#[test]
+#[ignore = "depends on the installed fonts, run in the Docker image"]
+fn python_wrapped() {
+    check(
+        "python-wrapped",
+        "py",
+        "def greet(name):\n    return f\"Hello, {name}! This line is long enough that it has to wrap.\"\n",
+        |conf| conf.max_width = Some(40),
+    );
+}
+
+#[test]
+#[ignore = "depends on the installed fonts, run in the Docker image"]
+fn javascript_guides() {
+    check(
+        "javascript-guides",
+        "js",
+        "function outer() {\n\tif (ready) {\n\t\treturn inner();\n\t}\n}\n",
+        |conf| {
+            conf.indent_guides = true;
+            conf.show_language = true;
+        },
+    );
+}
+
+#[test]
+#[ignore = "depends on the installed fonts, run in the Docker image"]
+fn diff() {
+    check(
+        "diff",
+        "rs",
+        " fn main() {\n-    println!(\"Hello\");\n+    println!(\"Hello, world!\");\n }\n",
+        |conf| conf.diff = true,
+    );
+}